@@ -0,0 +1,89 @@
+//! Run a sentinel monitoring mini-redis primaries, failing over to one of
+//! their replicas when a primary is down.
+//!
+//! ```text
+//! mini-redis-sentinel [--port <port>] [--down-after <ms>] [--failover-timeout <ms>]
+//!     --monitor <name> <host> <port> <quorum> [--monitor ...]
+//! ```
+//!
+//! The timeouts apply to every primary monitored. Several sentinels are
+//! started with the same `--monitor` options, on different ports.
+
+use std::process::exit;
+
+use my_mini_redis::sentinel::{self, Monitor};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::time::Duration;
+
+const USAGE: &str = "usage: mini-redis-sentinel [--port <port>] [--down-after <ms>] \
+                     [--failover-timeout <ms>] --monitor <name> <host> <port> <quorum> \
+                     [--monitor ...]";
+
+#[tokio::main]
+async fn main() {
+    let (port, monitors) = match parse_args(std::env::args().skip(1)) {
+        Some(args) => args,
+        None => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!(
+                "mini-redis-sentinel: cannot listen on port {}: {}",
+                port, err
+            );
+            exit(1);
+        }
+    };
+
+    sentinel::run(listener, monitors, signal::ctrl_c()).await;
+}
+
+/// Parse the port and the primaries to monitor from the command line
+/// arguments.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<(u16, Vec<Monitor>)> {
+    let mut port = sentinel::DEFAULT_PORT;
+    let mut down_after = None;
+    let mut failover_timeout = None;
+    let mut monitors = vec![];
+
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--port" => port = args.next()?.parse().ok()?,
+            "--down-after" => down_after = Some(parse_ms(&args.next()?)?),
+            "--failover-timeout" => failover_timeout = Some(parse_ms(&args.next()?)?),
+            "--monitor" => {
+                let name = args.next()?;
+                let host = args.next()?;
+                let port = args.next()?.parse().ok()?;
+                let quorum = args.next()?.parse().ok()?;
+                monitors.push(Monitor::new(name, host, port, quorum));
+            }
+            _ => return None,
+        }
+    }
+
+    if monitors.is_empty() {
+        return None;
+    }
+
+    for monitor in &mut monitors {
+        if let Some(down_after) = down_after {
+            monitor.down_after = down_after;
+        }
+        if let Some(failover_timeout) = failover_timeout {
+            monitor.failover_timeout = failover_timeout;
+        }
+    }
+
+    Some((port, monitors))
+}
+
+fn parse_ms(value: &str) -> Option<Duration> {
+    value.parse().ok().map(Duration::from_millis)
+}
@@ -0,0 +1,97 @@
+//! Import the keys of a Redis RDB file into a running mini-redis server.
+//!
+//! ```text
+//! rdb-import <file> [--host <host>] [--port <port>]
+//! ```
+//!
+//! Each key is sent as the command recreating it, with its expiration. The
+//! types mini-redis does not support are skipped and counted.
+
+use std::collections::BTreeMap;
+use std::process::exit;
+
+use my_mini_redis::{rdb, Connection, Frame, DEFAULT_PORT};
+use tokio::net::TcpStream;
+
+/// Number of commands sent before reading their replies.
+const BATCH_SIZE: usize = 1000;
+
+#[tokio::main]
+async fn main() {
+    let (path, host, port) = match parse_args(std::env::args().skip(1)) {
+        Some(args) => args,
+        None => {
+            eprintln!("usage: rdb-import <file> [--host <host>] [--port <port>]");
+            exit(2);
+        }
+    };
+
+    if let Err(err) = run(&path, &host, port).await {
+        eprintln!("rdb-import: {}", err);
+        exit(1);
+    }
+}
+
+async fn run(path: &str, host: &str, port: u16) -> my_mini_redis::Result<()> {
+    let data = std::fs::read(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+    let entries = rdb::read(&data)?;
+
+    let mut commands = vec![];
+    let mut skipped = BTreeMap::new();
+    for entry in entries {
+        let type_name = entry.value.type_name();
+        match entry.into_command() {
+            Some(frame) => commands.push(frame),
+            None => *skipped.entry(type_name).or_insert(0) += 1,
+        }
+    }
+
+    let socket = TcpStream::connect((host, port)).await?;
+    let mut connection = Connection::new(socket);
+
+    let mut failed = 0;
+    for batch in commands.chunks(BATCH_SIZE) {
+        for frame in batch {
+            connection.write_frame(frame).await?;
+        }
+
+        for _ in batch {
+            match connection.read_frame().await? {
+                Some(Frame::Error(err)) => {
+                    eprintln!("rdb-import: {}", err);
+                    failed += 1;
+                }
+                Some(_) => {}
+                None => return Err("connection closed by the server".into()),
+            }
+        }
+    }
+
+    println!("imported {} keys", commands.len() - failed);
+    if failed > 0 {
+        println!("failed {} keys", failed);
+    }
+    for (type_name, count) in skipped {
+        println!("skipped {} keys of type {}", count, type_name);
+    }
+
+    Ok(())
+}
+
+/// Parse the file, host and port from the command line arguments.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<(String, String, u16)> {
+    let mut path = None;
+    let mut host = "127.0.0.1".to_string();
+    let mut port = DEFAULT_PORT;
+
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--host" => host = args.next()?,
+            "--port" => port = args.next()?.parse().ok()?,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return None,
+        }
+    }
+
+    Some((path?, host, port))
+}
@@ -0,0 +1,213 @@
+//! The cluster bus: the links between the nodes of a cluster.
+//!
+//! Each node listens for the other nodes on its client port plus
+//! `BUS_PORT_OFFSET`. It keeps a link to every node it knows, on which it
+//! sends a `Ping` every second and reads the `Pong` reply. Both carry the
+//! slots of their sender and gossip about the other nodes, see
+//! `crate::db::Cluster`, which holds the state the messages update.
+//!
+//! Nodes met with `CLUSTER MEET` are sent a `Meet` instead, from a
+//! connection of its own. Nodes found failing are announced to all the
+//! others with `Fail`, also from connections of their own.
+//!
+//! Messages are frames, so that the bus reuses `Connection`.
+
+mod keys;
+pub(crate) use keys::command_keys;
+
+mod message;
+use message::{decode, encode};
+
+use std::collections::HashSet;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+use crate::db::BusMessage;
+use crate::{Connection, Db, Frame};
+
+/// The cluster bus port of a node is its client port plus this offset.
+pub(crate) const BUS_PORT_OFFSET: u16 = 10000;
+
+/// How often the state of the nodes is checked.
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+
+/// How often each node is pinged, and how long a broken link waits before
+/// connecting again.
+const PING_PERIOD: Duration = Duration::from_secs(1);
+
+/// Serve the cluster bus on `listener`, and keep the links to the other
+/// nodes, until the task is aborted.
+///
+/// The tasks serving the connections and links are aborted along with it.
+pub(crate) async fn run(db: Db, listener: TcpListener) {
+    let mut tasks = JoinSet::new();
+    let mut links = HashSet::new();
+    let mut ticks = time::interval(CRON_PERIOD);
+
+    loop {
+        select! {
+            res = listener.accept() => match res {
+                Ok((socket, addr)) => {
+                    tasks.spawn(serve(db.clone(), socket, addr.ip().to_string()));
+                }
+                Err(err) => warn!(cause = %err, "failed to accept on the cluster bus"),
+            },
+            _ = ticks.tick() => {
+                let outbox = match db.lock().await.cluster_cron() {
+                    Some(outbox) => outbox,
+                    None => return,
+                };
+
+                for (ip, port) in outbox.meets {
+                    tasks.spawn(meet(db.clone(), ip, port));
+                }
+
+                for msg in outbox.failures {
+                    tasks.spawn(announce(db.clone(), encode(msg), outbox.peers.clone()));
+                }
+
+                for id in outbox.peers {
+                    if links.insert(id.clone()) {
+                        tasks.spawn(link(db.clone(), id));
+                    }
+                }
+            }
+            // Reap the tasks that completed.
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+        }
+    }
+}
+
+/// Reply to the messages of a node connected from `ip`, until it
+/// disconnects.
+async fn serve(db: Db, socket: TcpStream, ip: String) {
+    let mut connection = Connection::new(socket);
+
+    let res: crate::Result<()> = async {
+        while let Some(frame) = connection.read_frame().await? {
+            let reply = db.lock().await.cluster_receive(decode(frame)?, &ip);
+            if let Some(reply) = reply {
+                connection.write_frame(&encode(reply)).await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(err) = res {
+        debug!(cause = %err, %ip, "cluster bus connection closed");
+    }
+}
+
+/// Ping the node `id` every `PING_PERIOD`, reconnecting whenever the link
+/// breaks, until the node is no longer known.
+async fn link(db: Db, id: String) {
+    loop {
+        let addr = match db.lock().await.cluster_bus_addr(&id) {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        if let Err(err) = ping(&db, &id, &addr).await {
+            debug!(cause = %err, %id, ?addr, "cluster bus link down");
+        }
+        time::sleep(PING_PERIOD).await;
+    }
+}
+
+async fn ping(db: &Db, id: &str, addr: &(String, u16)) -> crate::Result<()> {
+    let timeout = db.lock().await.cluster_node_timeout();
+    let mut connection = connect(addr, timeout).await?;
+
+    loop {
+        let heartbeat = match db.lock().await.cluster_ping(id) {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(()),
+        };
+
+        let reply = request(
+            &mut connection,
+            encode(BusMessage::Ping(heartbeat)),
+            timeout,
+        )
+        .await?;
+        db.lock().await.cluster_receive(decode(reply)?, &addr.0);
+
+        time::sleep(PING_PERIOD).await;
+    }
+}
+
+/// Send a `Meet` to the node with the cluster bus at `ip` and `port`.
+async fn meet(db: Db, ip: String, port: u16) {
+    let res: crate::Result<()> = async {
+        let timeout = db.lock().await.cluster_node_timeout();
+        let heartbeat = db
+            .lock()
+            .await
+            .cluster_heartbeat()
+            .ok_or("cluster mode is disabled")?;
+
+        let mut connection = connect(&(ip.clone(), port), timeout).await?;
+        let reply = request(
+            &mut connection,
+            encode(BusMessage::Meet(heartbeat)),
+            timeout,
+        )
+        .await?;
+        db.lock().await.cluster_receive(decode(reply)?, &ip);
+        Ok(())
+    }
+    .await;
+
+    match res {
+        Ok(()) => info!(%ip, port, "met node"),
+        Err(err) => warn!(cause = %err, %ip, port, "failed to meet node"),
+    }
+}
+
+/// Send `frame`, a `Fail`, to every node of `peers`.
+async fn announce(db: Db, frame: Frame, peers: Vec<String>) {
+    let timeout = db.lock().await.cluster_node_timeout();
+
+    for id in peers {
+        let addr = match db.lock().await.cluster_bus_addr(&id) {
+            Some(addr) => addr,
+            None => continue,
+        };
+
+        let res: crate::Result<()> = async {
+            let mut connection = connect(&addr, timeout).await?;
+            time::timeout(timeout, connection.write_frame(&frame)).await??;
+            Ok(())
+        }
+        .await;
+
+        if let Err(err) = res {
+            debug!(cause = %err, %id, "failed to announce failure");
+        }
+    }
+}
+
+async fn connect(addr: &(String, u16), timeout: Duration) -> crate::Result<Connection> {
+    let socket = time::timeout(timeout, TcpStream::connect((&addr.0[..], addr.1))).await??;
+    Ok(Connection::new(socket))
+}
+
+/// Send `frame` and return the reply.
+async fn request(
+    connection: &mut Connection,
+    frame: Frame,
+    timeout: Duration,
+) -> crate::Result<Frame> {
+    let reply = time::timeout(timeout, async {
+        connection.write_frame(&frame).await?;
+        connection.read_frame().await
+    })
+    .await??;
+
+    reply.ok_or_else(|| "connection closed by the peer".into())
+}
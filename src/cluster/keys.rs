@@ -0,0 +1,119 @@
+//! The keys of the commands, whose slot tells which node serves them.
+//!
+//! The keys are found at the positions Redis declares for each command, from
+//! the frame of the command, before it is parsed.
+
+use std::iter;
+
+use bytes::Bytes;
+
+use crate::Frame;
+
+/// Returns the keys of the command in `frame`. Commands not acting on keys,
+/// and malformed commands, have none.
+///
+/// Shard channels count as keys: they are served by the node serving their
+/// slot.
+pub(crate) fn command_keys(frame: &Frame) -> Vec<Bytes> {
+    let args = match frame {
+        Frame::Array(frames) => frames
+            .iter()
+            .map(|frame| match frame {
+                Frame::Bulk(arg) => Some(arg.clone()),
+                Frame::Simple(arg) => Some(Bytes::from(arg.clone())),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>(),
+        _ => None,
+    };
+    let args = match args {
+        Some(args) if !args.is_empty() => args,
+        _ => return vec![],
+    };
+
+    let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let positions: Vec<usize> = match &name[..] {
+        "get" | "set" | "bf.reserve" | "bf.add" | "bf.madd" | "bf.exists" | "bf.mexists"
+        | "cf.add" | "cf.del" | "cf.exists" | "cms.initbydim" | "cms.initbyprob" | "cms.incrby"
+        | "cms.query" | "topk.reserve" | "topk.add" | "topk.query" | "topk.list" | "ts.create"
+        | "ts.add" | "ts.range" | "ts.revrange" | "hset" | "hget" | "hdel" | "hgetall"
+        | "hscan" | "dump" | "restore" | "spublish" => vec![1],
+        "del" | "watch" | "ssubscribe" => (1..args.len()).collect(),
+        "ts.createrule" | "ts.deleterule" => vec![1, 2],
+        // Samples of a key, a timestamp and a value.
+        "ts.madd" => (1..args.len()).step_by(3).collect(),
+        // The number of keys, then the keys.
+        "eval" | "evalsha" => (3..3 + numkeys(&args, 2)).collect(),
+        // The destination, the number of sources, then the sources.
+        "cms.merge" => iter::once(1).chain(3..3 + numkeys(&args, 2)).collect(),
+        // A single key, or an empty one and the keys following KEYS.
+        "migrate" => match args.get(3) {
+            Some(key) if key.is_empty() => match args
+                .iter()
+                .skip(6)
+                .position(|arg| arg.eq_ignore_ascii_case(b"keys"))
+            {
+                Some(i) => (7 + i..args.len()).collect(),
+                None => vec![],
+            },
+            _ => vec![3],
+        },
+        _ => vec![],
+    };
+
+    positions
+        .into_iter()
+        .filter_map(|i| args.get(i).cloned())
+        .collect()
+}
+
+/// Returns the number of keys given at position `i` of `args`, or zero if it
+/// is not a number.
+fn numkeys(args: &[Bytes], i: usize) -> usize {
+    args.get(i)
+        .and_then(|arg| std::str::from_utf8(arg).ok()?.parse().ok())
+        .unwrap_or(0)
+        .min(args.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(args: &[&str]) -> Vec<Bytes> {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        command_keys(&frame)
+    }
+
+    #[test]
+    fn keys_are_found_at_their_positions() {
+        assert_eq!(keys(&["GET", "foo"]), ["foo"]);
+        assert_eq!(keys(&["del", "a", "b", "c"]), ["a", "b", "c"]);
+        assert_eq!(keys(&["EVAL", "return 1", "2", "a", "b", "c"]), ["a", "b"]);
+        assert_eq!(
+            keys(&["cms.merge", "dst", "2", "a", "b", "1", "1"]),
+            ["dst", "a", "b"]
+        );
+        assert!(keys(&["PING"]).is_empty());
+        assert!(keys(&["GET"]).is_empty());
+    }
+
+    #[test]
+    fn migrate_takes_a_key_or_the_keys_after_keys() {
+        assert_eq!(keys(&["MIGRATE", "h", "1", "foo", "0", "10"]), ["foo"]);
+        assert_eq!(
+            keys(&["MIGRATE", "h", "1", "", "0", "10", "COPY", "KEYS", "a", "b"]),
+            ["a", "b"]
+        );
+        // A key named `keys` among the options is not mistaken for them.
+        assert_eq!(
+            keys(&["MIGRATE", "h", "1", "", "0", "10", "keys", "keys"]),
+            ["keys"]
+        );
+        assert!(keys(&["MIGRATE", "h", "1", "", "0", "10"]).is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+//! Encoding of the messages of the cluster bus as frames.
+//!
+//! # Format
+//!
+//! ```text
+//! [ping|pong|meet, sender, port, bus-port, config-epoch, current-epoch,
+//!     [start, end, ...], [[id, ip, port, bus-port, failing], ...]]
+//! [fail, sender, node]
+//! ```
+
+use bytes::Bytes;
+
+use crate::db::{BusMessage, Gossip, Heartbeat};
+use crate::Frame;
+
+/// Converts `msg` into a frame.
+pub(super) fn encode(msg: BusMessage) -> Frame {
+    let (kind, hb) = match msg {
+        BusMessage::Ping(hb) => ("ping", hb),
+        BusMessage::Pong(hb) => ("pong", hb),
+        BusMessage::Meet(hb) => ("meet", hb),
+        BusMessage::Fail { sender, node } => {
+            return Frame::Array(vec![bulk("fail"), bulk(&sender), bulk(&node)]);
+        }
+    };
+
+    let slots = hb
+        .slots
+        .iter()
+        .flat_map(|(start, end)| [Frame::Int(u64::from(*start)), Frame::Int(u64::from(*end))])
+        .collect();
+
+    let gossip = hb
+        .gossip
+        .into_iter()
+        .map(|entry| {
+            Frame::Array(vec![
+                bulk(&entry.id),
+                bulk(&entry.ip),
+                Frame::Int(u64::from(entry.port)),
+                Frame::Int(u64::from(entry.bus_port)),
+                Frame::Int(u64::from(entry.failing)),
+            ])
+        })
+        .collect();
+
+    Frame::Array(vec![
+        bulk(kind),
+        bulk(&hb.sender),
+        Frame::Int(u64::from(hb.port)),
+        Frame::Int(u64::from(hb.bus_port)),
+        Frame::Int(hb.config_epoch),
+        Frame::Int(hb.current_epoch),
+        Frame::Array(slots),
+        Frame::Array(gossip),
+    ])
+}
+
+/// Parse a message from a received frame.
+pub(super) fn decode(frame: Frame) -> crate::Result<BusMessage> {
+    let frames = match frame {
+        Frame::Array(frames) => frames,
+        frame => return Err(format!("invalid cluster bus message: {:?}", frame).into()),
+    };
+
+    match &frames[..] {
+        [kind, sender, node] if string(kind)? == "fail" => Ok(BusMessage::Fail {
+            sender: string(sender)?,
+            node: string(node)?,
+        }),
+        [kind, sender, port, bus_port, config_epoch, current_epoch, Frame::Array(slots), Frame::Array(gossip)] =>
+        {
+            let mut ranges = vec![];
+            for range in slots.chunks(2) {
+                match range {
+                    [start, end] if slot(start)? <= slot(end)? => {
+                        ranges.push((slot(start)?, slot(end)?))
+                    }
+                    _ => return Err("invalid slot range in cluster bus message".into()),
+                }
+            }
+
+            let gossip = gossip
+                .iter()
+                .map(|entry| match entry {
+                    Frame::Array(fields) => match &fields[..] {
+                        [id, ip, port, bus_port, failing] => Ok(Gossip {
+                            id: string(id)?,
+                            ip: string(ip)?,
+                            port: int(port)?.try_into()?,
+                            bus_port: int(bus_port)?.try_into()?,
+                            failing: int(failing)? != 0,
+                        }),
+                        _ => Err("invalid gossip in cluster bus message".into()),
+                    },
+                    _ => Err("invalid gossip in cluster bus message".into()),
+                })
+                .collect::<crate::Result<_>>()?;
+
+            let hb = Heartbeat {
+                sender: string(sender)?,
+                port: int(port)?.try_into()?,
+                bus_port: int(bus_port)?.try_into()?,
+                config_epoch: int(config_epoch)?,
+                current_epoch: int(current_epoch)?,
+                slots: ranges,
+                gossip,
+            };
+
+            match &string(kind)?[..] {
+                "ping" => Ok(BusMessage::Ping(hb)),
+                "pong" => Ok(BusMessage::Pong(hb)),
+                "meet" => Ok(BusMessage::Meet(hb)),
+                kind => Err(format!("unknown cluster bus message '{}'", kind).into()),
+            }
+        }
+        _ => Err("invalid cluster bus message".into()),
+    }
+}
+
+fn bulk(value: &str) -> Frame {
+    Frame::Bulk(Bytes::from(value.to_string()))
+}
+
+fn string(frame: &Frame) -> crate::Result<String> {
+    match frame {
+        Frame::Bulk(bytes) => Ok(std::str::from_utf8(bytes)?.to_string()),
+        frame => Err(format!("expected a string, got {:?}", frame).into()),
+    }
+}
+
+fn int(frame: &Frame) -> crate::Result<u64> {
+    match frame {
+        Frame::Int(value) => Ok(*value),
+        frame => Err(format!("expected an integer, got {:?}", frame).into()),
+    }
+}
+
+fn slot(frame: &Frame) -> crate::Result<u16> {
+    match int(frame)? {
+        slot if slot < crate::db::SLOTS as u64 => Ok(slot as u16),
+        slot => Err(format!("invalid slot {}", slot).into()),
+    }
+}
@@ -5,19 +5,87 @@ mod unknown;
 pub use unknown::Unknown;
 
 mod subscribe;
+pub use subscribe::{Psubscribe, Punsubscribe, Ssubscribe, Subscribe, Sunsubscribe, Unsubscribe};
 
-use crate::{Connection, Db, Frame, Parse, ParseError, Shutdown};
+use bytes::Bytes;
+
+use crate::{Connection, Db, Frame, LockedDb, Parse, ParseError, Shutdown, WatchedKeys};
 
 mod set;
 pub use set::Set;
 
 mod publish;
-pub use publish::Publish;
+pub use publish::{Publish, Spublish};
 
 mod ping;
 pub use ping::Ping;
 
-use self::subscribe::{Subscribe, Unsubscribe};
+mod bloom;
+pub use bloom::{BfAdd, BfExists, BfMadd, BfMexists, BfReserve};
+
+mod cuckoo;
+pub use cuckoo::{CfAdd, CfDel, CfExists};
+
+mod cms;
+pub use cms::{CmsIncrBy, CmsInitByDim, CmsInitByProb, CmsMerge, CmsQuery};
+
+mod topk;
+pub use topk::{TopkAdd, TopkList, TopkQuery, TopkReserve};
+
+mod timeseries;
+pub use crate::db::{Aggregation, LabelFilter};
+pub use timeseries::{TsAdd, TsCreate, TsCreateRule, TsDeleteRule, TsMadd, TsMrange, TsRange};
+
+mod hash;
+pub use hash::{Hdel, Hget, Hgetall, Hset};
+
+mod search;
+pub use crate::db::{DistanceMetric, FieldType, VectorAlgorithm};
+pub use search::{FtCreate, FtDropIndex, FtSearch};
+
+mod keys;
+pub use keys::{DbSize, Del, Keys, RandomKey};
+
+mod scan;
+pub use scan::{Hscan, Scan};
+
+mod pubsub;
+pub use pubsub::Pubsub;
+
+mod config;
+pub use config::Config;
+
+mod client;
+pub use crate::db::LagPolicy;
+pub use client::Client;
+
+mod quit;
+pub use quit::{Quit, Reset};
+
+mod transaction;
+pub(crate) use transaction::Transaction;
+pub use transaction::{Discard, Exec, Multi, Unwatch, Watch};
+
+mod eval;
+pub use eval::{Eval, EvalSha, Script};
+
+mod save;
+pub use save::{Bgrewriteaof, Bgsave, Lastsave, Save};
+
+mod rdb;
+pub use rdb::RdbExport;
+
+mod dump;
+pub use dump::{Dump, Migrate, Restore};
+
+mod replication;
+pub use replication::{Psync, Replconf, ReplicaOf, Role, Wait};
+
+mod info;
+pub use info::Info;
+
+mod cluster;
+pub use cluster::{Asking, Cluster};
 
 /// Enumeration of supported Redis commands.
 ///
@@ -29,7 +97,78 @@ pub enum Command {
     Set(Set),
     Subscribe(Subscribe),
     Unsubscribe(Unsubscribe),
+    Psubscribe(Psubscribe),
+    Punsubscribe(Punsubscribe),
     Ping(Ping),
+    BfReserve(BfReserve),
+    BfAdd(BfAdd),
+    BfMadd(BfMadd),
+    BfExists(BfExists),
+    BfMexists(BfMexists),
+    CfAdd(CfAdd),
+    CfDel(CfDel),
+    CfExists(CfExists),
+    CmsInitByDim(CmsInitByDim),
+    CmsInitByProb(CmsInitByProb),
+    CmsIncrBy(CmsIncrBy),
+    CmsQuery(CmsQuery),
+    CmsMerge(CmsMerge),
+    TopkReserve(TopkReserve),
+    TopkAdd(TopkAdd),
+    TopkQuery(TopkQuery),
+    TopkList(TopkList),
+    TsCreate(TsCreate),
+    TsAdd(TsAdd),
+    TsMadd(TsMadd),
+    TsRange(TsRange),
+    TsMrange(TsMrange),
+    TsCreateRule(TsCreateRule),
+    TsDeleteRule(TsDeleteRule),
+    Hset(Hset),
+    Hget(Hget),
+    Hdel(Hdel),
+    Hgetall(Hgetall),
+    FtCreate(FtCreate),
+    FtSearch(FtSearch),
+    FtDropIndex(FtDropIndex),
+    Keys(Keys),
+    Del(Del),
+    DbSize(DbSize),
+    RandomKey(RandomKey),
+    Scan(Scan),
+    Hscan(Hscan),
+    Pubsub(Pubsub),
+    Config(Config),
+    Client(Client),
+    Quit(Quit),
+    Reset(Reset),
+    Spublish(Spublish),
+    Ssubscribe(Ssubscribe),
+    Sunsubscribe(Sunsubscribe),
+    Multi(Multi),
+    Exec(Exec),
+    Discard(Discard),
+    Watch(Watch),
+    Unwatch(Unwatch),
+    Eval(Eval),
+    EvalSha(EvalSha),
+    Script(Script),
+    Save(Save),
+    Bgsave(Bgsave),
+    Lastsave(Lastsave),
+    Bgrewriteaof(Bgrewriteaof),
+    RdbExport(RdbExport),
+    Dump(Dump),
+    Restore(Restore),
+    Migrate(Migrate),
+    ReplicaOf(ReplicaOf),
+    Replconf(Replconf),
+    Psync(Psync),
+    Wait(Wait),
+    Role(Role),
+    Info(Info),
+    Cluster(Cluster),
+    Asking(Asking),
     Unknown(Unknown),
 }
 
@@ -53,7 +192,80 @@ impl Command {
             "set" => Command::Set(Set::parse_frames(&mut parse)?),
             "subscribe" => Command::Subscribe(Subscribe::parse_frames(&mut parse)?),
             "unsubscribe" => Command::Unsubscribe(Unsubscribe::parse_frames(&mut parse)?),
+            "psubscribe" => Command::Psubscribe(Psubscribe::parse_frames(&mut parse)?),
+            "punsubscribe" => Command::Punsubscribe(Punsubscribe::parse_frames(&mut parse)?),
             "ping" => Command::Ping(Ping::parse_frames(&mut parse)?),
+            "bf.reserve" => Command::BfReserve(BfReserve::parse_frames(&mut parse)?),
+            "bf.add" => Command::BfAdd(BfAdd::parse_frames(&mut parse)?),
+            "bf.madd" => Command::BfMadd(BfMadd::parse_frames(&mut parse)?),
+            "bf.exists" => Command::BfExists(BfExists::parse_frames(&mut parse)?),
+            "bf.mexists" => Command::BfMexists(BfMexists::parse_frames(&mut parse)?),
+            "cf.add" => Command::CfAdd(CfAdd::parse_frames(&mut parse)?),
+            "cf.del" => Command::CfDel(CfDel::parse_frames(&mut parse)?),
+            "cf.exists" => Command::CfExists(CfExists::parse_frames(&mut parse)?),
+            "cms.initbydim" => Command::CmsInitByDim(CmsInitByDim::parse_frames(&mut parse)?),
+            "cms.initbyprob" => Command::CmsInitByProb(CmsInitByProb::parse_frames(&mut parse)?),
+            "cms.incrby" => Command::CmsIncrBy(CmsIncrBy::parse_frames(&mut parse)?),
+            "cms.query" => Command::CmsQuery(CmsQuery::parse_frames(&mut parse)?),
+            "cms.merge" => Command::CmsMerge(CmsMerge::parse_frames(&mut parse)?),
+            "topk.reserve" => Command::TopkReserve(TopkReserve::parse_frames(&mut parse)?),
+            "topk.add" => Command::TopkAdd(TopkAdd::parse_frames(&mut parse)?),
+            "topk.query" => Command::TopkQuery(TopkQuery::parse_frames(&mut parse)?),
+            "topk.list" => Command::TopkList(TopkList::parse_frames(&mut parse)?),
+            "ts.create" => Command::TsCreate(TsCreate::parse_frames(&mut parse)?),
+            "ts.add" => Command::TsAdd(TsAdd::parse_frames(&mut parse)?),
+            "ts.madd" => Command::TsMadd(TsMadd::parse_frames(&mut parse)?),
+            "ts.range" => Command::TsRange(TsRange::parse_frames(&mut parse, false)?),
+            "ts.revrange" => Command::TsRange(TsRange::parse_frames(&mut parse, true)?),
+            "ts.mrange" => Command::TsMrange(TsMrange::parse_frames(&mut parse, false)?),
+            "ts.mrevrange" => Command::TsMrange(TsMrange::parse_frames(&mut parse, true)?),
+            "ts.createrule" => Command::TsCreateRule(TsCreateRule::parse_frames(&mut parse)?),
+            "ts.deleterule" => Command::TsDeleteRule(TsDeleteRule::parse_frames(&mut parse)?),
+            "hset" => Command::Hset(Hset::parse_frames(&mut parse)?),
+            "hget" => Command::Hget(Hget::parse_frames(&mut parse)?),
+            "hdel" => Command::Hdel(Hdel::parse_frames(&mut parse)?),
+            "hgetall" => Command::Hgetall(Hgetall::parse_frames(&mut parse)?),
+            "ft.create" => Command::FtCreate(FtCreate::parse_frames(&mut parse)?),
+            "ft.search" => Command::FtSearch(FtSearch::parse_frames(&mut parse)?),
+            "ft.dropindex" => Command::FtDropIndex(FtDropIndex::parse_frames(&mut parse)?),
+            "keys" => Command::Keys(Keys::parse_frames(&mut parse)?),
+            "del" => Command::Del(Del::parse_frames(&mut parse)?),
+            "dbsize" => Command::DbSize(DbSize::parse_frames(&mut parse)?),
+            "randomkey" => Command::RandomKey(RandomKey::parse_frames(&mut parse)?),
+            "scan" => Command::Scan(Scan::parse_frames(&mut parse)?),
+            "hscan" => Command::Hscan(Hscan::parse_frames(&mut parse)?),
+            "pubsub" => Command::Pubsub(Pubsub::parse_frames(&mut parse)?),
+            "config" => Command::Config(Config::parse_frames(&mut parse)?),
+            "client" => Command::Client(Client::parse_frames(&mut parse)?),
+            "quit" => Command::Quit(Quit::parse_frames(&mut parse)?),
+            "reset" => Command::Reset(Reset::parse_frames(&mut parse)?),
+            "spublish" => Command::Spublish(Spublish::parse_frames(&mut parse)?),
+            "ssubscribe" => Command::Ssubscribe(Ssubscribe::parse_frames(&mut parse)?),
+            "sunsubscribe" => Command::Sunsubscribe(Sunsubscribe::parse_frames(&mut parse)?),
+            "multi" => Command::Multi(Multi::parse_frames(&mut parse)?),
+            "exec" => Command::Exec(Exec::parse_frames(&mut parse)?),
+            "discard" => Command::Discard(Discard::parse_frames(&mut parse)?),
+            "watch" => Command::Watch(Watch::parse_frames(&mut parse)?),
+            "unwatch" => Command::Unwatch(Unwatch::parse_frames(&mut parse)?),
+            "eval" => Command::Eval(Eval::parse_frames(&mut parse)?),
+            "evalsha" => Command::EvalSha(EvalSha::parse_frames(&mut parse)?),
+            "script" => Command::Script(Script::parse_frames(&mut parse)?),
+            "save" => Command::Save(Save::parse_frames(&mut parse)?),
+            "bgsave" => Command::Bgsave(Bgsave::parse_frames(&mut parse)?),
+            "lastsave" => Command::Lastsave(Lastsave::parse_frames(&mut parse)?),
+            "bgrewriteaof" => Command::Bgrewriteaof(Bgrewriteaof::parse_frames(&mut parse)?),
+            "rdb.export" => Command::RdbExport(RdbExport::parse_frames(&mut parse)?),
+            "dump" => Command::Dump(Dump::parse_frames(&mut parse)?),
+            "restore" => Command::Restore(Restore::parse_frames(&mut parse)?),
+            "migrate" => Command::Migrate(Migrate::parse_frames(&mut parse)?),
+            "replicaof" | "slaveof" => Command::ReplicaOf(ReplicaOf::parse_frames(&mut parse)?),
+            "replconf" => Command::Replconf(Replconf::parse_frames(&mut parse)?),
+            "psync" => Command::Psync(Psync::parse_frames(&mut parse)?),
+            "wait" => Command::Wait(Wait::parse_frames(&mut parse)?),
+            "role" => Command::Role(Role::parse_frames(&mut parse)?),
+            "info" => Command::Info(Info::parse_frames(&mut parse)?),
+            "cluster" => Command::Cluster(Cluster::parse_frames(&mut parse)?),
+            "asking" => Command::Asking(Asking::parse_frames(&mut parse)?),
             _ => {
                 return Ok(Command::Unknown(Unknown::new(command_name)));
             }
@@ -67,28 +279,309 @@ impl Command {
     /// Apply the command to the specified `Db` instance.
     ///
     /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
+    /// to execute a received command. `transaction` is the transaction started
+    /// on the connection by `MULTI`, if any, and `watched` the keys watched by
+    /// `WATCH`.
+    ///
+    /// In cluster mode, the command is refused unless this node serves
+    /// `keys`, the keys of the command, see `LockedDb::check_slot`.
     pub(crate) async fn apply(
         self,
         db: &Db,
         dst: &mut Connection,
         shutdown: &mut Shutdown,
+        transaction: &mut Option<Transaction>,
+        watched: &mut Option<WatchedKeys>,
+        keys: Vec<Bytes>,
     ) -> crate::Result<()> {
         use Command::*;
 
+        // Also once `ASKING` applies to the command, if any.
+        let asking = dst.take_asking();
+
+        // The commands applied below, rather than executed, check their keys
+        // beforehand.
+        if matches!(self, Spublish(_) | Ssubscribe(_) | Watch(_) | Migrate(_)) {
+            if let Err(resp) = check_slot(db, &keys, asking).await {
+                dst.write_frame(&resp).await?;
+                return Ok(());
+            }
+        }
+
         match self {
-            Get(cmd) => cmd.apply(db, dst).await,
             Publish(cmd) => cmd.apply(db, dst).await,
-            Set(cmd) => cmd.apply(db, dst).await,
             Subscribe(cmd) => cmd.apply(db, dst, shutdown).await,
-            Ping(cmd) => cmd.apply(dst).await,
-            Unknown(cmd) => cmd.apply(dst).await,
-            // `Unsubscribe` cannot be applied. It may only be received from the
-            // context of a `Subscribe` command.
+            Psubscribe(cmd) => cmd.apply(db, dst, shutdown).await,
+            Client(cmd) => cmd.apply(dst).await,
+            Quit(cmd) => cmd.apply(dst).await,
+            Reset(cmd) => {
+                // Leave the transaction, if any.
+                transaction.take();
+                watched.take();
+                cmd.apply(dst).await
+            }
+            Spublish(cmd) => cmd.apply(db, dst).await,
+            Ssubscribe(cmd) => cmd.apply(db, dst, shutdown).await,
+            Multi(cmd) => cmd.apply(dst, transaction).await,
+            Exec(cmd) => cmd.apply(db, dst, transaction, watched).await,
+            Discard(cmd) => cmd.apply(dst, transaction, watched).await,
+            Watch(cmd) => cmd.apply(db, dst, transaction, watched).await,
+            Unwatch(cmd) => cmd.apply(dst, watched).await,
+            Script(cmd) => cmd.apply(db, dst).await,
+            Migrate(cmd) => cmd.apply(db, dst).await,
+            ReplicaOf(cmd) => cmd.apply(db, dst).await,
+            Replconf(cmd) => cmd.apply(dst).await,
+            Psync(cmd) => cmd.apply(db, dst, shutdown).await,
+            Wait(cmd) => cmd.apply(db, dst, shutdown).await,
+            Asking(cmd) => cmd.apply(dst).await,
+            // `Unsubscribe`, `Punsubscribe` and `Sunsubscribe` cannot be
+            // applied. They may only be received from the context of a
+            // `Subscribe` command.
             Unsubscribe(_) => Err("`Unsubscribe` is unsupported in this context".into()),
+            Punsubscribe(_) => Err("`Punsubscribe` is unsupported in this context".into()),
+            Sunsubscribe(_) => Err("`Sunsubscribe` is unsupported in this context".into()),
+            // Other commands only need the `Db`. It is locked for the time it
+            // takes to check the slot of the keys and execute the command,
+            // and released before writing the response.
+            //
+            // While a script holds the lock past `busy-reply-threshold`,
+            // commands are refused rather than left waiting.
+            cmd => {
+                let resp = match cmd {
+                    // Scripts may run for a long time. They run on a blocking
+                    // thread, leaving the runtime free to serve the other
+                    // connections.
+                    //
+                    // Like the other commands, they are refused while another
+                    // script is busy.
+                    cmd if cmd.runs_script() => {
+                        if db.lock_unless_busy().await.is_none() {
+                            dst.write_frame(&busy_reply()).await?;
+                            return Ok(());
+                        }
+
+                        let db = db.clone();
+                        tokio::task::spawn_blocking(move || {
+                            cmd.execute_in_slot(&mut db.blocking_lock_for_script(), &keys, asking)
+                        })
+                        .await??
+                    }
+                    cmd => match db.lock_unless_busy().await {
+                        Some(mut db) => cmd.execute_in_slot(&mut db, &keys, asking)?,
+                        None => busy_reply(),
+                    },
+                };
+                dst.write_frame(&resp).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Execute the command against the locked `Db` and return the response.
+    ///
+    /// Commands executed with the same `LockedDb` are atomic, which is how
+    /// `EXEC` runs a transaction. Commands that act on the connection, such as
+    /// `SUBSCRIBE`, cannot be executed this way and return `Err`.
+    ///
+    /// Write commands are logged to the append-only file and propagated to
+    /// replicas, unless they reply with an error. Times relative to now are
+    /// made absolute beforehand, so that replaying the log has the same
+    /// effect. Replicas refuse write commands.
+    pub(crate) fn execute(self, db: &mut LockedDb) -> crate::Result<Frame> {
+        if self.is_write() && db.is_replica() {
+            return Ok(Frame::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            ));
+        }
+
+        self.replicate(db)
+    }
+
+    /// Execute the command like `execute`, unless this node does not serve
+    /// `keys` in cluster mode. The reply is then the error of
+    /// `LockedDb::check_slot`.
+    fn execute_in_slot(
+        self,
+        db: &mut LockedDb,
+        keys: &[Bytes],
+        asking: bool,
+    ) -> crate::Result<Frame> {
+        match db.check_slot(keys, asking) {
+            Ok(()) => self.execute(db),
+            Err(err) => Ok(Frame::Error(err.to_string())),
+        }
+    }
+
+    /// Execute a command propagated by the primary, which replicas do not
+    /// refuse.
+    pub(crate) fn replicate(mut self, db: &mut LockedDb) -> crate::Result<Frame> {
+        use Command::*;
+
+        let logged = if db.logs_writes() {
+            self.make_absolute();
+            self.aof_frame()
+        } else {
+            None
+        };
+
+        let resp = match self {
+            Get(cmd) => cmd.execute(db),
+            Publish(cmd) => cmd.execute(db),
+            Set(cmd) => cmd.execute(db),
+            Ping(cmd) => cmd.execute(),
+            BfReserve(cmd) => cmd.execute(db),
+            BfAdd(cmd) => cmd.execute(db),
+            BfMadd(cmd) => cmd.execute(db),
+            BfExists(cmd) => cmd.execute(db),
+            BfMexists(cmd) => cmd.execute(db),
+            CfAdd(cmd) => cmd.execute(db),
+            CfDel(cmd) => cmd.execute(db),
+            CfExists(cmd) => cmd.execute(db),
+            CmsInitByDim(cmd) => cmd.execute(db),
+            CmsInitByProb(cmd) => cmd.execute(db),
+            CmsIncrBy(cmd) => cmd.execute(db),
+            CmsQuery(cmd) => cmd.execute(db),
+            CmsMerge(cmd) => cmd.execute(db),
+            TopkReserve(cmd) => cmd.execute(db),
+            TopkAdd(cmd) => cmd.execute(db),
+            TopkQuery(cmd) => cmd.execute(db),
+            TopkList(cmd) => cmd.execute(db),
+            TsCreate(cmd) => cmd.execute(db),
+            TsAdd(cmd) => cmd.execute(db),
+            TsMadd(cmd) => cmd.execute(db),
+            TsRange(cmd) => cmd.execute(db),
+            TsMrange(cmd) => cmd.execute(db),
+            TsCreateRule(cmd) => cmd.execute(db),
+            TsDeleteRule(cmd) => cmd.execute(db),
+            Hset(cmd) => cmd.execute(db),
+            Hget(cmd) => cmd.execute(db),
+            Hdel(cmd) => cmd.execute(db),
+            Hgetall(cmd) => cmd.execute(db),
+            FtCreate(cmd) => cmd.execute(db),
+            FtSearch(cmd) => cmd.execute(db),
+            FtDropIndex(cmd) => cmd.execute(db),
+            Keys(cmd) => cmd.execute(db),
+            Del(cmd) => cmd.execute(db),
+            DbSize(cmd) => cmd.execute(db),
+            RandomKey(cmd) => cmd.execute(db),
+            Scan(cmd) => cmd.execute(db),
+            Hscan(cmd) => cmd.execute(db),
+            Pubsub(cmd) => cmd.execute(db),
+            Config(cmd) => cmd.execute(db),
+            Spublish(cmd) => cmd.execute(db),
+            Unwatch(cmd) => cmd.execute(),
+            Eval(cmd) => cmd.execute(db),
+            EvalSha(cmd) => cmd.execute(db),
+            Script(cmd) => cmd.execute(db),
+            Save(cmd) => cmd.execute(db),
+            Bgsave(cmd) => cmd.execute(db),
+            Lastsave(cmd) => cmd.execute(db),
+            Bgrewriteaof(cmd) => cmd.execute(db),
+            RdbExport(cmd) => cmd.execute(db),
+            Dump(cmd) => cmd.execute(db),
+            Restore(cmd) => cmd.execute(db),
+            Role(cmd) => cmd.execute(db),
+            Info(cmd) => cmd.execute(db),
+            Cluster(cmd) => cmd.execute(db),
+            Unknown(cmd) => cmd.execute(),
+            cmd => {
+                return Err(format!("`{}` is unsupported in this context", cmd.get_name()).into())
+            }
+        };
+
+        if let Some(frame) = logged {
+            if !matches!(resp, Frame::Error(_)) {
+                db.propagate(&frame);
+            }
+        }
+
+        Ok(resp)
+    }
+
+    /// Replace times relative to now by the absolute times they stand for.
+    fn make_absolute(&mut self) {
+        match self {
+            Command::Set(cmd) => cmd.make_absolute(),
+            Command::TsAdd(cmd) => cmd.make_absolute(),
+            Command::TsMadd(cmd) => cmd.make_absolute(),
+            Command::Restore(cmd) => cmd.make_absolute(),
+            _ => {}
         }
     }
 
+    /// Returns `true` for the write commands, those `aof_frame` logs.
+    fn is_write(&self) -> bool {
+        use Command::*;
+
+        matches!(
+            self,
+            Set(_)
+                | BfReserve(_)
+                | BfAdd(_)
+                | BfMadd(_)
+                | CfAdd(_)
+                | CfDel(_)
+                | CmsInitByDim(_)
+                | CmsInitByProb(_)
+                | CmsIncrBy(_)
+                | CmsMerge(_)
+                | TopkReserve(_)
+                | TopkAdd(_)
+                | TsCreate(_)
+                | TsAdd(_)
+                | TsMadd(_)
+                | TsCreateRule(_)
+                | TsDeleteRule(_)
+                | Hset(_)
+                | Hdel(_)
+                | FtCreate(_)
+                | FtDropIndex(_)
+                | Del(_)
+                | Restore(_)
+        )
+    }
+
+    /// Returns `true` for the commands running a script, which must be
+    /// executed against `Db::blocking_lock_for_script`.
+    pub(crate) fn runs_script(&self) -> bool {
+        matches!(self, Command::Eval(_) | Command::EvalSha(_))
+    }
+
+    /// Returns the frame logged to the append-only file for write commands,
+    /// or `None` if the command does not modify the data.
+    fn aof_frame(&self) -> Option<Frame> {
+        use Command::*;
+
+        let frame = match self {
+            Set(cmd) => cmd.clone().into_frame(),
+            BfReserve(cmd) => cmd.clone().into_frame(),
+            BfAdd(cmd) => cmd.clone().into_frame(),
+            BfMadd(cmd) => cmd.clone().into_frame(),
+            CfAdd(cmd) => cmd.clone().into_frame(),
+            CfDel(cmd) => cmd.clone().into_frame(),
+            CmsInitByDim(cmd) => cmd.clone().into_frame(),
+            CmsInitByProb(cmd) => cmd.clone().into_frame(),
+            CmsIncrBy(cmd) => cmd.clone().into_frame(),
+            CmsMerge(cmd) => cmd.clone().into_frame(),
+            TopkReserve(cmd) => cmd.clone().into_frame(),
+            TopkAdd(cmd) => cmd.clone().into_frame(),
+            TsCreate(cmd) => cmd.clone().into_frame(),
+            TsAdd(cmd) => cmd.clone().into_frame(),
+            TsMadd(cmd) => cmd.clone().into_frame(),
+            TsCreateRule(cmd) => cmd.clone().into_frame(),
+            TsDeleteRule(cmd) => cmd.clone().into_frame(),
+            Hset(cmd) => cmd.clone().into_frame(),
+            Hdel(cmd) => cmd.clone().into_frame(),
+            FtCreate(cmd) => cmd.clone().into_frame(),
+            FtDropIndex(cmd) => cmd.clone().into_frame(),
+            Del(cmd) => cmd.clone().into_frame(),
+            Restore(cmd) => cmd.clone().into_frame(),
+            _ => return None,
+        };
+
+        Some(frame)
+    }
+
     /// Returns the command name
     pub(crate) fn get_name(&self) -> &str {
         match self {
@@ -97,8 +590,145 @@ impl Command {
             Command::Set(_) => "set",
             Command::Subscribe(_) => "subscribe",
             Command::Unsubscribe(_) => "unsubscribe",
+            Command::Psubscribe(_) => "psubscribe",
+            Command::Punsubscribe(_) => "punsubscribe",
             Command::Ping(_) => "ping",
+            Command::BfReserve(_) => "bf.reserve",
+            Command::BfAdd(_) => "bf.add",
+            Command::BfMadd(_) => "bf.madd",
+            Command::BfExists(_) => "bf.exists",
+            Command::BfMexists(_) => "bf.mexists",
+            Command::CfAdd(_) => "cf.add",
+            Command::CfDel(_) => "cf.del",
+            Command::CfExists(_) => "cf.exists",
+            Command::CmsInitByDim(_) => "cms.initbydim",
+            Command::CmsInitByProb(_) => "cms.initbyprob",
+            Command::CmsIncrBy(_) => "cms.incrby",
+            Command::CmsQuery(_) => "cms.query",
+            Command::CmsMerge(_) => "cms.merge",
+            Command::TopkReserve(_) => "topk.reserve",
+            Command::TopkAdd(_) => "topk.add",
+            Command::TopkQuery(_) => "topk.query",
+            Command::TopkList(_) => "topk.list",
+            Command::TsCreate(_) => "ts.create",
+            Command::TsAdd(_) => "ts.add",
+            Command::TsMadd(_) => "ts.madd",
+            Command::TsRange(cmd) => cmd.get_name(),
+            Command::TsMrange(cmd) => cmd.get_name(),
+            Command::TsCreateRule(_) => "ts.createrule",
+            Command::TsDeleteRule(_) => "ts.deleterule",
+            Command::Hset(_) => "hset",
+            Command::Hget(_) => "hget",
+            Command::Hdel(_) => "hdel",
+            Command::Hgetall(_) => "hgetall",
+            Command::FtCreate(_) => "ft.create",
+            Command::FtSearch(_) => "ft.search",
+            Command::FtDropIndex(_) => "ft.dropindex",
+            Command::Keys(_) => "keys",
+            Command::Del(_) => "del",
+            Command::DbSize(_) => "dbsize",
+            Command::RandomKey(_) => "randomkey",
+            Command::Scan(_) => "scan",
+            Command::Hscan(_) => "hscan",
+            Command::Pubsub(_) => "pubsub",
+            Command::Config(_) => "config",
+            Command::Client(_) => "client",
+            Command::Quit(_) => "quit",
+            Command::Reset(_) => "reset",
+            Command::Spublish(_) => "spublish",
+            Command::Ssubscribe(_) => "ssubscribe",
+            Command::Sunsubscribe(_) => "sunsubscribe",
+            Command::Multi(_) => "multi",
+            Command::Exec(_) => "exec",
+            Command::Discard(_) => "discard",
+            Command::Watch(_) => "watch",
+            Command::Unwatch(_) => "unwatch",
+            Command::Eval(_) => "eval",
+            Command::EvalSha(_) => "evalsha",
+            Command::Script(_) => "script",
+            Command::Save(_) => "save",
+            Command::Bgsave(_) => "bgsave",
+            Command::Lastsave(_) => "lastsave",
+            Command::Bgrewriteaof(_) => "bgrewriteaof",
+            Command::RdbExport(_) => "rdb.export",
+            Command::Dump(_) => "dump",
+            Command::Restore(_) => "restore",
+            Command::Migrate(_) => "migrate",
+            Command::ReplicaOf(_) => "replicaof",
+            Command::Replconf(_) => "replconf",
+            Command::Psync(_) => "psync",
+            Command::Wait(_) => "wait",
+            Command::Role(_) => "role",
+            Command::Info(_) => "info",
+            Command::Cluster(_) => "cluster",
+            Command::Asking(_) => "asking",
             Command::Unknown(cmd) => cmd.get_name(),
         }
     }
 }
+
+/// Check that this node serves `keys`, the keys of a command, in cluster
+/// mode. Returns the reply to refuse the command with otherwise, see
+/// `LockedDb::check_slot`.
+///
+/// Like the commands, the check waits for a running script, up to
+/// `busy-reply-threshold`.
+pub(crate) async fn check_slot(db: &Db, keys: &[Bytes], asking: bool) -> Result<(), Frame> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    match db.lock_unless_busy().await {
+        Some(mut db) => db
+            .check_slot(keys, asking)
+            .map_err(|err| Frame::Error(err.to_string())),
+        None => Err(busy_reply()),
+    }
+}
+
+/// Returns the reply to a command refused while a script has been running
+/// for `busy-reply-threshold`.
+pub(crate) fn busy_reply() -> Frame {
+    Frame::Error(
+        "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."
+            .to_string(),
+    )
+}
+
+/// Returns the reply to a command `Command::from_frame` could not parse,
+/// failing with `err`.
+///
+/// Messages that already start with `ERR` are sent as is, other ones get
+/// the prefix.
+pub(crate) fn error_reply(err: crate::Error) -> Frame {
+    let msg = err.to_string();
+    if msg.starts_with("ERR ") {
+        Frame::Error(msg)
+    } else {
+        Frame::Error(format!("ERR {}", msg))
+    }
+}
+
+/// Consume the remainder of the frame as a non-empty list of items.
+fn parse_items(parse: &mut Parse) -> crate::Result<Vec<Bytes>> {
+    let mut items = vec![parse.next_bytes()?];
+
+    loop {
+        match parse.next_bytes() {
+            Ok(item) => items.push(item),
+            Err(ParseError::EndOfStream) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(items)
+}
+
+/// Creates an array of `0` and `1` integers.
+fn make_bool_array(values: Vec<bool>) -> Frame {
+    let mut resp = Frame::array();
+    for value in values {
+        resp.push_int(value as u64);
+    }
+    resp
+}
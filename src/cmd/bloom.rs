@@ -0,0 +1,361 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use super::{make_bool_array, parse_items};
+use crate::db::{bloom, ScalingBloom};
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Create an empty Bloom filter.
+///
+/// The filter is sized for `capacity` items at the requested false positive
+/// `error_rate`. Once it is full, a new sub-filter `expansion` times larger is
+/// stacked on top, unless `NONSCALING` is given.
+#[derive(Debug, Clone)]
+pub struct BfReserve {
+    key: String,
+    error_rate: f64,
+    capacity: u64,
+    /// Growth factor of new sub-filters. `None` if the filter is non scaling.
+    expansion: Option<u64>,
+}
+
+/// Add an item to a Bloom filter, creating the filter if it does not exist.
+#[derive(Debug, Clone)]
+pub struct BfAdd {
+    key: String,
+    item: Bytes,
+}
+
+/// Add one or more items to a Bloom filter, creating the filter if it does
+/// not exist.
+#[derive(Debug, Clone)]
+pub struct BfMadd {
+    key: String,
+    items: Vec<Bytes>,
+}
+
+/// Check whether an item may have been added to a Bloom filter.
+#[derive(Debug)]
+pub struct BfExists {
+    key: String,
+    item: Bytes,
+}
+
+/// Check whether one or more items may have been added to a Bloom filter.
+#[derive(Debug)]
+pub struct BfMexists {
+    key: String,
+    items: Vec<Bytes>,
+}
+
+impl BfReserve {
+    /// Create a new `BfReserve` command. Passing `None` as `expansion` creates
+    /// a non scaling filter.
+    pub fn new(key: impl ToString, error_rate: f64, capacity: u64, expansion: Option<u64>) -> Self {
+        BfReserve {
+            key: key.to_string(),
+            error_rate,
+            capacity,
+            expansion,
+        }
+    }
+
+    /// Parse a `BfReserve` instance from a received frame.
+    ///
+    /// The `BF.RESERVE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BF.RESERVE key error_rate capacity [EXPANSION expansion] [NONSCALING]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BfReserve> {
+        use ParseError::EndOfStream;
+
+        let key = parse.next_string()?;
+        let error_rate = parse.next_float()?;
+        let capacity = parse.next_int()?;
+
+        if !(error_rate > 0.0 && error_rate < 1.0) {
+            return Err("ERR (0 < error rate range < 1)".into());
+        }
+
+        if capacity == 0 {
+            return Err("ERR (capacity should be larger than 0)".into());
+        }
+
+        let mut expansion = Some(bloom::DEFAULT_EXPANSION);
+
+        loop {
+            match parse.next_string() {
+                Ok(s) if s.to_uppercase() == "EXPANSION" => {
+                    expansion = Some(parse.next_int()?.max(1));
+                }
+                Ok(s) if s.to_uppercase() == "NONSCALING" => expansion = None,
+                Ok(s) => return Err(format!("ERR unknown `BF.RESERVE` option `{}`", s).into()),
+                Err(EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(BfReserve {
+            key,
+            error_rate,
+            capacity,
+            expansion,
+        })
+    }
+
+    /// Apply the `BfReserve` command to the specified `Db` instance.
+    ///
+    /// Returns the response to send to the client.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let filter = ScalingBloom::new(self.error_rate, self.capacity, self.expansion);
+
+        let resp = match db.bf_reserve(self.key, filter) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bf.reserve".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(Bytes::from(self.error_rate.to_string()));
+        frame.push_int(self.capacity);
+
+        match self.expansion {
+            Some(expansion) => {
+                frame.push_bulk(Bytes::from("expansion".as_bytes()));
+                frame.push_int(expansion);
+            }
+            None => frame.push_bulk(Bytes::from("nonscaling".as_bytes())),
+        }
+
+        frame
+    }
+}
+
+impl BfAdd {
+    /// Create a new `BfAdd` command which adds `item` to the filter at `key`.
+    pub fn new(key: impl ToString, item: Bytes) -> Self {
+        BfAdd {
+            key: key.to_string(),
+            item,
+        }
+    }
+
+    /// Parse a `BfAdd` instance from a received frame.
+    ///
+    /// The `BF.ADD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BF.ADD key item
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BfAdd> {
+        let key = parse.next_string()?;
+        let item = parse.next_bytes()?;
+
+        Ok(BfAdd { key, item })
+    }
+
+    /// Apply the `BfAdd` command to the specified `Db` instance.
+    ///
+    /// Responds with `1` if the item was newly added and `0` if it may have
+    /// existed already.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.bf_add(self.key, &[self.item]) {
+            Ok(added) => add_reply(added[0]),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bf.add".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(self.item);
+        frame
+    }
+}
+
+impl BfMadd {
+    /// Create a new `BfMadd` command which adds `items` to the filter at `key`.
+    pub fn new(key: impl ToString, items: Vec<Bytes>) -> Self {
+        BfMadd {
+            key: key.to_string(),
+            items,
+        }
+    }
+
+    /// Parse a `BfMadd` instance from a received frame.
+    ///
+    /// The `BF.MADD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BF.MADD key item [item ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BfMadd> {
+        let key = parse.next_string()?;
+        let items = parse_items(parse)?;
+
+        Ok(BfMadd { key, items })
+    }
+
+    /// Apply the `BfMadd` command to the specified `Db` instance.
+    ///
+    /// Responds with an array holding, for each item, the reply `BF.ADD` would
+    /// have given. Items that could not be added, because a non scaling
+    /// filter is full, have an error in the array, as in Redis, so that the
+    /// command still reaches the replicas and the append-only file along with
+    /// the items added before.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.bf_add(self.key, &self.items) {
+            Ok(added) => Frame::Array(added.into_iter().map(add_reply).collect()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bf.madd".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for item in self.items {
+            frame.push_bulk(item);
+        }
+        frame
+    }
+}
+
+impl BfExists {
+    /// Create a new `BfExists` command which checks for `item` in the filter at
+    /// `key`.
+    pub fn new(key: impl ToString, item: Bytes) -> Self {
+        BfExists {
+            key: key.to_string(),
+            item,
+        }
+    }
+
+    /// Parse a `BfExists` instance from a received frame.
+    ///
+    /// The `BF.EXISTS` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BF.EXISTS key item
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BfExists> {
+        let key = parse.next_string()?;
+        let item = parse.next_bytes()?;
+
+        Ok(BfExists { key, item })
+    }
+
+    /// Apply the `BfExists` command to the specified `Db` instance.
+    ///
+    /// Responds with `1` if the item may exist and `0` if it definitely does
+    /// not.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.bf_exists(&self.key, &[self.item]) {
+            Ok(exists) => Frame::Int(exists[0] as u64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bf.exists".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(self.item);
+        frame
+    }
+}
+
+impl BfMexists {
+    /// Create a new `BfMexists` command which checks for `items` in the filter
+    /// at `key`.
+    pub fn new(key: impl ToString, items: Vec<Bytes>) -> Self {
+        BfMexists {
+            key: key.to_string(),
+            items,
+        }
+    }
+
+    /// Parse a `BfMexists` instance from a received frame.
+    ///
+    /// The `BF.MEXISTS` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BF.MEXISTS key item [item ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BfMexists> {
+        let key = parse.next_string()?;
+        let items = parse_items(parse)?;
+
+        Ok(BfMexists { key, items })
+    }
+
+    /// Apply the `BfMexists` command to the specified `Db` instance.
+    ///
+    /// Responds with an array holding, for each item, the reply `BF.EXISTS`
+    /// would have given.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.bf_exists(&self.key, &self.items) {
+            Ok(exists) => make_bool_array(exists),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bf.mexists".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for item in self.items {
+            frame.push_bulk(item);
+        }
+        frame
+    }
+}
+
+/// Returns the reply of `BF.ADD` for an item: whether it was newly added, or
+/// the error adding it.
+fn add_reply(added: Result<bool, &'static str>) -> Frame {
+    match added {
+        Ok(added) => Frame::Int(added as u64),
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
@@ -0,0 +1,73 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::cmd::LagPolicy;
+use crate::{Connection, Frame, Parse};
+
+/// Change settings of the current connection.
+#[derive(Debug)]
+pub enum Client {
+    /// Choose what happens when the connection falls behind the channels it
+    /// subscribes to. Applies to subscriptions made after the change.
+    PubsubLag(LagPolicy),
+}
+
+impl Client {
+    /// Parse a `Client` instance from a received frame.
+    ///
+    /// The `CLIENT` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CLIENT PUBSUB-LAG NOTIFY|DISCONNECT|BACKPRESSURE
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Client> {
+        let subcommand = parse.next_string()?.to_lowercase();
+
+        match &subcommand[..] {
+            "pubsub-lag" => {
+                let policy = match &parse.next_string()?.to_lowercase()[..] {
+                    "notify" => LagPolicy::Notify,
+                    "disconnect" => LagPolicy::Disconnect,
+                    "backpressure" => LagPolicy::Backpressure,
+                    policy => return Err(format!("ERR unknown lag policy '{}'", policy).into()),
+                };
+                Ok(Client::PubsubLag(policy))
+            }
+            _ => Err(format!("ERR unknown subcommand '{}'", subcommand).into()),
+        }
+    }
+
+    /// Apply the `Client` command to the connection.
+    #[instrument(skip(self, dst))]
+    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+        match self {
+            Client::PubsubLag(policy) => dst.set_lag_policy(policy),
+        }
+
+        let resp = Frame::Simple("OK".to_string());
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("client".as_bytes()));
+        match self {
+            Client::PubsubLag(policy) => {
+                let policy = match policy {
+                    LagPolicy::Notify => "notify",
+                    LagPolicy::Disconnect => "disconnect",
+                    LagPolicy::Backpressure => "backpressure",
+                };
+                frame.push_bulk(Bytes::from("pubsub-lag".as_bytes()));
+                frame.push_bulk(Bytes::from(policy.as_bytes()));
+            }
+        }
+        frame
+    }
+}
@@ -0,0 +1,482 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use tracing::{debug, instrument};
+
+use crate::cluster::BUS_PORT_OFFSET;
+use crate::cmd::{Parse, ParseError};
+use crate::db::{key_slot, Cluster as ClusterState, DbError, Node, SetSlot, SLOTS};
+use crate::{Connection, Frame, LockedDb};
+
+/// Manage the cluster, in cluster mode, and report its state.
+///
+/// Each node of a cluster serves the keys of some of the 16384 hash slots.
+/// Commands on the keys of another slot are replied `-MOVED slot host:port`,
+/// the address of the node serving it. While a slot is moved to another
+/// node, with `SETSLOT MIGRATING` on its node and `SETSLOT IMPORTING` on the
+/// other one, commands on its keys no longer on the first node are replied
+/// `-ASK slot host:port`. The other node serves them after `ASKING`.
+#[derive(Debug)]
+pub struct Cluster {
+    subcommand: Subcommand,
+}
+
+#[derive(Debug)]
+enum Subcommand {
+    /// Meet the node at an IP and port, with the cluster bus at a port of
+    /// its own or the default one.
+    Meet(String, u64, Option<u64>),
+    AddSlots(Vec<u64>),
+    /// Ranges of slots, both ends included.
+    AddSlotsRange(Vec<(u64, u64)>),
+    DelSlots(Vec<u64>),
+    DelSlotsRange(Vec<(u64, u64)>),
+    SetSlot(u64, SetSlot),
+    Slots,
+    Shards,
+    Nodes,
+    Info,
+    MyId,
+    KeySlot(Bytes),
+    CountKeysInSlot(u64),
+    GetKeysInSlot(u64, u64),
+}
+
+/// Allow the next command on the keys of a slot being moved to this node,
+/// sent by clients redirected with `-ASK`.
+#[derive(Debug, Default)]
+pub struct Asking {}
+
+impl Cluster {
+    /// Parse a `Cluster` instance from a received frame.
+    ///
+    /// The `CLUSTER` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CLUSTER MEET ip port [bus-port]
+    /// CLUSTER ADDSLOTS slot [slot ...]
+    /// CLUSTER ADDSLOTSRANGE start end [start end ...]
+    /// CLUSTER DELSLOTS slot [slot ...]
+    /// CLUSTER DELSLOTSRANGE start end [start end ...]
+    /// CLUSTER SETSLOT slot IMPORTING|MIGRATING|NODE node-id
+    /// CLUSTER SETSLOT slot STABLE
+    /// CLUSTER SLOTS|SHARDS|NODES|INFO|MYID
+    /// CLUSTER KEYSLOT key
+    /// CLUSTER COUNTKEYSINSLOT slot
+    /// CLUSTER GETKEYSINSLOT slot count
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Cluster> {
+        let subcommand = parse.next_string()?.to_lowercase();
+
+        let subcommand = match &subcommand[..] {
+            "meet" => {
+                let ip = parse.next_string()?;
+                let port = parse.next_int()?;
+                let bus_port = match parse.next_int() {
+                    Ok(bus_port) => Some(bus_port),
+                    Err(ParseError::EndOfStream) => None,
+                    Err(err) => return Err(err.into()),
+                };
+                Subcommand::Meet(ip, port, bus_port)
+            }
+            "addslots" => Subcommand::AddSlots(parse_ints(parse)?),
+            "addslotsrange" => Subcommand::AddSlotsRange(parse_ranges(parse)?),
+            "delslots" => Subcommand::DelSlots(parse_ints(parse)?),
+            "delslotsrange" => Subcommand::DelSlotsRange(parse_ranges(parse)?),
+            "setslot" => {
+                let slot = parse.next_int()?;
+                let change = match &parse.next_string()?.to_lowercase()[..] {
+                    "importing" => SetSlot::Importing(parse.next_string()?),
+                    "migrating" => SetSlot::Migrating(parse.next_string()?),
+                    "node" => SetSlot::Node(parse.next_string()?),
+                    "stable" => SetSlot::Stable,
+                    change => {
+                        return Err(format!("ERR unknown SETSLOT subcommand '{}'", change).into())
+                    }
+                };
+                Subcommand::SetSlot(slot, change)
+            }
+            "slots" => Subcommand::Slots,
+            "shards" => Subcommand::Shards,
+            "nodes" => Subcommand::Nodes,
+            "info" => Subcommand::Info,
+            "myid" => Subcommand::MyId,
+            "keyslot" => Subcommand::KeySlot(parse.next_bytes()?),
+            "countkeysinslot" => Subcommand::CountKeysInSlot(parse.next_int()?),
+            "getkeysinslot" => Subcommand::GetKeysInSlot(parse.next_int()?, parse.next_int()?),
+            _ => return Err(format!("ERR unknown subcommand '{}'", subcommand).into()),
+        };
+
+        Ok(Cluster { subcommand })
+    }
+
+    /// Apply the `Cluster` command to the specified `Db` instance.
+    ///
+    /// Out of cluster mode, every subcommand is replied an error.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match self.subcommand.execute(db) {
+            Ok(resp) => resp,
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+}
+
+impl Subcommand {
+    fn execute(self, db: &mut LockedDb) -> Result<Frame, DbError> {
+        let resp = match self {
+            Subcommand::Meet(ip, port, bus_port) => {
+                let port = u16::try_from(port).ok();
+                let bus_port = match bus_port {
+                    Some(bus_port) => u16::try_from(bus_port).ok(),
+                    None => port.and_then(|port| port.checked_add(BUS_PORT_OFFSET)),
+                };
+                let bus_port = match (port, bus_port) {
+                    (Some(_), Some(bus_port)) => bus_port,
+                    _ => return Err("ERR Invalid node address specified".into()),
+                };
+
+                db.cluster()?.meet(ip, bus_port);
+                ok()
+            }
+            Subcommand::AddSlots(slots) => {
+                let slots = to_slots(&slots)?;
+                db.cluster()?.add_slots(&slots)?;
+                ok()
+            }
+            Subcommand::AddSlotsRange(ranges) => {
+                let slots = range_slots(&ranges)?;
+                db.cluster()?.add_slots(&slots)?;
+                ok()
+            }
+            Subcommand::DelSlots(slots) => {
+                let slots = to_slots(&slots)?;
+                db.cluster()?.del_slots(&slots)?;
+                ok()
+            }
+            Subcommand::DelSlotsRange(ranges) => {
+                let slots = range_slots(&ranges)?;
+                db.cluster()?.del_slots(&slots)?;
+                ok()
+            }
+            Subcommand::SetSlot(slot, change) => {
+                let slot = to_slot(slot)?;
+                let has_keys = !db.keys_in_slot(slot, 1).is_empty();
+                db.cluster()?.set_slot(slot, change, has_keys)?;
+                ok()
+            }
+            Subcommand::Slots => {
+                let cluster = db.cluster()?;
+                Frame::Array(
+                    cluster
+                        .ranges()
+                        .into_iter()
+                        .map(|(start, end, node)| {
+                            Frame::Array(vec![
+                                Frame::Int(u64::from(start)),
+                                Frame::Int(u64::from(end)),
+                                Frame::Array(vec![
+                                    bulk(&node.ip),
+                                    Frame::Int(u64::from(node.port)),
+                                    bulk(&node.id),
+                                ]),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            Subcommand::Shards => {
+                let offset = db.replication_info().offset;
+                let cluster = db.cluster()?;
+                let myself = &cluster.myself().id;
+                Frame::Array(
+                    cluster
+                        .nodes()
+                        .into_iter()
+                        .map(|node| {
+                            let slots = cluster
+                                .slot_ranges(&node.id)
+                                .into_iter()
+                                .flat_map(|(start, end)| {
+                                    [Frame::Int(u64::from(start)), Frame::Int(u64::from(end))]
+                                })
+                                .collect();
+                            let offset = if node.id == *myself { offset } else { 0 };
+                            Frame::Array(vec![
+                                bulk("slots"),
+                                Frame::Array(slots),
+                                bulk("nodes"),
+                                Frame::Array(vec![shard_node(node, offset)]),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            Subcommand::Nodes => Frame::Bulk(Bytes::from(nodes(db.cluster()?))),
+            Subcommand::Info => Frame::Bulk(Bytes::from(info(db.cluster()?))),
+            Subcommand::MyId => bulk(&db.cluster()?.myself().id),
+            Subcommand::KeySlot(key) => {
+                db.cluster()?;
+                Frame::Int(u64::from(key_slot(&key)))
+            }
+            Subcommand::CountKeysInSlot(slot) => {
+                db.cluster()?;
+                let slot = to_slot(slot)?;
+                Frame::Int(db.keys_in_slot(slot, usize::MAX).len() as u64)
+            }
+            Subcommand::GetKeysInSlot(slot, count) => {
+                db.cluster()?;
+                let slot = to_slot(slot)?;
+                let count = usize::try_from(count).unwrap_or(usize::MAX);
+                Frame::Array(
+                    db.keys_in_slot(slot, count)
+                        .into_iter()
+                        .map(|key| Frame::Bulk(Bytes::from(key)))
+                        .collect(),
+                )
+            }
+        };
+
+        Ok(resp)
+    }
+}
+
+impl Asking {
+    /// Create a new `Asking` command.
+    pub fn new() -> Asking {
+        Asking {}
+    }
+
+    /// Parse an `Asking` instance from a received frame.
+    ///
+    /// The `ASKING` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// ASKING
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Asking> {
+        Ok(Asking {})
+    }
+
+    /// Apply the `Asking` command to the connection.
+    #[instrument(skip(self, dst))]
+    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+        dst.set_asking();
+
+        let resp = Frame::Simple("OK".to_string());
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("asking".as_bytes()));
+        frame
+    }
+}
+
+/// Consume the remainder of the frame as a non-empty list of integers.
+fn parse_ints(parse: &mut Parse) -> crate::Result<Vec<u64>> {
+    let mut ints = vec![parse.next_int()?];
+
+    loop {
+        match parse.next_int() {
+            Ok(int) => ints.push(int),
+            Err(ParseError::EndOfStream) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(ints)
+}
+
+/// Consume the remainder of the frame as a non-empty list of ranges.
+fn parse_ranges(parse: &mut Parse) -> crate::Result<Vec<(u64, u64)>> {
+    let ints = parse_ints(parse)?;
+    if ints.len() % 2 != 0 {
+        return Err("ERR wrong number of arguments for 'cluster' command".into());
+    }
+
+    Ok(ints.chunks(2).map(|range| (range[0], range[1])).collect())
+}
+
+fn to_slot(slot: u64) -> Result<u16, DbError> {
+    match slot {
+        slot if slot < SLOTS as u64 => Ok(slot as u16),
+        _ => Err("ERR Invalid or out of range slot".into()),
+    }
+}
+
+fn to_slots(slots: &[u64]) -> Result<Vec<u16>, DbError> {
+    slots.iter().map(|slot| to_slot(*slot)).collect()
+}
+
+/// Returns the slots of `ranges`, both ends included.
+fn range_slots(ranges: &[(u64, u64)]) -> Result<Vec<u16>, DbError> {
+    let mut slots = vec![];
+    for (start, end) in ranges {
+        let (start, end) = (to_slot(*start)?, to_slot(*end)?);
+        if start > end {
+            return Err(format!(
+                "ERR start slot number {} is greater than end slot number {}",
+                start, end
+            )
+            .into());
+        }
+        slots.extend(start..=end);
+    }
+    Ok(slots)
+}
+
+/// Returns a node as `CLUSTER SHARDS` replies it.
+fn shard_node(node: &Node, offset: u64) -> Frame {
+    let health = if node.fail { "fail" } else { "online" };
+    Frame::Array(vec![
+        bulk("id"),
+        bulk(&node.id),
+        bulk("port"),
+        Frame::Int(u64::from(node.port)),
+        bulk("ip"),
+        bulk(&node.ip),
+        bulk("endpoint"),
+        bulk(&node.ip),
+        bulk("role"),
+        bulk("master"),
+        bulk("replication-offset"),
+        Frame::Int(offset),
+        bulk("health"),
+        bulk(health),
+    ])
+}
+
+/// Returns the nodes of the cluster, one per line, in the format of Redis.
+///
+/// ```text
+/// id ip:port@bus-port flags primary ping-sent pong-received config-epoch link-state slot...
+/// ```
+fn nodes(cluster: &ClusterState) -> String {
+    let myself = &cluster.myself().id;
+    let (migrating, importing) = (cluster.migrating(), cluster.importing());
+
+    let mut out = String::new();
+    for node in cluster.nodes() {
+        let mut flags = vec![];
+        if node.id == *myself {
+            flags.push("myself");
+        }
+        flags.push("master");
+        if node.fail {
+            flags.push("fail");
+        } else if node.pfail {
+            flags.push("fail?");
+        }
+
+        let link = if node.id == *myself || !node.pfail {
+            "connected"
+        } else {
+            "disconnected"
+        };
+
+        // Writing to a `String` cannot fail.
+        let _ = write!(
+            out,
+            "{} {}:{}@{} {} - {} {} {} {}",
+            node.id,
+            node.ip,
+            node.port,
+            node.bus_port,
+            flags.join(","),
+            node.ping_sent.map_or(0, unix_time_ms),
+            node.pong_received.map_or(0, unix_time_ms),
+            node.config_epoch,
+            link
+        );
+
+        for (start, end) in cluster.slot_ranges(&node.id) {
+            if start == end {
+                let _ = write!(out, " {}", start);
+            } else {
+                let _ = write!(out, " {}-{}", start, end);
+            }
+        }
+
+        if node.id == *myself {
+            for (slot, id) in &migrating {
+                let _ = write!(out, " [{}->-{}]", slot, id);
+            }
+            for (slot, id) in &importing {
+                let _ = write!(out, " [{}-<-{}]", slot, id);
+            }
+        }
+
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Returns the state of the cluster as `field:value` lines, in the format of
+/// Redis.
+fn info(cluster: &ClusterState) -> String {
+    let (mut assigned, mut pfail, mut fail) = (0, 0, 0);
+    for slot in 0..SLOTS as u16 {
+        if let Some(node) = cluster.owner(slot) {
+            assigned += 1;
+            if node.fail {
+                fail += 1;
+            } else if node.pfail {
+                pfail += 1;
+            }
+        }
+    }
+
+    let state = if cluster.is_ok() { "ok" } else { "fail" };
+    format!(
+        "cluster_state:{}\r\n\
+         cluster_slots_assigned:{}\r\n\
+         cluster_slots_ok:{}\r\n\
+         cluster_slots_pfail:{}\r\n\
+         cluster_slots_fail:{}\r\n\
+         cluster_known_nodes:{}\r\n\
+         cluster_size:{}\r\n\
+         cluster_current_epoch:{}\r\n\
+         cluster_my_epoch:{}\r\n",
+        state,
+        assigned,
+        assigned - pfail - fail,
+        pfail,
+        fail,
+        cluster.nodes().len(),
+        cluster.size(),
+        cluster.current_epoch(),
+        cluster.myself().config_epoch
+    )
+}
+
+fn ok() -> Frame {
+    Frame::Simple("OK".to_string())
+}
+
+fn bulk(value: &str) -> Frame {
+    Frame::Bulk(Bytes::from(value.to_string()))
+}
+
+/// Convert `at` to a Unix time in milliseconds.
+fn unix_time_ms(at: Instant) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.saturating_sub(at.elapsed()).as_millis() as u64
+}
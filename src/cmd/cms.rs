@@ -0,0 +1,375 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use super::parse_items;
+use crate::db::CountMinSketch;
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Create a Count-Min sketch with explicit dimensions.
+#[derive(Debug, Clone)]
+pub struct CmsInitByDim {
+    key: String,
+    /// Number of counters in each row.
+    width: u64,
+    /// Number of rows.
+    depth: u64,
+}
+
+/// Create a Count-Min sketch sized for an error bound.
+///
+/// Estimates exceed the true count by at most `error` times the total count,
+/// except with the given `probability`.
+#[derive(Debug, Clone)]
+pub struct CmsInitByProb {
+    key: String,
+    error: f64,
+    probability: f64,
+}
+
+/// Increase the count of one or more items in a Count-Min sketch.
+#[derive(Debug, Clone)]
+pub struct CmsIncrBy {
+    key: String,
+    items: Vec<(Bytes, u64)>,
+}
+
+/// Return the estimated count of one or more items in a Count-Min sketch.
+#[derive(Debug)]
+pub struct CmsQuery {
+    key: String,
+    items: Vec<Bytes>,
+}
+
+/// Overwrite a Count-Min sketch with the weighted sum of other sketches of the
+/// same dimensions.
+#[derive(Debug, Clone)]
+pub struct CmsMerge {
+    destination: String,
+    /// Source keys with their weights. Weights default to `1`.
+    sources: Vec<(String, u64)>,
+}
+
+impl CmsInitByDim {
+    /// Create a new `CmsInitByDim` command.
+    pub fn new(key: impl ToString, width: u64, depth: u64) -> Self {
+        CmsInitByDim {
+            key: key.to_string(),
+            width,
+            depth,
+        }
+    }
+
+    /// Parse a `CmsInitByDim` instance from a received frame.
+    ///
+    /// The `CMS.INITBYDIM` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CMS.INITBYDIM key width depth
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CmsInitByDim> {
+        let key = parse.next_string()?;
+        let width = parse.next_int()?;
+        let depth = parse.next_int()?;
+
+        if width == 0 || depth == 0 {
+            return Err("ERR CMS: invalid width/depth".into());
+        }
+        if !CountMinSketch::fits(width, depth) {
+            return Err("ERR CMS: sketch too large".into());
+        }
+
+        Ok(CmsInitByDim { key, width, depth })
+    }
+
+    /// Apply the `CmsInitByDim` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let sketch = CountMinSketch::new(self.width as usize, self.depth as usize);
+
+        let resp = match db.cms_init(self.key, sketch) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cms.initbydim".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_int(self.width);
+        frame.push_int(self.depth);
+        frame
+    }
+}
+
+impl CmsInitByProb {
+    /// Create a new `CmsInitByProb` command.
+    pub fn new(key: impl ToString, error: f64, probability: f64) -> Self {
+        CmsInitByProb {
+            key: key.to_string(),
+            error,
+            probability,
+        }
+    }
+
+    /// Parse a `CmsInitByProb` instance from a received frame.
+    ///
+    /// The `CMS.INITBYPROB` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CMS.INITBYPROB key error probability
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CmsInitByProb> {
+        let key = parse.next_string()?;
+        let error = parse.next_float()?;
+        let probability = parse.next_float()?;
+
+        let in_range = |value: f64| value > 0.0 && value < 1.0;
+        if !in_range(error) || !in_range(probability) {
+            return Err("ERR CMS: invalid prob value".into());
+        }
+        let (width, depth) = CountMinSketch::dimensions(error, probability);
+        if !CountMinSketch::fits(width, depth) {
+            return Err("ERR CMS: sketch too large".into());
+        }
+
+        Ok(CmsInitByProb {
+            key,
+            error,
+            probability,
+        })
+    }
+
+    /// Apply the `CmsInitByProb` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let sketch = CountMinSketch::with_error(self.error, self.probability);
+
+        let resp = match db.cms_init(self.key, sketch) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cms.initbyprob".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(Bytes::from(self.error.to_string()));
+        frame.push_bulk(Bytes::from(self.probability.to_string()));
+        frame
+    }
+}
+
+impl CmsIncrBy {
+    /// Create a new `CmsIncrBy` command.
+    pub fn new(key: impl ToString, items: Vec<(Bytes, u64)>) -> Self {
+        CmsIncrBy {
+            key: key.to_string(),
+            items,
+        }
+    }
+
+    /// Parse a `CmsIncrBy` instance from a received frame.
+    ///
+    /// The `CMS.INCRBY` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CMS.INCRBY key item increment [item increment ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CmsIncrBy> {
+        let key = parse.next_string()?;
+        let mut items = vec![(parse.next_bytes()?, parse.next_int()?)];
+
+        loop {
+            match parse.next_bytes() {
+                Ok(item) => items.push((item, parse.next_int()?)),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(CmsIncrBy { key, items })
+    }
+
+    /// Apply the `CmsIncrBy` command to the specified `Db` instance.
+    ///
+    /// Responds with the new estimated count of each item.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.cms_incr_by(&self.key, &self.items) {
+            Ok(counts) => make_count_array(counts),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cms.incrby".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for (item, increment) in self.items {
+            frame.push_bulk(item);
+            frame.push_int(increment);
+        }
+        frame
+    }
+}
+
+impl CmsQuery {
+    /// Create a new `CmsQuery` command.
+    pub fn new(key: impl ToString, items: Vec<Bytes>) -> Self {
+        CmsQuery {
+            key: key.to_string(),
+            items,
+        }
+    }
+
+    /// Parse a `CmsQuery` instance from a received frame.
+    ///
+    /// The `CMS.QUERY` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CMS.QUERY key item [item ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CmsQuery> {
+        let key = parse.next_string()?;
+        let items = parse_items(parse)?;
+
+        Ok(CmsQuery { key, items })
+    }
+
+    /// Apply the `CmsQuery` command to the specified `Db` instance.
+    ///
+    /// Responds with the estimated count of each item.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.cms_query(&self.key, &self.items) {
+            Ok(counts) => make_count_array(counts),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cms.query".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for item in self.items {
+            frame.push_bulk(item);
+        }
+        frame
+    }
+}
+
+impl CmsMerge {
+    /// Create a new `CmsMerge` command.
+    pub fn new(destination: impl ToString, sources: Vec<(String, u64)>) -> Self {
+        CmsMerge {
+            destination: destination.to_string(),
+            sources,
+        }
+    }
+
+    /// Parse a `CmsMerge` instance from a received frame.
+    ///
+    /// The `CMS.MERGE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CMS.MERGE destination numkeys source [source ...] [WEIGHTS weight [weight ...]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CmsMerge> {
+        let destination = parse.next_string()?;
+        let num_keys = parse.next_int()?;
+
+        if num_keys == 0 {
+            return Err("ERR CMS: numkeys must be positive".into());
+        }
+
+        let mut sources = Vec::with_capacity(num_keys as usize);
+        for _ in 0..num_keys {
+            sources.push((parse.next_string()?, 1));
+        }
+
+        match parse.next_string() {
+            Ok(s) if s.to_uppercase() == "WEIGHTS" => {
+                for (_, weight) in sources.iter_mut() {
+                    *weight = parse.next_int()?;
+                }
+            }
+            Ok(s) => return Err(format!("ERR unknown `CMS.MERGE` option `{}`", s).into()),
+            Err(ParseError::EndOfStream) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(CmsMerge {
+            destination,
+            sources,
+        })
+    }
+
+    /// Apply the `CmsMerge` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.cms_merge(&self.destination, &self.sources) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cms.merge".as_bytes()));
+        frame.push_bulk(Bytes::from(self.destination.into_bytes()));
+        frame.push_int(self.sources.len() as u64);
+
+        let mut weights = Vec::with_capacity(self.sources.len());
+        for (key, weight) in self.sources {
+            frame.push_bulk(Bytes::from(key.into_bytes()));
+            weights.push(weight);
+        }
+
+        frame.push_bulk(Bytes::from("weights".as_bytes()));
+        for weight in weights {
+            frame.push_int(weight);
+        }
+        frame
+    }
+}
+
+/// Creates an array of counts.
+fn make_count_array(counts: Vec<u64>) -> Frame {
+    let mut resp = Frame::array();
+    for count in counts {
+        resp.push_int(count);
+    }
+    resp
+}
@@ -0,0 +1,76 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse};
+
+/// Read or change server parameters at runtime.
+#[derive(Debug)]
+pub enum Config {
+    /// Return the parameters matching a glob-style pattern, with their
+    /// values.
+    Get(Bytes),
+    /// Set a parameter.
+    Set(String, String),
+}
+
+impl Config {
+    /// Parse a `Config` instance from a received frame.
+    ///
+    /// The `CONFIG` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CONFIG GET pattern
+    /// CONFIG SET parameter value
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Config> {
+        let subcommand = parse.next_string()?.to_lowercase();
+
+        match &subcommand[..] {
+            "get" => Ok(Config::Get(parse.next_bytes()?)),
+            "set" => Ok(Config::Set(parse.next_string()?, parse.next_string()?)),
+            _ => Err(format!("ERR unknown subcommand '{}'", subcommand).into()),
+        }
+    }
+
+    /// Apply the `Config` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match self {
+            Config::Get(pattern) => {
+                let mut resp = Frame::array();
+                for (name, value) in db.config_get(&pattern) {
+                    resp.push_bulk(Bytes::from(name));
+                    resp.push_bulk(Bytes::from(value));
+                }
+                resp
+            }
+            Config::Set(name, value) => match db.config_set(&name, &value) {
+                Ok(()) => Frame::Simple("OK".to_string()),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("config".as_bytes()));
+        match self {
+            Config::Get(pattern) => {
+                frame.push_bulk(Bytes::from("get".as_bytes()));
+                frame.push_bulk(pattern);
+            }
+            Config::Set(name, value) => {
+                frame.push_bulk(Bytes::from("set".as_bytes()));
+                frame.push_bulk(Bytes::from(name));
+                frame.push_bulk(Bytes::from(value));
+            }
+        }
+        frame
+    }
+}
@@ -0,0 +1,178 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse};
+
+/// Add an item to a cuckoo filter, creating the filter if it does not exist.
+///
+/// An item may be added more than once. It then has to be deleted as many
+/// times before `CF.EXISTS` stops reporting it.
+#[derive(Debug, Clone)]
+pub struct CfAdd {
+    key: String,
+    item: Bytes,
+}
+
+/// Delete one occurrence of an item from a cuckoo filter.
+#[derive(Debug, Clone)]
+pub struct CfDel {
+    key: String,
+    item: Bytes,
+}
+
+/// Check whether an item may be in a cuckoo filter.
+#[derive(Debug)]
+pub struct CfExists {
+    key: String,
+    item: Bytes,
+}
+
+impl CfAdd {
+    /// Create a new `CfAdd` command which adds `item` to the filter at `key`.
+    pub fn new(key: impl ToString, item: Bytes) -> Self {
+        CfAdd {
+            key: key.to_string(),
+            item,
+        }
+    }
+
+    /// Parse a `CfAdd` instance from a received frame.
+    ///
+    /// The `CF.ADD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CF.ADD key item
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CfAdd> {
+        let key = parse.next_string()?;
+        let item = parse.next_bytes()?;
+
+        Ok(CfAdd { key, item })
+    }
+
+    /// Apply the `CfAdd` command to the specified `Db` instance.
+    ///
+    /// Responds with `1` once the item is added.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.cf_add(self.key, &self.item) {
+            Ok(()) => Frame::Int(1),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cf.add".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(self.item);
+        frame
+    }
+}
+
+impl CfDel {
+    /// Create a new `CfDel` command which deletes `item` from the filter at
+    /// `key`.
+    pub fn new(key: impl ToString, item: Bytes) -> Self {
+        CfDel {
+            key: key.to_string(),
+            item,
+        }
+    }
+
+    /// Parse a `CfDel` instance from a received frame.
+    ///
+    /// The `CF.DEL` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CF.DEL key item
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CfDel> {
+        let key = parse.next_string()?;
+        let item = parse.next_bytes()?;
+
+        Ok(CfDel { key, item })
+    }
+
+    /// Apply the `CfDel` command to the specified `Db` instance.
+    ///
+    /// Responds with `1` if the item was deleted and `0` if it was not found.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.cf_del(&self.key, &self.item) {
+            Ok(deleted) => Frame::Int(deleted as u64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cf.del".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(self.item);
+        frame
+    }
+}
+
+impl CfExists {
+    /// Create a new `CfExists` command which checks for `item` in the filter
+    /// at `key`.
+    pub fn new(key: impl ToString, item: Bytes) -> Self {
+        CfExists {
+            key: key.to_string(),
+            item,
+        }
+    }
+
+    /// Parse a `CfExists` instance from a received frame.
+    ///
+    /// The `CF.EXISTS` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// CF.EXISTS key item
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CfExists> {
+        let key = parse.next_string()?;
+        let item = parse.next_bytes()?;
+
+        Ok(CfExists { key, item })
+    }
+
+    /// Apply the `CfExists` command to the specified `Db` instance.
+    ///
+    /// Responds with `1` if the item may exist and `0` if it definitely does
+    /// not.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.cf_exists(&self.key, &self.item) {
+            Ok(exists) => Frame::Int(exists as u64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("cf.exists".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(self.item);
+        frame
+    }
+}
@@ -0,0 +1,420 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tracing::{debug, instrument};
+
+use crate::cmd::{Asking, Del, Parse, ParseError};
+use crate::db::unix_time_ms;
+use crate::{Command, Connection, Db, Frame, LockedDb};
+
+/// Serialize the value at `key` along with its expiration.
+///
+/// The payload is versioned and checksummed. `RESTORE` recreates the value
+/// from it, on this server or another one.
+#[derive(Debug)]
+pub struct Dump {
+    key: String,
+}
+
+/// Recreate a value serialized by `DUMP` at `key`.
+///
+/// `ttl` is the time to live of the key in milliseconds, or with `ABSTTL`
+/// the Unix time at which it expires. Zero keeps the expiration saved in the
+/// payload, if any.
+///
+/// # Options
+///
+/// * REPLACE -- Replace the value at `key`, rather than fail, if the key
+///   exists.
+/// * ABSTTL -- `ttl` is a Unix time in milliseconds.
+#[derive(Debug, Clone)]
+pub struct Restore {
+    key: String,
+    ttl: u64,
+    payload: Bytes,
+    replace: bool,
+    absttl: bool,
+}
+
+/// Move keys to another server, serialized with `DUMP` and recreated with
+/// `RESTORE`.
+///
+/// Keys that do not exist are skipped. The keys are removed once the target
+/// server restored them, unless they were modified in the meantime.
+///
+/// # Options
+///
+/// * COPY -- Do not remove the keys.
+/// * REPLACE -- Replace the keys that already exist on the target server.
+/// * KEYS -- The keys to move, when `key` is the empty string.
+#[derive(Debug)]
+pub struct Migrate {
+    host: String,
+    port: u16,
+    keys: Vec<String>,
+    db: u64,
+    /// Maximum time waited for each exchange with the target server.
+    timeout: Duration,
+    copy: bool,
+    replace: bool,
+}
+
+impl Dump {
+    /// Create a new `Dump` command serializing the value at `key`.
+    pub fn new(key: impl ToString) -> Dump {
+        Dump {
+            key: key.to_string(),
+        }
+    }
+
+    /// Parse a `Dump` instance from a received frame.
+    ///
+    /// The `DUMP` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// DUMP key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Dump> {
+        let key = parse.next_string()?;
+        Ok(Dump { key })
+    }
+
+    /// Apply the `Dump` command to the specified `Db` instance.
+    ///
+    /// Replies with the payload, or `Null` if the key does not exist.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.dump(&self.key) {
+            Some(payload) => Frame::Bulk(payload),
+            None => Frame::Null,
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("dump".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame
+    }
+}
+
+impl Restore {
+    /// Create a new `Restore` command recreating `payload` at `key`, with a
+    /// time to live of `ttl` milliseconds.
+    pub fn new(key: impl ToString, ttl: u64, payload: Bytes, replace: bool) -> Restore {
+        Restore {
+            key: key.to_string(),
+            ttl,
+            payload,
+            replace,
+            absttl: false,
+        }
+    }
+
+    /// Parse a `Restore` instance from a received frame.
+    ///
+    /// The `RESTORE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// RESTORE key ttl payload [REPLACE] [ABSTTL]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Restore> {
+        let key = parse.next_string()?;
+        let ttl = parse.next_int()?;
+        let payload = parse.next_bytes()?;
+
+        let mut replace = false;
+        let mut absttl = false;
+        loop {
+            match parse.next_string() {
+                Ok(s) if s.eq_ignore_ascii_case("replace") => replace = true,
+                Ok(s) if s.eq_ignore_ascii_case("absttl") => absttl = true,
+                Ok(s) => return Err(format!("unsupported `RESTORE` option '{}'", s).into()),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Restore {
+            key,
+            ttl,
+            payload,
+            replace,
+            absttl,
+        })
+    }
+
+    /// Apply the `Restore` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let expires_at = match self.ttl {
+            0 => None,
+            ttl if self.absttl => Some(ttl),
+            ttl => Some(unix_time_ms(Instant::now()).saturating_add(ttl)),
+        };
+
+        let resp = match db.restore(self.key, &self.payload, expires_at, self.replace) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Replace the time to live by the Unix time it stands for, so that the
+    /// command has the same effect when replayed later.
+    pub(crate) fn make_absolute(&mut self) {
+        if self.ttl > 0 && !self.absttl {
+            self.ttl = unix_time_ms(Instant::now()).saturating_add(self.ttl);
+            self.absttl = true;
+        }
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("restore".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_int(self.ttl);
+        frame.push_bulk(self.payload);
+        if self.replace {
+            frame.push_bulk(Bytes::from("replace".as_bytes()));
+        }
+        if self.absttl {
+            frame.push_bulk(Bytes::from("absttl".as_bytes()));
+        }
+        frame
+    }
+}
+
+impl Migrate {
+    /// Create a new `Migrate` command moving `keys` to the server at `host`
+    /// and `port`.
+    pub fn new(host: impl ToString, port: u16, keys: Vec<String>, timeout: Duration) -> Migrate {
+        Migrate {
+            host: host.to_string(),
+            port,
+            keys,
+            db: 0,
+            timeout,
+            copy: false,
+            replace: false,
+        }
+    }
+
+    /// Parse a `Migrate` instance from a received frame.
+    ///
+    /// The `MIGRATE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// MIGRATE host port key|"" db timeout [COPY] [REPLACE] [KEYS key [key ...]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Migrate> {
+        let host = parse.next_string()?;
+        let port = parse.next_int()?.try_into()?;
+        let key = parse.next_string()?;
+        let db = parse.next_int()?;
+        let timeout = match parse.next_int()? {
+            // As in Redis, zero stands for the default timeout of one second.
+            0 => Duration::from_secs(1),
+            ms => Duration::from_millis(ms),
+        };
+
+        let mut copy = false;
+        let mut replace = false;
+        let mut keys = vec![];
+        loop {
+            match parse.next_string() {
+                Ok(s) if s.eq_ignore_ascii_case("copy") => copy = true,
+                Ok(s) if s.eq_ignore_ascii_case("replace") => replace = true,
+                Ok(s) if s.eq_ignore_ascii_case("keys") => {
+                    if !key.is_empty() {
+                        return Err("`MIGRATE` takes either a key or KEYS".into());
+                    }
+                    loop {
+                        match parse.next_string() {
+                            Ok(key) => keys.push(key),
+                            Err(ParseError::EndOfStream) => break,
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                }
+                Ok(s) => return Err(format!("unsupported `MIGRATE` option '{}'", s).into()),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if !key.is_empty() {
+            keys.push(key);
+        } else if keys.is_empty() {
+            return Err("`MIGRATE` needs a key, or KEYS".into());
+        }
+
+        Ok(Migrate {
+            host,
+            port,
+            keys,
+            db,
+            timeout,
+            copy,
+            replace,
+        })
+    }
+
+    /// Apply the `Migrate` command to the specified `Db` instance.
+    ///
+    /// The lock is only held to serialize the keys, then to remove them, not
+    /// while waiting for the target server.
+    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+        let resp = self.migrate(db).await;
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    async fn migrate(self, db: &Db) -> Frame {
+        // mini-redis has a single database.
+        if self.db != 0 {
+            return Frame::Error("ERR DB index is out of range".to_string());
+        }
+
+        let (payloads, asking) = {
+            let mut db = db.lock().await;
+            let payloads: Vec<_> = self
+                .keys
+                .iter()
+                .filter_map(|key| Some((key.clone(), db.dump(key)?)))
+                .collect();
+            (payloads, db.cluster_enabled())
+        };
+
+        if payloads.is_empty() {
+            return Frame::Simple("NOKEY".to_string());
+        }
+
+        let replies = match self.send(&payloads, asking).await {
+            Ok(replies) => replies,
+            Err(err) => {
+                return Frame::Error(format!(
+                    "IOERR error or timeout migrating to target instance: {}",
+                    err
+                ))
+            }
+        };
+
+        let mut error = None;
+        let mut restored = vec![];
+        for ((key, payload), reply) in payloads.into_iter().zip(replies) {
+            match reply {
+                Frame::Error(msg) => {
+                    error.get_or_insert(msg);
+                }
+                _ => restored.push((key, payload)),
+            }
+        }
+
+        if !self.copy {
+            let mut db = db.lock().await;
+            let keys: Vec<_> = restored
+                .into_iter()
+                .filter(|(key, payload)| db.is_unchanged(key, payload))
+                .map(|(key, _)| key)
+                .collect();
+
+            // Executed as a command to be logged to the append-only file.
+            // Its error, such as `READONLY` on a replica, is the reply: the
+            // keys were copied rather than moved.
+            if !keys.is_empty() {
+                match Command::Del(Del::new(keys)).execute(&mut db) {
+                    Ok(Frame::Error(msg)) => return Frame::Error(msg),
+                    Ok(_) => {}
+                    Err(err) => return Frame::Error(format!("ERR {}", err)),
+                }
+            }
+        }
+
+        match error {
+            Some(msg) => Frame::Error(format!("ERR Target instance replied with error: {}", msg)),
+            None => Frame::Simple("OK".to_string()),
+        }
+    }
+
+    /// Send a `RESTORE` command for each of `payloads` to the target server
+    /// and return the replies.
+    ///
+    /// With `asking`, each `RESTORE` follows an `ASKING`, so that a cluster
+    /// node importing the slot of the key accepts it.
+    async fn send(&self, payloads: &[(String, Bytes)], asking: bool) -> crate::Result<Vec<Frame>> {
+        let socket = time::timeout(
+            self.timeout,
+            TcpStream::connect((&self.host[..], self.port)),
+        )
+        .await??;
+        let mut connection = Connection::new(socket);
+
+        // The expirations are part of the payloads.
+        for (key, payload) in payloads {
+            if asking {
+                let frame = Asking::new().into_frame();
+                time::timeout(self.timeout, connection.write_frame(&frame)).await??;
+            }
+            let frame = Restore::new(key, 0, payload.clone(), self.replace).into_frame();
+            time::timeout(self.timeout, connection.write_frame(&frame)).await??;
+        }
+
+        let mut replies = vec![];
+        for _ in payloads {
+            // The reply to `ASKING` is always OK.
+            if asking
+                && time::timeout(self.timeout, connection.read_frame())
+                    .await??
+                    .is_none()
+            {
+                return Err("connection closed by the target instance".into());
+            }
+            match time::timeout(self.timeout, connection.read_frame()).await?? {
+                Some(frame) => replies.push(frame),
+                None => return Err("connection closed by the target instance".into()),
+            }
+        }
+
+        Ok(replies)
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("migrate".as_bytes()));
+        frame.push_bulk(Bytes::from(self.host.into_bytes()));
+        frame.push_int(self.port as u64);
+        frame.push_bulk(Bytes::new());
+        frame.push_int(self.db);
+        frame.push_int(self.timeout.as_millis() as u64);
+        if self.copy {
+            frame.push_bulk(Bytes::from("copy".as_bytes()));
+        }
+        if self.replace {
+            frame.push_bulk(Bytes::from("replace".as_bytes()));
+        }
+        frame.push_bulk(Bytes::from("keys".as_bytes()));
+        for key in self.keys {
+            frame.push_bulk(Bytes::from(key.into_bytes()));
+        }
+        frame
+    }
+}
@@ -0,0 +1,350 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::cmd::{error_reply, Command, ParseError};
+use crate::db::{CachedScript, Host, RunningScript};
+use crate::{Connection, Db, Frame, LockedDb, Parse};
+
+/// Run a script.
+///
+/// The script runs while holding the lock: no other command executes until
+/// it completes. See `db::script` for the language.
+#[derive(Debug)]
+pub struct Eval {
+    script: Bytes,
+    numkeys: i64,
+    /// The keys, then the other arguments.
+    args: Vec<Bytes>,
+}
+
+/// Run a script cached by `EVAL` or `SCRIPT LOAD`, by its SHA-1 digest.
+#[derive(Debug)]
+pub struct EvalSha {
+    sha: String,
+    numkeys: i64,
+    /// The keys, then the other arguments.
+    args: Vec<Bytes>,
+}
+
+/// Manage the script cache and the running script.
+#[derive(Debug)]
+pub enum Script {
+    /// Cache a script without running it. Replies with its SHA-1 digest.
+    Load(Bytes),
+    /// Return whether each script is cached.
+    Exists(Vec<String>),
+    /// Empty the cache.
+    Flush,
+    /// Stop the running script, provided it did not modify any key.
+    Kill,
+}
+
+/// Executes the commands called by a script.
+struct ScriptHost<'a, 'b> {
+    db: &'a mut LockedDb<'b>,
+    running: RunningScript<'b>,
+}
+
+impl Eval {
+    /// Create a new `Eval` command running `script` with `keys` and `args`.
+    pub fn new(script: impl Into<Bytes>, keys: Vec<Bytes>, args: Vec<Bytes>) -> Eval {
+        Eval {
+            script: script.into(),
+            numkeys: keys.len() as i64,
+            args: keys.into_iter().chain(args).collect(),
+        }
+    }
+
+    /// Parse an `Eval` instance from a received frame.
+    ///
+    /// The `EVAL` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// EVAL script numkeys [key ...] [arg ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Eval> {
+        let script = parse.next_bytes()?;
+        let (numkeys, args) = parse_args(parse)?;
+
+        Ok(Eval {
+            script,
+            numkeys,
+            args,
+        })
+    }
+
+    /// Apply the `Eval` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match split_keys(self.numkeys, self.args) {
+            Ok((keys, args)) => match db.script_load(&self.script) {
+                Ok((_, script)) => run(&script, keys, args, db),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Err(resp) => resp,
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("eval".as_bytes()));
+        frame.push_bulk(self.script);
+        frame.push_bulk(Bytes::from(self.numkeys.to_string()));
+        for arg in self.args {
+            frame.push_bulk(arg);
+        }
+        frame
+    }
+}
+
+impl EvalSha {
+    /// Create a new `EvalSha` command running the script cached as `sha`
+    /// with `keys` and `args`.
+    pub fn new(sha: impl ToString, keys: Vec<Bytes>, args: Vec<Bytes>) -> EvalSha {
+        EvalSha {
+            sha: sha.to_string(),
+            numkeys: keys.len() as i64,
+            args: keys.into_iter().chain(args).collect(),
+        }
+    }
+
+    /// Parse an `EvalSha` instance from a received frame.
+    ///
+    /// The `EVALSHA` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// EVALSHA sha1 numkeys [key ...] [arg ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<EvalSha> {
+        let sha = parse.next_string()?;
+        let (numkeys, args) = parse_args(parse)?;
+
+        Ok(EvalSha { sha, numkeys, args })
+    }
+
+    /// Apply the `EvalSha` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match split_keys(self.numkeys, self.args) {
+            Ok((keys, args)) => match db.script_get(&self.sha) {
+                Some(script) => run(&script, keys, args, db),
+                None => Frame::Error("NOSCRIPT No matching script. Please use EVAL.".to_string()),
+            },
+            Err(resp) => resp,
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("evalsha".as_bytes()));
+        frame.push_bulk(Bytes::from(self.sha));
+        frame.push_bulk(Bytes::from(self.numkeys.to_string()));
+        for arg in self.args {
+            frame.push_bulk(arg);
+        }
+        frame
+    }
+}
+
+impl Script {
+    /// Parse a `Script` instance from a received frame.
+    ///
+    /// The `SCRIPT` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SCRIPT LOAD script
+    /// SCRIPT EXISTS sha1 [sha1 ...]
+    /// SCRIPT FLUSH [ASYNC | SYNC]
+    /// SCRIPT KILL
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Script> {
+        let subcommand = parse.next_string()?.to_lowercase();
+
+        match &subcommand[..] {
+            "load" => Ok(Script::Load(parse.next_bytes()?)),
+            "exists" => {
+                let mut shas = vec![parse.next_string()?];
+                loop {
+                    match parse.next_string() {
+                        Ok(sha) => shas.push(sha),
+                        Err(ParseError::EndOfStream) => break,
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                Ok(Script::Exists(shas))
+            }
+            "flush" => {
+                // The cache is always flushed synchronously.
+                match parse.next_string() {
+                    Ok(mode) if mode.eq_ignore_ascii_case("async") => {}
+                    Ok(mode) if mode.eq_ignore_ascii_case("sync") => {}
+                    Ok(_) => return Err("ERR SCRIPT FLUSH only support SYNC|ASYNC option".into()),
+                    Err(ParseError::EndOfStream) => {}
+                    Err(err) => return Err(err.into()),
+                }
+                Ok(Script::Flush)
+            }
+            "kill" => Ok(Script::Kill),
+            _ => Err(format!("ERR unknown subcommand '{}'", subcommand).into()),
+        }
+    }
+
+    /// Apply the `Script` command to the specified `Db` instance.
+    ///
+    /// `SCRIPT KILL` is applied without locking the `Db`, which the script
+    /// to kill is holding.
+    #[instrument(skip(self, db, dst))]
+    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+        let resp = match self {
+            Script::Kill => match db.kill_script() {
+                Ok(()) => Frame::Simple("OK".to_string()),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            cmd => cmd.execute(&mut db.lock().await),
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Execute the `Script` command against the locked `Db`.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match self {
+            Script::Load(source) => match db.script_load(&source) {
+                Ok((sha, _)) => Frame::Bulk(Bytes::from(sha)),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            Script::Exists(shas) => {
+                let mut resp = Frame::array();
+                for exists in db.script_exists(&shas) {
+                    resp.push_int(exists as u64);
+                }
+                resp
+            }
+            Script::Flush => {
+                db.script_flush();
+                Frame::Simple("OK".to_string())
+            }
+            // No script runs while the lock is held, as it is here.
+            Script::Kill => Frame::Error("NOTBUSY No scripts in execution right now.".to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("script".as_bytes()));
+        match self {
+            Script::Load(source) => {
+                frame.push_bulk(Bytes::from("load".as_bytes()));
+                frame.push_bulk(source);
+            }
+            Script::Exists(shas) => {
+                frame.push_bulk(Bytes::from("exists".as_bytes()));
+                for sha in shas {
+                    frame.push_bulk(Bytes::from(sha));
+                }
+            }
+            Script::Flush => frame.push_bulk(Bytes::from("flush".as_bytes())),
+            Script::Kill => frame.push_bulk(Bytes::from("kill".as_bytes())),
+        }
+        frame
+    }
+}
+
+impl Host for ScriptHost<'_, '_> {
+    fn call(&mut self, args: Vec<Bytes>) -> Frame {
+        let mut frame = Frame::array();
+        for arg in args {
+            frame.push_bulk(arg);
+        }
+
+        let cmd = match Command::from_frame(frame) {
+            Ok(cmd) => cmd,
+            Err(err) => return error_reply(err),
+        };
+
+        let not_allowed =
+            || Frame::Error("ERR This Redis command is not allowed from script".to_string());
+        if matches!(
+            cmd,
+            Command::Eval(_) | Command::EvalSha(_) | Command::Script(_)
+        ) {
+            return not_allowed();
+        }
+
+        match self.running.call(self.db, |db| cmd.execute(db)) {
+            Some(Ok(resp)) => resp,
+            // Commands acting on the connection cannot be executed.
+            Some(Err(_)) => not_allowed(),
+            None => Frame::Error("ERR Script killed by user with SCRIPT KILL...".to_string()),
+        }
+    }
+
+    fn killed(&self) -> bool {
+        self.running.killed()
+    }
+}
+
+/// Parse `numkeys` and the keys and arguments that follow.
+fn parse_args(parse: &mut Parse) -> crate::Result<(i64, Vec<Bytes>)> {
+    let numkeys = parse
+        .next_string()?
+        .parse()
+        .map_err(|_| "ERR value is not an integer or out of range")?;
+
+    let mut args = vec![];
+    loop {
+        match parse.next_bytes() {
+            Ok(arg) => args.push(arg),
+            Err(ParseError::EndOfStream) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok((numkeys, args))
+}
+
+/// Split `args` into the `numkeys` keys and the other arguments. Returns the
+/// error reply if there are not enough arguments.
+fn split_keys(numkeys: i64, mut args: Vec<Bytes>) -> Result<(Vec<Bytes>, Vec<Bytes>), Frame> {
+    if numkeys < 0 {
+        return Err(Frame::Error(
+            "ERR Number of keys can't be negative".to_string(),
+        ));
+    }
+    if numkeys as usize > args.len() {
+        return Err(Frame::Error(
+            "ERR Number of keys can't be greater than number of args".to_string(),
+        ));
+    }
+
+    let rest = args.split_off(numkeys as usize);
+    Ok((args, rest))
+}
+
+/// Run `script`, registered as the running script for the time it takes.
+fn run(script: &CachedScript, keys: Vec<Bytes>, args: Vec<Bytes>, db: &mut LockedDb) -> Frame {
+    let running = db.start_script();
+    let mut host = ScriptHost { db, running };
+    script.run(keys, args, &mut host)
+}
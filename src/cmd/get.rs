@@ -1,4 +1,4 @@
-use crate::{Connection, Db, Frame, Parse};
+use crate::{Frame, LockedDb, Parse};
 use bytes::Bytes;
 use tracing::{debug, instrument};
 /// Get the value of key
@@ -52,31 +52,28 @@ impl Get {
 
     /// Apply the `Get` command to the specified `Db` instance.
     ///
-    /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
-    #[instrument(skip(self, db, dst))]
-    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+    /// Returns the response to send to the client. This is called by the server
+    /// in order to execute a received command.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
         // Get the value from the shared database state
-        let resp = if let Some(value) = db.get(&self.key) {
+        let resp = match db.get(&self.key) {
             // If a value is present, it is written to the client in "bulk"
             // format.
-            Frame::Bulk(value)
-        } else {
-            Frame::Null
+            Ok(Some(value)) => Frame::Bulk(value),
+            Ok(None) => Frame::Null,
+            Err(err) => Frame::Error(err.to_string()),
         };
 
         debug!(?resp);
-
-        // Write the response back to the client
-        dst.write_frame(&resp).await?;
-        Ok(())
+        resp
     }
 
     /// Converts the command into an equivalent `Frame`.
     ///
     /// This is called by the client when encoding a `Get` command to send to
     /// the server.
-    pub(crate) fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> Frame {
         let mut frame = Frame::array();
         frame.push_bulk(Bytes::from("get".as_bytes()));
         frame.push_bulk(Bytes::from(self.key.into_bytes()));
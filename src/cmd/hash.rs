@@ -0,0 +1,248 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use super::parse_items;
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Set one or more fields of a hash, creating the hash if the key does not
+/// exist.
+#[derive(Debug, Clone)]
+pub struct Hset {
+    key: String,
+    fields: Vec<(Bytes, Bytes)>,
+}
+
+/// Get the value of a hash field.
+#[derive(Debug)]
+pub struct Hget {
+    key: String,
+    field: Bytes,
+}
+
+/// Remove one or more fields from a hash.
+#[derive(Debug, Clone)]
+pub struct Hdel {
+    key: String,
+    fields: Vec<Bytes>,
+}
+
+/// Get every field of a hash along with its value.
+#[derive(Debug)]
+pub struct Hgetall {
+    key: String,
+}
+
+impl Hset {
+    /// Create a new `Hset` command which sets `fields` in the hash at `key`.
+    pub fn new(key: impl ToString, fields: Vec<(Bytes, Bytes)>) -> Self {
+        Hset {
+            key: key.to_string(),
+            fields,
+        }
+    }
+
+    /// Parse a `Hset` instance from a received frame.
+    ///
+    /// The `HSET` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// HSET key field value [field value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Hset> {
+        let key = parse.next_string()?;
+        let mut fields = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+
+        loop {
+            match parse.next_bytes() {
+                Ok(field) => fields.push((field, parse.next_bytes()?)),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Hset { key, fields })
+    }
+
+    /// Apply the `Hset` command to the specified `Db` instance.
+    ///
+    /// Responds with the number of fields that were added.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.hset(self.key, self.fields) {
+            Ok(added) => Frame::Int(added),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("hset".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for (field, value) in self.fields {
+            frame.push_bulk(field);
+            frame.push_bulk(value);
+        }
+        frame
+    }
+}
+
+impl Hget {
+    /// Create a new `Hget` command which fetches `field` of the hash at `key`.
+    pub fn new(key: impl ToString, field: Bytes) -> Self {
+        Hget {
+            key: key.to_string(),
+            field,
+        }
+    }
+
+    /// Parse a `Hget` instance from a received frame.
+    ///
+    /// The `HGET` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// HGET key field
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Hget> {
+        let key = parse.next_string()?;
+        let field = parse.next_bytes()?;
+
+        Ok(Hget { key, field })
+    }
+
+    /// Apply the `Hget` command to the specified `Db` instance.
+    ///
+    /// Responds with the value, or `nil` if the field or key does not exist.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.hget(&self.key, &self.field) {
+            Ok(Some(value)) => Frame::Bulk(value),
+            Ok(None) => Frame::Null,
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("hget".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_bulk(self.field);
+        frame
+    }
+}
+
+impl Hdel {
+    /// Create a new `Hdel` command which removes `fields` from the hash at
+    /// `key`.
+    pub fn new(key: impl ToString, fields: Vec<Bytes>) -> Self {
+        Hdel {
+            key: key.to_string(),
+            fields,
+        }
+    }
+
+    /// Parse a `Hdel` instance from a received frame.
+    ///
+    /// The `HDEL` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// HDEL key field [field ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Hdel> {
+        let key = parse.next_string()?;
+        let fields = parse_items(parse)?;
+
+        Ok(Hdel { key, fields })
+    }
+
+    /// Apply the `Hdel` command to the specified `Db` instance.
+    ///
+    /// Responds with the number of fields that were removed.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.hdel(&self.key, &self.fields) {
+            Ok(removed) => Frame::Int(removed),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("hdel".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for field in self.fields {
+            frame.push_bulk(field);
+        }
+        frame
+    }
+}
+
+impl Hgetall {
+    /// Create a new `Hgetall` command which fetches the hash at `key`.
+    pub fn new(key: impl ToString) -> Self {
+        Hgetall {
+            key: key.to_string(),
+        }
+    }
+
+    /// Parse a `Hgetall` instance from a received frame.
+    ///
+    /// The `HGETALL` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// HGETALL key
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Hgetall> {
+        let key = parse.next_string()?;
+
+        Ok(Hgetall { key })
+    }
+
+    /// Apply the `Hgetall` command to the specified `Db` instance.
+    ///
+    /// Responds with a flat array of fields, each followed by its value.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.hgetall(&self.key) {
+            Ok(fields) => {
+                let mut resp = Frame::array();
+                for (field, value) in fields {
+                    resp.push_bulk(field);
+                    resp.push_bulk(value);
+                }
+                resp
+            }
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("hgetall".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Write;
+
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::cmd::{Parse, ParseError};
+use crate::db::ReplicationInfo;
+use crate::{Frame, LockedDb};
+
+/// Report information about the server, as `field:value` lines grouped in
+/// sections.
+///
+/// Only the `replication` and `cluster` sections are supported. They are
+/// reported when no section is given, as well as for `all`, `default` and
+/// `everything`. Other sections are empty.
+#[derive(Debug, Default)]
+pub struct Info {
+    sections: Vec<String>,
+}
+
+impl Info {
+    /// Create a new `Info` command reporting `sections`, or the default
+    /// sections if empty.
+    pub fn new(sections: Vec<String>) -> Info {
+        Info { sections }
+    }
+
+    /// Parse an `Info` instance from a received frame.
+    ///
+    /// The `INFO` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// INFO [section [section ...]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Info> {
+        let mut sections = vec![];
+
+        loop {
+            match parse.next_string() {
+                Ok(section) => sections.push(section.to_lowercase()),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Info { sections })
+    }
+
+    /// Apply the `Info` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let mut info = String::new();
+        if self.wants("replication") {
+            write_replication(&mut info, db.replication_info());
+        }
+        if self.wants("cluster") {
+            // Sections are separated by an empty line.
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            let enabled = u8::from(db.cluster_enabled());
+            // Writing to a `String` cannot fail.
+            let _ = write!(info, "# Cluster\r\ncluster_enabled:{}\r\n", enabled);
+        }
+
+        let resp = Frame::Bulk(Bytes::from(info));
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Returns `true` if the section `name` is to be reported.
+    fn wants(&self, name: &str) -> bool {
+        self.sections.is_empty()
+            || self.sections.iter().any(|section| {
+                section == name || matches!(&section[..], "all" | "default" | "everything")
+            })
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("info".as_bytes()));
+        for section in self.sections {
+            frame.push_bulk(Bytes::from(section.into_bytes()));
+        }
+        frame
+    }
+}
+
+/// Write the `replication` section, in the format of Redis.
+fn write_replication(out: &mut String, info: ReplicationInfo) {
+    // Writing to a `String` cannot fail.
+    let _ = write!(out, "# Replication\r\n");
+
+    match info.primary {
+        Some(primary) => {
+            let status = if primary.link_up { "up" } else { "down" };
+            let last_io = primary.last_io.map_or(-1, |secs| secs as i64);
+            let _ = write!(
+                out,
+                "role:slave\r\n\
+                 master_host:{}\r\n\
+                 master_port:{}\r\n\
+                 master_link_status:{}\r\n\
+                 master_last_io_seconds_ago:{}\r\n\
+                 master_sync_in_progress:0\r\n\
+                 slave_repl_offset:{}\r\n",
+                primary.host, primary.port, status, last_io, info.offset
+            );
+        }
+        None => {
+            let _ = write!(out, "role:master\r\n");
+        }
+    }
+
+    let _ = write!(out, "connected_slaves:{}\r\n", info.replicas.len());
+    for (i, replica) in info.replicas.iter().enumerate() {
+        let _ = write!(
+            out,
+            "slave{}:ip={},port={},state=online,offset={},lag={}\r\n",
+            i, replica.ip, replica.port, replica.ack_offset, replica.lag
+        );
+    }
+
+    // As in Redis, the second history is reported with a zero ID, and
+    // starts at offset -1, when there is none.
+    let (replid2, second_offset) = match &info.replid2 {
+        Some((replid2, end)) => (replid2.clone(), *end as i64 + 1),
+        None => ("0".repeat(40), -1),
+    };
+    let (active, first_byte, histlen) = match info.backlog {
+        Some((first_byte, histlen)) => (1, first_byte, histlen),
+        None => (0, 0, 0),
+    };
+    let _ = write!(
+        out,
+        "master_replid:{}\r\n\
+         master_replid2:{}\r\n\
+         master_repl_offset:{}\r\n\
+         second_repl_offset:{}\r\n\
+         repl_backlog_active:{}\r\n\
+         repl_backlog_size:{}\r\n\
+         repl_backlog_first_byte_offset:{}\r\n\
+         repl_backlog_histlen:{}\r\n",
+        info.replid,
+        replid2,
+        info.offset,
+        second_offset,
+        active,
+        info.backlog_size,
+        first_byte,
+        histlen
+    );
+}
@@ -0,0 +1,199 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Return every key matching a glob-style pattern.
+///
+/// This walks the whole keyspace while holding the database lock. Prefer
+/// `SCAN` on large databases.
+#[derive(Debug)]
+pub struct Keys {
+    pattern: Bytes,
+}
+
+/// Remove one or more keys, whatever their type.
+#[derive(Debug, Clone)]
+pub struct Del {
+    keys: Vec<String>,
+}
+
+/// Return the number of keys.
+#[derive(Debug, Default)]
+pub struct DbSize {}
+
+/// Return a random key.
+#[derive(Debug, Default)]
+pub struct RandomKey {}
+
+impl Keys {
+    /// Create a new `Keys` command listing the keys matching `pattern`.
+    pub fn new(pattern: impl Into<Bytes>) -> Self {
+        Keys {
+            pattern: pattern.into(),
+        }
+    }
+
+    /// Parse a `Keys` instance from a received frame.
+    ///
+    /// The `KEYS` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// KEYS pattern
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Keys> {
+        let pattern = parse.next_bytes()?;
+
+        Ok(Keys { pattern })
+    }
+
+    /// Apply the `Keys` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let mut resp = Frame::array();
+        for key in db.keys(&self.pattern) {
+            resp.push_bulk(Bytes::from(key));
+        }
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("keys".as_bytes()));
+        frame.push_bulk(self.pattern);
+        frame
+    }
+}
+
+impl Del {
+    /// Create a new `Del` command removing `keys`.
+    pub fn new(keys: Vec<String>) -> Self {
+        Del { keys }
+    }
+
+    /// Parse a `Del` instance from a received frame.
+    ///
+    /// The `DEL` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// DEL key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Del> {
+        let mut keys = vec![parse.next_string()?];
+
+        loop {
+            match parse.next_string() {
+                Ok(key) => keys.push(key),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Del { keys })
+    }
+
+    /// Apply the `Del` command to the specified `Db` instance.
+    ///
+    /// Replies with the number of keys that were removed.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = Frame::Int(db.del(&self.keys));
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("del".as_bytes()));
+        for key in self.keys {
+            frame.push_bulk(Bytes::from(key));
+        }
+        frame
+    }
+}
+
+impl DbSize {
+    /// Create a new `DbSize` command.
+    pub fn new() -> Self {
+        DbSize {}
+    }
+
+    /// Parse a `DbSize` instance from a received frame.
+    ///
+    /// The `DBSIZE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// DBSIZE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<DbSize> {
+        Ok(DbSize {})
+    }
+
+    /// Apply the `DbSize` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = Frame::Int(db.dbsize());
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("dbsize".as_bytes()));
+        frame
+    }
+}
+
+impl RandomKey {
+    /// Create a new `RandomKey` command.
+    pub fn new() -> Self {
+        RandomKey {}
+    }
+
+    /// Parse a `RandomKey` instance from a received frame.
+    ///
+    /// The `RANDOMKEY` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// RANDOMKEY
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<RandomKey> {
+        Ok(RandomKey {})
+    }
+
+    /// Apply the `RandomKey` command to the specified `Db` instance.
+    ///
+    /// Responds with `nil` if the database is empty.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.random_key() {
+            Some(key) => Frame::Bulk(Bytes::from(key)),
+            None => Frame::Null,
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("randomkey".as_bytes()));
+        frame
+    }
+}
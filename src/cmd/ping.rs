@@ -48,20 +48,31 @@ impl Ping {
 
     /// Apply the `Ping` command and return the message.
     ///
-    /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
-    #[instrument(skip(self, dst))]
-    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+    /// Returns the response to send to the client. This is called by the server
+    /// in order to execute a received command.
+    #[instrument(skip(self))]
+    pub(crate) fn execute(self) -> Frame {
         let resp = match self.msg {
             None => Frame::Simple("PONG".to_string()),
             Some(msg) => Frame::Bulk(msg),
         };
 
         debug!(?resp);
+        resp
+    }
 
-        // Write the response back to the client
-        dst.write_frame(&resp).await?;
+    /// Apply the `Ping` command in the subscribed state.
+    ///
+    /// The reply is then a `pong` message, carrying the message or an empty
+    /// string.
+    #[instrument(skip(self, dst))]
+    pub(crate) async fn apply_subscribed(self, dst: &mut Connection) -> crate::Result<()> {
+        let mut resp = Frame::array();
+        resp.push_bulk(Bytes::from_static(b"pong"));
+        resp.push_bulk(self.msg.unwrap_or_default());
 
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
         Ok(())
     }
 
@@ -69,7 +80,7 @@ impl Ping {
     ///
     /// This is called by the client when encoding a `Ping` command to send
     /// to the server.
-    pub(crate) fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> Frame {
         let mut frame = Frame::array();
 
         frame.push_bulk(Bytes::from("ping".as_bytes()));
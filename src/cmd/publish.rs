@@ -1,6 +1,4 @@
-use core::num;
-
-use crate::{Connection, Db, Frame, Parse};
+use crate::{Connection, Db, Frame, LockedDb, Parse};
 
 use bytes::Bytes;
 
@@ -19,9 +17,19 @@ pub struct Publish {
     message: Bytes,
 }
 
+/// Posts a message to the given shard channel.
+///
+/// Shard channels are a separate namespace from the channels used by
+/// `PUBLISH`, and are not matched by pattern subscriptions.
+#[derive(Debug)]
+pub struct Spublish {
+    channel: String,
+    message: Bytes,
+}
+
 impl Publish {
     /// Create a new `Publish` command which sends `message` on `channel`.
-    pub(crate) fn new(channel: impl ToString, message: Bytes) -> Self {
+    pub fn new(channel: impl ToString, message: Bytes) -> Self {
         Self {
             channel: channel.to_string(),
             message,
@@ -75,7 +83,10 @@ impl Publish {
         // receive the message. Subscribers may drop before receiving the
         // message. Given this, `num_subscribers` should only be used as a
         // "hint".
-        let num_subscribers = db.publish(&self.channel, self.message);
+        //
+        // Subscribers applying backpressure may make this wait until they
+        // catch up.
+        let num_subscribers = db.publish(&self.channel, self.message).await;
 
         // The number of subscribers is returned as the response to the publish
         // request.
@@ -86,11 +97,19 @@ impl Publish {
         Ok(())
     }
 
+    /// Execute the `Publish` command as part of a transaction.
+    ///
+    /// The lock is held for the whole transaction, so this cannot wait for
+    /// subscribers applying backpressure, see `LockedDb::publish`.
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        Frame::Int(db.publish(&self.channel, self.message) as u64)
+    }
+
     /// Converts the command into an equivalent `Frame`.
     ///
     /// This is called by the client when encoding a `Publish` command to send
     /// to the server.
-    pub(crate) fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> Frame {
         let mut frame = Frame::array();
 
         frame.push_bulk(Bytes::from("publish".as_bytes()));
@@ -99,3 +118,60 @@ impl Publish {
         frame
     }
 }
+
+impl Spublish {
+    /// Create a new `Spublish` command which sends `message` on the shard
+    /// `channel`.
+    pub fn new(channel: impl ToString, message: Bytes) -> Self {
+        Self {
+            channel: channel.to_string(),
+            message,
+        }
+    }
+
+    /// Parse a `Spublish` instance from a received frame.
+    ///
+    /// The `SPUBLISH` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SPUBLISH shardchannel message
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Spublish> {
+        let channel = parse.next_string()?;
+        let message = parse.next_bytes()?;
+
+        Ok(Spublish { channel, message })
+    }
+
+    /// Apply the `Spublish` command to the specified `Db` instance.
+    ///
+    /// Replies with the number of subscribers of the shard channel, see
+    /// `Publish::apply`.
+    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+        let num_subscribers = db.spublish(&self.channel, self.message).await;
+
+        let resp = Frame::Int(num_subscribers as u64);
+
+        dst.write_frame(&resp).await?;
+
+        Ok(())
+    }
+
+    /// Execute the `Spublish` command as part of a transaction, see
+    /// `Publish::execute`.
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        Frame::Int(db.spublish(&self.channel, self.message) as u64)
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+
+        frame.push_bulk(Bytes::from("spublish".as_bytes()));
+        frame.push_bulk(Bytes::from(self.channel.into_bytes()));
+        frame.push_bulk(self.message);
+        frame
+    }
+}
@@ -0,0 +1,147 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Inspect the state of the pub/sub system.
+///
+/// Only channels with at least one subscriber are reported; subscribers of
+/// patterns are counted separately by `NUMPAT`. Shard channels are only
+/// reported by `SHARDCHANNELS` and `SHARDNUMSUB`.
+#[derive(Debug)]
+pub enum Pubsub {
+    /// List the active channels, optionally only those matching a
+    /// glob-style pattern.
+    Channels(Option<Bytes>),
+    /// Return the number of subscribers of each channel.
+    NumSub(Vec<String>),
+    /// Return the number of subscribed patterns.
+    NumPat,
+    /// List the active shard channels, optionally only those matching a
+    /// glob-style pattern.
+    ShardChannels(Option<Bytes>),
+    /// Return the number of subscribers of each shard channel.
+    ShardNumSub(Vec<String>),
+}
+
+impl Pubsub {
+    /// Parse a `Pubsub` instance from a received frame.
+    ///
+    /// The `PUBSUB` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// PUBSUB CHANNELS [pattern]
+    /// PUBSUB NUMSUB [channel [channel ...]]
+    /// PUBSUB NUMPAT
+    /// PUBSUB SHARDCHANNELS [pattern]
+    /// PUBSUB SHARDNUMSUB [shardchannel [shardchannel ...]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Pubsub> {
+        let subcommand = parse.next_string()?.to_lowercase();
+
+        match &subcommand[..] {
+            "channels" => Ok(Pubsub::Channels(parse_pattern(parse)?)),
+            "numsub" => Ok(Pubsub::NumSub(parse_channels(parse)?)),
+            "numpat" => Ok(Pubsub::NumPat),
+            "shardchannels" => Ok(Pubsub::ShardChannels(parse_pattern(parse)?)),
+            "shardnumsub" => Ok(Pubsub::ShardNumSub(parse_channels(parse)?)),
+            _ => Err(format!("ERR unknown subcommand '{}'", subcommand).into()),
+        }
+    }
+
+    /// Apply the `Pubsub` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match self {
+            Pubsub::Channels(pattern) => {
+                make_channels_frame(db.pubsub_channels(pattern.as_deref()))
+            }
+            Pubsub::NumSub(channels) => {
+                let counts = db.pubsub_numsub(&channels);
+                make_numsub_frame(channels, counts)
+            }
+            Pubsub::NumPat => Frame::Int(db.pubsub_numpat() as u64),
+            Pubsub::ShardChannels(pattern) => {
+                make_channels_frame(db.pubsub_shardchannels(pattern.as_deref()))
+            }
+            Pubsub::ShardNumSub(channels) => {
+                let counts = db.pubsub_shardnumsub(&channels);
+                make_numsub_frame(channels, counts)
+            }
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let subcommand = match self {
+            Pubsub::Channels(_) => "channels",
+            Pubsub::NumSub(_) => "numsub",
+            Pubsub::NumPat => "numpat",
+            Pubsub::ShardChannels(_) => "shardchannels",
+            Pubsub::ShardNumSub(_) => "shardnumsub",
+        };
+
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("pubsub".as_bytes()));
+        match self {
+            Pubsub::Channels(pattern) | Pubsub::ShardChannels(pattern) => {
+                frame.push_bulk(Bytes::from(subcommand.as_bytes()));
+                if let Some(pattern) = pattern {
+                    frame.push_bulk(pattern);
+                }
+            }
+            Pubsub::NumSub(channels) | Pubsub::ShardNumSub(channels) => {
+                frame.push_bulk(Bytes::from(subcommand.as_bytes()));
+                for channel in channels {
+                    frame.push_bulk(Bytes::from(channel));
+                }
+            }
+            Pubsub::NumPat => frame.push_bulk(Bytes::from(subcommand.as_bytes())),
+        }
+        frame
+    }
+}
+
+/// Parse the optional pattern of `CHANNELS` and `SHARDCHANNELS`.
+fn parse_pattern(parse: &mut Parse) -> crate::Result<Option<Bytes>> {
+    match parse.next_bytes() {
+        Ok(pattern) => Ok(Some(pattern)),
+        Err(ParseError::EndOfStream) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Parse the channels of `NUMSUB` and `SHARDNUMSUB`.
+fn parse_channels(parse: &mut Parse) -> crate::Result<Vec<String>> {
+    let mut channels = vec![];
+    loop {
+        match parse.next_string() {
+            Ok(channel) => channels.push(channel),
+            Err(ParseError::EndOfStream) => return Ok(channels),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn make_channels_frame(channels: Vec<String>) -> Frame {
+    let mut resp = Frame::array();
+    for channel in channels {
+        resp.push_bulk(Bytes::from(channel));
+    }
+    resp
+}
+
+/// Pairs each channel with its number of subscribers.
+fn make_numsub_frame(channels: Vec<String>, counts: Vec<usize>) -> Frame {
+    let mut resp = Frame::array();
+    for (channel, count) in channels.into_iter().zip(counts) {
+        resp.push_bulk(Bytes::from(channel));
+        resp.push_int(count as u64);
+    }
+    resp
+}
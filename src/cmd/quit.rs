@@ -0,0 +1,97 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::cmd::LagPolicy;
+use crate::{Connection, Frame, Parse};
+
+/// Ask the server to close the connection.
+///
+/// The server replies `OK` and closes the connection once the reply is sent.
+#[derive(Debug, Default)]
+pub struct Quit {}
+
+/// Reset the connection to its initial state.
+///
+/// This leaves the subscribed state and restores the connection settings to
+/// their defaults.
+#[derive(Debug, Default)]
+pub struct Reset {}
+
+impl Quit {
+    /// Create a new `Quit` command.
+    pub fn new() -> Quit {
+        Quit {}
+    }
+
+    /// Parse a `Quit` instance from a received frame.
+    ///
+    /// The `QUIT` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// QUIT
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Quit> {
+        Ok(Quit {})
+    }
+
+    /// Apply the `Quit` command, closing `dst`.
+    #[instrument(skip(self, dst))]
+    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+        let resp = Frame::Simple("OK".to_string());
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        dst.close().await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("quit".as_bytes()));
+        frame
+    }
+}
+
+impl Reset {
+    /// Create a new `Reset` command.
+    pub fn new() -> Reset {
+        Reset {}
+    }
+
+    /// Parse a `Reset` instance from a received frame.
+    ///
+    /// The `RESET` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// RESET
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Reset> {
+        Ok(Reset {})
+    }
+
+    /// Apply the `Reset` command to `dst`.
+    ///
+    /// Subscriptions are dropped by `Subscribe::apply` before this is called.
+    #[instrument(skip(self, dst))]
+    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+        dst.set_lag_policy(LagPolicy::default());
+
+        let resp = Frame::Simple("RESET".to_string());
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("reset".as_bytes()));
+        frame
+    }
+}
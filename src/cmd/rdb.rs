@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse};
+
+/// Write the keys to a Redis RDB file, for stock Redis to load.
+///
+/// The file is written within `dir`. Only strings and hashes are exported,
+/// the other types having no Redis equivalent.
+#[derive(Debug)]
+pub struct RdbExport {
+    filename: String,
+}
+
+impl RdbExport {
+    /// Create a new `RdbExport` command writing to `filename`.
+    pub fn new(filename: impl ToString) -> RdbExport {
+        RdbExport {
+            filename: filename.to_string(),
+        }
+    }
+
+    /// Parse a `RdbExport` instance from a received frame.
+    ///
+    /// The `RDB.EXPORT` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// RDB.EXPORT filename
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<RdbExport> {
+        let filename = parse.next_string()?;
+        Ok(RdbExport { filename })
+    }
+
+    /// Apply the `RdbExport` command to the specified `Db` instance.
+    ///
+    /// Replies with the number of keys written.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.export_rdb(&self.filename) {
+            Ok(count) => Frame::Int(count as u64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("rdb.export".as_bytes()));
+        frame.push_bulk(Bytes::from(self.filename.into_bytes()));
+        frame
+    }
+}
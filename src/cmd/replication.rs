@@ -0,0 +1,479 @@
+use bytes::Bytes;
+use tokio::select;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, instrument, warn};
+
+use crate::cmd::{Parse, ParseError};
+use crate::db::SyncStart;
+use crate::{Command, Connection, Db, Frame, LockedDb, Shutdown};
+
+/// Replicate another server, or stop replicating with `NO ONE`.
+///
+/// A replica loads a snapshot of its primary, then applies the write
+/// commands the primary propagates. It is read-only in the meantime.
+#[derive(Debug)]
+pub struct ReplicaOf {
+    /// Host and port of the primary, `None` for `NO ONE`.
+    primary: Option<(String, u16)>,
+}
+
+/// Configure the replication link, sent by replicas before `PSYNC`.
+///
+/// `listening-port` announces the port the replica listens on. Once
+/// replicating, the replica sends `ACK offset` to acknowledge the history it
+/// processed, every second and when the primary asks with `GETACK *`. Other
+/// options are accepted and ignored.
+#[derive(Debug)]
+pub struct Replconf {
+    options: Vec<(String, String)>,
+}
+
+/// Start replicating from the history `replid` at `offset`, sent by a
+/// replica to its primary.
+///
+/// The primary replies `+CONTINUE replid` followed by the commands the
+/// replica misses, if they are in its backlog. Otherwise, it replies
+/// `+FULLRESYNC replid offset` followed by a snapshot of the data set, as a
+/// bulk string. The connection then carries the propagated commands.
+#[derive(Debug)]
+pub struct Psync {
+    replid: String,
+    offset: u64,
+}
+
+impl ReplicaOf {
+    /// Create a new `ReplicaOf` command replicating the server at `host` and
+    /// `port`, or stopping replication if `None`.
+    pub fn new(primary: Option<(String, u16)>) -> ReplicaOf {
+        ReplicaOf { primary }
+    }
+
+    /// Parse a `ReplicaOf` instance from a received frame.
+    ///
+    /// The `REPLICAOF` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// REPLICAOF host port
+    /// REPLICAOF NO ONE
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ReplicaOf> {
+        let host = parse.next_string()?;
+        let port = parse.next_string()?;
+
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            return Ok(ReplicaOf { primary: None });
+        }
+
+        let port = port
+            .parse()
+            .map_err(|_| format!("ERR Invalid master port '{}'", port))?;
+        Ok(ReplicaOf {
+            primary: Some((host, port)),
+        })
+    }
+
+    /// Apply the `ReplicaOf` command to the specified `Db` instance.
+    #[instrument(skip(self, db, dst))]
+    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+        let resp = match self.primary {
+            Some((host, port)) => {
+                if db.replicaof(host, port).await {
+                    Frame::Simple("OK".to_string())
+                } else {
+                    Frame::Simple("OK Already connected to specified master".to_string())
+                }
+            }
+            None => {
+                db.lock().await.replicaof_no_one();
+                Frame::Simple("OK".to_string())
+            }
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("replicaof".as_bytes()));
+        match self.primary {
+            Some((host, port)) => {
+                frame.push_bulk(Bytes::from(host.into_bytes()));
+                frame.push_bulk(Bytes::from(port.to_string().into_bytes()));
+            }
+            None => {
+                frame.push_bulk(Bytes::from("no".as_bytes()));
+                frame.push_bulk(Bytes::from("one".as_bytes()));
+            }
+        }
+        frame
+    }
+}
+
+impl Replconf {
+    /// Create a new `Replconf` command setting `options`.
+    pub fn new(options: Vec<(String, String)>) -> Replconf {
+        Replconf { options }
+    }
+
+    /// Parse a `Replconf` instance from a received frame.
+    ///
+    /// The `REPLCONF` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// REPLCONF option value [option value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Replconf> {
+        let mut options = vec![];
+
+        loop {
+            match parse.next_string() {
+                Ok(option) => options.push((option.to_lowercase(), parse.next_string()?)),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Replconf { options })
+    }
+
+    /// Create a new `Replconf` command acknowledging `offset`.
+    pub(crate) fn ack(offset: u64) -> Replconf {
+        Replconf::new(vec![("ack".to_string(), offset.to_string())])
+    }
+
+    /// Returns the offset acknowledged with `ACK`, if any.
+    pub(crate) fn acked_offset(&self) -> Option<u64> {
+        self.option("ack")?.parse().ok()
+    }
+
+    /// Returns `true` if the primary asks for an acknowledgement with
+    /// `GETACK`.
+    pub(crate) fn is_getack(&self) -> bool {
+        self.option("getack").is_some()
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(option, _)| option == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Apply the `Replconf` command to the connection.
+    #[instrument(skip(self, dst))]
+    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+        let resp = match self.option("listening-port").map(str::parse) {
+            Some(Ok(port)) => {
+                dst.set_replica_port(port);
+                Frame::Simple("OK".to_string())
+            }
+            Some(Err(_)) => Frame::Error("ERR value is out of range".to_string()),
+            None => Frame::Simple("OK".to_string()),
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("replconf".as_bytes()));
+        for (option, value) in self.options {
+            frame.push_bulk(Bytes::from(option.into_bytes()));
+            frame.push_bulk(Bytes::from(value.into_bytes()));
+        }
+        frame
+    }
+}
+
+impl Psync {
+    /// Create a new `Psync` command continuing the history `replid` from
+    /// `offset`.
+    pub fn new(replid: impl ToString, offset: u64) -> Psync {
+        Psync {
+            replid: replid.to_string(),
+            offset,
+        }
+    }
+
+    /// Parse a `Psync` instance from a received frame.
+    ///
+    /// The `PSYNC` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// PSYNC replid offset
+    /// ```
+    ///
+    /// Replicas without a history send `PSYNC ? -1`.
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Psync> {
+        let replid = parse.next_string()?;
+        let offset = parse.next_string()?;
+
+        Ok(Psync {
+            replid,
+            // Any offset that is not a valid one, such as `-1`, requires a
+            // full synchronization, as does the unknown replid `?`.
+            offset: offset.parse().unwrap_or(u64::MAX),
+        })
+    }
+
+    /// Apply the `Psync` command, turning the connection into the link of a
+    /// replica, until it disconnects or the server shuts down.
+    pub(crate) async fn apply(
+        self,
+        db: &Db,
+        dst: &mut Connection,
+        shutdown: &mut Shutdown,
+    ) -> crate::Result<()> {
+        // Replicas that did not announce their port are reported with the
+        // port they connect from.
+        let peer = dst.peer_addr()?;
+        let port = dst.replica_port().unwrap_or(peer.port());
+        let (id, start, mut feed, timeout) = {
+            let mut db = db.lock().await;
+            let (id, start, feed) =
+                db.attach_replica(&self.replid, self.offset, peer.ip().to_string(), port);
+            (id, start, feed, db.repl_timeout())
+        };
+
+        let res: crate::Result<()> = async {
+            match start {
+                SyncStart::Continue { replid, missed } => {
+                    debug!(%replid, missed = missed.len(), "replica continues");
+                    dst.write_frame(&Frame::Simple(format!("CONTINUE {}", replid)))
+                        .await?;
+                    dst.write_encoded(&missed).await?;
+                }
+                SyncStart::Full {
+                    replid,
+                    offset,
+                    snapshot,
+                } => {
+                    debug!(%replid, offset, "replica starts a full synchronization");
+                    let resp = Frame::Simple(format!("FULLRESYNC {} {}", replid, offset));
+                    dst.write_frame(&resp).await?;
+
+                    let snapshot = task::spawn_blocking(move || snapshot.encode()).await?;
+                    dst.write_frame(&Frame::Bulk(Bytes::from(snapshot))).await?;
+                }
+            }
+
+            // Replicas acknowledge their offset every second.
+            let mut deadline = Instant::now() + timeout;
+
+            loop {
+                select! {
+                    buf = feed.recv() => match buf {
+                        Some(buf) => dst.write_encoded(&buf).await?,
+                        // The replica was detached, and must synchronize
+                        // again.
+                        None => return dst.close().await.map_err(Into::into),
+                    },
+                    // The replica only sends acknowledgements.
+                    frame = dst.read_frame() => match frame? {
+                        Some(frame) => {
+                            deadline = Instant::now() + timeout;
+                            if let Ok(Command::Replconf(cmd)) = Command::from_frame(frame) {
+                                if let Some(offset) = cmd.acked_offset() {
+                                    db.replica_ack(id, offset).await;
+                                }
+                            }
+                        }
+                        None => return Ok(()),
+                    },
+                    _ = time::sleep_until(deadline) => {
+                        warn!(%peer, "replica timed out");
+                        return dst.close().await.map_err(Into::into);
+                    }
+                    _ = shutdown.recv() => return Ok(()),
+                }
+            }
+        }
+        .await;
+
+        db.lock().await.detach_replica(id);
+        res
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("psync".as_bytes()));
+        frame.push_bulk(Bytes::from(self.replid.into_bytes()));
+        frame.push_bulk(Bytes::from(self.offset.to_string().into_bytes()));
+        frame
+    }
+}
+
+/// Block until `numreplicas` replicas acknowledged the writes made on the
+/// connection so far, or `timeout` milliseconds elapsed.
+///
+/// Replies with the number of replicas that acknowledged the writes, which
+/// may be fewer than `numreplicas` once the timeout elapsed. A timeout of
+/// zero blocks with no limit.
+#[derive(Debug)]
+pub struct Wait {
+    numreplicas: u64,
+    timeout: u64,
+}
+
+/// Report the role of the server in replication.
+///
+/// A primary replies with its replication offset and, for each replica, its
+/// address and the offset it acknowledged. A replica replies with the
+/// address of its primary, the state of the link and its offset.
+#[derive(Debug, Default)]
+pub struct Role {}
+
+impl Wait {
+    /// Create a new `Wait` command waiting for `numreplicas` replicas for at
+    /// most `timeout`.
+    pub fn new(numreplicas: u64, timeout: Duration) -> Wait {
+        Wait {
+            numreplicas,
+            timeout: timeout.as_millis() as u64,
+        }
+    }
+
+    /// Parse a `Wait` instance from a received frame.
+    ///
+    /// The `WAIT` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// WAIT numreplicas timeout
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Wait> {
+        let numreplicas = parse.next_int()?;
+        let timeout = parse.next_int()?;
+
+        Ok(Wait {
+            numreplicas,
+            timeout,
+        })
+    }
+
+    /// Apply the `Wait` command to the specified `Db` instance.
+    ///
+    /// The lock is not held while waiting.
+    #[instrument(skip(self, db, dst, shutdown))]
+    pub(crate) async fn apply(
+        self,
+        db: &Db,
+        dst: &mut Connection,
+        shutdown: &mut Shutdown,
+    ) -> crate::Result<()> {
+        let timeout = match self.timeout {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+
+        let numreplicas = self.numreplicas.try_into().unwrap_or(usize::MAX);
+        let resp = select! {
+            res = db.wait_for_replicas(numreplicas, timeout) => match res {
+                Ok(count) => Frame::Int(count as u64),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            _ = shutdown.recv() => return Ok(()),
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("wait".as_bytes()));
+        frame.push_int(self.numreplicas);
+        frame.push_int(self.timeout);
+        frame
+    }
+}
+
+impl Role {
+    /// Create a new `Role` command.
+    pub fn new() -> Role {
+        Role {}
+    }
+
+    /// Parse a `Role` instance from a received frame.
+    ///
+    /// The `ROLE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// ROLE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Role> {
+        Ok(Role {})
+    }
+
+    /// Apply the `Role` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let info = db.replication_info();
+
+        let resp = match info.primary {
+            Some(primary) => {
+                // As in Redis, the link is `connect` until synchronized.
+                let state = if primary.link_up {
+                    "connected"
+                } else {
+                    "connect"
+                };
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("slave".as_bytes())),
+                    Frame::Bulk(Bytes::from(primary.host.into_bytes())),
+                    Frame::Int(primary.port as u64),
+                    Frame::Bulk(Bytes::from(state.as_bytes())),
+                    Frame::Int(info.offset),
+                ])
+            }
+            None => {
+                let replicas = info
+                    .replicas
+                    .into_iter()
+                    .map(|replica| {
+                        Frame::Array(vec![
+                            Frame::Bulk(Bytes::from(replica.ip.into_bytes())),
+                            Frame::Bulk(Bytes::from(replica.port.to_string().into_bytes())),
+                            Frame::Bulk(Bytes::from(replica.ack_offset.to_string().into_bytes())),
+                        ])
+                    })
+                    .collect();
+
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("master".as_bytes())),
+                    Frame::Int(info.offset),
+                    Frame::Array(replicas),
+                ])
+            }
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("role".as_bytes()));
+        frame
+    }
+}
@@ -0,0 +1,181 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse};
+
+/// Write a snapshot of the keys to disk, blocking other clients until it is
+/// written.
+#[derive(Debug, Default)]
+pub struct Save {}
+
+/// Write a snapshot of the keys to disk in the background.
+///
+/// The values are cloned while holding the lock, so the snapshot is
+/// consistent, then encoded and written once it is released.
+#[derive(Debug, Default)]
+pub struct Bgsave {}
+
+/// Return the Unix time of the last successful save.
+#[derive(Debug, Default)]
+pub struct Lastsave {}
+
+/// Rewrite the append-only file in the background, replacing it by a
+/// minimal one rebuilding the current keys.
+///
+/// Write commands executed in the meantime are buffered, then appended to
+/// the new file before it replaces the current one.
+#[derive(Debug, Default)]
+pub struct Bgrewriteaof {}
+
+impl Save {
+    /// Create a new `Save` command.
+    pub fn new() -> Save {
+        Save {}
+    }
+
+    /// Parse a `Save` instance from a received frame.
+    ///
+    /// The `SAVE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SAVE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Save> {
+        Ok(Save {})
+    }
+
+    /// Apply the `Save` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.save() {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("save".as_bytes()));
+        frame
+    }
+}
+
+impl Bgsave {
+    /// Create a new `Bgsave` command.
+    pub fn new() -> Bgsave {
+        Bgsave {}
+    }
+
+    /// Parse a `Bgsave` instance from a received frame.
+    ///
+    /// The `BGSAVE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BGSAVE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Bgsave> {
+        Ok(Bgsave {})
+    }
+
+    /// Apply the `Bgsave` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.bgsave() {
+            Ok(()) => Frame::Simple("Background saving started".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bgsave".as_bytes()));
+        frame
+    }
+}
+
+impl Lastsave {
+    /// Create a new `Lastsave` command.
+    pub fn new() -> Lastsave {
+        Lastsave {}
+    }
+
+    /// Parse a `Lastsave` instance from a received frame.
+    ///
+    /// The `LASTSAVE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// LASTSAVE
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Lastsave> {
+        Ok(Lastsave {})
+    }
+
+    /// Apply the `Lastsave` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = Frame::Int(db.last_save());
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("lastsave".as_bytes()));
+        frame
+    }
+}
+
+impl Bgrewriteaof {
+    /// Create a new `Bgrewriteaof` command.
+    pub fn new() -> Bgrewriteaof {
+        Bgrewriteaof {}
+    }
+
+    /// Parse a `Bgrewriteaof` instance from a received frame.
+    ///
+    /// The `BGREWRITEAOF` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// BGREWRITEAOF
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Bgrewriteaof> {
+        Ok(Bgrewriteaof {})
+    }
+
+    /// Apply the `Bgrewriteaof` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.bgrewriteaof() {
+            Ok(()) => Frame::Simple("Background append only file rewriting started".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("bgrewriteaof".as_bytes()));
+        frame
+    }
+}
@@ -0,0 +1,252 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Incrementally iterate over the keys.
+///
+/// Each call returns a cursor to pass to the next call, and the iteration is
+/// complete when the returned cursor is `0`. Every key present for the whole
+/// iteration is returned at least once.
+#[derive(Debug)]
+pub struct Scan {
+    cursor: u64,
+    options: ScanOptions,
+    /// Only return keys holding this type, as named by `TYPE`.
+    ty: Option<String>,
+}
+
+/// Incrementally iterate over the fields of a hash, like `SCAN`.
+#[derive(Debug)]
+pub struct Hscan {
+    key: String,
+    cursor: u64,
+    options: ScanOptions,
+}
+
+/// Options shared by the `SCAN` family of commands.
+#[derive(Debug)]
+struct ScanOptions {
+    /// Only return items matching this glob-style pattern.
+    pattern: Option<Bytes>,
+    /// Number of items to examine per call.
+    count: u64,
+}
+
+/// Number of items examined per call when no `COUNT` is given.
+const DEFAULT_COUNT: u64 = 10;
+
+impl Scan {
+    /// Create a new `Scan` command resuming the iteration at `cursor`.
+    pub fn new(
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: Option<u64>,
+        ty: Option<String>,
+    ) -> Self {
+        Scan {
+            cursor,
+            options: ScanOptions {
+                pattern,
+                count: count.unwrap_or(DEFAULT_COUNT),
+            },
+            ty,
+        }
+    }
+
+    /// Parse a `Scan` instance from a received frame.
+    ///
+    /// The `SCAN` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Scan> {
+        let cursor = parse.next_int()?;
+        let mut ty = None;
+
+        let options = ScanOptions::parse_frames(parse, |option, parse| match option {
+            "TYPE" => {
+                ty = Some(parse.next_string()?);
+                Ok(true)
+            }
+            _ => Ok(false),
+        })?;
+
+        Ok(Scan {
+            cursor,
+            options,
+            ty,
+        })
+    }
+
+    /// Apply the `Scan` command to the specified `Db` instance.
+    ///
+    /// Responds with the next cursor followed by an array of keys.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let (cursor, keys) = db.scan(
+            self.cursor,
+            self.options.count as usize,
+            self.options.pattern.as_deref(),
+            self.ty.as_deref(),
+        );
+
+        let mut page = Frame::array();
+        for key in keys {
+            page.push_bulk(Bytes::from(key));
+        }
+
+        let resp = make_scan_reply(cursor, page);
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("scan".as_bytes()));
+        frame.push_int(self.cursor);
+        self.options.push_frames(&mut frame);
+        if let Some(ty) = self.ty {
+            frame.push_bulk(Bytes::from("type".as_bytes()));
+            frame.push_bulk(Bytes::from(ty.into_bytes()));
+        }
+        frame
+    }
+}
+
+impl Hscan {
+    /// Create a new `Hscan` command resuming the iteration of the hash at
+    /// `key` at `cursor`.
+    pub fn new(
+        key: impl ToString,
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: Option<u64>,
+    ) -> Self {
+        Hscan {
+            key: key.to_string(),
+            cursor,
+            options: ScanOptions {
+                pattern,
+                count: count.unwrap_or(DEFAULT_COUNT),
+            },
+        }
+    }
+
+    /// Parse a `Hscan` instance from a received frame.
+    ///
+    /// The `HSCAN` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// HSCAN key cursor [MATCH pattern] [COUNT count]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Hscan> {
+        let key = parse.next_string()?;
+        let cursor = parse.next_int()?;
+        let options = ScanOptions::parse_frames(parse, |_, _| Ok(false))?;
+
+        Ok(Hscan {
+            key,
+            cursor,
+            options,
+        })
+    }
+
+    /// Apply the `Hscan` command to the specified `Db` instance.
+    ///
+    /// Responds with the next cursor followed by a flat array of fields, each
+    /// followed by its value.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.hscan(
+            &self.key,
+            self.cursor,
+            self.options.count as usize,
+            self.options.pattern.as_deref(),
+        ) {
+            Ok((cursor, fields)) => {
+                let mut page = Frame::array();
+                for (field, value) in fields {
+                    page.push_bulk(field);
+                    page.push_bulk(value);
+                }
+                make_scan_reply(cursor, page)
+            }
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("hscan".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_int(self.cursor);
+        self.options.push_frames(&mut frame);
+        frame
+    }
+}
+
+impl ScanOptions {
+    /// Parse the `MATCH` and `COUNT` options. Other options are passed to
+    /// `extra`, which returns `false` for options it does not know.
+    fn parse_frames(
+        parse: &mut Parse,
+        mut extra: impl FnMut(&str, &mut Parse) -> crate::Result<bool>,
+    ) -> crate::Result<ScanOptions> {
+        let mut options = ScanOptions {
+            pattern: None,
+            count: DEFAULT_COUNT,
+        };
+
+        loop {
+            let option = match parse.next_string() {
+                Ok(option) => option.to_uppercase(),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            match &option[..] {
+                "MATCH" => options.pattern = Some(parse.next_bytes()?),
+                "COUNT" => {
+                    options.count = parse.next_int()?;
+                    if options.count == 0 {
+                        return Err("ERR syntax error".into());
+                    }
+                }
+                _ => {
+                    if !extra(&option, parse)? {
+                        return Err(format!("ERR unknown scan option `{}`", option).into());
+                    }
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn push_frames(self, frame: &mut Frame) {
+        if let Some(pattern) = self.pattern {
+            frame.push_bulk(Bytes::from("match".as_bytes()));
+            frame.push_bulk(pattern);
+        }
+        frame.push_bulk(Bytes::from("count".as_bytes()));
+        frame.push_int(self.count);
+    }
+}
+
+/// Creates the `[cursor, page]` reply of the `SCAN` family. The cursor is sent
+/// as a bulk string, like Redis does.
+fn make_scan_reply(cursor: u64, page: Frame) -> Frame {
+    Frame::Array(vec![Frame::Bulk(Bytes::from(cursor.to_string())), page])
+}
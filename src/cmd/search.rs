@@ -0,0 +1,628 @@
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::db::{search, DistanceMetric, FieldType, Index, Query, VectorAlgorithm};
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Create a search index over the hashes whose key starts with one of the
+/// given prefixes.
+///
+/// Existing keys are indexed right away and the index is kept up to date as
+/// keys are written, deleted or expire.
+#[derive(Debug, Clone)]
+pub struct FtCreate {
+    index: String,
+    /// Key prefixes covered by the index. Empty to cover every key.
+    prefixes: Vec<String>,
+    /// Indexed hash fields and their types.
+    schema: Vec<(String, FieldType)>,
+}
+
+/// Query a search index.
+#[derive(Debug)]
+pub struct FtSearch {
+    index: String,
+    query: String,
+    /// Values referred to by the query as `$name`.
+    params: Vec<(String, Bytes)>,
+    /// Field to order results by, and whether the order is ascending.
+    sort_by: Option<(String, bool)>,
+    /// Number of results to skip.
+    offset: u64,
+    /// Maximum number of results to return.
+    count: u64,
+    /// Fields to return with each result. `None` returns every field.
+    fields: Option<Vec<String>>,
+    /// When `true`, only keys are returned.
+    no_content: bool,
+}
+
+/// Number of results returned by `FT.SEARCH` without a `LIMIT`.
+const DEFAULT_LIMIT: u64 = 10;
+
+/// Drop a search index. The indexed keys are left untouched.
+#[derive(Debug, Clone)]
+pub struct FtDropIndex {
+    index: String,
+}
+
+impl FtCreate {
+    /// Create a new `FtCreate` command.
+    pub fn new(
+        index: impl ToString,
+        prefixes: Vec<String>,
+        schema: Vec<(String, FieldType)>,
+    ) -> Self {
+        FtCreate {
+            index: index.to_string(),
+            prefixes,
+            schema,
+        }
+    }
+
+    /// Parse a `FtCreate` instance from a received frame.
+    ///
+    /// The `FT.CREATE` string has already been consumed. Only hashes can be
+    /// indexed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// FT.CREATE index [ON HASH] [PREFIX count prefix [prefix ...]]
+    ///     SCHEMA field type [options] [field type [options] ...]
+    /// ```
+    ///
+    /// The field types are:
+    ///
+    /// ```text
+    /// TEXT [WEIGHT weight] [SORTABLE]
+    /// NUMERIC [SORTABLE]
+    /// TAG [SEPARATOR separator] [CASESENSITIVE] [SORTABLE]
+    /// VECTOR {FLAT | HNSW} nargs attribute value [attribute value ...]
+    /// ```
+    ///
+    /// Vector fields require the `TYPE FLOAT32`, `DIM` and `DISTANCE_METRIC`
+    /// attributes. HNSW fields also accept `M`, `EF_CONSTRUCTION` and
+    /// `EF_RUNTIME`.
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<FtCreate> {
+        let index = parse.next_string()?;
+        let mut prefixes = vec![];
+
+        loop {
+            match &parse.next_string()?.to_uppercase()[..] {
+                "ON" => {
+                    let on = parse.next_string()?;
+                    if on.to_uppercase() != "HASH" {
+                        return Err(format!("ERR unsupported index type `{}`", on).into());
+                    }
+                }
+                "PREFIX" => {
+                    for _ in 0..parse.next_int()? {
+                        prefixes.push(parse.next_string()?);
+                    }
+                }
+                "SCHEMA" => break,
+                s => return Err(format!("ERR unknown `FT.CREATE` option `{}`", s).into()),
+            }
+        }
+
+        // Field options are optional, so the schema is parsed from a list of
+        // tokens that can be peeked at.
+        let mut tokens = vec![];
+        loop {
+            match parse.next_string() {
+                Ok(token) => tokens.push(token),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let mut tokens = tokens.into_iter().peekable();
+        let mut schema = vec![];
+
+        while let Some(name) = tokens.next() {
+            schema.push((name, parse_field_type(&mut tokens)?));
+        }
+
+        if schema.is_empty() {
+            return Err("ERR the schema declares no field".into());
+        }
+
+        Ok(FtCreate {
+            index,
+            prefixes,
+            schema,
+        })
+    }
+
+    /// Apply the `FtCreate` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let index = Index::new(self.prefixes, self.schema);
+
+        let resp = match db.ft_create(self.index, index) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ft.create".as_bytes()));
+        frame.push_bulk(Bytes::from(self.index.into_bytes()));
+        frame.push_bulk(Bytes::from("on".as_bytes()));
+        frame.push_bulk(Bytes::from("hash".as_bytes()));
+
+        if !self.prefixes.is_empty() {
+            frame.push_bulk(Bytes::from("prefix".as_bytes()));
+            frame.push_int(self.prefixes.len() as u64);
+            for prefix in self.prefixes {
+                frame.push_bulk(Bytes::from(prefix.into_bytes()));
+            }
+        }
+
+        frame.push_bulk(Bytes::from("schema".as_bytes()));
+        for (name, ty) in self.schema {
+            frame.push_bulk(Bytes::from(name.into_bytes()));
+            push_field_type(&mut frame, ty);
+        }
+        frame
+    }
+}
+
+impl FtSearch {
+    /// Create a new `FtSearch` command returning the first results with all
+    /// their fields.
+    pub fn new(index: impl ToString, query: impl ToString, params: Vec<(String, Bytes)>) -> Self {
+        FtSearch {
+            index: index.to_string(),
+            query: query.to_string(),
+            params,
+            sort_by: None,
+            offset: 0,
+            count: DEFAULT_LIMIT,
+            fields: None,
+            no_content: false,
+        }
+    }
+
+    /// Parse a `FtSearch` instance from a received frame.
+    ///
+    /// The `FT.SEARCH` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// FT.SEARCH index query [NOCONTENT] [RETURN count field [field ...]]
+    ///     [SORTBY field [ASC | DESC]] [LIMIT offset num]
+    ///     [PARAMS nargs name value [name value ...]] [DIALECT dialect]
+    /// ```
+    ///
+    /// See `Query::parse` for the query syntax. Without `SORTBY`, results are
+    /// ordered by relevance, or by distance for vector queries. Without
+    /// `LIMIT`, the first 10 results are returned.
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<FtSearch> {
+        let mut search = FtSearch::new(parse.next_string()?, parse.next_string()?, vec![]);
+
+        // `SORTBY` takes an optional direction, so the option following it is
+        // read ahead.
+        let mut next = parse.next_string();
+
+        loop {
+            let option = match next {
+                Ok(option) => option.to_uppercase(),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            };
+            next = Err(ParseError::EndOfStream);
+
+            match &option[..] {
+                "NOCONTENT" => search.no_content = true,
+                "RETURN" => {
+                    let count = parse.next_int()?;
+                    let mut fields = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        fields.push(parse.next_string()?);
+                    }
+                    search.fields = Some(fields);
+                }
+                "SORTBY" => {
+                    let field = parse.next_string()?;
+                    let mut ascending = true;
+
+                    match parse.next_string() {
+                        Ok(s) if s.to_uppercase() == "ASC" => {}
+                        Ok(s) if s.to_uppercase() == "DESC" => ascending = false,
+                        other => next = other,
+                    }
+
+                    search.sort_by = Some((field, ascending));
+                }
+                "LIMIT" => {
+                    search.offset = parse.next_int()?;
+                    search.count = parse.next_int()?;
+                }
+                "PARAMS" => {
+                    let nargs = parse.next_int()?;
+                    if nargs % 2 != 0 {
+                        return Err("ERR PARAMS expects name value pairs".into());
+                    }
+                    for _ in 0..nargs / 2 {
+                        search
+                            .params
+                            .push((parse.next_string()?, parse.next_bytes()?));
+                    }
+                }
+                // Only one dialect is supported. The option is accepted for
+                // compatibility with clients that always send it.
+                "DIALECT" => {
+                    parse.next_int()?;
+                }
+                _ => return Err(format!("ERR unknown `FT.SEARCH` option `{}`", option).into()),
+            }
+
+            if next.is_err() {
+                next = parse.next_string();
+            }
+        }
+
+        Ok(search)
+    }
+
+    /// Apply the `FtSearch` command to the specified `Db` instance.
+    ///
+    /// Responds with the total number of matches, followed by the key and
+    /// fields of each returned document. The fields of vector query results
+    /// start with the score.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let query = match Query::parse(&self.query, &self.params) {
+            Ok(query) => query,
+            Err(err) => {
+                let resp = Frame::Error(err);
+                debug!(?resp);
+                return resp;
+            }
+        };
+
+        let sort_by = self
+            .sort_by
+            .as_ref()
+            .map(|(field, ascending)| (&field[..], *ascending));
+
+        let resp = match db.ft_search(
+            &self.index,
+            &query,
+            sort_by,
+            self.offset as usize,
+            self.count as usize,
+        ) {
+            Ok((total, docs)) => {
+                let mut resp = vec![Frame::Int(total as u64)];
+
+                for (key, score, fields) in docs {
+                    resp.push(Frame::Bulk(Bytes::from(key)));
+
+                    if self.no_content {
+                        continue;
+                    }
+
+                    let score = query.score_field().zip(score).map(|(name, score)| {
+                        (
+                            Bytes::from(name.to_string()),
+                            Bytes::from(score.to_string()),
+                        )
+                    });
+
+                    let returned = |field: &Bytes| match &self.fields {
+                        Some(names) => names.iter().any(|name| name.as_bytes() == &field[..]),
+                        None => true,
+                    };
+
+                    let mut doc = Frame::array();
+                    for (field, value) in score.into_iter().chain(fields) {
+                        if returned(&field) {
+                            doc.push_bulk(field);
+                            doc.push_bulk(value);
+                        }
+                    }
+                    resp.push(doc);
+                }
+
+                Frame::Array(resp)
+            }
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ft.search".as_bytes()));
+        frame.push_bulk(Bytes::from(self.index.into_bytes()));
+        frame.push_bulk(Bytes::from(self.query.into_bytes()));
+
+        if self.no_content {
+            frame.push_bulk(Bytes::from("nocontent".as_bytes()));
+        }
+
+        if let Some(fields) = self.fields {
+            frame.push_bulk(Bytes::from("return".as_bytes()));
+            frame.push_int(fields.len() as u64);
+            for field in fields {
+                frame.push_bulk(Bytes::from(field.into_bytes()));
+            }
+        }
+
+        if let Some((field, ascending)) = self.sort_by {
+            frame.push_bulk(Bytes::from("sortby".as_bytes()));
+            frame.push_bulk(Bytes::from(field.into_bytes()));
+            let order = if ascending { "asc" } else { "desc" };
+            frame.push_bulk(Bytes::from(order.as_bytes()));
+        }
+
+        frame.push_bulk(Bytes::from("limit".as_bytes()));
+        frame.push_int(self.offset);
+        frame.push_int(self.count);
+
+        if !self.params.is_empty() {
+            frame.push_bulk(Bytes::from("params".as_bytes()));
+            frame.push_int(self.params.len() as u64 * 2);
+            for (name, value) in self.params {
+                frame.push_bulk(Bytes::from(name.into_bytes()));
+                frame.push_bulk(value);
+            }
+        }
+        frame
+    }
+}
+
+impl FtDropIndex {
+    /// Create a new `FtDropIndex` command.
+    pub fn new(index: impl ToString) -> Self {
+        FtDropIndex {
+            index: index.to_string(),
+        }
+    }
+
+    /// Parse a `FtDropIndex` instance from a received frame.
+    ///
+    /// The `FT.DROPINDEX` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// FT.DROPINDEX index
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<FtDropIndex> {
+        let index = parse.next_string()?;
+
+        Ok(FtDropIndex { index })
+    }
+
+    /// Apply the `FtDropIndex` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.ft_dropindex(&self.index) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ft.dropindex".as_bytes()));
+        frame.push_bulk(Bytes::from(self.index.into_bytes()));
+        frame
+    }
+}
+
+/// Parse the type and options of a field declared by `FT.CREATE`.
+fn parse_field_type(tokens: &mut Peekable<IntoIter<String>>) -> crate::Result<FieldType> {
+    let ty = next_token(tokens)?;
+
+    // Any field can be sorted on, so `SORTABLE` is accepted and ignored.
+    match &ty.to_uppercase()[..] {
+        "TEXT" => {
+            let mut weight = 1.0;
+
+            while let Some(option) = next_option(tokens, &["WEIGHT", "SORTABLE"]) {
+                if option == "WEIGHT" {
+                    weight = next_token(tokens)?
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|weight| *weight >= 0.0)
+                        .ok_or("ERR invalid field weight")?;
+                }
+            }
+
+            Ok(FieldType::Text { weight })
+        }
+        "NUMERIC" => {
+            while next_option(tokens, &["SORTABLE"]).is_some() {}
+            Ok(FieldType::Numeric)
+        }
+        "TAG" => {
+            let mut separator = search::DEFAULT_TAG_SEPARATOR;
+            let mut case_sensitive = false;
+
+            while let Some(option) =
+                next_option(tokens, &["SEPARATOR", "CASESENSITIVE", "SORTABLE"])
+            {
+                match &option[..] {
+                    "SEPARATOR" => {
+                        let token = next_token(tokens)?;
+                        let mut chars = token.chars();
+                        separator = match (chars.next(), chars.next()) {
+                            (Some(c), None) => c,
+                            _ => return Err("ERR tag separator must be a single character".into()),
+                        };
+                    }
+                    "CASESENSITIVE" => case_sensitive = true,
+                    _ => {}
+                }
+            }
+
+            Ok(FieldType::Tag {
+                separator,
+                case_sensitive,
+            })
+        }
+        "VECTOR" => parse_vector_field(tokens),
+        _ => Err(format!("ERR unsupported field type `{}`", ty).into()),
+    }
+}
+
+/// Parse the options of a `VECTOR` field.
+fn parse_vector_field(tokens: &mut Peekable<IntoIter<String>>) -> crate::Result<FieldType> {
+    let algorithm = next_token(tokens)?;
+    let nargs = next_number(tokens)?;
+
+    if nargs % 2 != 0 {
+        return Err("ERR vector attributes expect name value pairs".into());
+    }
+
+    let mut dim = None;
+    let mut metric = None;
+    let mut m = search::DEFAULT_M;
+    let mut ef_construction = search::DEFAULT_EF_CONSTRUCTION;
+    let mut ef_runtime = search::DEFAULT_EF_RUNTIME;
+
+    for _ in 0..nargs / 2 {
+        let attribute = next_token(tokens)?;
+
+        match &attribute.to_uppercase()[..] {
+            "TYPE" => {
+                let ty = next_token(tokens)?;
+                if ty.to_uppercase() != "FLOAT32" {
+                    return Err(format!("ERR unsupported vector type `{}`", ty).into());
+                }
+            }
+            "DIM" => dim = Some(next_number(tokens)?),
+            "DISTANCE_METRIC" => {
+                let name = next_token(tokens)?;
+                metric = Some(
+                    DistanceMetric::parse(&name)
+                        .ok_or_else(|| format!("ERR unknown distance metric `{}`", name))?,
+                );
+            }
+            "M" => m = next_number(tokens)?,
+            "EF_CONSTRUCTION" => ef_construction = next_number(tokens)?,
+            "EF_RUNTIME" => ef_runtime = next_number(tokens)?,
+            // Sizing hints, not needed by the in-memory structures.
+            "INITIAL_CAP" | "BLOCK_SIZE" => {
+                next_number(tokens)?;
+            }
+            _ => return Err(format!("ERR unknown vector attribute `{}`", attribute).into()),
+        }
+    }
+
+    let algorithm = match &algorithm.to_uppercase()[..] {
+        "FLAT" => VectorAlgorithm::Flat,
+        "HNSW" => VectorAlgorithm::Hnsw {
+            m,
+            ef_construction,
+            ef_runtime,
+        },
+        _ => return Err(format!("ERR unknown vector algorithm `{}`", algorithm).into()),
+    };
+
+    match (dim, metric) {
+        (Some(dim), Some(metric)) if dim > 0 => Ok(FieldType::Vector {
+            algorithm,
+            dim,
+            metric,
+        }),
+        _ => Err("ERR vector fields require TYPE, DIM and DISTANCE_METRIC".into()),
+    }
+}
+
+/// Consume the next token if it is one of `options`, ignoring case. Returns
+/// the option in uppercase.
+fn next_option(tokens: &mut Peekable<IntoIter<String>>, options: &[&str]) -> Option<String> {
+    tokens
+        .next_if(|token| {
+            options
+                .iter()
+                .any(|option| token.eq_ignore_ascii_case(option))
+        })
+        .map(|token| token.to_uppercase())
+}
+
+fn next_token(tokens: &mut Peekable<IntoIter<String>>) -> crate::Result<String> {
+    tokens.next().ok_or_else(|| ParseError::EndOfStream.into())
+}
+
+fn next_number(tokens: &mut Peekable<IntoIter<String>>) -> crate::Result<usize> {
+    let token = next_token(tokens)?;
+    token
+        .parse()
+        .map_err(|_| format!("ERR expected a number, got `{}`", token).into())
+}
+
+/// Append the type and options of a schema field to `frame`.
+fn push_field_type(frame: &mut Frame, ty: FieldType) {
+    match ty {
+        FieldType::Text { weight } => {
+            frame.push_bulk(Bytes::from("text".as_bytes()));
+            frame.push_bulk(Bytes::from("weight".as_bytes()));
+            frame.push_bulk(Bytes::from(weight.to_string()));
+        }
+        FieldType::Numeric => frame.push_bulk(Bytes::from("numeric".as_bytes())),
+        FieldType::Tag {
+            separator,
+            case_sensitive,
+        } => {
+            frame.push_bulk(Bytes::from("tag".as_bytes()));
+            frame.push_bulk(Bytes::from("separator".as_bytes()));
+            frame.push_bulk(Bytes::from(separator.to_string()));
+            if case_sensitive {
+                frame.push_bulk(Bytes::from("casesensitive".as_bytes()));
+            }
+        }
+        FieldType::Vector {
+            algorithm,
+            dim,
+            metric,
+        } => {
+            let mut attributes = vec![
+                ("type", "FLOAT32".to_string()),
+                ("dim", dim.to_string()),
+                ("distance_metric", metric.name().to_string()),
+            ];
+
+            if let VectorAlgorithm::Hnsw {
+                m,
+                ef_construction,
+                ef_runtime,
+            } = algorithm
+            {
+                attributes.push(("m", m.to_string()));
+                attributes.push(("ef_construction", ef_construction.to_string()));
+                attributes.push(("ef_runtime", ef_runtime.to_string()));
+            }
+
+            frame.push_bulk(Bytes::from("vector".as_bytes()));
+            frame.push_bulk(Bytes::from(algorithm.name().as_bytes()));
+            frame.push_int(attributes.len() as u64 * 2);
+            for (name, value) in attributes {
+                frame.push_bulk(Bytes::from(name.as_bytes()));
+                frame.push_bulk(Bytes::from(value.into_bytes()));
+            }
+        }
+    }
+}
@@ -3,11 +3,10 @@ use tracing::{debug, instrument};
 
 use crate::{
     cmd::{Parse, ParseError},
-    db::Db,
-    Connection, Frame,
+    Frame, LockedDb,
 };
 
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Set `key` to hold the string `value`.
 ///
@@ -21,7 +20,11 @@ use std::time::Duration;
 ///
 /// * EX `seconds` -- Set the specified expire time, in seconds.
 /// * PX `milliseconds` -- Set the specified expire time, in milliseconds.
-#[derive(Debug)]
+/// * EXAT `timestamp` -- Set the Unix time at which the key expires, in
+///   seconds.
+/// * PXAT `timestamp` -- Set the Unix time at which the key expires, in
+///   milliseconds.
+#[derive(Debug, Clone)]
 pub struct Set {
     /// the lookup key
     key: String,
@@ -30,6 +33,9 @@ pub struct Set {
 
     /// When to expire the key
     expire: Option<Duration>,
+    /// When to expire the key, as a Unix time in milliseconds. Takes
+    /// precedence over `expire`.
+    expire_at: Option<u64>,
 }
 
 impl Set {
@@ -42,6 +48,7 @@ impl Set {
             key: key.to_string(),
             value,
             expire,
+            expire_at: None,
         }
     }
 
@@ -78,7 +85,7 @@ impl Set {
     /// Expects an array frame containing at least 3 entries.
     ///
     /// ```text
-    /// SET key value [EX seconds|PX milliseconds]
+    /// SET key value [EX seconds|PX milliseconds|EXAT timestamp|PXAT timestamp]
     /// ```
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Set> {
         use ParseError::EndOfStream;
@@ -88,6 +95,7 @@ impl Set {
         let value = parse.next_bytes()?;
 
         let mut expire = None;
+        let mut expire_at = None;
 
         //  Attempt to parse another string.
         match parse.next_string() {
@@ -102,6 +110,15 @@ impl Set {
                 expire = Some(Duration::from_millis(ms));
             }
 
+            Ok(s) if s.to_uppercase() == "EXAT" => {
+                let secs = parse.next_int()?;
+                expire_at = Some(secs.saturating_mul(1000));
+            }
+
+            Ok(s) if s.to_uppercase() == "PXAT" => {
+                expire_at = Some(parse.next_int()?);
+            }
+
             Ok(_) => return Err("currently `SET` only supports the expiration option".into()),
             // The `EndOfStream` error indicates there is no further data to
             // parse. In this case, it is a normal run time situation and
@@ -112,37 +129,70 @@ impl Set {
             Err(err) => return Err(err.into()),
         }
 
-        Ok(Set { key, value, expire })
+        Ok(Set {
+            key,
+            value,
+            expire,
+            expire_at,
+        })
     }
 
     /// Apply the `Set` command to the specified `Db` instance.
     ///
-    /// The response is written to `dst`. This is called by the server in order
-    /// to execute a received command.
-    #[instrument(skip(self, db, dst))]
-    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+    /// Returns the response to send to the client. This is called by the server
+    /// in order to execute a received command.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let expire = match self.expire_at {
+            Some(when) => match when.checked_sub(unix_time_ms()) {
+                Some(left) if left > 0 => Some(Duration::from_millis(left)),
+                // A key set to expire in the past is deleted right away.
+                _ => {
+                    db.del(&[self.key]);
+                    return Frame::Simple("OK".to_string());
+                }
+            },
+            None => self.expire,
+        };
+
         // Set the value in the shared database state.
-        db.set(self.key, self.value, self.expire);
+        db.set(self.key, self.value, expire);
 
-        // Create a success response and write it to `dst`.
+        // Create a success response.
         let resp = Frame::Simple("OK".to_string());
         debug!(?resp);
-        dst.write_frame(&resp).await?;
-        Ok(())
+        resp
+    }
+
+    /// Expire the key at the Unix time `when`, in milliseconds.
+    pub(crate) fn set_expire_at(&mut self, when: u64) {
+        self.expire = None;
+        self.expire_at = Some(when);
+    }
+
+    /// Replace the expiration relative to now by the Unix time it stands
+    /// for, so that the command has the same effect when replayed later.
+    pub(crate) fn make_absolute(&mut self) {
+        if let Some(expire) = self.expire.take() {
+            self.expire_at = Some(unix_time_ms() + expire.as_millis() as u64);
+        }
     }
 
     /// Converts the command into an equivalent `Frame`.
     ///
     /// This is called by the client when encoding a `Set` command to send to
     /// the server.
-    pub(crate) fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> Frame {
         let mut frame = Frame::array();
 
         frame.push_bulk(Bytes::from("set".as_bytes()));
         frame.push_bulk(Bytes::from(self.key.into_bytes()));
         frame.push_bulk(self.value);
 
-        if let Some(ms) = self.expire {
+        if let Some(when) = self.expire_at {
+            frame.push_bulk(Bytes::from("pxat".as_bytes()));
+            frame.push_int(when);
+        } else if let Some(ms) = self.expire {
             // Expirations in Redis procotol can be specified in two ways
             // 1. SET key value EX seconds
             // 2. SET key value PX milliseconds
@@ -155,3 +205,11 @@ impl Set {
         frame
     }
 }
+
+/// Returns the current Unix time in milliseconds.
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::ops::ControlFlow;
 use std::pin::Pin;
 
 use bytes::Bytes;
@@ -6,8 +8,8 @@ use tokio::{select, sync::broadcast};
 use tokio_stream::{Stream, StreamExt, StreamMap};
 
 use crate::{
-    cmd::{Parse, ParseError, Unknown},
-    db::Db,
+    cmd::{error_reply, LagPolicy, Parse, ParseError, Unknown},
+    db::{Db, Message, Subscriber},
     shutdown::Shutdown,
     Command, Connection, Frame,
 };
@@ -15,11 +17,44 @@ use crate::{
 /// Subscribes the client to one or more channels.
 ///
 /// Once the client enters the subscribed state, it is not supposed to issue any
-/// other commands, except for additional SUBSCRIBE, PSUBSCRIBE, UNSUBSCRIBE,
-/// PUNSUBSCRIBE, PING and QUIT commands.
+/// other commands, except for additional SUBSCRIBE, PSUBSCRIBE, SSUBSCRIBE,
+/// UNSUBSCRIBE, PUNSUBSCRIBE, SUNSUBSCRIBE, PING, RESET and QUIT commands.
+/// RESET leaves the subscribed state.
+///
+/// When `pubsub-log-size` is set, messages carry their offset in the
+/// channel's log. A client coming back after a disconnection may then pass
+/// `FROM offset` after a channel to first receive the messages it missed.
 #[derive(Debug)]
 pub struct Subscribe {
     channels: Vec<String>,
+    /// Offsets to replay the channels' logs from, by channel.
+    offsets: HashMap<String, u64>,
+    /// Patterns to subscribe to. Always empty for `SUBSCRIBE`, this is used
+    /// when `PSUBSCRIBE` enters the subscribed state.
+    patterns: Vec<String>,
+    /// Shard channels to subscribe to. Always empty for `SUBSCRIBE`, this is
+    /// used when `SSUBSCRIBE` enters the subscribed state.
+    shard_channels: Vec<String>,
+}
+
+/// Subscribes the client to one or more glob-style patterns.
+///
+/// The client receives the messages published to every channel matching one
+/// of the patterns, as `pmessage` frames carrying the pattern and the channel.
+/// Like `SUBSCRIBE`, this enters the subscribed state.
+#[derive(Debug)]
+pub struct Psubscribe {
+    patterns: Vec<String>,
+}
+
+/// Subscribes the client to one or more shard channels.
+///
+/// Shard channels are a separate namespace from regular channels. Messages
+/// sent with `SPUBLISH` are received as `smessage` frames. Like `SUBSCRIBE`,
+/// this enters the subscribed state.
+#[derive(Debug)]
+pub struct Ssubscribe {
+    shard_channels: Vec<String>,
 }
 
 /// Unsubscribes the client from one or more channels.
@@ -31,15 +66,60 @@ pub struct Unsubscribe {
     channels: Vec<String>,
 }
 
+/// Unsubscribes the client from one or more patterns.
+///
+/// When no patterns are specified, the client is unsubscribed from all the
+/// previously subscribed patterns.
+#[derive(Clone, Debug)]
+pub struct Punsubscribe {
+    patterns: Vec<String>,
+}
+
+/// Unsubscribes the client from one or more shard channels.
+///
+/// When no shard channels are specified, the client is unsubscribed from all
+/// the previously subscribed shard channels.
+#[derive(Clone, Debug)]
+pub struct Sunsubscribe {
+    shard_channels: Vec<String>,
+}
+
+/// A subscription held by a client in the subscribed state. Channels,
+/// patterns and shard channels live in separate namespaces, so a client may
+/// subscribe to a channel and a pattern of the same name.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Subscription {
+    Channel(String),
+    Pattern(String),
+    ShardChannel(String),
+}
+
 /// Stream of messages. The stream receives messages from the
 /// `broadcast::Receiver`. We use `stream!` to create a `Stream` that consumes
 /// messages. Because `stream!` values cannot be named, we box the stream using
 /// a trait object.
-type Messages = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+///
+/// Each message comes with the channel it was published to. For channel
+/// subscriptions, this is the subscribed channel itself.
+type Messages = Pin<Box<dyn Stream<Item = Received> + Send>>;
+
+/// An item received from a subscription.
+#[derive(Debug)]
+enum Received {
+    /// A message, along with the channel it was published to.
+    Message(String, Message),
+    /// The client fell behind, and this many messages were dropped.
+    Lagged(u64),
+}
 
 impl Subscribe {
-    pub(crate) fn new(channels: Vec<String>) -> Self {
-        Self { channels }
+    pub fn new(channels: Vec<String>) -> Self {
+        Self {
+            channels,
+            offsets: HashMap::new(),
+            patterns: vec![],
+            shard_channels: vec![],
+        }
     }
 
     /// Parse a `Subscribe` instance from a received frame.
@@ -60,7 +140,7 @@ impl Subscribe {
     /// Expects an array frame containing two or more entries.
     ///
     /// ```text
-    /// SUBSCRIBE channel [channel ...]
+    /// SUBSCRIBE channel [FROM offset] [channel [FROM offset] ...]
     /// ```
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Subscribe> {
         use ParseError::EndOfStream;
@@ -72,12 +152,18 @@ impl Subscribe {
         // Extract the first string. If there is none, the the frame is
         // malformed and the error is bubbled up.
         let mut channels = vec![parse.next_string()?];
+        let mut offsets = HashMap::new();
 
         // Now, the remainder of the frame is consumed. Each value must be a
         // string or the frame is malformed. Once all values in the frame have
         // been consumed, the command is fully parsed.
         loop {
             match parse.next_string() {
+                // `FROM` sets the offset of the channel before it.
+                Ok(s) if s.eq_ignore_ascii_case("from") => {
+                    let channel = channels.last().unwrap().clone();
+                    offsets.insert(channel, parse.next_int()?);
+                }
                 // A string has been consumed from the `parse`, push it into the
                 // list of channels to subscribe to.
                 Ok(s) => channels.push(s),
@@ -90,7 +176,10 @@ impl Subscribe {
             }
         }
 
-        Ok(Subscribe { channels })
+        Ok(Subscribe {
+            offsets,
+            ..Subscribe::new(channels)
+        })
     }
 
     /// Apply the `Subscribe` command to the specified `Db` instance.
@@ -123,7 +212,16 @@ impl Subscribe {
             // to. When new `SUBSCRIBE` commands are received during the
             // execution of `apply`, the new channels are pushed onto this vec.
             for chan_name in self.channels.drain(..) {
-                subscribe_to_channel(chan_name, &mut subscriptions, db, dst).await?;
+                let from = self.offsets.remove(&chan_name);
+                subscribe_to_channel(chan_name, from, &mut subscriptions, db, dst).await?;
+            }
+
+            for pattern in self.patterns.drain(..) {
+                subscribe_to_pattern(pattern, &mut subscriptions, db, dst).await?;
+            }
+
+            for chan_name in self.shard_channels.drain(..) {
+                subscribe_to_shard_channel(chan_name, &mut subscriptions, db, dst).await?;
             }
 
             // Wait for one of the following to happen:
@@ -133,10 +231,39 @@ impl Subscribe {
             // - A server shutdown signal.
             select! {
 
-                Some((chan_name, msg)) = subscriptions.next() => {
-                    dst.write_frame(
-                        &make_message_frame(chan_name, msg)
-                    ).await?;
+                Some((subscription, received)) = subscriptions.next() => {
+                    let frame = match (subscription, received) {
+                        (Subscription::Channel(_), Received::Message(chan_name, msg)) => {
+                            make_message_frame(chan_name, msg)
+                        }
+                        (Subscription::Pattern(pattern), Received::Message(chan_name, msg)) => {
+                            make_pmessage_frame(pattern, chan_name, msg.payload)
+                        }
+                        (Subscription::ShardChannel(_), Received::Message(chan_name, msg)) => {
+                            make_smessage_frame(chan_name, msg.payload)
+                        }
+                        (subscription, Received::Lagged(dropped)) => {
+                            let name = match subscription {
+                                Subscription::Channel(name)
+                                | Subscription::Pattern(name)
+                                | Subscription::ShardChannel(name) => name,
+                            };
+
+                            // Subscribers applying backpressure never lag, so
+                            // the client either wants to know or to leave.
+                            if dst.lag_policy() == LagPolicy::Disconnect {
+                                let msg = format!(
+                                    "ERR subscriber lagged behind '{}', {} messages dropped",
+                                    name, dropped
+                                );
+                                dst.write_frame(&Frame::Error(msg.clone())).await?;
+                                return Err(msg.into());
+                            }
+
+                            make_lag_frame(name, dropped)
+                        }
+                    };
+                    dst.write_frame(&frame).await?;
                 }
                 res = dst.read_frame() => {
                     let frame = match res? {
@@ -145,12 +272,18 @@ impl Subscribe {
                         None => return Ok(())
                     };
 
-                    handle_command(
+                    let flow = handle_command(
                         frame,
-                        &mut self.channels,
+                        &mut self,
                         &mut subscriptions,
                         dst
                     ).await?;
+
+                    // `RESET` or `QUIT`, the subscriptions are dropped on
+                    // return.
+                    if flow.is_break() {
+                        return Ok(());
+                    }
                 }
 
                 _ = shutdown.recv() => {
@@ -164,11 +297,17 @@ impl Subscribe {
     ///
     /// This is called by the client when encoding a `Subscribe` command to send
     /// to the server.
-    pub(crate) fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> Frame {
         let mut frame = Frame::array();
         frame.push_bulk(Bytes::from("subscribe".as_bytes()));
         for channel in self.channels {
+            let offset = self.offsets.get(&channel).copied();
+
             frame.push_bulk(Bytes::from(channel.into_bytes()));
+            if let Some(offset) = offset {
+                frame.push_bulk(Bytes::from("from".as_bytes()));
+                frame.push_int(offset);
+            }
         }
         frame
     }
@@ -176,71 +315,215 @@ impl Subscribe {
 
 async fn subscribe_to_channel(
     chan_name: String,
-    subscription: &mut StreamMap<String, Messages>,
+    from: Option<u64>,
+    subscription: &mut StreamMap<Subscription, Messages>,
     db: &Db,
     dst: &mut Connection,
 ) -> crate::Result<()> {
-    let mut rx = db.subscribe(chan_name.clone());
+    let rx = db
+        .subscribe(chan_name.clone(), dst.lag_policy(), from)
+        .await;
 
     // Subscribe to the channel.
-    let rx = Box::pin(async_stream::stream! {
-        loop {
-            match rx.recv().await {
-                Ok(msg) => yield msg,
-                // If we lagged in consuming messages, just resume.
-                Err(broadcast::error::RecvError::Lagged(_)) => {}
-                Err(_) => break,
-            }
-        }
+    let name = chan_name.clone();
+    let rx = into_stream(rx, move |msg| (name.clone(), msg));
+
+    subscription.insert(Subscription::Channel(chan_name.clone()), rx);
+
+    let resp = make_subscibe_frame(chan_name, count(subscription, false));
+    dst.write_frame(&resp).await?;
+    Ok(())
+}
+
+async fn subscribe_to_pattern(
+    pattern: String,
+    subscription: &mut StreamMap<Subscription, Messages>,
+    db: &Db,
+    dst: &mut Connection,
+) -> crate::Result<()> {
+    let rx = db.psubscribe(pattern.clone(), dst.lag_policy()).await;
+
+    let rx = into_stream(rx, |(chan_name, payload)| {
+        let msg = Message {
+            offset: None,
+            payload,
+        };
+        (chan_name, msg)
     });
 
-    subscription.insert(chan_name.clone(), rx);
+    subscription.insert(Subscription::Pattern(pattern.clone()), rx);
 
-    let resp = make_subscibe_frame(chan_name, subscription.len());
+    let resp = make_psubscribe_frame(pattern, count(subscription, false));
     dst.write_frame(&resp).await?;
     Ok(())
 }
 
+async fn subscribe_to_shard_channel(
+    chan_name: String,
+    subscription: &mut StreamMap<Subscription, Messages>,
+    db: &Db,
+    dst: &mut Connection,
+) -> crate::Result<()> {
+    let rx = db.ssubscribe(chan_name.clone(), dst.lag_policy()).await;
+
+    let name = chan_name.clone();
+    let rx = into_stream(rx, move |payload| {
+        let msg = Message {
+            offset: None,
+            payload,
+        };
+        (name.clone(), msg)
+    });
+
+    subscription.insert(Subscription::ShardChannel(chan_name.clone()), rx);
+
+    let resp = make_ssubscribe_frame(chan_name, count(subscription, true));
+    dst.write_frame(&resp).await?;
+    Ok(())
+}
+
+/// Returns the number of shard channel subscriptions if `shard` is true,
+/// otherwise the number of channel and pattern subscriptions. Replies to
+/// subscribe and unsubscribe commands carry one of these counts.
+fn count(subscription: &StreamMap<Subscription, Messages>, shard: bool) -> usize {
+    subscription
+        .keys()
+        .filter(|subscription| matches!(subscription, Subscription::ShardChannel(_)) == shard)
+        .count()
+}
+
+/// Turn a `Subscriber` into a stream of its messages. `into_message` maps
+/// each message to the channel it was published to and a `Message`.
+fn into_stream<T: Clone + Send + 'static>(
+    mut rx: Subscriber<T>,
+    into_message: impl Fn(T) -> (String, Message) + Send + 'static,
+) -> Messages {
+    Box::pin(async_stream::stream! {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => {
+                    let (chan_name, msg) = into_message(msg);
+                    yield Received::Message(chan_name, msg);
+                }
+                // If we lagged in consuming messages, report how many were
+                // dropped and resume.
+                Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                    yield Received::Lagged(dropped);
+                }
+                Err(_) => break,
+            }
+        }
+    })
+}
+
 /// Handle a command received while inside `Subscribe::apply`. Only subscribe
-/// and unsubscribe commands are permitted in this context.
+/// and unsubscribe commands, `PING`, `RESET` and `QUIT` are permitted in this
+/// context.
 ///
 /// Any new subscriptions are appended to `subscribe_to` instead of modifying
-/// `subscriptions`.
+/// `subscriptions`. Returns `ControlFlow::Break` when the client leaves the
+/// subscribed state.
 async fn handle_command(
     frame: Frame,
-    subscribe_to: &mut Vec<String>,
-    subscription: &mut StreamMap<String, Messages>,
+    subscribe_to: &mut Subscribe,
+    subscription: &mut StreamMap<Subscription, Messages>,
     dst: &mut Connection,
-) -> crate::Result<()> {
+) -> crate::Result<ControlFlow<()>> {
     // A command has been received from the client.
     //
-    // Only `SUBSCRIBE` and `UNSUBSCRIBE` commands are permitted
-    // in this context.
-    match Command::from_frame(frame)? {
+    // Only subscribe and unsubscribe commands, `PING`, `RESET` and `QUIT` are
+    // permitted in this context.
+    let command = match Command::from_frame(frame) {
+        Ok(command) => command,
+        Err(err) => {
+            dst.write_frame(&error_reply(err)).await?;
+            return Ok(ControlFlow::Continue(()));
+        }
+    };
+
+    match command {
         Command::Subscribe(subscribe) => {
-            subscribe_to.extend(subscribe.channels.into_iter());
+            subscribe_to.channels.extend(subscribe.channels);
+            subscribe_to.offsets.extend(subscribe.offsets);
+        }
+        Command::Psubscribe(psubscribe) => {
+            subscribe_to.patterns.extend(psubscribe.patterns);
         }
         Command::Unsubscribe(mut unsubscribe) => {
             if unsubscribe.channels.is_empty() {
                 unsubscribe.channels = subscription
                     .keys()
-                    .map(|channel_name| channel_name.to_string())
+                    .filter_map(|subscription| match subscription {
+                        Subscription::Channel(channel_name) => Some(channel_name.to_string()),
+                        _ => None,
+                    })
                     .collect()
             }
 
             for channel_name in unsubscribe.channels {
-                subscription.remove(&channel_name);
+                subscription.remove(&Subscription::Channel(channel_name.clone()));
+
+                let resp = make_unsubscribe_frame(channel_name, count(subscription, false));
+                dst.write_frame(&resp).await?;
+            }
+        }
+        Command::Punsubscribe(mut punsubscribe) => {
+            if punsubscribe.patterns.is_empty() {
+                punsubscribe.patterns = subscription
+                    .keys()
+                    .filter_map(|subscription| match subscription {
+                        Subscription::Pattern(pattern) => Some(pattern.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            }
+
+            for pattern in punsubscribe.patterns {
+                subscription.remove(&Subscription::Pattern(pattern.clone()));
+
+                let resp = make_punsubscribe_frame(pattern, count(subscription, false));
+                dst.write_frame(&resp).await?;
+            }
+        }
+        Command::Ssubscribe(ssubscribe) => {
+            subscribe_to
+                .shard_channels
+                .extend(ssubscribe.shard_channels);
+        }
+        Command::Sunsubscribe(mut sunsubscribe) => {
+            if sunsubscribe.shard_channels.is_empty() {
+                sunsubscribe.shard_channels = subscription
+                    .keys()
+                    .filter_map(|subscription| match subscription {
+                        Subscription::ShardChannel(channel_name) => Some(channel_name.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            }
 
-                let resp = make_unsubscribe_frame(channel_name, subscription.len());
+            for channel_name in sunsubscribe.shard_channels {
+                subscription.remove(&Subscription::ShardChannel(channel_name.clone()));
+
+                let resp = make_sunsubscribe_frame(channel_name, count(subscription, true));
                 dst.write_frame(&resp).await?;
             }
         }
+        Command::Ping(ping) => ping.apply_subscribed(dst).await?,
+        Command::Reset(reset) => {
+            subscription.clear();
+            reset.apply(dst).await?;
+            return Ok(ControlFlow::Break(()));
+        }
+        Command::Quit(quit) => {
+            quit.apply(dst).await?;
+            return Ok(ControlFlow::Break(()));
+        }
         command => {
             let cmd = Unknown::new(command.get_name());
-            cmd.apply(dst).await?;
+            dst.write_frame(&cmd.execute()).await?;
         }
     }
-    Ok(())
+    Ok(ControlFlow::Continue(()))
 }
 
 /// Creates the response to a subcribe request.
@@ -266,20 +549,211 @@ fn make_unsubscribe_frame(chan_name: String, num_subs: usize) -> Frame {
     resp
 }
 
+/// Creates the response to a psubscribe request.
+fn make_psubscribe_frame(pattern: String, num_subs: usize) -> Frame {
+    let mut resp = Frame::array();
+    resp.push_bulk(Bytes::from_static(b"psubscribe"));
+    resp.push_bulk(Bytes::from(pattern));
+    resp.push_int(num_subs as u64);
+    resp
+}
+
+/// Creates the response to a punsubscribe request.
+fn make_punsubscribe_frame(pattern: String, num_subs: usize) -> Frame {
+    let mut resp = Frame::array();
+    resp.push_bulk(Bytes::from_static(b"punsubscribe"));
+    resp.push_bulk(Bytes::from(pattern));
+    resp.push_int(num_subs as u64);
+    resp
+}
+
 /// Creates a message informing the client about a new message on a channel that
-/// the client subscribes to.
-fn make_message_frame(chan_name: String, msg: Bytes) -> Frame {
+/// the client subscribes to. Logged messages carry their offset last.
+fn make_message_frame(chan_name: String, msg: Message) -> Frame {
     let mut resp = Frame::array();
 
     resp.push_bulk(Bytes::from_static(b"message"));
     resp.push_bulk(Bytes::from(chan_name));
+    resp.push_bulk(msg.payload);
+    if let Some(offset) = msg.offset {
+        resp.push_int(offset);
+    }
+    resp
+}
+
+/// Creates the response to a ssubscribe request.
+fn make_ssubscribe_frame(chan_name: String, num_subs: usize) -> Frame {
+    let mut resp = Frame::array();
+    resp.push_bulk(Bytes::from_static(b"ssubscribe"));
+    resp.push_bulk(Bytes::from(chan_name));
+    resp.push_int(num_subs as u64);
+    resp
+}
+
+/// Creates the response to a sunsubscribe request.
+fn make_sunsubscribe_frame(chan_name: String, num_subs: usize) -> Frame {
+    let mut resp = Frame::array();
+    resp.push_bulk(Bytes::from_static(b"sunsubscribe"));
+    resp.push_bulk(Bytes::from(chan_name));
+    resp.push_int(num_subs as u64);
+    resp
+}
+
+/// Creates a message informing the client about a new message on a shard
+/// channel that the client subscribes to.
+fn make_smessage_frame(chan_name: String, msg: Bytes) -> Frame {
+    let mut resp = Frame::array();
+
+    resp.push_bulk(Bytes::from_static(b"smessage"));
+    resp.push_bulk(Bytes::from(chan_name));
     resp.push_bulk(msg);
     resp
 }
 
+/// Creates a message informing the client that it fell behind a channel or
+/// pattern it subscribes to, and that `dropped` messages were lost.
+fn make_lag_frame(name: String, dropped: u64) -> Frame {
+    let mut resp = Frame::array();
+
+    resp.push_bulk(Bytes::from_static(b"lag"));
+    resp.push_bulk(Bytes::from(name));
+    resp.push_int(dropped);
+    resp
+}
+
+/// Creates a message informing the client about a new message on a channel
+/// matching a pattern that the client subscribes to.
+fn make_pmessage_frame(pattern: String, chan_name: String, msg: Bytes) -> Frame {
+    let mut resp = Frame::array();
+
+    resp.push_bulk(Bytes::from_static(b"pmessage"));
+    resp.push_bulk(Bytes::from(pattern));
+    resp.push_bulk(Bytes::from(chan_name));
+    resp.push_bulk(msg);
+    resp
+}
+
+impl Psubscribe {
+    /// Create a new `Psubscribe` command with the given `patterns`.
+    pub fn new(patterns: Vec<String>) -> Self {
+        Psubscribe { patterns }
+    }
+
+    /// Parse a `Psubscribe` instance from a received frame.
+    ///
+    /// The `PSUBSCRIBE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// PSUBSCRIBE pattern [pattern ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Psubscribe> {
+        let mut patterns = vec![parse.next_string()?];
+
+        loop {
+            match parse.next_string() {
+                Ok(s) => patterns.push(s),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Psubscribe { patterns })
+    }
+
+    /// Apply the `Psubscribe` command to the specified `Db` instance.
+    ///
+    /// This enters the subscribed state, see `Subscribe::apply`.
+    pub(crate) async fn apply(
+        self,
+        db: &Db,
+        dst: &mut Connection,
+        shutdown: &mut Shutdown,
+    ) -> crate::Result<()> {
+        let subscribe = Subscribe {
+            channels: vec![],
+            offsets: HashMap::new(),
+            patterns: self.patterns,
+            shard_channels: vec![],
+        };
+
+        subscribe.apply(db, dst, shutdown).await
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("psubscribe".as_bytes()));
+        for pattern in self.patterns {
+            frame.push_bulk(Bytes::from(pattern.into_bytes()));
+        }
+        frame
+    }
+}
+
+impl Ssubscribe {
+    /// Create a new `Ssubscribe` command with the given `shard_channels`.
+    pub fn new(shard_channels: Vec<String>) -> Self {
+        Ssubscribe { shard_channels }
+    }
+
+    /// Parse a `Ssubscribe` instance from a received frame.
+    ///
+    /// The `SSUBSCRIBE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SSUBSCRIBE shardchannel [shardchannel ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Ssubscribe> {
+        let mut shard_channels = vec![parse.next_string()?];
+
+        loop {
+            match parse.next_string() {
+                Ok(s) => shard_channels.push(s),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Ssubscribe { shard_channels })
+    }
+
+    /// Apply the `Ssubscribe` command to the specified `Db` instance.
+    ///
+    /// This enters the subscribed state, see `Subscribe::apply`.
+    pub(crate) async fn apply(
+        self,
+        db: &Db,
+        dst: &mut Connection,
+        shutdown: &mut Shutdown,
+    ) -> crate::Result<()> {
+        let subscribe = Subscribe {
+            channels: vec![],
+            offsets: HashMap::new(),
+            patterns: vec![],
+            shard_channels: self.shard_channels,
+        };
+
+        subscribe.apply(db, dst, shutdown).await
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ssubscribe".as_bytes()));
+        for channel in self.shard_channels {
+            frame.push_bulk(Bytes::from(channel.into_bytes()));
+        }
+        frame
+    }
+}
+
 impl Unsubscribe {
     /// Create a new `Unsubscribe` command with the given `channels`.
-    pub(crate) fn new(channels: &[String]) -> Unsubscribe {
+    pub fn new(channels: &[String]) -> Unsubscribe {
         Unsubscribe {
             channels: channels.to_vec(),
         }
@@ -335,7 +809,7 @@ impl Unsubscribe {
     ///
     /// This is called by the client when encoding an `Unsubscribe` command to
     /// send to the server.
-    pub(crate) fn into_frame(self) -> Frame {
+    pub fn into_frame(self) -> Frame {
         let mut frame = Frame::array();
         frame.push_bulk(Bytes::from("unsubscribe".as_bytes()));
 
@@ -346,3 +820,91 @@ impl Unsubscribe {
         frame
     }
 }
+
+impl Punsubscribe {
+    /// Create a new `Punsubscribe` command with the given `patterns`.
+    pub fn new(patterns: &[String]) -> Punsubscribe {
+        Punsubscribe {
+            patterns: patterns.to_vec(),
+        }
+    }
+
+    /// Parse a `Punsubscribe` instance from a received frame.
+    ///
+    /// The `PUNSUBSCRIBE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// PUNSUBSCRIBE [pattern [pattern ...]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Punsubscribe, ParseError> {
+        let mut patterns = vec![];
+
+        loop {
+            match parse.next_string() {
+                Ok(s) => patterns.push(s),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Punsubscribe { patterns })
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("punsubscribe".as_bytes()));
+
+        for pattern in self.patterns {
+            frame.push_bulk(Bytes::from(pattern.into_bytes()));
+        }
+
+        frame
+    }
+}
+
+impl Sunsubscribe {
+    /// Create a new `Sunsubscribe` command with the given `shard_channels`.
+    pub fn new(shard_channels: &[String]) -> Sunsubscribe {
+        Sunsubscribe {
+            shard_channels: shard_channels.to_vec(),
+        }
+    }
+
+    /// Parse a `Sunsubscribe` instance from a received frame.
+    ///
+    /// The `SUNSUBSCRIBE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// SUNSUBSCRIBE [shardchannel [shardchannel ...]]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> Result<Sunsubscribe, ParseError> {
+        let mut shard_channels = vec![];
+
+        loop {
+            match parse.next_string() {
+                Ok(s) => shard_channels.push(s),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Sunsubscribe { shard_channels })
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("sunsubscribe".as_bytes()));
+
+        for channel in self.shard_channels {
+            frame.push_bulk(Bytes::from(channel.into_bytes()));
+        }
+
+        frame
+    }
+}
@@ -0,0 +1,840 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::db::{Aggregation, CompactionRule, LabelFilter, TimeSeries};
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Create an empty time series.
+///
+/// # Options
+///
+/// * RETENTION `milliseconds` -- Trim samples older than this, measured from
+///   the newest sample. `0`, the default, keeps samples forever.
+/// * LABELS `label value ...` -- Labels used by `TS.MRANGE` to select series.
+#[derive(Debug, Clone)]
+pub struct TsCreate {
+    key: String,
+    retention: u64,
+    labels: Vec<(String, String)>,
+}
+
+/// Append a sample to a time series, creating it if it does not exist.
+///
+/// The `RETENTION` and `LABELS` options of `TS.CREATE` apply when the series
+/// is created.
+#[derive(Debug, Clone)]
+pub struct TsAdd {
+    key: String,
+    /// Timestamp in milliseconds since the Unix epoch. `None` stands for `*`,
+    /// the server's current time.
+    timestamp: Option<u64>,
+    value: f64,
+    retention: u64,
+    labels: Vec<(String, String)>,
+}
+
+/// Append samples to one or more existing time series.
+#[derive(Debug, Clone)]
+pub struct TsMadd {
+    /// `(key, timestamp, value)`. A `None` timestamp stands for `*`.
+    samples: Vec<(String, Option<u64>, f64)>,
+}
+
+/// Query a range of samples from a time series, optionally aggregated into
+/// buckets.
+///
+/// This implements both `TS.RANGE` and `TS.REVRANGE`, which returns the
+/// newest samples first.
+#[derive(Debug)]
+pub struct TsRange {
+    key: String,
+    from: u64,
+    to: u64,
+    options: RangeOptions,
+}
+
+/// Query a range of samples from every time series matching a label filter.
+///
+/// This implements both `TS.MRANGE` and `TS.MREVRANGE`.
+#[derive(Debug)]
+pub struct TsMrange {
+    from: u64,
+    to: u64,
+    options: RangeOptions,
+    filters: Vec<LabelFilter>,
+}
+
+/// Downsample a time series into another one.
+///
+/// Whenever a bucket of the source series completes, its aggregate is added
+/// to the destination series at the bucket's start time.
+#[derive(Debug, Clone)]
+pub struct TsCreateRule {
+    src: String,
+    dst: String,
+    aggregation: Aggregation,
+    bucket: u64,
+}
+
+/// Remove a compaction rule.
+#[derive(Debug, Clone)]
+pub struct TsDeleteRule {
+    src: String,
+    dst: String,
+}
+
+/// Options shared by the range queries.
+#[derive(Debug, Default)]
+struct RangeOptions {
+    /// Return the newest samples first.
+    rev: bool,
+    /// Maximum number of samples returned per series.
+    count: Option<u64>,
+    /// Aggregation and bucket duration in milliseconds.
+    aggregation: Option<(Aggregation, u64)>,
+}
+
+impl TsCreate {
+    /// Create a new `TsCreate` command.
+    pub fn new(key: impl ToString, retention: u64, labels: Vec<(String, String)>) -> Self {
+        TsCreate {
+            key: key.to_string(),
+            retention,
+            labels,
+        }
+    }
+
+    /// Parse a `TsCreate` instance from a received frame.
+    ///
+    /// The `TS.CREATE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.CREATE key [RETENTION milliseconds] [LABELS label value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TsCreate> {
+        let key = parse.next_string()?;
+        let (retention, labels) = parse_create_options(parse)?;
+
+        Ok(TsCreate {
+            key,
+            retention,
+            labels,
+        })
+    }
+
+    /// Apply the `TsCreate` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let series = TimeSeries::new(self.retention, self.labels);
+
+        let resp = match db.ts_create(self.key, series) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ts.create".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        push_create_options(&mut frame, self.retention, self.labels);
+        frame
+    }
+}
+
+impl TsAdd {
+    /// Create a new `TsAdd` command. A `None` timestamp uses the server's
+    /// current time.
+    pub fn new(key: impl ToString, timestamp: Option<u64>, value: f64) -> Self {
+        TsAdd {
+            key: key.to_string(),
+            timestamp,
+            value,
+            retention: 0,
+            labels: vec![],
+        }
+    }
+
+    /// Parse a `TsAdd` instance from a received frame.
+    ///
+    /// The `TS.ADD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.ADD key timestamp|* value [RETENTION milliseconds] [LABELS label value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TsAdd> {
+        let key = parse.next_string()?;
+        let timestamp = parse_sample_timestamp(parse)?;
+        let value = parse_sample_value(parse)?;
+        let (retention, labels) = parse_create_options(parse)?;
+
+        Ok(TsAdd {
+            key,
+            timestamp,
+            value,
+            retention,
+            labels,
+        })
+    }
+
+    /// Apply the `TsAdd` command to the specified `Db` instance.
+    ///
+    /// Responds with the timestamp of the added sample.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let timestamp = self.timestamp.unwrap_or_else(now_millis);
+        let series = TimeSeries::new(self.retention, self.labels);
+
+        let mut results = db.ts_add(vec![(self.key, timestamp, self.value)], Some(series));
+
+        let resp = match results.remove(0) {
+            Ok(timestamp) => Frame::Int(timestamp),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Replace a `*` timestamp by the current time, so that the command has
+    /// the same effect when replayed later.
+    pub(crate) fn make_absolute(&mut self) {
+        self.timestamp.get_or_insert_with(now_millis);
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ts.add".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        push_sample_timestamp(&mut frame, self.timestamp);
+        frame.push_bulk(Bytes::from(self.value.to_string()));
+        push_create_options(&mut frame, self.retention, self.labels);
+        frame
+    }
+}
+
+impl TsMadd {
+    /// Create a new `TsMadd` command. A `None` timestamp uses the server's
+    /// current time.
+    pub fn new(samples: Vec<(String, Option<u64>, f64)>) -> Self {
+        TsMadd { samples }
+    }
+
+    /// Parse a `TsMadd` instance from a received frame.
+    ///
+    /// The `TS.MADD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.MADD key timestamp|* value [key timestamp|* value ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TsMadd> {
+        let mut samples = vec![];
+
+        loop {
+            let key = match parse.next_string() {
+                Ok(key) => key,
+                Err(ParseError::EndOfStream) if !samples.is_empty() => break,
+                Err(err) => return Err(err.into()),
+            };
+
+            let timestamp = parse_sample_timestamp(parse)?;
+            let value = parse_sample_value(parse)?;
+            samples.push((key, timestamp, value));
+        }
+
+        Ok(TsMadd { samples })
+    }
+
+    /// Apply the `TsMadd` command to the specified `Db` instance.
+    ///
+    /// Responds with an array holding, for each sample, its timestamp or an
+    /// error.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let now = now_millis();
+        let samples = self
+            .samples
+            .into_iter()
+            .map(|(key, timestamp, value)| (key, timestamp.unwrap_or(now), value))
+            .collect();
+
+        let resp = Frame::Array(
+            db.ts_add(samples, None)
+                .into_iter()
+                .map(|res| match res {
+                    Ok(timestamp) => Frame::Int(timestamp),
+                    Err(err) => Frame::Error(err.to_string()),
+                })
+                .collect(),
+        );
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Replace the `*` timestamps by the current time, so that the command
+    /// has the same effect when replayed later.
+    pub(crate) fn make_absolute(&mut self) {
+        let now = now_millis();
+        for (_, timestamp, _) in &mut self.samples {
+            timestamp.get_or_insert(now);
+        }
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ts.madd".as_bytes()));
+        for (key, timestamp, value) in self.samples {
+            frame.push_bulk(Bytes::from(key.into_bytes()));
+            push_sample_timestamp(&mut frame, timestamp);
+            frame.push_bulk(Bytes::from(value.to_string()));
+        }
+        frame
+    }
+}
+
+impl TsRange {
+    /// Create a new `TsRange` command querying samples between `from` and
+    /// `to`, inclusive. `rev` selects `TS.REVRANGE`.
+    pub fn new(key: impl ToString, from: u64, to: u64, rev: bool) -> Self {
+        TsRange {
+            key: key.to_string(),
+            from,
+            to,
+            options: RangeOptions {
+                rev,
+                ..RangeOptions::default()
+            },
+        }
+    }
+
+    /// Returns the command name
+    pub(crate) fn get_name(&self) -> &str {
+        if self.options.rev {
+            "ts.revrange"
+        } else {
+            "ts.range"
+        }
+    }
+
+    /// Parse a `TsRange` instance from a received frame.
+    ///
+    /// The `TS.RANGE` or `TS.REVRANGE` string has already been consumed. `rev`
+    /// tells which.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.RANGE key from|- to|+ [COUNT count] [AGGREGATION aggregator bucket]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse, rev: bool) -> crate::Result<TsRange> {
+        let key = parse.next_string()?;
+        let from = parse_range_timestamp(parse)?;
+        let to = parse_range_timestamp(parse)?;
+
+        let mut options = RangeOptions {
+            rev,
+            ..RangeOptions::default()
+        };
+
+        loop {
+            match parse.next_string() {
+                Ok(s) if options.parse(&s, parse)? => {}
+                Ok(s) => return Err(format!("ERR TSDB: unknown option `{}`", s).into()),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(TsRange {
+            key,
+            from,
+            to,
+            options,
+        })
+    }
+
+    /// Apply the `TsRange` command to the specified `Db` instance.
+    ///
+    /// Responds with an array of `[timestamp, value]` pairs.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.ts_range(
+            &self.key,
+            self.from,
+            self.to,
+            self.options.aggregation,
+            self.options.rev,
+            self.options.count.map(|count| count as usize),
+        ) {
+            Ok(samples) => make_samples_frame(samples),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from(self.get_name().to_string()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_int(self.from);
+        frame.push_int(self.to);
+        self.options.push(&mut frame);
+        frame
+    }
+}
+
+impl TsMrange {
+    /// Create a new `TsMrange` command querying samples between `from` and
+    /// `to`, inclusive, of every series matching `filters`. `rev` selects
+    /// `TS.MREVRANGE`.
+    pub fn new(from: u64, to: u64, filters: Vec<LabelFilter>, rev: bool) -> Self {
+        TsMrange {
+            from,
+            to,
+            options: RangeOptions {
+                rev,
+                ..RangeOptions::default()
+            },
+            filters,
+        }
+    }
+
+    /// Returns the command name
+    pub(crate) fn get_name(&self) -> &str {
+        if self.options.rev {
+            "ts.mrevrange"
+        } else {
+            "ts.mrange"
+        }
+    }
+
+    /// Parse a `TsMrange` instance from a received frame.
+    ///
+    /// The `TS.MRANGE` or `TS.MREVRANGE` string has already been consumed.
+    /// `rev` tells which.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.MRANGE from|- to|+ [COUNT count] [AGGREGATION aggregator bucket] FILTER filter ...
+    /// ```
+    ///
+    /// Each filter is one of `label=value`, `label!=value`, `label=` (the
+    /// label is not set), `label!=` (the label is set) or `label=(a,b,...)`.
+    pub(crate) fn parse_frames(parse: &mut Parse, rev: bool) -> crate::Result<TsMrange> {
+        let from = parse_range_timestamp(parse)?;
+        let to = parse_range_timestamp(parse)?;
+
+        let mut options = RangeOptions {
+            rev,
+            ..RangeOptions::default()
+        };
+
+        loop {
+            match parse.next_string()? {
+                s if s.to_uppercase() == "FILTER" => break,
+                s if options.parse(&s, parse)? => {}
+                s => return Err(format!("ERR TSDB: unknown option `{}`", s).into()),
+            }
+        }
+
+        // The filters take up the rest of the frame.
+        let mut filters = vec![];
+        loop {
+            match parse.next_string() {
+                Ok(s) => filters.push(parse_label_filter(&s)?),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // Like Redis, require at least one filter that selects series by a
+        // label value. Filters that only exclude series would match every
+        // series in the database.
+        let selects = filters.iter().any(|filter| match filter {
+            LabelFilter::Eq(_, values) => values.iter().any(|value| !value.is_empty()),
+            LabelFilter::NotEq(..) => false,
+        });
+
+        if !selects {
+            return Err("ERR TSDB: please provide at least one matcher".into());
+        }
+
+        Ok(TsMrange {
+            from,
+            to,
+            options,
+            filters,
+        })
+    }
+
+    /// Apply the `TsMrange` command to the specified `Db` instance.
+    ///
+    /// Responds with one `[key, labels, samples]` array per matching series.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let series = db.ts_mrange(
+            self.from,
+            self.to,
+            self.options.aggregation,
+            &self.filters,
+            self.options.rev,
+            self.options.count.map(|count| count as usize),
+        );
+
+        let resp = Frame::Array(
+            series
+                .into_iter()
+                .map(|(key, labels, samples)| {
+                    let labels = labels
+                        .into_iter()
+                        .map(|(label, value)| {
+                            Frame::Array(vec![
+                                Frame::Bulk(Bytes::from(label.into_bytes())),
+                                Frame::Bulk(Bytes::from(value.into_bytes())),
+                            ])
+                        })
+                        .collect();
+
+                    Frame::Array(vec![
+                        Frame::Bulk(Bytes::from(key.into_bytes())),
+                        Frame::Array(labels),
+                        make_samples_frame(samples),
+                    ])
+                })
+                .collect(),
+        );
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from(self.get_name().to_string()));
+        frame.push_int(self.from);
+        frame.push_int(self.to);
+        self.options.push(&mut frame);
+
+        frame.push_bulk(Bytes::from("filter".as_bytes()));
+        for filter in self.filters {
+            let (label, op, values) = match filter {
+                LabelFilter::Eq(label, values) => (label, "=", values),
+                LabelFilter::NotEq(label, values) => (label, "!=", values),
+            };
+
+            let values = match &values[..] {
+                [value] => value.clone(),
+                values => format!("({})", values.join(",")),
+            };
+
+            frame.push_bulk(Bytes::from(format!("{}{}{}", label, op, values)));
+        }
+        frame
+    }
+}
+
+impl TsCreateRule {
+    /// Create a new `TsCreateRule` command.
+    pub fn new(
+        src: impl ToString,
+        dst: impl ToString,
+        aggregation: Aggregation,
+        bucket: u64,
+    ) -> Self {
+        TsCreateRule {
+            src: src.to_string(),
+            dst: dst.to_string(),
+            aggregation,
+            bucket,
+        }
+    }
+
+    /// Parse a `TsCreateRule` instance from a received frame.
+    ///
+    /// The `TS.CREATERULE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.CREATERULE src dst AGGREGATION aggregator bucket
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TsCreateRule> {
+        let src = parse.next_string()?;
+        let dst = parse.next_string()?;
+
+        if parse.next_string()?.to_uppercase() != "AGGREGATION" {
+            return Err("ERR TSDB: expected AGGREGATION".into());
+        }
+
+        let (aggregation, bucket) = parse_aggregation(parse)?;
+
+        Ok(TsCreateRule {
+            src,
+            dst,
+            aggregation,
+            bucket,
+        })
+    }
+
+    /// Apply the `TsCreateRule` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let rule = CompactionRule {
+            dst: self.dst,
+            aggregation: self.aggregation,
+            bucket: self.bucket,
+        };
+
+        let resp = match db.ts_create_rule(&self.src, rule) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ts.createrule".as_bytes()));
+        frame.push_bulk(Bytes::from(self.src.into_bytes()));
+        frame.push_bulk(Bytes::from(self.dst.into_bytes()));
+        frame.push_bulk(Bytes::from("aggregation".as_bytes()));
+        frame.push_bulk(Bytes::from(self.aggregation.name().as_bytes()));
+        frame.push_int(self.bucket);
+        frame
+    }
+}
+
+impl TsDeleteRule {
+    /// Create a new `TsDeleteRule` command.
+    pub fn new(src: impl ToString, dst: impl ToString) -> Self {
+        TsDeleteRule {
+            src: src.to_string(),
+            dst: dst.to_string(),
+        }
+    }
+
+    /// Parse a `TsDeleteRule` instance from a received frame.
+    ///
+    /// The `TS.DELETERULE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TS.DELETERULE src dst
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TsDeleteRule> {
+        let src = parse.next_string()?;
+        let dst = parse.next_string()?;
+
+        Ok(TsDeleteRule { src, dst })
+    }
+
+    /// Apply the `TsDeleteRule` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.ts_delete_rule(&self.src, &self.dst) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("ts.deleterule".as_bytes()));
+        frame.push_bulk(Bytes::from(self.src.into_bytes()));
+        frame.push_bulk(Bytes::from(self.dst.into_bytes()));
+        frame
+    }
+}
+
+impl RangeOptions {
+    /// Parse the option named `name`. Returns `false` if `name` is not a range
+    /// option.
+    fn parse(&mut self, name: &str, parse: &mut Parse) -> crate::Result<bool> {
+        match &name.to_uppercase()[..] {
+            "COUNT" => self.count = Some(parse.next_int()?),
+            "AGGREGATION" => self.aggregation = Some(parse_aggregation(parse)?),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn push(self, frame: &mut Frame) {
+        if let Some(count) = self.count {
+            frame.push_bulk(Bytes::from("count".as_bytes()));
+            frame.push_int(count);
+        }
+
+        if let Some((aggregation, bucket)) = self.aggregation {
+            frame.push_bulk(Bytes::from("aggregation".as_bytes()));
+            frame.push_bulk(Bytes::from(aggregation.name().as_bytes()));
+            frame.push_int(bucket);
+        }
+    }
+}
+
+/// Parse the `RETENTION` and `LABELS` options. `LABELS` takes up the rest of
+/// the frame.
+fn parse_create_options(parse: &mut Parse) -> crate::Result<(u64, Vec<(String, String)>)> {
+    let mut retention = 0;
+    let mut labels = vec![];
+
+    loop {
+        match parse.next_string() {
+            Ok(s) if s.to_uppercase() == "RETENTION" => retention = parse.next_int()?,
+            Ok(s) if s.to_uppercase() == "LABELS" => loop {
+                match parse.next_string() {
+                    Ok(label) => labels.push((label, parse.next_string()?)),
+                    Err(ParseError::EndOfStream) => break,
+                    Err(err) => return Err(err.into()),
+                }
+            },
+            Ok(s) => return Err(format!("ERR TSDB: unknown option `{}`", s).into()),
+            Err(ParseError::EndOfStream) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok((retention, labels))
+}
+
+fn push_create_options(frame: &mut Frame, retention: u64, labels: Vec<(String, String)>) {
+    frame.push_bulk(Bytes::from("retention".as_bytes()));
+    frame.push_int(retention);
+
+    if !labels.is_empty() {
+        frame.push_bulk(Bytes::from("labels".as_bytes()));
+        for (label, value) in labels {
+            frame.push_bulk(Bytes::from(label.into_bytes()));
+            frame.push_bulk(Bytes::from(value.into_bytes()));
+        }
+    }
+}
+
+/// Parse `aggregator bucket`.
+fn parse_aggregation(parse: &mut Parse) -> crate::Result<(Aggregation, u64)> {
+    let name = parse.next_string()?;
+    let aggregation = Aggregation::parse(&name)
+        .ok_or_else(|| format!("ERR TSDB: unknown aggregation type `{}`", name))?;
+
+    let bucket = parse.next_int()?;
+    if bucket == 0 {
+        return Err("ERR TSDB: bucket duration must be positive".into());
+    }
+
+    Ok((aggregation, bucket))
+}
+
+/// Parse a sample timestamp, where `*` stands for the current time.
+fn parse_sample_timestamp(parse: &mut Parse) -> crate::Result<Option<u64>> {
+    let timestamp = parse.next_string()?;
+
+    if timestamp == "*" {
+        return Ok(None);
+    }
+
+    timestamp
+        .parse()
+        .map(Some)
+        .map_err(|_| "ERR TSDB: invalid timestamp".into())
+}
+
+/// Parse a sample value. NaN is not a value.
+fn parse_sample_value(parse: &mut Parse) -> crate::Result<f64> {
+    match parse.next_float()? {
+        value if value.is_nan() => Err("ERR TSDB: invalid value".into()),
+        value => Ok(value),
+    }
+}
+
+fn push_sample_timestamp(frame: &mut Frame, timestamp: Option<u64>) {
+    match timestamp {
+        Some(timestamp) => frame.push_int(timestamp),
+        None => frame.push_bulk(Bytes::from("*".as_bytes())),
+    }
+}
+
+/// Parse a range bound, where `-` and `+` stand for the oldest and newest
+/// possible timestamps.
+fn parse_range_timestamp(parse: &mut Parse) -> crate::Result<u64> {
+    match &parse.next_string()?[..] {
+        "-" => Ok(0),
+        "+" => Ok(u64::MAX),
+        timestamp => timestamp
+            .parse()
+            .map_err(|_| "ERR TSDB: invalid timestamp".into()),
+    }
+}
+
+fn parse_label_filter(filter: &str) -> crate::Result<LabelFilter> {
+    let (label, values, negated) = match filter.split_once("!=") {
+        Some((label, values)) => (label, values, true),
+        None => match filter.split_once('=') {
+            Some((label, values)) => (label, values, false),
+            None => return Err(format!("ERR TSDB: invalid filter `{}`", filter).into()),
+        },
+    };
+
+    let values = match values.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+        Some(list) => list.split(',').map(|value| value.to_string()).collect(),
+        None => vec![values.to_string()],
+    };
+
+    let label = label.to_string();
+    Ok(if negated {
+        LabelFilter::NotEq(label, values)
+    } else {
+        LabelFilter::Eq(label, values)
+    })
+}
+
+/// Creates an array of `[timestamp, value]` pairs.
+fn make_samples_frame(samples: Vec<(u64, f64)>) -> Frame {
+    Frame::Array(
+        samples
+            .into_iter()
+            .map(|(timestamp, value)| {
+                Frame::Array(vec![
+                    Frame::Int(timestamp),
+                    Frame::Bulk(Bytes::from(value.to_string())),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
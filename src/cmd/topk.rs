@@ -0,0 +1,306 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use super::{make_bool_array, parse_items};
+use crate::db::{topk, TopK};
+use crate::{Frame, LockedDb, Parse, ParseError};
+
+/// Create an empty Top-K list tracking the `k` most frequent items.
+///
+/// `width` and `depth` size the HeavyKeeper counters behind the list and
+/// `decay` controls how quickly infrequent items lose their counters.
+#[derive(Debug, Clone)]
+pub struct TopkReserve {
+    key: String,
+    k: u64,
+    width: u64,
+    depth: u64,
+    decay: f64,
+}
+
+/// Count one occurrence of each of the given items in a Top-K list.
+#[derive(Debug, Clone)]
+pub struct TopkAdd {
+    key: String,
+    items: Vec<Bytes>,
+}
+
+/// Check whether items are currently in a Top-K list.
+#[derive(Debug)]
+pub struct TopkQuery {
+    key: String,
+    items: Vec<Bytes>,
+}
+
+/// Return the items of a Top-K list, most frequent first.
+#[derive(Debug)]
+pub struct TopkList {
+    key: String,
+    /// When `true`, each item is followed by its estimated count.
+    with_count: bool,
+}
+
+impl TopkReserve {
+    /// Create a new `TopkReserve` command.
+    pub fn new(key: impl ToString, k: u64, width: u64, depth: u64, decay: f64) -> Self {
+        TopkReserve {
+            key: key.to_string(),
+            k,
+            width,
+            depth,
+            decay,
+        }
+    }
+
+    /// Parse a `TopkReserve` instance from a received frame.
+    ///
+    /// The `TOPK.RESERVE` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TOPK.RESERVE key topk [width depth decay]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TopkReserve> {
+        let key = parse.next_string()?;
+        let k = parse.next_int()?;
+
+        if k == 0 {
+            return Err("ERR TopK: invalid k".into());
+        }
+
+        let (width, depth, decay) = match parse.next_int() {
+            Ok(width) => (width, parse.next_int()?, parse.next_float()?),
+            Err(ParseError::EndOfStream) => (
+                topk::DEFAULT_WIDTH as u64,
+                topk::DEFAULT_DEPTH as u64,
+                topk::DEFAULT_DECAY,
+            ),
+            Err(err) => return Err(err.into()),
+        };
+
+        if width == 0 || depth == 0 {
+            return Err("ERR TopK: invalid width/depth".into());
+        }
+        if !(decay > 0.0 && decay <= 1.0) {
+            return Err("ERR TopK: decay must be in (0, 1]".into());
+        }
+        if !TopK::fits(k, width, depth) {
+            return Err("ERR TopK: structure too large".into());
+        }
+
+        Ok(TopkReserve {
+            key,
+            k,
+            width,
+            depth,
+            decay,
+        })
+    }
+
+    /// Apply the `TopkReserve` command to the specified `Db` instance.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let topk = TopK::new(
+            self.k as usize,
+            self.width as usize,
+            self.depth as usize,
+            self.decay,
+        );
+
+        let resp = match db.topk_reserve(self.key, topk) {
+            Ok(()) => Frame::Simple("OK".to_string()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("topk.reserve".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        frame.push_int(self.k);
+        frame.push_int(self.width);
+        frame.push_int(self.depth);
+        frame.push_bulk(Bytes::from(self.decay.to_string()));
+        frame
+    }
+}
+
+impl TopkAdd {
+    /// Create a new `TopkAdd` command.
+    pub fn new(key: impl ToString, items: Vec<Bytes>) -> Self {
+        TopkAdd {
+            key: key.to_string(),
+            items,
+        }
+    }
+
+    /// Parse a `TopkAdd` instance from a received frame.
+    ///
+    /// The `TOPK.ADD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TOPK.ADD key item [item ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TopkAdd> {
+        let key = parse.next_string()?;
+        let items = parse_items(parse)?;
+
+        Ok(TopkAdd { key, items })
+    }
+
+    /// Apply the `TopkAdd` command to the specified `Db` instance.
+    ///
+    /// Responds with an array holding, for each item, the item it expelled
+    /// from the list or `nil`.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.topk_add(&self.key, &self.items) {
+            Ok(expelled) => Frame::Array(
+                expelled
+                    .into_iter()
+                    .map(|item| item.map(Frame::Bulk).unwrap_or(Frame::Null))
+                    .collect(),
+            ),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("topk.add".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for item in self.items {
+            frame.push_bulk(item);
+        }
+        frame
+    }
+}
+
+impl TopkQuery {
+    /// Create a new `TopkQuery` command.
+    pub fn new(key: impl ToString, items: Vec<Bytes>) -> Self {
+        TopkQuery {
+            key: key.to_string(),
+            items,
+        }
+    }
+
+    /// Parse a `TopkQuery` instance from a received frame.
+    ///
+    /// The `TOPK.QUERY` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TOPK.QUERY key item [item ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TopkQuery> {
+        let key = parse.next_string()?;
+        let items = parse_items(parse)?;
+
+        Ok(TopkQuery { key, items })
+    }
+
+    /// Apply the `TopkQuery` command to the specified `Db` instance.
+    ///
+    /// Responds with `1` for each item in the list and `0` otherwise.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.topk_query(&self.key, &self.items) {
+            Ok(found) => make_bool_array(found),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("topk.query".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        for item in self.items {
+            frame.push_bulk(item);
+        }
+        frame
+    }
+}
+
+impl TopkList {
+    /// Create a new `TopkList` command.
+    pub fn new(key: impl ToString, with_count: bool) -> Self {
+        TopkList {
+            key: key.to_string(),
+            with_count,
+        }
+    }
+
+    /// Parse a `TopkList` instance from a received frame.
+    ///
+    /// The `TOPK.LIST` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// TOPK.LIST key [WITHCOUNT]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<TopkList> {
+        let key = parse.next_string()?;
+
+        let with_count = match parse.next_string() {
+            Ok(s) if s.to_uppercase() == "WITHCOUNT" => true,
+            Ok(s) => return Err(format!("ERR unknown `TOPK.LIST` option `{}`", s).into()),
+            Err(ParseError::EndOfStream) => false,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(TopkList { key, with_count })
+    }
+
+    /// Apply the `TopkList` command to the specified `Db` instance.
+    ///
+    /// Responds with the items in the list. With `WITHCOUNT`, each item is
+    /// followed by its estimated count.
+    #[instrument(skip(self, db))]
+    pub(crate) fn execute(self, db: &mut LockedDb) -> Frame {
+        let resp = match db.topk_list(&self.key) {
+            Ok(list) => {
+                let mut resp = Frame::array();
+                for (item, count) in list {
+                    resp.push_bulk(item);
+                    if self.with_count {
+                        resp.push_int(count);
+                    }
+                }
+                resp
+            }
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("topk.list".as_bytes()));
+        frame.push_bulk(Bytes::from(self.key.into_bytes()));
+        if self.with_count {
+            frame.push_bulk(Bytes::from("withcount".as_bytes()));
+        }
+        frame
+    }
+}
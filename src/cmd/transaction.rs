@@ -0,0 +1,446 @@
+use bytes::Bytes;
+use tracing::{debug, instrument};
+
+use crate::cmd::{busy_reply, error_reply, Command, ParseError};
+use crate::db::LockedDb;
+use crate::{Connection, Db, Frame, Parse, WatchedKeys};
+
+/// Start a transaction.
+///
+/// The commands that follow are queued instead of being executed, until
+/// `EXEC` executes them all at once or `DISCARD` drops them.
+#[derive(Debug, Default)]
+pub struct Multi {}
+
+/// Execute the commands queued since `MULTI`.
+///
+/// The commands are executed atomically, no other client observes the state
+/// between two of them. The reply is an array of their replies.
+#[derive(Debug, Default)]
+pub struct Exec {}
+
+/// Drop the commands queued since `MULTI` and leave the transaction.
+#[derive(Debug, Default)]
+pub struct Discard {}
+
+/// Watch keys for the next transaction.
+///
+/// `EXEC` replies with a null and executes nothing if one of the keys was
+/// modified, deleted or expired since it was watched. Keys are watched until
+/// the transaction completes.
+#[derive(Debug)]
+pub struct Watch {
+    keys: Vec<String>,
+}
+
+/// Stop watching all keys.
+#[derive(Debug, Default)]
+pub struct Unwatch {}
+
+/// The commands a connection queued since `MULTI`.
+#[derive(Debug, Default)]
+pub(crate) struct Transaction {
+    queued: Vec<Command>,
+    /// Set when a command is rejected while queuing. `EXEC` then fails
+    /// without executing any command.
+    aborted: bool,
+    /// The keys of the queued commands, in cluster mode. `EXEC` fails unless
+    /// this node serves all of them, with `CROSSSLOT` if they span several
+    /// slots.
+    keys: Vec<Bytes>,
+    /// Set if a command was queued following `ASKING`, which then applies to
+    /// the whole transaction.
+    asking: bool,
+}
+
+impl Multi {
+    /// Create a new `Multi` command.
+    pub fn new() -> Multi {
+        Multi {}
+    }
+
+    /// Parse a `Multi` instance from a received frame.
+    ///
+    /// The `MULTI` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// MULTI
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Multi> {
+        Ok(Multi {})
+    }
+
+    /// Apply the `Multi` command, starting `transaction`.
+    #[instrument(skip(self, dst, transaction))]
+    pub(crate) async fn apply(
+        self,
+        dst: &mut Connection,
+        transaction: &mut Option<Transaction>,
+    ) -> crate::Result<()> {
+        let resp = match transaction {
+            Some(_) => Frame::Error("ERR MULTI calls can not be nested".to_string()),
+            None => {
+                *transaction = Some(Transaction::default());
+                Frame::Simple("OK".to_string())
+            }
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("multi".as_bytes()));
+        frame
+    }
+}
+
+impl Exec {
+    /// Create a new `Exec` command.
+    pub fn new() -> Exec {
+        Exec {}
+    }
+
+    /// Parse an `Exec` instance from a received frame.
+    ///
+    /// The `EXEC` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// EXEC
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Exec> {
+        Ok(Exec {})
+    }
+
+    /// Apply the `Exec` command, executing the commands of `transaction`
+    /// against `db` unless one of the `watched` keys was modified.
+    #[instrument(skip(self, db, dst, transaction, watched))]
+    pub(crate) async fn apply(
+        self,
+        db: &Db,
+        dst: &mut Connection,
+        transaction: &mut Option<Transaction>,
+        watched: &mut Option<WatchedKeys>,
+    ) -> crate::Result<()> {
+        // Whether or not the transaction runs, the keys are no longer watched.
+        let watched = watched.take();
+
+        let resp = match transaction.take() {
+            Some(transaction) => transaction.exec(db, watched).await?,
+            None => Frame::Error("ERR EXEC without MULTI".to_string()),
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("exec".as_bytes()));
+        frame
+    }
+}
+
+impl Discard {
+    /// Create a new `Discard` command.
+    pub fn new() -> Discard {
+        Discard {}
+    }
+
+    /// Parse a `Discard` instance from a received frame.
+    ///
+    /// The `DISCARD` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// DISCARD
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Discard> {
+        Ok(Discard {})
+    }
+
+    /// Apply the `Discard` command, dropping `transaction` and the `watched`
+    /// keys.
+    #[instrument(skip(self, dst, transaction, watched))]
+    pub(crate) async fn apply(
+        self,
+        dst: &mut Connection,
+        transaction: &mut Option<Transaction>,
+        watched: &mut Option<WatchedKeys>,
+    ) -> crate::Result<()> {
+        let resp = match transaction.take() {
+            Some(_) => {
+                *watched = None;
+                Frame::Simple("OK".to_string())
+            }
+            None => Frame::Error("ERR DISCARD without MULTI".to_string()),
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("discard".as_bytes()));
+        frame
+    }
+}
+
+impl Watch {
+    /// Create a new `Watch` command watching `keys`.
+    pub fn new(keys: Vec<String>) -> Watch {
+        Watch { keys }
+    }
+
+    /// Parse a `Watch` instance from a received frame.
+    ///
+    /// The `WATCH` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// WATCH key [key ...]
+    /// ```
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Watch> {
+        let mut keys = vec![parse.next_string()?];
+
+        loop {
+            match parse.next_string() {
+                Ok(key) => keys.push(key),
+                Err(ParseError::EndOfStream) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Watch { keys })
+    }
+
+    /// Apply the `Watch` command, adding the keys to `watched`.
+    ///
+    /// Keys cannot be watched once the transaction started.
+    #[instrument(skip(self, db, dst, transaction, watched))]
+    pub(crate) async fn apply(
+        self,
+        db: &Db,
+        dst: &mut Connection,
+        transaction: &Option<Transaction>,
+        watched: &mut Option<WatchedKeys>,
+    ) -> crate::Result<()> {
+        let resp = match transaction {
+            Some(_) => Frame::Error("ERR WATCH inside MULTI is not allowed".to_string()),
+            None => {
+                watched
+                    .get_or_insert_with(|| db.watch())
+                    .add(self.keys)
+                    .await;
+                Frame::Simple("OK".to_string())
+            }
+        };
+
+        debug!(?resp);
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("watch".as_bytes()));
+        for key in self.keys {
+            frame.push_bulk(Bytes::from(key.into_bytes()));
+        }
+        frame
+    }
+}
+
+impl Unwatch {
+    /// Create a new `Unwatch` command.
+    pub fn new() -> Unwatch {
+        Unwatch {}
+    }
+
+    /// Parse an `Unwatch` instance from a received frame.
+    ///
+    /// The `UNWATCH` string has already been consumed.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// UNWATCH
+    /// ```
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Unwatch> {
+        Ok(Unwatch {})
+    }
+
+    /// Apply the `Unwatch` command, dropping the `watched` keys.
+    #[instrument(skip(self, dst, watched))]
+    pub(crate) async fn apply(
+        self,
+        dst: &mut Connection,
+        watched: &mut Option<WatchedKeys>,
+    ) -> crate::Result<()> {
+        *watched = None;
+
+        let resp = self.execute();
+        dst.write_frame(&resp).await?;
+        Ok(())
+    }
+
+    /// Execute the `Unwatch` command as part of a transaction. The watched
+    /// keys were already checked and dropped by `EXEC`, this only replies.
+    #[instrument(skip(self))]
+    pub(crate) fn execute(self) -> Frame {
+        let resp = Frame::Simple("OK".to_string());
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("unwatch".as_bytes()));
+        frame
+    }
+}
+
+impl Transaction {
+    /// Returns `true` if `cmd` is queued during a transaction. The commands
+    /// controlling the transaction and the connection are applied at once.
+    pub(crate) fn queues(cmd: &Command) -> bool {
+        !matches!(
+            cmd,
+            Command::Multi(_)
+                | Command::Exec(_)
+                | Command::Discard(_)
+                | Command::Watch(_)
+                | Command::Quit(_)
+                | Command::Reset(_)
+                | Command::Asking(_)
+        )
+    }
+
+    /// Queue `cmd`, acting on `keys` and following `ASKING` if `asking` is
+    /// set, and return the reply.
+    ///
+    /// Unknown commands and commands that cannot be executed as part of a
+    /// transaction are rejected, which aborts the transaction.
+    pub(crate) fn queue(&mut self, cmd: Command, keys: Vec<Bytes>, asking: bool) -> Frame {
+        match cmd {
+            Command::Unknown(cmd) => {
+                self.aborted = true;
+                cmd.execute()
+            }
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::Psubscribe(_)
+            | Command::Punsubscribe(_)
+            | Command::Ssubscribe(_)
+            | Command::Sunsubscribe(_)
+            | Command::Client(_)
+            | Command::Migrate(_)
+            | Command::ReplicaOf(_)
+            | Command::Replconf(_)
+            | Command::Psync(_)
+            | Command::Wait(_) => {
+                self.aborted = true;
+                Frame::Error("ERR Command not allowed inside a transaction".to_string())
+            }
+            cmd => {
+                self.asking |= asking && !keys.is_empty();
+                self.keys.extend(keys);
+                self.queued.push(cmd);
+                Frame::Simple("QUEUED".to_string())
+            }
+        }
+    }
+
+    /// Reject a command that could not be parsed, aborting the transaction.
+    /// Returns the reply.
+    pub(crate) fn reject(&mut self, err: crate::Error) -> Frame {
+        self.aborted = true;
+        error_reply(err)
+    }
+
+    /// Refuse a command on keys this node does not serve, in cluster mode,
+    /// aborting the transaction. Returns `resp`, the redirection.
+    pub(crate) fn refuse(&mut self, resp: Frame) -> Frame {
+        self.aborted = true;
+        resp
+    }
+
+    /// Execute the queued commands against `db`, unless the transaction was
+    /// aborted. Returns the array of their replies, or a null if one of the
+    /// `watched` keys was modified.
+    ///
+    /// Like `EVAL`, a transaction running a script runs on a blocking thread.
+    async fn exec(self, db: &Db, watched: Option<WatchedKeys>) -> crate::Result<Frame> {
+        if self.aborted {
+            return Ok(Frame::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            ));
+        }
+
+        if !self.queued.iter().any(Command::runs_script) {
+            return Ok(match db.lock_unless_busy().await {
+                Some(mut db) => self.run(&mut db, watched.as_ref()),
+                None => busy_reply(),
+            });
+        }
+
+        if db.lock_unless_busy().await.is_none() {
+            return Ok(busy_reply());
+        }
+
+        let db = db.clone();
+        let resp = tokio::task::spawn_blocking(move || {
+            let mut db = db.blocking_lock_for_script();
+            let resp = self.run(&mut db, watched.as_ref());
+
+            // The watched keys are dropped once the lock is released.
+            drop(db);
+            resp
+        })
+        .await?;
+
+        Ok(resp)
+    }
+
+    /// Execute the queued commands against `db`, see `exec`.
+    fn run(self, db: &mut LockedDb, watched: Option<&WatchedKeys>) -> Frame {
+        // The lock is held from checking the slot of the keys and the
+        // watched keys until the last command is executed.
+        if let Err(err) = db.check_slot(&self.keys, self.asking) {
+            return Frame::Error(err.to_string());
+        }
+        if watched.is_some_and(|watched| watched.is_dirty(db)) {
+            return Frame::Null;
+        }
+
+        db.begin_batch();
+        let replies = self
+            .queued
+            .into_iter()
+            .map(|cmd| match cmd.execute(db) {
+                Ok(resp) => resp,
+                Err(err) => Frame::Error(err.to_string()),
+            })
+            .collect();
+        db.end_batch();
+
+        Frame::Array(replies)
+    }
+}
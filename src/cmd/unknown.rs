@@ -1,4 +1,3 @@
-use crate::Connection;
 use crate::Frame;
 
 use tracing::{debug, instrument};
@@ -22,16 +21,15 @@ impl Unknown {
         &self.cmd_name
     }
 
-    /// Responds to the client, indicating the command is not recognized.
+    /// Returns the error sent to the client, indicating the command is not
+    /// recognized.
     ///
     /// This usually means the command is not yet implemented by `mini-redis`.
-    #[instrument(skip(self, dst))]
-    pub(crate) async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+    #[instrument(skip(self))]
+    pub(crate) fn execute(self) -> Frame {
         let resp = Frame::Error(format!("err unknown command '{}'", self.cmd_name));
 
         debug!(?resp);
-
-        dst.write_frame(&resp).await?;
-        Ok(())
+        resp
     }
 }
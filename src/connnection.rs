@@ -1,4 +1,5 @@
 use std::io::{self, Cursor};
+use std::net::SocketAddr;
 
 use bytes::{Buf, BytesMut};
 use tokio::{
@@ -6,6 +7,7 @@ use tokio::{
     net::TcpStream,
 };
 
+use crate::cmd::LagPolicy;
 use crate::frame::{self, Frame};
 
 #[derive(Debug)]
@@ -16,6 +18,16 @@ pub struct Connection {
     stream: BufWriter<TcpStream>,
     // The buffer for reading frames.
     buffer: BytesMut,
+    // What to do when the connection falls behind the channels it subscribes
+    // to. Set with `CLIENT PUBSUB-LAG`.
+    lag_policy: LagPolicy,
+    // Set by `close`, no more frames are read once this is `true`.
+    closed: bool,
+    // The port a replica listens on, announced with `REPLCONF
+    // listening-port`.
+    replica_port: Option<u16>,
+    // Set by `ASKING`, for the next command only.
+    asking: bool,
 }
 
 impl Connection {
@@ -23,9 +35,56 @@ impl Connection {
         Self {
             stream: BufWriter::new(socket),
             buffer: BytesMut::with_capacity(4 * 1024),
+            lag_policy: LagPolicy::default(),
+            closed: false,
+            replica_port: None,
+            asking: false,
         }
     }
 
+    /// Returns the address of the peer.
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().peer_addr()
+    }
+
+    /// Returns the local address of the connection.
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().local_addr()
+    }
+
+    /// Returns the port the peer listens on, if it is a replica that
+    /// announced it.
+    pub(crate) fn replica_port(&self) -> Option<u16> {
+        self.replica_port
+    }
+
+    /// Record the port the peer listens on, announced by a replica.
+    pub(crate) fn set_replica_port(&mut self, port: u16) {
+        self.replica_port = Some(port);
+    }
+
+    /// Allow the next command on a slot being moved to this node, in cluster
+    /// mode.
+    pub(crate) fn set_asking(&mut self) {
+        self.asking = true;
+    }
+
+    /// Returns `true` if `ASKING` was sent before the current command, and
+    /// resets it for the next one.
+    pub(crate) fn take_asking(&mut self) -> bool {
+        std::mem::take(&mut self.asking)
+    }
+
+    /// Returns what to do when the connection falls behind as a subscriber.
+    pub(crate) fn lag_policy(&self) -> LagPolicy {
+        self.lag_policy
+    }
+
+    /// Set what to do when the connection falls behind as a subscriber.
+    pub(crate) fn set_lag_policy(&mut self, policy: LagPolicy) {
+        self.lag_policy = policy;
+    }
+
     /// Read a single `Frame` value from the underlying stream.
     ///
     /// The function waits until it has retrieved enough data to parse a frame.
@@ -38,6 +97,12 @@ impl Connection {
     /// is closed in a way that doesn't break a frame in half, it returns
     /// `None`. Otherwise, an error is returned.
     pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        // The connection was closed on our side, frames the peer sent since
+        // are ignored.
+        if self.closed {
+            return Ok(None);
+        }
+
         loop {
             if let Some(frame) = self.parse_frame()? {
                 return Ok(Some(frame));
@@ -97,6 +162,16 @@ impl Connection {
         }
     }
 
+    /// Close the connection after the frames written so far, as requested by
+    /// `QUIT`.
+    ///
+    /// The write half of the socket is shut down and `read_frame` returns
+    /// `None` from now on, which ends the connection's handler.
+    pub(crate) async fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        self.stream.shutdown().await
+    }
+
     /// Write a single `Frame` value to the underlying stream.
     ///
     /// The `Frame` value is written to the socket using the various `write_*`
@@ -107,8 +182,8 @@ impl Connection {
     /// full, it is flushed to the underlying socket.
     pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
         // Arrays are encoded by encoding each entry. All other frame types are
-        // considered literals. For now, mini-redis is not able to encode
-        // recursive frame structures. See below for more details.
+        // considered literals. Arrays nested inside the top level array are
+        // handled by `write_value`.
         match frame {
             Frame::Array(val) => {
                 self.stream.write_u8(b'*').await?;
@@ -126,6 +201,13 @@ impl Connection {
         self.stream.flush().await
     }
 
+    /// Write frames encoded beforehand, such as the commands propagated to
+    /// replicas.
+    pub(crate) async fn write_encoded(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.stream.write_all(buf).await?;
+        self.stream.flush().await
+    }
+
     async fn write_value(&mut self, frame: &Frame) -> io::Result<()> {
         match frame {
             Frame::Simple(val) => {
@@ -153,11 +235,17 @@ impl Connection {
                 self.stream.write_all(val).await?;
                 self.stream.write_all(b"\r\n").await?;
             }
-            // Encoding an `Array` from within a value cannot be done using a
-            // recursive strategy. In general, async fns do not support
-            // recursion. Mini-redis has not needed to encode nested arrays yet,
-            // so for now it is skipped.
-            Frame::Array(_val) => unimplemented!(),
+            // Nested arrays are encoded recursively. An async fn cannot call
+            // itself directly, as its future would have infinite size, so the
+            // recursive call is boxed.
+            Frame::Array(val) => {
+                self.stream.write_u8(b'*').await?;
+                self.write_decimal(val.len() as u64).await?;
+
+                for entry in val {
+                    Box::pin(self.write_value(entry)).await?;
+                }
+            }
         }
         Ok(())
     }
@@ -1,10 +1,82 @@
-use std::collections::{BTreeSet, HashMap};
-use std::sync::{Arc, Mutex};
+pub(crate) mod bloom;
+pub(crate) use bloom::ScalingBloom;
+
+pub(crate) mod cuckoo;
+pub(crate) use cuckoo::Cuckoo;
+
+mod cms;
+pub(crate) use cms::CountMinSketch;
+
+pub(crate) mod topk;
+pub(crate) use topk::TopK;
+
+mod timeseries;
+pub use timeseries::{Aggregation, LabelFilter};
+pub(crate) use timeseries::{CompactionRule, TimeSeries};
+
+mod hash;
+
+mod glob;
+pub(crate) use glob::glob_match;
+
+mod scan;
+use scan::ScanMap;
+
+mod config;
+use config::{AppendFsync, Config};
+
+mod notify;
+use notify::KeyspaceEvents;
+
+mod pubsub;
+pub use pubsub::LagPolicy;
+pub(crate) use pubsub::Message;
+use pubsub::{Channel, Log};
+
+mod hnsw;
+
+pub(crate) mod search;
+pub use search::{DistanceMetric, FieldType, VectorAlgorithm};
+pub(crate) use search::{Index, Query, SearchResult};
+
+mod script;
+pub(crate) use script::{CachedScript, Host};
+
+mod sha1;
+use sha1::sha1_hex;
+
+mod snapshot;
+use snapshot::unix_time;
+pub(crate) use snapshot::{unix_time_ms, Snapshot};
+
+mod aof;
+use aof::Aof;
+
+mod dump;
+
+mod replication;
+pub(crate) use replication::{new_replid, ReplicaFeed, ReplicationInfo, SyncStart};
+use replication::{Primary, Replication};
+
+mod crc16;
+pub(crate) use crc16::{key_slot, SLOTS};
+
+mod cluster;
+pub(crate) use cluster::{BusMessage, Cluster, Gossip, Heartbeat, Node, Outbox, SetSlot};
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use bytes::Bytes;
-use tokio::sync::{broadcast, Notify};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tokio::time::{self, Duration, Instant};
-use tracing::debug;
+use tracing::{debug, error};
+
+use crate::cmd::{Exec, Multi, Ping, Replconf};
+use crate::{rdb, Frame};
 
 /// A wrapper around a `Db` instance. This exists to allow orderly cleanup
 /// of the `Db` by signalling the background purge task to shut down when
@@ -35,6 +107,30 @@ pub(crate) struct Db {
     shared: Arc<Shared>,
 }
 
+/// Exclusive access to the shared state, returned by `Db::lock`.
+///
+/// The key-value operations are implemented on `LockedDb` rather than `Db`,
+/// so that a caller may group several of them under the same lock.
+#[derive(Debug)]
+pub(crate) struct LockedDb<'a> {
+    /// Always `Some`, until the `LockedDb` is dropped.
+    state: Option<MutexGuard<'a, State>>,
+    /// The permit of `Shared::script_gate` taken before locking `state`. It
+    /// is released after `state`.
+    _gate: Gate<'a>,
+    shared: &'a Arc<Shared>,
+    /// Set when the background task needs to be notified. This is done once
+    /// the lock is released.
+    notify: bool,
+}
+
+/// A permit of `Shared::script_gate`.
+#[derive(Debug)]
+enum Gate<'a> {
+    Read(#[allow(dead_code)] RwLockReadGuard<'a, ()>),
+    Write(#[allow(dead_code)] RwLockWriteGuard<'a, ()>),
+}
+
 #[derive(Debug)]
 struct Shared {
     /// The shared state is guarded by a mutex. This is a `std::sync::Mutex` and
@@ -50,20 +146,52 @@ struct Shared {
     /// is considered a "blocking" operation and `tokio::task::spawn_blocking`
     /// should be used.
     state: Mutex<State>,
+    /// Taken for writing by a script for as long as it holds `state`, and
+    /// for reading by everyone else before locking `state`.
+    ///
+    /// A script may hold `state` for a long time. Waiting for this Tokio lock
+    /// rather than for `state` lets tasks wait for a script without blocking
+    /// their thread, which the connection sending `SCRIPT KILL` may need.
+    /// Once a read permit is held, no script holds `state` and locking it
+    /// only waits for the short critical sections of the other holders.
+    script_gate: RwLock<()>,
     /// Notifies the background task handling entry expiration. The background
     /// task waits on this to be notified, then checks for expired values or the
     /// shutdown signal.
     backgroup_task: Notify,
+    /// The script being run by `EVAL`, if any. This is kept out of `state`,
+    /// which the script holds locked while it runs, so that `SCRIPT KILL` and
+    /// the other connections can reach it.
+    running_script: Mutex<Option<Arc<ScriptControl>>>,
+    /// Notified when a script starts running and when it completes.
+    script_changed: Notify,
+    /// Notified when a replica acknowledges its replication offset.
+    replica_ack: Notify,
 }
 
 #[derive(Debug)]
 struct State {
     /// The key-value data. We are not trying to do anything fancy so a
-    /// `std::collections::HashMap` works fine.
-    entries: HashMap<String, Entry>,
+    /// `std::collections::HashMap` works fine, wrapped in a `ScanMap` so that
+    /// `SCAN` can page through it.
+    entries: ScanMap<String, Entry>,
     /// The pub/sub key-space. Redis uses a **separate** key space for key-value
     /// and pub/sub. `mini-redis` handles this by using a separate `HashMap`.
-    pub_sub: HashMap<String, broadcast::Sender<Bytes>>,
+    pub_sub: HashMap<String, Channel<Message>>,
+    /// Pattern subscriptions, by glob-style pattern. Messages carry the name
+    /// of the channel they were published to.
+    pattern_sub: HashMap<String, Channel<(String, Bytes)>>,
+    /// Shard channels, used by `SSUBSCRIBE` and `SPUBLISH`. These are a
+    /// separate namespace from `pub_sub` and are not matched by patterns.
+    shard_sub: HashMap<String, Channel<Bytes>>,
+    /// Logs of the last messages published to each channel, kept when
+    /// `pubsub-log-size` is not zero. Unlike `pub_sub`, a channel's log
+    /// outlives its subscribers so that they may come back and replay it, but
+    /// only for `pubsub-log-ttl` seconds after its last message.
+    logs: HashMap<String, Log>,
+
+    /// Parameters set with `CONFIG SET`.
+    config: Config,
 
     /// Tracks key TTLs.
     ///
@@ -77,6 +205,56 @@ struct State {
     /// break these ties.
     expirations: BTreeSet<(Instant, String)>,
 
+    /// Keys of time series with a retention period. The background task
+    /// trims these every `RETENTION_INTERVAL`. Keys that were since deleted or
+    /// overwritten are dropped from the set lazily, by the task.
+    retained_series: HashSet<String>,
+
+    /// Search indexes, by name. Indexes live in their own namespace, like
+    /// pub/sub channels.
+    indexes: HashMap<String, Index>,
+
+    /// The flags of the `WatchedKeys` watching each key. A key is only listed
+    /// until it is modified, at which point its watchers are flagged.
+    watched: HashMap<String, Vec<Arc<AtomicBool>>>,
+
+    /// Number of changes to the keys, incremented by `touch`.
+    dirty: u64,
+
+    /// Scripts compiled by `EVAL` and `SCRIPT LOAD`, by SHA-1 digest of their
+    /// source.
+    scripts: HashMap<String, Arc<CachedScript>>,
+
+    /// Value of `dirty` when the last snapshot was taken. The save rules
+    /// compare it to the current value.
+    saved_dirty: u64,
+    /// Unix time in seconds of the last successful save, reported by
+    /// `LASTSAVE`.
+    last_save: u64,
+    /// When the last save was attempted, successful or not.
+    last_save_attempt: Instant,
+    /// True while a `BGSAVE` is writing a snapshot.
+    saving: bool,
+
+    /// The append-only file write commands are logged to. Opened on startup
+    /// when `appendonly` is enabled, once the file was replayed, or by a
+    /// rewrite.
+    aof: Option<Aof>,
+    /// Write commands executed while the append-only file is rewritten,
+    /// appended to the new file once written. `None` unless a rewrite is in
+    /// progress.
+    aof_rewrite: Option<Vec<u8>>,
+    /// Write commands of the transaction being executed, propagated at once
+    /// by `end_batch`. `None` outside of transactions.
+    batch: Option<Vec<Frame>>,
+
+    /// Replication ID and offset, backlog and attached replicas, and the
+    /// primary if this server is a replica.
+    replication: Replication,
+
+    /// The nodes of the cluster and the slots they serve, in cluster mode.
+    cluster: Option<Cluster>,
+
     /// True when the Db instance is shutting down. This happens when all `Db`
     /// values drop. Setting this to `true` signals to the background task to
     /// exit.
@@ -87,12 +265,154 @@ struct State {
 #[derive(Debug)]
 struct Entry {
     /// Stored data
-    data: Bytes,
+    value: Value,
     /// Instant at which the entry expires and should be removed from the
     /// database.
     expires_at: Option<Instant>,
 }
 
+/// The value types a key may hold.
+#[derive(Debug, Clone)]
+enum Value {
+    /// Plain string, set with `SET`.
+    String(Bytes),
+    /// Scalable Bloom filter, created by `BF.RESERVE` or `BF.ADD`.
+    Bloom(ScalingBloom),
+    /// Cuckoo filter, created by `CF.ADD`.
+    Cuckoo(Cuckoo),
+    /// Count-Min sketch, created by `CMS.INITBYDIM` or `CMS.INITBYPROB`.
+    Cms(CountMinSketch),
+    /// Top-K heavy hitters, created by `TOPK.RESERVE`.
+    TopK(TopK),
+    /// Time series, created by `TS.CREATE` or `TS.ADD`.
+    TimeSeries(TimeSeries),
+    /// Field-value map, created by `HSET`.
+    Hash(ScanMap<Bytes, Bytes>),
+}
+
+/// Receives the messages of a pub/sub channel or pattern.
+///
+/// Dropping a `Subscriber` releases its subscription. A channel is removed
+/// from `State` along with its last subscriber, so the pub/sub maps only hold
+/// channels that are listened to.
+#[derive(Debug)]
+pub(crate) struct Subscriber<T> {
+    rx: broadcast::Receiver<T>,
+    /// Set for subscribers applying backpressure, see `SpaceNotifier`. Must
+    /// be declared after `rx` so it is dropped after it.
+    space: Option<SpaceNotifier>,
+    db: Db,
+    kind: SubscriptionKind,
+    name: String,
+    /// Logged messages to receive before those from `rx`.
+    replay: VecDeque<T>,
+    /// Number of requested messages that are no longer logged, reported as a
+    /// lag before `replay`.
+    missed: u64,
+}
+
+/// Keys watched by a connection with `WATCH`.
+///
+/// The `WatchedKeys` are flagged as soon as one of the keys is modified,
+/// deleted or expires. Dropping them stops watching the keys.
+#[derive(Debug)]
+pub(crate) struct WatchedKeys {
+    db: Db,
+    keys: Vec<String>,
+    dirty: Arc<AtomicBool>,
+}
+
+/// The script currently run by `EVAL`, as seen by the other connections.
+#[derive(Debug)]
+struct ScriptControl {
+    started: Instant,
+    /// Once the script has run for this long, other connections are told the
+    /// server is busy rather than waiting for the lock.
+    busy_after: Duration,
+    status: Mutex<ScriptStatus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScriptStatus {
+    Running,
+    /// The script modified a key, it can no longer be killed.
+    Wrote,
+    /// `SCRIPT KILL` was called, the script stops at the next check.
+    Killed,
+}
+
+/// Registration of the script run by `EVAL`, returned by
+/// `LockedDb::start_script`. Dropping it unregisters the script.
+#[derive(Debug)]
+pub(crate) struct RunningScript<'a> {
+    shared: &'a Shared,
+    control: Arc<ScriptControl>,
+}
+
+/// Wakes up the publishers waiting for a backpressure subscriber to make
+/// room, whenever it receives a message and once it is dropped.
+#[derive(Debug)]
+struct SpaceNotifier(Arc<Notify>);
+
+/// The pub/sub namespace a `Subscriber` listens in.
+#[derive(Debug, Clone, Copy)]
+enum SubscriptionKind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+
+/// A series produced by `Db::ts_mrange`: the key, its labels and the matching
+/// samples.
+pub(crate) type SeriesRange = (String, Vec<(String, String)>, Vec<(u64, f64)>);
+
+/// How often the background task trims time series down to their retention
+/// period.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the background task drops the logs of channels left without
+/// subscribers.
+const LOG_EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the background task checks the save rules.
+const SAVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the append-only file is flushed to disk with the `everysec`
+/// policy.
+const AOF_FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the background task checks whether the append-only file grew
+/// enough to be rewritten.
+const AOF_REWRITE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a primary pings its replicas, so that they can tell an idle
+/// link from a broken one within `repl-timeout`.
+const REPL_PING_PERIOD: Duration = Duration::from_secs(10);
+
+/// Parameters that can only be set on startup.
+///
+/// `dir` is one of them so that clients cannot have `SAVE`, `RDB.EXPORT`
+/// and the append-only file written anywhere the server may write.
+const STARTUP_PARAMETERS: &[&str] = &[
+    "dir",
+    "appendfilename",
+    "rdb-import",
+    "replicaof",
+    "cluster-enabled",
+];
+
+/// Error returned when a `Db` operation is rejected.
+///
+/// Unlike `crate::Error`, these errors do not terminate the connection. The
+/// command reports them to the client as an error frame and carries on.
+#[derive(Debug)]
+pub(crate) enum DbError {
+    /// The key holds a value of a different type than the operation expects.
+    WrongType,
+    /// All other errors. The message is sent to the client as is.
+    Other(String),
+}
+
 impl DbDropGuard {
     /// Create a new `DbHolder`, wrapping a `Db` instance. When this is dropped
     /// the `Db`'s purge task will be shut down.
@@ -119,12 +439,34 @@ impl Db {
     pub(crate) fn new() -> Self {
         let shared = Arc::new(Shared {
             state: Mutex::new(State {
-                entries: HashMap::new(),
+                entries: ScanMap::new(),
                 pub_sub: HashMap::new(),
+                pattern_sub: HashMap::new(),
+                shard_sub: HashMap::new(),
+                logs: HashMap::new(),
                 expirations: BTreeSet::new(),
+                retained_series: HashSet::new(),
+                indexes: HashMap::new(),
+                watched: HashMap::new(),
+                dirty: 0,
+                scripts: HashMap::new(),
+                saved_dirty: 0,
+                last_save: unix_time(),
+                last_save_attempt: Instant::now(),
+                saving: false,
+                aof: None,
+                aof_rewrite: None,
+                batch: None,
+                replication: Replication::new(),
+                cluster: None,
+                config: Config::default(),
                 shutdown: false,
             }),
             backgroup_task: Notify::new(),
+            script_gate: RwLock::new(()),
+            running_script: Mutex::new(None),
+            script_changed: Notify::new(),
+            replica_ack: Notify::new(),
         });
 
         // Start the background task.
@@ -133,188 +475,2923 @@ impl Db {
         Db { shared }
     }
 
-    /// Get the value associated with a key.
+    /// Lock the shared state for exclusive access, once no script is
+    /// running.
     ///
-    /// Returns `None` if there is no value associated with the key. This may be
-    /// due to never having assigned a value to the key or a previously assigned
-    /// value expired.
-    pub(crate) fn get(&self, key: &str) -> Option<Bytes> {
-        let state = self.shared.state.lock().unwrap();
-        state.entries.get(key).map(|entry| entry.data.clone())
+    /// Commands are executed against the returned `LockedDb`. Executing
+    /// several commands with the same `LockedDb` makes them atomic, no other
+    /// connection sees the state in between.
+    ///
+    /// While a script runs, this waits without blocking the thread. Every
+    /// task locks the state this way, so that no runtime thread is stuck
+    /// behind a script.
+    pub(crate) async fn lock(&self) -> LockedDb<'_> {
+        self.shared.lock().await
     }
 
-    /// Set the value associated with a key along with an optional expiration
-    /// Duration.
+    /// Like `lock`, but returns `None` rather than waiting once the running
+    /// script has run for `busy-reply-threshold`.
+    pub(crate) async fn lock_unless_busy(&self) -> Option<LockedDb<'_>> {
+        loop {
+            // Registered before checking, so that a script starting or
+            // completing in between is not missed.
+            let changed = self.shared.script_changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let busy_at = self
+                .shared
+                .running_script
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|control| control.started + control.busy_after);
+
+            tokio::select! {
+                biased;
+                gate = self.shared.script_gate.read() => {
+                    return Some(self.shared.locked(Gate::Read(gate)));
+                }
+                _ = time::sleep_until(busy_at.unwrap_or_else(Instant::now)), if busy_at.is_some() => {
+                    return None;
+                }
+                _ = changed => {}
+            }
+        }
+    }
+
+    /// Lock the shared state to run a script, from a blocking thread. Other
+    /// connections wait for the returned `LockedDb` to be dropped without
+    /// blocking their thread.
+    pub(crate) fn blocking_lock_for_script(&self) -> LockedDb<'_> {
+        let gate = self.shared.script_gate.blocking_write();
+        self.shared.locked(Gate::Write(gate))
+    }
+
+    /// Lock the shared state if no script is running, or is about to.
+    /// Returns `None` otherwise.
     ///
-    /// If a value is already associated with the key, it is removed.
-    pub(crate) fn set(&self, key: String, value: Bytes, expire: Option<Duration>) {
-        let mut state = self.shared.state.lock().unwrap();
+    /// Used from `Drop` implementations, which cannot wait. These leave the
+    /// work to a new task when a script is running.
+    fn try_lock(&self) -> Option<LockedDb<'_>> {
+        let gate = self.shared.script_gate.try_read().ok()?;
+        Some(self.shared.locked(Gate::Read(gate)))
+    }
 
-        let mut notify = false;
+    /// Kill the running script, unless it already modified keys.
+    pub(crate) fn kill_script(&self) -> Result<(), DbError> {
+        let running = self.shared.running_script.lock().unwrap();
 
-        let exipires_at = expire.map(|dur| {
-            let when = Instant::now() + dur;
+        let control = match &*running {
+            Some(control) => control,
+            None => return Err("NOTBUSY No scripts in execution right now.".into()),
+        };
 
-            // Only notify the worker task if the newly inserted expiration is the
-            // **next** key to evict. In this case, the worker needs to be woken up
-            // to update its state.
-            notify = state
-                .next_expiration()
-                .map(|expiration| expiration > when)
-                .unwrap_or(true);
+        let mut status = control.status.lock().unwrap();
+        match *status {
+            ScriptStatus::Wrote => Err("UNKILLABLE Sorry the script already executed write \
+                 commands against the dataset. You can either wait the script termination \
+                 or kill the server in a hard way using the SHUTDOWN NOSAVE command."
+                .into()),
+            _ => {
+                *status = ScriptStatus::Killed;
+                Ok(())
+            }
+        }
+    }
 
-            // track the expiration
-            state.expirations.insert((when, key.clone()));
-            when
-        });
+    /// Returns an empty set of `WatchedKeys`.
+    pub(crate) fn watch(&self) -> WatchedKeys {
+        WatchedKeys {
+            db: self.clone(),
+            keys: Vec::new(),
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
 
-        // Insert the entry into the `HashMap`.
-        let prev = state.entries.insert(
-            key.clone(),
-            Entry {
-                data: value,
-                expires_at: exipires_at,
+    /// Returns a `Subscriber` for the requested channel.
+    ///
+    /// The returned `Subscriber` is used to receive values broadcast by
+    /// `PUBLISH` commands. `policy` decides what happens when the subscriber
+    /// falls behind.
+    ///
+    /// With `from`, the `Subscriber` first receives the logged messages from
+    /// this offset onward. Both happen under the lock, so no message is
+    /// missed or received twice in between.
+    pub(crate) async fn subscribe(
+        &self,
+        key: String,
+        policy: LagPolicy,
+        from: Option<u64>,
+    ) -> Subscriber<Message> {
+        // Acquire the mutex
+        let mut db = self.lock().await;
+        let state = db.state();
+        let capacity = state.config.pubsub_channel_capacity;
+
+        // If there is no entry for the requested channel, then create a new
+        // channel and associate it with the key. If one already exists, return
+        // an associated receiver.
+        //
+        // The channel is created with a capacity of `pubsub-channel-capacity`
+        // messages. A message is stored in the channel until **all**
+        // subscribers have seen it. This means that a slow subscriber could
+        // result in messages being held indefinitely.
+        //
+        // When the channel's capacity fills up, publishing will result in old
+        // messages being dropped, unless a subscriber applies backpressure.
+        let channel = state
+            .pub_sub
+            .entry(key.clone())
+            .or_insert_with(|| Channel::new(capacity));
+        let rx = channel.subscribe(policy);
+        let space = channel.space().clone();
+
+        // A channel that was never published to while logging has nothing to
+        // replay.
+        let (missed, replay) = match (from, state.logs.get(&key)) {
+            (Some(offset), Some(log)) => log.since(offset),
+            _ => (0, VecDeque::new()),
+        };
+
+        Subscriber {
+            rx,
+            db: self.clone(),
+            kind: SubscriptionKind::Channel,
+            name: key,
+            space: (policy == LagPolicy::Backpressure).then(|| SpaceNotifier(space)),
+            replay,
+            missed,
+        }
+    }
+
+    /// Returns a `Subscriber` for the channels matching the glob-style
+    /// `pattern`.
+    ///
+    /// Each message is received along with the channel it was published to.
+    pub(crate) async fn psubscribe(
+        &self,
+        pattern: String,
+        policy: LagPolicy,
+    ) -> Subscriber<(String, Bytes)> {
+        let mut db = self.lock().await;
+        let state = db.state();
+        let capacity = state.config.pubsub_channel_capacity;
+
+        // Pattern channels are created like regular channels, see
+        // `subscribe`.
+        let channel = state
+            .pattern_sub
+            .entry(pattern.clone())
+            .or_insert_with(|| Channel::new(capacity));
+
+        Subscriber {
+            rx: channel.subscribe(policy),
+            db: self.clone(),
+            kind: SubscriptionKind::Pattern,
+            name: pattern,
+            space: (policy == LagPolicy::Backpressure)
+                .then(|| SpaceNotifier(channel.space().clone())),
+            replay: VecDeque::new(),
+            missed: 0,
+        }
+    }
+
+    /// Returns a `Subscriber` for the requested shard channel.
+    ///
+    /// Shard channels live in their own namespace and receive the messages
+    /// sent by `SPUBLISH`.
+    pub(crate) async fn ssubscribe(&self, key: String, policy: LagPolicy) -> Subscriber<Bytes> {
+        let mut db = self.lock().await;
+        let state = db.state();
+        let capacity = state.config.pubsub_channel_capacity;
+
+        // Shard channels are created like regular channels, see `subscribe`.
+        let channel = state
+            .shard_sub
+            .entry(key.clone())
+            .or_insert_with(|| Channel::new(capacity));
+
+        Subscriber {
+            rx: channel.subscribe(policy),
+            db: self.clone(),
+            kind: SubscriptionKind::ShardChannel,
+            name: key,
+            space: (policy == LagPolicy::Backpressure)
+                .then(|| SpaceNotifier(channel.space().clone())),
+            replay: VecDeque::new(),
+            missed: 0,
+        }
+    }
+
+    /// Publish a message to the channel. Returns the number of subscribers
+    /// listening on the channel, including the subscribers of every pattern
+    /// matching the channel.
+    ///
+    /// When `pubsub-log-size` is not zero, the message is also appended to
+    /// the channel's log, whether or not anyone is subscribed.
+    ///
+    /// If a subscriber applying backpressure is full, this waits until it
+    /// catches up. The message is sent to every subscriber at once, never to
+    /// some of them only.
+    pub(crate) async fn publish(&self, key: &str, value: Bytes) -> usize {
+        self.send_when_ready(
+            |state| state.full_channel(key),
+            |state| state.publish(key, value),
+        )
+        .await
+    }
+
+    /// Publish a message to the shard channel. Returns the number of
+    /// subscribers listening on the shard channel.
+    ///
+    /// Shard channels are not matched by patterns. Once the keyspace is split
+    /// across a cluster, the message is only delivered by the node owning the
+    /// channel's slot. Backpressure applies as with `publish`.
+    pub(crate) async fn spublish(&self, key: &str, value: Bytes) -> usize {
+        self.send_when_ready(
+            |state| {
+                state
+                    .shard_sub
+                    .get(key)
+                    .filter(|tx| tx.is_full())
+                    .map(|tx| tx.space())
             },
-        );
+            |state| state.spublish(key, value),
+        )
+        .await
+    }
 
-        // If there was a value previously associated with the key **and** it
-        // had an expiration time. The associated entry in the `expirations` map
-        // must also be removed. This avoids leaking data.
-        if let Some(prev) = prev {
-            if let Some(when) = prev.expires_at {
-                state.expirations.remove(&(when, key));
+    /// Wait until `full` finds no full backpressure subscriber, then `send`
+    /// while still holding the lock.
+    async fn send_when_ready(
+        &self,
+        full: impl Fn(&State) -> Option<&Arc<Notify>>,
+        send: impl FnOnce(&mut State) -> usize,
+    ) -> usize {
+        loop {
+            let space = {
+                let mut db = self.lock().await;
+                match full(db.state()) {
+                    Some(space) => space.clone(),
+                    None => return send(db.state()),
+                }
+            };
+
+            // Register for notifications before checking again, the
+            // subscriber may have made room since the lock was released.
+            let notified = space.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let full = full(self.lock().await.state()).is_some();
+            if full {
+                notified.await;
             }
         }
+    }
 
-        // Release the mutex before notifying the background task. This helps
-        // reduce contention by avoiding the background task waking up only to
-        // be unable to acquire the mutex due to this function still holding it.
-        drop(state);
+    /// Called when a `Subscriber` is dropped. Removes its channel if the
+    /// `Subscriber` was the last one.
+    fn release(&self, kind: SubscriptionKind, name: &str) {
+        // The subscriber being dropped still holds its receiver, so the
+        // channel is unused if a single receiver is left. Holding the lock
+        // guarantees no new subscriber shows up in the meantime.
+        if let Some(mut db) = self.try_lock() {
+            db.state().release(kind, name, 1);
+            return;
+        }
 
-        if notify {
-            self.shared.backgroup_task.notify_one();
+        // By the time the script completes, the receiver is gone.
+        let db = self.clone();
+        let name = name.to_string();
+        tokio::spawn(async move { db.lock().await.state().release(kind, &name, 0) });
+    }
+
+    /// Replicate the primary at `host` and `port`, replacing the current
+    /// one, if any. Returns `false` if it is the current one already.
+    ///
+    /// The replica is read-only from now on. A task keeps the link to the
+    /// primary in the background, reconnecting as needed.
+    pub(crate) async fn replicaof(&self, host: String, port: u16) -> bool {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if let Some(primary) = &state.replication.primary {
+            if primary.host == host && primary.port == port {
+                return false;
+            }
+            primary.task.abort();
         }
+
+        // The replicas of this server follow the new history once it has
+        // synchronized.
+        state.replication.detach_all();
+
+        let task = tokio::spawn(crate::replica::run(self.clone(), host.clone(), port));
+        state.replication.primary = Some(Primary {
+            host,
+            port,
+            link_up: false,
+            last_io: None,
+            task,
+        });
+        true
     }
 
-    /// Returns a `Receiver` for the requested channel.
+    /// Record that the replica `id` has processed the history up to
+    /// `offset`, waking up the connections waiting for it with `WAIT`.
+    pub(crate) async fn replica_ack(&self, id: u64, offset: u64) {
+        self.lock().await.state().replication.ack(id, offset);
+        self.shared.replica_ack.notify_waiters();
+    }
+
+    /// Wait until `numreplicas` replicas acknowledged the writes made so far,
+    /// or `timeout` elapsed. Waits with no limit if `timeout` is `None`.
     ///
-    /// The returned `Receiver` is used to receive values broadcast by `PUBLISH`
-    /// commands.
-    pub(crate) fn subscribe(&self, key: String) -> broadcast::Receiver<Bytes> {
-        use std::collections::hash_map::Entry;
+    /// Returns the number of replicas that acknowledged the writes.
+    pub(crate) async fn wait_for_replicas(
+        &self,
+        numreplicas: usize,
+        timeout: Option<Duration>,
+    ) -> Result<usize, DbError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
 
-        // Acquire the mutex
-        let mut state = self.shared.state.lock().unwrap();
+        let offset = {
+            let mut db = self.lock().await;
+            if db.is_replica() {
+                return Err("ERR WAIT cannot be used with replica instances.".into());
+            }
 
-        // If there is no entry for the requested channel, then create a new
-        // broadcast channel and associate it with the key. If one already
-        // exists, return an associated receiver.
-        match state.pub_sub.entry(key) {
-            Entry::Occupied(e) => e.get().subscribe(),
+            let replication = &db.state().replication;
+            let offset = replication.offset;
+            if numreplicas == 0 || replication.acked(offset) >= numreplicas {
+                return Ok(replication.acked(offset));
+            }
+
+            // Rather than waiting for the periodic acknowledgements.
+            db.request_acks();
+            offset
+        };
+
+        loop {
+            // Registered before checking, so that an acknowledgement
+            // received in between is not missed.
+            let acked = self.shared.replica_ack.notified();
+            tokio::pin!(acked);
+            acked.as_mut().enable();
 
-            Entry::Vacant(e) => {
-                // No broadcast channel exists yet, so create one.
-                //
-                // The channel is created with a capacity of `1024` messages. A
-                // message is stored in the channel until **all** subscribers
-                // have seen it. This means that a slow subscriber could result
-                // in messages being held indefinitely.
-                //
-                // When the channel's capacity fills up, publishing will result
-                // in old messages being dropped. This prevents slow consumers
-                // from blocking the entire system.
-                let (tx, rx) = broadcast::channel(1024);
-                e.insert(tx);
-                rx
+            let count = self.lock().await.state().replication.acked(offset);
+            if count >= numreplicas {
+                return Ok(count);
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if time::timeout_at(deadline, acked).await.is_err() {
+                        return Ok(self.lock().await.state().replication.acked(offset));
+                    }
+                }
+                None => acked.await,
             }
         }
     }
 
-    /// Publish a message to the channel. Returns the number of subscribers
-    /// listening on the channel.
-    pub(crate) fn publish(&self, key: &str, value: Bytes) -> usize {
-        let state = self.shared.state.lock().unwrap();
+    /// Run in cluster mode, as the node listening on `ip` and `port` for
+    /// clients, and on `bus` for the other nodes. The node starts alone,
+    /// serving no slots.
+    pub(crate) async fn enable_cluster(&self, ip: String, port: u16, bus: TcpListener) {
+        let mut db = self.lock().await;
+        let state = db.state();
+        let bus_port = match bus.local_addr() {
+            Ok(addr) => addr.port(),
+            Err(_) => port + crate::cluster::BUS_PORT_OFFSET,
+        };
 
-        state
-            .pub_sub
-            .get(key)
-            // On a successful message send on the broadcast channel, the number
-            // of subscribers is returned. An error indicates there are no
-            // receivers, in which case, `0` should be returned.
-            .map(|tx| tx.send(value).unwrap_or(0))
-            // If there is no entry for the channel key, then there are no
-            // subscribers. In this case, return `0`.
-            .unwrap_or(0)
+        let task = tokio::spawn(crate::cluster::run(self.clone(), bus));
+        state.cluster = Some(Cluster::new(ip, port, bus_port, task));
     }
 
     /// Signals the purge background task to shut down. This is called by the
     /// `DbShutdown`s `Drop` implementation.
+    ///
+    /// If a script is running, this happens once it completes.
     fn shutdown_purge_task(&self) {
-        let mut state = self.shared.state.lock().unwrap();
+        match self.try_lock() {
+            Some(mut db) => db.shutdown(),
+            None => {
+                let db = self.clone();
+                tokio::spawn(async move { db.lock().await.shutdown() });
+            }
+        }
+    }
+}
+
+impl<'a> LockedDb<'a> {
+    fn state(&mut self) -> &mut State {
+        // Only `None` once dropped.
+        self.state.as_mut().unwrap()
+    }
+
+    /// Flag the state as shut down, see `Db::shutdown_purge_task`.
+    fn shutdown(&mut self) {
+        let state = self.state();
 
         state.shutdown = true;
 
-        drop(state);
-        self.shared.backgroup_task.notify_one();
+        // The link to the primary holds a `Db` handle.
+        if let Some(primary) = state.replication.primary.take() {
+            primary.task.abort();
+        }
+
+        // So does the cluster bus.
+        if let Some(cluster) = state.cluster.take() {
+            cluster.task.abort();
+        }
+
+        self.notify_background_task();
     }
-}
 
-impl Shared {
-    /// Purge all expired keys and return the `Instant` at which the **next**
-    /// key will expire. The background task will sleep until this instant.
-    fn purge_expired_key(&self) -> Option<Instant> {
-        let mut state = self.state.lock().unwrap();
+    /// Wake up the background task once the lock is released.
+    fn notify_background_task(&mut self) {
+        self.notify = true;
+    }
 
-        if state.shutdown {
-            // The database is shutting down. All handles to the shared state
-            // have dropped. The background task should exit.
-            return None;
+    /// Get the value associated with a key.
+    ///
+    /// Returns `None` if there is no value associated with the key. This may be
+    /// due to never having assigned a value to the key or a previously assigned
+    /// value expired. Returns `Err` if the key does not hold a string.
+    pub(crate) fn get(&mut self, key: &str) -> Result<Option<Bytes>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::String(data)) => Ok(Some(data.clone())),
+            Some(_) => Err(DbError::WrongType),
+            None => Ok(None),
         }
-        // This is needed to make the borrow checker happy. In short, `lock()`
-        // returns a `MutexGuard` and not a `&mut State`. The borrow checker is
-        // not able to see "through" the mutex guard and determine that it is
-        // safe to access both `state.expirations` and `state.entries` mutably,
-        // so we get a "real" mutable reference to `State` outside of the loop.
-        let state = &mut *state;
+    }
 
-        // Find all keys scheduled to expire **before** now.
-        let now = Instant::now();
+    /// Set the value associated with a key along with an optional expiration
+    /// Duration.
+    ///
+    /// If a value is already associated with the key, it is removed.
+    pub(crate) fn set(&mut self, key: String, value: Bytes, expire: Option<Duration>) {
+        let state = self.state();
 
-        while let Some(&(when, ref key)) = state.expirations.iter().next() {
-            if when > now {
-                return Some(when);
-            }
+        state.notify_keyspace_event(KeyspaceEvents::STRING, "set", &key);
+        if expire.is_some() {
+            state.notify_keyspace_event(KeyspaceEvents::GENERIC, "expire", &key);
+        }
 
-            // The key iss expired, remove it
-            state.entries.remove(key);
-            state.expirations.remove(&(when, key.clone()));
+        let notify = state.insert(key, Value::String(value), expire);
+
+        if notify {
+            self.notify_background_task();
         }
-        None
     }
 
-    fn is_shutdown(&self) -> bool {
-        self.state.lock().unwrap().shutdown
+    /// Create an empty Bloom filter at `key`.
+    ///
+    /// Returns `Err` if the key already exists.
+    pub(crate) fn bf_reserve(&mut self, key: String, filter: ScalingBloom) -> Result<(), DbError> {
+        let state = self.state();
+
+        if state.entries.contains_key(&key) {
+            return Err("ERR item exists".into());
+        }
+
+        state.insert(key, Value::Bloom(filter), None);
+        Ok(())
     }
-}
 
-impl State {
-    fn next_expiration(&self) -> Option<Instant> {
-        self.expirations.iter().next().map(|expire| expire.0)
+    /// Add `items` to the Bloom filter at `key`, creating a filter with the
+    /// default parameters if the key does not exist.
+    ///
+    /// For each item, returns whether it was newly added, or the error adding
+    /// it. Once a non scaling filter is full, the remaining items are not
+    /// added, while those before them stay added.
+    pub(crate) fn bf_add(
+        &mut self,
+        key: String,
+        items: &[Bytes],
+    ) -> Result<Vec<Result<bool, &'static str>>, DbError> {
+        let state = self.state();
+
+        let entry = state.entries.get_or_insert_with(key.clone(), || Entry {
+            value: Value::Bloom(ScalingBloom::new(
+                bloom::DEFAULT_ERROR_RATE,
+                bloom::DEFAULT_CAPACITY,
+                Some(bloom::DEFAULT_EXPANSION),
+            )),
+            expires_at: None,
+        });
+
+        let added = match &mut entry.value {
+            Value::Bloom(filter) => items.iter().map(|item| filter.add(item)).collect(),
+            _ => return Err(DbError::WrongType),
+        };
+
+        state.touch(&key);
+        Ok(added)
     }
-}
 
-/// Routine executed by the background task.
-///
-/// Wait to be notified. On notification, purge any expired keys from the shared
-/// state handle. If `shutdown` is set, terminate the task.
-async fn purge_expired_tasks(shared: Arc<Shared>) {
-    // If the shutdown flag is set, then the task should exit.
+    /// For each item, returns whether it may have been added to the Bloom
+    /// filter at `key`. A missing key is an empty filter.
+    pub(crate) fn bf_exists(&mut self, key: &str, items: &[Bytes]) -> Result<Vec<bool>, DbError> {
+        let state = self.state();
 
-    while !shared.is_shutdown() {
-        // Purge all keys that are expired. The function returns the instant at
-        // which the **next** key will expire. The worker should wait until the
-        // instant has passed then purge again.
-        if let Some(when) = shared.purge_expired_key() {
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Bloom(filter)) => {
+                Ok(items.iter().map(|item| filter.contains(item)).collect())
+            }
+            Some(_) => Err(DbError::WrongType),
+            None => Ok(vec![false; items.len()]),
+        }
+    }
+
+    /// Add `item` to the cuckoo filter at `key`, creating a filter with the
+    /// default capacity if the key does not exist.
+    pub(crate) fn cf_add(&mut self, key: String, item: &[u8]) -> Result<(), DbError> {
+        let state = self.state();
+
+        let entry = state.entries.get_or_insert_with(key.clone(), || Entry {
+            value: Value::Cuckoo(Cuckoo::new(cuckoo::DEFAULT_CAPACITY)),
+            expires_at: None,
+        });
+
+        match &mut entry.value {
+            Value::Cuckoo(filter) => filter.add(item),
+            _ => return Err(DbError::WrongType),
+        }
+
+        state.touch(&key);
+        Ok(())
+    }
+
+    /// Remove one occurrence of `item` from the cuckoo filter at `key`.
+    ///
+    /// Returns whether the item was found. Returns `Err` if the key does not
+    /// exist.
+    pub(crate) fn cf_del(&mut self, key: &str, item: &[u8]) -> Result<bool, DbError> {
+        let state = self.state();
+
+        let res = match state.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::Cuckoo(filter)) => Ok(filter.delete(item)),
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR not found".into()),
+        };
+
+        if matches!(res, Ok(true)) {
+            state.touch(key);
+        }
+        res
+    }
+
+    /// Returns whether `item` may be in the cuckoo filter at `key`. A missing
+    /// key is an empty filter.
+    pub(crate) fn cf_exists(&mut self, key: &str, item: &[u8]) -> Result<bool, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Cuckoo(filter)) => Ok(filter.contains(item)),
+            Some(_) => Err(DbError::WrongType),
+            None => Ok(false),
+        }
+    }
+
+    /// Create an empty Count-Min sketch at `key`.
+    ///
+    /// Returns `Err` if the key already exists.
+    pub(crate) fn cms_init(&mut self, key: String, sketch: CountMinSketch) -> Result<(), DbError> {
+        let state = self.state();
+
+        if state.entries.contains_key(&key) {
+            return Err("ERR CMS: key already exists".into());
+        }
+
+        state.insert(key, Value::Cms(sketch), None);
+        Ok(())
+    }
+
+    /// Increase the count of each item in the Count-Min sketch at `key` by
+    /// its increment. Returns the new estimate of each item.
+    pub(crate) fn cms_incr_by(
+        &mut self,
+        key: &str,
+        items: &[(Bytes, u64)],
+    ) -> Result<Vec<u64>, DbError> {
+        let state = self.state();
+
+        let res = match state.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::Cms(sketch)) => Ok(items
+                .iter()
+                .map(|(item, increment)| sketch.incr_by(item, *increment))
+                .collect()),
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR CMS: key does not exist".into()),
+        };
+
+        if res.is_ok() {
+            state.touch(key);
+        }
+        res
+    }
+
+    /// Returns the estimated count of each item in the Count-Min sketch at
+    /// `key`.
+    pub(crate) fn cms_query(&mut self, key: &str, items: &[Bytes]) -> Result<Vec<u64>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Cms(sketch)) => Ok(items.iter().map(|item| sketch.query(item)).collect()),
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR CMS: key does not exist".into()),
+        }
+    }
+
+    /// Overwrite the Count-Min sketch at `dst` with the weighted sum of the
+    /// sketches at the `sources` keys. All sketches must have the same
+    /// dimensions.
+    pub(crate) fn cms_merge(
+        &mut self,
+        dst: &str,
+        sources: &[(String, u64)],
+    ) -> Result<(), DbError> {
+        let state = self.state();
+
+        // The sources are copied out first, as `dst` may be one of them.
+        let sources = sources
+            .iter()
+            .map(
+                |(key, weight)| match state.entries.get(key).map(|entry| &entry.value) {
+                    Some(Value::Cms(sketch)) => Ok((sketch.clone(), *weight)),
+                    Some(_) => Err(DbError::WrongType),
+                    None => Err("ERR CMS: key does not exist".into()),
+                },
+            )
+            .collect::<Result<Vec<_>, DbError>>()?;
+
+        match state.entries.get_mut(dst).map(|entry| &mut entry.value) {
+            Some(Value::Cms(sketch)) => sketch.merge(&sources)?,
+            Some(_) => return Err(DbError::WrongType),
+            None => return Err("ERR CMS: key does not exist".into()),
+        }
+
+        state.touch(dst);
+        Ok(())
+    }
+
+    /// Create an empty Top-K list at `key`.
+    ///
+    /// Returns `Err` if the key already exists.
+    pub(crate) fn topk_reserve(&mut self, key: String, topk: TopK) -> Result<(), DbError> {
+        let state = self.state();
+
+        if state.entries.contains_key(&key) {
+            return Err("ERR TopK: key already exists".into());
+        }
+
+        state.insert(key, Value::TopK(topk), None);
+        Ok(())
+    }
+
+    /// Count one occurrence of each item in the Top-K list at `key`.
+    ///
+    /// For each item, returns the item it expelled from the list, if any.
+    pub(crate) fn topk_add(
+        &mut self,
+        key: &str,
+        items: &[Bytes],
+    ) -> Result<Vec<Option<Bytes>>, DbError> {
+        let state = self.state();
+
+        let res = match state.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::TopK(topk)) => Ok(items.iter().map(|item| topk.add(item)).collect()),
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR TopK: key does not exist".into()),
+        };
+
+        if res.is_ok() {
+            state.touch(key);
+        }
+        res
+    }
+
+    /// For each item, returns whether it is in the Top-K list at `key`.
+    pub(crate) fn topk_query(&mut self, key: &str, items: &[Bytes]) -> Result<Vec<bool>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::TopK(topk)) => Ok(items.iter().map(|item| topk.contains(item)).collect()),
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR TopK: key does not exist".into()),
+        }
+    }
+
+    /// Returns the items of the Top-K list at `key` with their estimated
+    /// counts, most frequent first.
+    pub(crate) fn topk_list(&mut self, key: &str) -> Result<Vec<(Bytes, u64)>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::TopK(topk)) => Ok(topk.list()),
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR TopK: key does not exist".into()),
+        }
+    }
+
+    /// Create an empty time series at `key`.
+    ///
+    /// Returns `Err` if the key already exists.
+    pub(crate) fn ts_create(&mut self, key: String, series: TimeSeries) -> Result<(), DbError> {
+        let state = self.state();
+
+        if state.entries.contains_key(&key) {
+            return Err("ERR TSDB: key already exists".into());
+        }
+
+        let notify = series.retention() > 0 && state.retained_series.is_empty();
+        if series.retention() > 0 {
+            state.retained_series.insert(key.clone());
+        }
+
+        state.insert(key, Value::TimeSeries(series), None);
+
+        // Wake up the background task so it starts trimming.
+        if notify {
+            self.notify_background_task();
+        }
+
+        Ok(())
+    }
+
+    /// Append samples, given as `(key, timestamp, value)`, to time series.
+    ///
+    /// When `create` is set, a missing key is created as a copy of that empty
+    /// series. Otherwise, adding to a missing key fails. Each sample is added
+    /// independently and gets its own result.
+    pub(crate) fn ts_add(
+        &mut self,
+        samples: Vec<(String, u64, f64)>,
+        create: Option<TimeSeries>,
+    ) -> Vec<Result<u64, DbError>> {
+        let state = self.state();
+        let mut notify = false;
+
+        let results = samples
+            .into_iter()
+            .map(|(key, timestamp, value)| {
+                if let Some(series) = &create {
+                    if !state.entries.contains_key(&key) {
+                        let series = TimeSeries::new(series.retention(), series.labels().to_vec());
+                        if series.retention() > 0 {
+                            notify |= state.retained_series.is_empty();
+                            state.retained_series.insert(key.clone());
+                        }
+                        state.insert(key.clone(), Value::TimeSeries(series), None);
+                    }
+                }
+
+                state.ts_add(key, timestamp, value).map(|_| timestamp)
+            })
+            .collect();
+
+        if notify {
+            self.notify_background_task();
+        }
+
+        results
+    }
+
+    /// Returns the samples of the time series at `key` between `from` and
+    /// `to`, inclusive, oldest first unless `rev` is set. At most `count`
+    /// samples are returned.
+    pub(crate) fn ts_range(
+        &mut self,
+        key: &str,
+        from: u64,
+        to: u64,
+        aggregation: Option<(Aggregation, u64)>,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Result<Vec<(u64, f64)>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::TimeSeries(series)) => {
+                let mut samples = series.range(from, to, aggregation);
+                if rev {
+                    samples.reverse();
+                }
+                samples.truncate(count.unwrap_or(usize::MAX));
+                Ok(samples)
+            }
+            Some(_) => Err(DbError::WrongType),
+            None => Err("ERR TSDB: the key does not exist".into()),
+        }
+    }
+
+    /// Query every time series matching `filters`, sorted by key.
+    pub(crate) fn ts_mrange(
+        &mut self,
+        from: u64,
+        to: u64,
+        aggregation: Option<(Aggregation, u64)>,
+        filters: &[LabelFilter],
+        rev: bool,
+        count: Option<usize>,
+    ) -> Vec<SeriesRange> {
+        let state = self.state();
+
+        let mut series: Vec<SeriesRange> = state
+            .entries
+            .iter()
+            .filter_map(|(key, entry)| match &entry.value {
+                Value::TimeSeries(series) if series.matches(filters) => {
+                    let mut samples = series.range(from, to, aggregation);
+                    if rev {
+                        samples.reverse();
+                    }
+                    samples.truncate(count.unwrap_or(usize::MAX));
+                    Some((key.clone(), series.labels().to_vec(), samples))
+                }
+                _ => None,
+            })
+            .collect();
+
+        series.sort_by(|a, b| a.0.cmp(&b.0));
+        series
+    }
+
+    /// Add a compaction rule downsampling the time series at `src` into the
+    /// time series at `rule.dst`.
+    pub(crate) fn ts_create_rule(
+        &mut self,
+        src: &str,
+        rule: CompactionRule,
+    ) -> Result<(), DbError> {
+        let state = self.state();
+
+        if src == rule.dst {
+            return Err("ERR TSDB: the source key and destination key should be different".into());
+        }
+
+        match state.entries.get(&rule.dst).map(|entry| &entry.value) {
+            Some(Value::TimeSeries(series)) => {
+                // As in RedisTimeSeries, rules do not chain: a destination
+                // has a single source and no rules of its own. This also
+                // rules out cycles.
+                if !series.rules().is_empty() {
+                    return Err("ERR TSDB: the destination key already has a dst rule".into());
+                }
+            }
+            Some(_) => return Err(DbError::WrongType),
+            None => return Err("ERR TSDB: the key does not exist".into()),
+        }
+        match state.entries.get(src).map(|entry| &entry.value) {
+            Some(Value::TimeSeries(_)) => {}
+            Some(_) => return Err(DbError::WrongType),
+            None => return Err("ERR TSDB: the key does not exist".into()),
+        }
+
+        if state.ts_is_destination(&rule.dst) {
+            return Err("ERR TSDB: the destination key already has a src rule".into());
+        }
+        if state.ts_is_destination(src) {
+            return Err("ERR TSDB: the source key already has a source rule".into());
+        }
+
+        if let Some(Value::TimeSeries(series)) =
+            state.entries.get_mut(src).map(|entry| &mut entry.value)
+        {
+            series.rules_mut().push(rule);
+        }
+
+        state.touch(src);
+        Ok(())
+    }
+
+    /// Remove the compaction rule from `src` into `dst`.
+    pub(crate) fn ts_delete_rule(&mut self, src: &str, dst: &str) -> Result<(), DbError> {
+        let state = self.state();
+
+        match state.entries.get_mut(src).map(|entry| &mut entry.value) {
+            Some(Value::TimeSeries(series)) => {
+                let rules = series.rules_mut();
+                let len = rules.len();
+                rules.retain(|rule| rule.dst != dst);
+
+                if rules.len() == len {
+                    return Err("ERR TSDB: compaction rule does not exist".into());
+                }
+            }
+            Some(_) => return Err(DbError::WrongType),
+            None => return Err("ERR TSDB: the key does not exist".into()),
+        }
+
+        state.touch(src);
+        Ok(())
+    }
+
+    /// Serialize the value at `key` along with its expiration, for `RESTORE`
+    /// to recreate. Returns `None` if the key does not exist.
+    pub(crate) fn dump(&mut self, key: &str) -> Option<Bytes> {
+        let state = self.state();
+
+        let entry = state.entries.get(key)?;
+        Some(dump::encode(
+            &entry.value,
+            entry.expires_at.map(unix_time_ms),
+        ))
+    }
+
+    /// Returns `true` if `key` still holds the value serialized by `dump` to
+    /// `payload`.
+    pub(crate) fn is_unchanged(&mut self, key: &str, payload: &[u8]) -> bool {
+        self.dump(key)
+            .is_some_and(|current| dump::same_value(&current, payload))
+    }
+
+    /// Recreate at `key` the value serialized by `dump`.
+    ///
+    /// `expires_at`, a Unix time in milliseconds, replaces the expiration
+    /// saved in the payload. The key is not created if it expired already.
+    /// Returns `Err` if the payload is invalid, or if the key exists and
+    /// `replace` is not set.
+    pub(crate) fn restore(
+        &mut self,
+        key: String,
+        payload: &Bytes,
+        expires_at: Option<u64>,
+        replace: bool,
+    ) -> Result<(), DbError> {
+        let (value, saved_expires_at) =
+            dump::decode(payload).ok_or("ERR DUMP payload version or checksum are wrong")?;
+
+        let state = self.state();
+
+        if !replace && state.entries.contains_key(&key) {
+            return Err("BUSYKEY Target key name already exists.".into());
+        }
+
+        let now = unix_time_ms(Instant::now());
+        let expire = match expires_at.or(saved_expires_at) {
+            Some(when) if when <= now => {
+                // Like a key that expires right after being restored.
+                if state.remove(&key) {
+                    state.notify_keyspace_event(KeyspaceEvents::GENERIC, "del", &key);
+                }
+                return Ok(());
+            }
+            Some(when) => Some(Duration::from_millis(when - now)),
+            None => None,
+        };
+
+        let mut notify = false;
+        if let Value::TimeSeries(series) = &value {
+            if series.retention() > 0 {
+                notify |= state.retained_series.is_empty();
+                state.retained_series.insert(key.clone());
+            }
+        }
+
+        state.notify_keyspace_event(KeyspaceEvents::GENERIC, "restore", &key);
+        notify |= state.insert(key, value, expire);
+
+        if notify {
+            self.notify_background_task();
+        }
+
+        Ok(())
+    }
+
+    /// Remove `keys`, whatever the type of their values.
+    ///
+    /// Returns the number of keys that existed.
+    pub(crate) fn del(&mut self, keys: &[String]) -> u64 {
+        let state = self.state();
+
+        let mut removed = 0;
+        for key in keys {
+            if state.remove(key) {
+                state.notify_keyspace_event(KeyspaceEvents::GENERIC, "del", key);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    /// Returns every key matching the glob-style `pattern`.
+    pub(crate) fn keys(&mut self, pattern: &[u8]) -> Vec<String> {
+        let state = self.state();
+
+        state
+            .entries
+            .keys()
+            .filter(|key| glob_match(pattern, key.as_bytes()))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the number of keys.
+    pub(crate) fn dbsize(&mut self) -> u64 {
+        self.state().entries.len() as u64
+    }
+
+    /// Returns a random key, or `None` if there are no keys.
+    pub(crate) fn random_key(&mut self) -> Option<String> {
+        let state = self.state();
+
+        if state.entries.is_empty() {
+            return None;
+        }
+
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let nth = hash::mix(seed) % state.entries.len() as u64;
+        state.entries.keys().nth(nth as usize).cloned()
+    }
+
+    /// Returns a page of keys starting at `cursor`, along with the cursor of
+    /// the next page. `count` bounds the number of keys examined.
+    ///
+    /// The examined keys are then filtered by the glob-style `pattern` and by
+    /// the name of their type, as reported by `TYPE`. Pages may therefore be
+    /// empty before the iteration completes.
+    pub(crate) fn scan(
+        &mut self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        ty: Option<&str>,
+    ) -> (u64, Vec<String>) {
+        let state = self.state();
+
+        let (cursor, entries) = state.entries.scan(cursor, count);
+
+        let keys = entries
+            .into_iter()
+            .filter(|(key, _)| pattern.is_none_or(|pattern| glob_match(pattern, key.as_bytes())))
+            .filter(|(_, entry)| {
+                ty.is_none_or(|ty| entry.value.type_name().eq_ignore_ascii_case(ty))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        (cursor, keys)
+    }
+
+    /// Returns a page of the fields of the hash at `key`, with their values,
+    /// along with the cursor of the next page. Works like `scan`, with
+    /// `pattern` matching field names.
+    pub(crate) fn hscan(
+        &mut self,
+        key: &str,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<(u64, Vec<(Bytes, Bytes)>), DbError> {
+        let state = self.state();
+
+        let hash = match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return Err(DbError::WrongType),
+            None => return Ok((0, vec![])),
+        };
+
+        let (cursor, fields) = hash.scan(cursor, count);
+
+        let fields = fields
+            .into_iter()
+            .filter(|(field, _)| pattern.is_none_or(|pattern| glob_match(pattern, field)))
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+
+        Ok((cursor, fields))
+    }
+
+    /// Set `fields` in the hash at `key`, creating the hash if the key does not
+    /// exist.
+    ///
+    /// Returns the number of fields that were added rather than updated.
+    pub(crate) fn hset(
+        &mut self,
+        key: String,
+        fields: Vec<(Bytes, Bytes)>,
+    ) -> Result<u64, DbError> {
+        let state = self.state();
+
+        let entry = state.entries.get_or_insert_with(key.clone(), || Entry {
+            value: Value::Hash(ScanMap::new()),
+            expires_at: None,
+        });
+
+        let added = match &mut entry.value {
+            Value::Hash(hash) => fields
+                .into_iter()
+                .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+                .count(),
+            _ => return Err(DbError::WrongType),
+        };
+
+        state.reindex(&key);
+        state.touch(&key);
+        state.notify_keyspace_event(KeyspaceEvents::HASH, "hset", &key);
+        Ok(added as u64)
+    }
+
+    /// Get the value of `field` in the hash at `key`.
+    pub(crate) fn hget(&mut self, key: &str, field: &[u8]) -> Result<Option<Bytes>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Hash(hash)) => Ok(hash.get(field).cloned()),
+            Some(_) => Err(DbError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove `fields` from the hash at `key`. The key is removed along with
+    /// its last field.
+    ///
+    /// Returns the number of fields that were removed.
+    pub(crate) fn hdel(&mut self, key: &str, fields: &[Bytes]) -> Result<u64, DbError> {
+        let state = self.state();
+
+        let (removed, empty) = match state.entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(Value::Hash(hash)) => {
+                let removed = fields
+                    .iter()
+                    .filter(|field| hash.remove(&field[..]).is_some())
+                    .count();
+                (removed, hash.is_empty())
+            }
+            Some(_) => return Err(DbError::WrongType),
+            None => return Ok(0),
+        };
+
+        if removed > 0 {
+            state.touch(key);
+            state.notify_keyspace_event(KeyspaceEvents::HASH, "hdel", key);
+        }
+
+        if empty {
+            state.remove(key);
+            state.notify_keyspace_event(KeyspaceEvents::GENERIC, "del", key);
+        }
+
+        state.reindex(key);
+        Ok(removed as u64)
+    }
+
+    /// Returns every field of the hash at `key` along with its value.
+    pub(crate) fn hgetall(&mut self, key: &str) -> Result<Vec<(Bytes, Bytes)>, DbError> {
+        let state = self.state();
+
+        match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Hash(hash)) => Ok(hash
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect()),
+            Some(_) => Err(DbError::WrongType),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Create the search index `name`, then index the existing keys it covers.
+    ///
+    /// Returns `Err` if an index with this name already exists.
+    pub(crate) fn ft_create(&mut self, name: String, mut index: Index) -> Result<(), DbError> {
+        let state = self.state();
+
+        if state.indexes.contains_key(&name) {
+            return Err("ERR Index already exists".into());
+        }
+
+        for (key, entry) in &state.entries {
+            if let Value::Hash(hash) = &entry.value {
+                index.update(key, Some(hash));
+            }
+        }
+
+        state.indexes.insert(name, index);
+        Ok(())
+    }
+
+    /// Drop the search index `name`. The indexed keys are left untouched.
+    pub(crate) fn ft_dropindex(&mut self, name: &str) -> Result<(), DbError> {
+        let state = self.state();
+
+        match state.indexes.remove(name) {
+            Some(_) => Ok(()),
+            None => Err("ERR Unknown Index name".into()),
+        }
+    }
+
+    /// Run `query` against the search index `name`.
+    ///
+    /// Results come in the query's order, or ordered by the hash field
+    /// `sort_by` if given, ascending unless the flag is `false`. Fields holding
+    /// numbers compare as numbers and documents missing the field come last.
+    /// The results are then paged by skipping `offset` and keeping `count`.
+    ///
+    /// Returns the total number of matches along with the page of documents
+    /// and their fields.
+    pub(crate) fn ft_search(
+        &mut self,
+        name: &str,
+        query: &Query,
+        sort_by: Option<(&str, bool)>,
+        offset: usize,
+        count: usize,
+    ) -> Result<(usize, Vec<SearchResult>), DbError> {
+        let state = self.state();
+
+        let index = state.indexes.get(name).ok_or("ERR Unknown Index name")?;
+        let mut matches = index.search(query)?;
+        let total = matches.len();
+
+        let hash = |key: &str| match state.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Hash(hash)) => Some(hash),
+            _ => None,
+        };
+
+        if let Some((field, ascending)) = sort_by {
+            if !index.has_field(field) {
+                return Err(format!("ERR Property `{}` not in schema", field).into());
+            }
+
+            let sort_value = |key: &str| hash(key).and_then(|hash| hash.get(field.as_bytes()));
+
+            matches.sort_by(|(a, _), (b, _)| match (sort_value(a), sort_value(b)) {
+                (Some(a), Some(b)) => {
+                    let number = |value: &Bytes| {
+                        std::str::from_utf8(value)
+                            .ok()
+                            .and_then(|s| s.parse::<f64>().ok())
+                    };
+
+                    let order = match (number(a), number(b)) {
+                        (Some(a), Some(b)) => a.total_cmp(&b),
+                        _ => a.cmp(b),
+                    };
+
+                    if ascending {
+                        order
+                    } else {
+                        order.reverse()
+                    }
+                }
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            });
+        }
+
+        let docs = matches
+            .into_iter()
+            .skip(offset)
+            .take(count)
+            .map(|(key, score)| {
+                let fields = hash(&key)
+                    .map(|hash| {
+                        hash.iter()
+                            .map(|(field, value)| (field.clone(), value.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                (key, score, fields)
+            })
+            .collect();
+
+        Ok((total, docs))
+    }
+
+    /// Publish a message to the channel, like `Db::publish`, but without
+    /// waiting for subscribers applying backpressure.
+    ///
+    /// This is used to publish while holding the lock, as part of a
+    /// transaction. A full backpressure subscriber lags behind instead.
+    pub(crate) fn publish(&mut self, key: &str, value: Bytes) -> usize {
+        self.state().publish(key, value)
+    }
+
+    /// Publish a message to the shard channel, like `Db::spublish`, but
+    /// without waiting for subscribers applying backpressure.
+    pub(crate) fn spublish(&mut self, key: &str, value: Bytes) -> usize {
+        self.state().spublish(key, value)
+    }
+
+    /// Returns the channels with at least one subscriber, optionally only
+    /// those matching the glob-style `pattern`.
+    ///
+    /// Subscribers of patterns are not counted.
+    pub(crate) fn pubsub_channels(&mut self, pattern: Option<&[u8]>) -> Vec<String> {
+        let state = self.state();
+        active_channels(&state.pub_sub, pattern)
+    }
+
+    /// Returns the number of subscribers of each of `channels`. Subscribers
+    /// of patterns are not counted.
+    pub(crate) fn pubsub_numsub(&mut self, channels: &[String]) -> Vec<usize> {
+        let state = self.state();
+        count_subscribers(&state.pub_sub, channels)
+    }
+
+    /// Returns the shard channels with at least one subscriber, optionally
+    /// only those matching the glob-style `pattern`.
+    pub(crate) fn pubsub_shardchannels(&mut self, pattern: Option<&[u8]>) -> Vec<String> {
+        let state = self.state();
+        active_channels(&state.shard_sub, pattern)
+    }
+
+    /// Returns the number of subscribers of each of the shard `channels`.
+    pub(crate) fn pubsub_shardnumsub(&mut self, channels: &[String]) -> Vec<usize> {
+        let state = self.state();
+        count_subscribers(&state.shard_sub, channels)
+    }
+
+    /// Returns the number of distinct patterns with at least one subscriber.
+    pub(crate) fn pubsub_numpat(&mut self) -> usize {
+        let state = self.state();
+
+        state
+            .pattern_sub
+            .values()
+            .filter(|tx| tx.receiver_count() > 0)
+            .count()
+    }
+
+    /// Compile `source` and add it to the script cache, unless it is already
+    /// cached. Returns the script and its SHA-1 digest.
+    pub(crate) fn script_load(
+        &mut self,
+        source: &[u8],
+    ) -> Result<(String, Arc<CachedScript>), DbError> {
+        let state = self.state();
+        let sha = sha1_hex(source);
+
+        if let Some(script) = state.scripts.get(&sha) {
+            return Ok((sha, script.clone()));
+        }
+
+        let script = Arc::new(CachedScript::compile(source)?);
+        state.scripts.insert(sha.clone(), script.clone());
+        Ok((sha, script))
+    }
+
+    /// Returns the cached script with the SHA-1 digest `sha`.
+    pub(crate) fn script_get(&mut self, sha: &str) -> Option<Arc<CachedScript>> {
+        let state = self.state();
+        state.scripts.get(&sha.to_lowercase()).cloned()
+    }
+
+    /// Returns whether each of the `shas` is cached.
+    pub(crate) fn script_exists(&mut self, shas: &[String]) -> Vec<bool> {
+        let state = self.state();
+        shas.iter()
+            .map(|sha| state.scripts.contains_key(&sha.to_lowercase()))
+            .collect()
+    }
+
+    /// Empty the script cache.
+    pub(crate) fn script_flush(&mut self) {
+        let state = self.state();
+        state.scripts.clear();
+    }
+
+    /// Register a script as running, for `Db::lock_unless_busy` and
+    /// `Db::kill_script`. The script runs until the returned
+    /// `RunningScript` is dropped.
+    pub(crate) fn start_script(&mut self) -> RunningScript<'a> {
+        let busy_after = Duration::from_millis(self.state().config.busy_reply_threshold);
+
+        let control = Arc::new(ScriptControl {
+            started: Instant::now(),
+            busy_after,
+            status: Mutex::new(ScriptStatus::Running),
+        });
+        *self.shared.running_script.lock().unwrap() = Some(control.clone());
+        self.shared.script_changed.notify_waiters();
+
+        RunningScript {
+            shared: self.shared,
+            control,
+        }
+    }
+
+    /// Write a snapshot of the keys to disk. The lock is held until the
+    /// snapshot is written.
+    pub(crate) fn save(&mut self) -> Result<(), DbError> {
+        let state = self.state();
+
+        if state.saving {
+            return Err("ERR Background save already in progress".into());
+        }
+
+        state.last_save_attempt = Instant::now();
+        state
+            .snapshot()
+            .write(&state.config.snapshot_path())
+            .map_err(|err| format!("ERR {}", err))?;
+
+        state.saved(state.dirty);
+        Ok(())
+    }
+
+    /// Start writing a snapshot of the keys to disk in the background. The
+    /// lock is only held while the values are cloned.
+    pub(crate) fn bgsave(&mut self) -> Result<(), DbError> {
+        let shared = self.shared;
+        self.state().bgsave(shared)
+    }
+
+    /// Returns the Unix time in seconds of the last successful save.
+    pub(crate) fn last_save(&mut self) -> u64 {
+        self.state().last_save
+    }
+
+    /// Load the snapshot written by a previous save, if any, adding its keys.
+    /// Keys that expired in the meantime are skipped.
+    ///
+    /// As in Redis, the snapshot is loaded whether or not save rules are
+    /// set, so that one written by `SAVE` is picked up.
+    ///
+    /// Returns the number of keys loaded.
+    pub(crate) fn load_snapshot(&mut self) -> Result<usize, DbError> {
+        let state = self.state();
+        let snapshot = match Snapshot::read(&state.config.snapshot_path())? {
+            Some(snapshot) => snapshot,
+            None => return Ok(0),
+        };
+
+        let count = state.load(snapshot);
+
+        // The loaded keys are already on disk.
+        state.saved_dirty = state.dirty;
+
+        // The background task may have keys to expire or series to trim.
+        self.notify_background_task();
+        Ok(count)
+    }
+
+    /// Returns the commands logged to the append-only file by a previous
+    /// run, for the server to replay, or `None` if `appendonly` is disabled.
+    ///
+    /// The keys of the snapshot preamble of the file, if any, are loaded
+    /// right away.
+    pub(crate) fn read_aof(&mut self) -> Result<Option<Vec<Frame>>, DbError> {
+        let state = self.state();
+        if !state.config.appendonly {
+            return Ok(None);
+        }
+
+        let (preamble, frames) = aof::read(&state.config.aof_path())?;
+        if let Some(preamble) = preamble {
+            state.load(preamble);
+            self.notify_background_task();
+        }
+
+        Ok(Some(frames))
+    }
+
+    /// Start logging write commands to the append-only file, if `appendonly`
+    /// is enabled. This is done once the file was replayed.
+    pub(crate) fn open_aof(&mut self) -> Result<(), DbError> {
+        let state = self.state();
+        if !state.config.appendonly {
+            return Ok(());
+        }
+
+        let aof = Aof::open(&state.config.aof_path()).map_err(|err| format!("ERR {}", err))?;
+        state.aof = Some(aof);
+
+        // The background task flushes the file every second.
+        self.notify_background_task();
+        Ok(())
+    }
+
+    /// Returns the entries of the Redis RDB file set with `rdb-import`, for
+    /// the server to import on startup. There are none if it is not set.
+    pub(crate) fn read_rdb_import(&mut self) -> Result<Vec<rdb::Entry>, DbError> {
+        let path = &self.state().config.rdb_import;
+        if path.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let data = std::fs::read(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+        rdb::read(&data).map_err(|err| format!("{} is not a valid RDB file: {}", path, err).into())
+    }
+
+    /// Add the keys of `entries`, read from a Redis RDB file, with their
+    /// expirations. Keys that expired already are left out.
+    ///
+    /// The values are stored as is rather than through the commands
+    /// recreating them, which are only logged to the append-only file and
+    /// propagated to replicas, if any. Returns the number of keys imported,
+    /// and of keys skipped because of their type or a name that is not valid
+    /// UTF-8.
+    pub(crate) fn import_rdb(&mut self, entries: Vec<rdb::Entry>) -> (usize, usize) {
+        let logs_writes = self.logs_writes();
+        let now = unix_time_ms(Instant::now());
+        let (mut count, mut skipped, mut notify) = (0, 0, false);
+
+        for entry in entries {
+            let frame = logs_writes.then(|| entry.clone().into_command()).flatten();
+
+            let key = String::from_utf8(entry.key.to_vec()).ok();
+            let (key, value) = match (key, Value::from_rdb(entry.value)) {
+                (Some(key), Some(value)) => (key, value),
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            let expire = match entry.expires_at {
+                Some(when) if when <= now => continue,
+                Some(when) => Some(Duration::from_millis(when - now)),
+                None => None,
+            };
+
+            notify |= self.state().insert(key, value, expire);
+            if let Some(frame) = frame {
+                self.propagate(&frame);
+            }
+            count += 1;
+        }
+
+        if notify {
+            self.notify_background_task();
+        }
+        (count, skipped)
+    }
+
+    /// Write the keys to the Redis RDB file `filename`, within `dir`, for
+    /// stock Redis to load.
+    ///
+    /// Only strings and hashes have a Redis equivalent. Returns the number
+    /// of keys written.
+    pub(crate) fn export_rdb(&mut self, filename: &str) -> Result<usize, DbError> {
+        if filename.is_empty() || filename.contains('/') {
+            return Err("ERR invalid file name".into());
+        }
+
+        let state = self.state();
+        let now = Instant::now();
+        let entries: Vec<_> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|when| when > now))
+            .filter_map(|(key, entry)| {
+                let value = match &entry.value {
+                    Value::String(value) => rdb::Value::String(value.clone()),
+                    Value::Hash(hash) => rdb::Value::Hash(
+                        hash.iter()
+                            .map(|(field, value)| (field.clone(), value.clone()))
+                            .collect(),
+                    ),
+                    _ => return None,
+                };
+
+                Some(rdb::Entry {
+                    db: 0,
+                    key: Bytes::from(key.clone()),
+                    value,
+                    expires_at: entry.expires_at.map(unix_time_ms),
+                })
+            })
+            .collect();
+
+        // Written to a temporary file first, like snapshots, so that the
+        // file is never left half written.
+        let path = std::path::PathBuf::from(&state.config.dir).join(filename);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, rdb::write(&entries))
+            .and_then(|()| std::fs::rename(&tmp, &path))
+            .map_err(|err| format!("ERR {}", err))?;
+
+        Ok(entries.len())
+    }
+
+    /// Returns `true` if write commands are logged to the append-only file,
+    /// or to the file being rewritten, or propagated to replicas.
+    pub(crate) fn logs_writes(&mut self) -> bool {
+        let state = self.state();
+        state.aof.is_some() || state.aof_rewrite.is_some() || state.replication.is_active()
+    }
+
+    /// Log the write command `frame` to the append-only file and propagate
+    /// it to the replicas. Between `begin_batch` and `end_batch`, it is held
+    /// back until the end of the batch.
+    pub(crate) fn propagate(&mut self, frame: &Frame) {
+        let state = self.state();
+        if let Some(batch) = &mut state.batch {
+            batch.push(frame.clone());
+            return;
+        }
+
+        let fsync = state.config.appendfsync;
+        let buf = aof::encode(frame);
+
+        if state.replication.is_active() {
+            state.feed_replicas(&buf);
+        }
+
+        if let Some(aof) = &mut state.aof {
+            if let Err(err) = aof.append(&buf, fsync) {
+                error!(cause = %err, "failed to write to the append-only file");
+            }
+        }
+
+        if let Some(rewrite) = &mut state.aof_rewrite {
+            rewrite.extend_from_slice(&buf);
+        }
+    }
+
+    /// Hold back the write commands propagated from now on, those of a
+    /// transaction, until `end_batch`.
+    pub(crate) fn begin_batch(&mut self) {
+        self.state().batch = Some(vec![]);
+    }
+
+    /// Propagate the write commands held back since `begin_batch`. Several
+    /// commands are wrapped in `MULTI` and `EXEC`, so that a truncated
+    /// append-only file or a broken replication link never applies part of
+    /// the transaction.
+    pub(crate) fn end_batch(&mut self) {
+        let frames = self.state().batch.take().unwrap_or_default();
+        if frames.len() > 1 {
+            self.propagate(&Multi::new().into_frame());
+        }
+        for frame in &frames {
+            self.propagate(frame);
+        }
+        if frames.len() > 1 {
+            self.propagate(&Exec::new().into_frame());
+        }
+    }
+
+    /// Returns `true` if this server replicates a primary, and is read-only.
+    pub(crate) fn is_replica(&mut self) -> bool {
+        self.state().replication.primary.is_some()
+    }
+
+    /// Attach a replica continuing from `offset` in the history `replid`,
+    /// or synchronizing from a snapshot if the bytes it misses are no longer
+    /// in the backlog.
+    ///
+    /// Returns the id of the replica, to detach it with, how it starts, and
+    /// the receiver of the bytes propagated from now on.
+    pub(crate) fn attach_replica(
+        &mut self,
+        replid: &str,
+        offset: u64,
+        ip: String,
+        port: u16,
+    ) -> (u64, SyncStart, ReplicaFeed) {
+        let state = self.state();
+
+        let start = match state.replication.since(replid, offset) {
+            Some(missed) => SyncStart::Continue {
+                replid: state.replication.replid.clone(),
+                missed,
+            },
+            None => SyncStart::Full {
+                replid: state.replication.replid.clone(),
+                offset: state.replication.offset,
+                snapshot: state.snapshot(),
+            },
+        };
+
+        let (id, feed) = state.replication.attach(ip, port);
+
+        // The background task pings the replicas.
+        self.notify_background_task();
+        (id, start, feed)
+    }
+
+    pub(crate) fn detach_replica(&mut self, id: u64) {
+        self.state().replication.detach(id);
+    }
+
+    /// Returns how long a replication link may stay silent before it is
+    /// dropped, `repl-timeout`.
+    pub(crate) fn repl_timeout(&mut self) -> Duration {
+        Duration::from_secs(self.state().config.repl_timeout)
+    }
+
+    /// Returns the replication ID and offset this server is at, for a
+    /// replica to continue from, and the port it listens on.
+    pub(crate) fn replication_position(&mut self) -> (String, u64, u16) {
+        let replication = &self.state().replication;
+        (
+            replication.replid.clone(),
+            replication.offset,
+            replication.listening_port,
+        )
+    }
+
+    /// Returns the state of replication, as reported by `ROLE` and `INFO
+    /// replication`.
+    pub(crate) fn replication_info(&mut self) -> ReplicationInfo {
+        let state = self.state();
+        state.replication.info(state.config.repl_backlog_size)
+    }
+
+    /// Ask the replicas to acknowledge their offset with `REPLCONF GETACK`.
+    ///
+    /// The request is part of the history, so that the replicas reply once
+    /// they processed everything before it.
+    fn request_acks(&mut self) {
+        let state = self.state();
+        if !state.replication.is_active() {
+            return;
+        }
+
+        let frame = Replconf::new(vec![("getack".to_string(), "*".to_string())]).into_frame();
+        state.feed_replicas(&aof::encode(&frame));
+    }
+
+    /// Returns the address of the primary set with `replicaof` to replicate
+    /// from startup, if any.
+    pub(crate) fn configured_primary(&mut self) -> Option<(String, u16)> {
+        self.state().config.replicaof()
+    }
+
+    /// Record the port the server listens on, announced to primaries.
+    pub(crate) fn set_listening_port(&mut self, port: u16) {
+        self.state().replication.listening_port = port;
+    }
+
+    /// Replace every key by those of the primary's `snapshot`, taken at
+    /// `offset` in the history `replid`. Returns the number of keys loaded.
+    pub(crate) fn full_sync(
+        &mut self,
+        snapshot: Bytes,
+        replid: String,
+        offset: u64,
+    ) -> Result<usize, DbError> {
+        let snapshot = Snapshot::decode(snapshot).ok_or("ERR invalid snapshot from the primary")?;
+
+        let state = self.state();
+        let keys: Vec<_> = state.entries.keys().cloned().collect();
+        for key in keys {
+            state.remove(&key);
+        }
+        let count = state.load(snapshot);
+        state.replication.reset(replid, offset);
+
+        self.notify_background_task();
+        Ok(count)
+    }
+
+    /// Continue the history `replid` of the primary from the current offset.
+    pub(crate) fn continue_sync(&mut self, replid: String) {
+        self.state().replication.continue_as(replid);
+    }
+
+    /// Add `buf`, received from the primary and applied, to the history.
+    pub(crate) fn replication_feed(&mut self, buf: &[u8]) {
+        let state = self.state();
+        state.feed_replicas(buf);
+
+        if let Some(primary) = &mut state.replication.primary {
+            primary.last_io = Some(Instant::now());
+        }
+    }
+
+    /// Flag the link to the primary as up, once synchronized, or down.
+    pub(crate) fn set_link_up(&mut self, up: bool) {
+        if let Some(primary) = &mut self.state().replication.primary {
+            primary.link_up = up;
+            if up {
+                primary.last_io = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Stop replicating, turning this replica into a primary. Does nothing
+    /// if it is a primary already.
+    pub(crate) fn replicaof_no_one(&mut self) {
+        let replication = &mut self.state().replication;
+        if let Some(primary) = replication.primary.take() {
+            primary.task.abort();
+            replication.promote();
+        }
+    }
+
+    /// Returns `true` if `cluster-enabled` is set.
+    pub(crate) fn cluster_enabled(&mut self) -> bool {
+        self.state().config.cluster_enabled
+    }
+
+    /// Returns the state of the cluster, or an error out of cluster mode.
+    pub(crate) fn cluster(&mut self) -> Result<&mut Cluster, DbError> {
+        match &mut self.state().cluster {
+            Some(cluster) => Ok(cluster),
+            None => Err("ERR This instance has cluster support disabled".into()),
+        }
+    }
+
+    /// Check that this node serves `keys`, the keys of a command, in cluster
+    /// mode.
+    ///
+    /// Returns the error to reply otherwise: `MOVED` to the node serving
+    /// their slot, or `ASK` to the node the slot is being moved to if the
+    /// keys are no longer here. Slots being moved to this node are served to
+    /// connections `asking`, which sent `ASKING` before the command.
+    pub(crate) fn check_slot(&mut self, keys: &[Bytes], asking: bool) -> Result<(), DbError> {
+        let state = self.state();
+        let cluster = match &state.cluster {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+
+        let slot = match keys.first() {
+            Some(key) => key_slot(key),
+            None => return Ok(()),
+        };
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Err("CROSSSLOT Keys in request don't hash to the same slot".into());
+        }
+
+        if !cluster.is_ok() {
+            return Err("CLUSTERDOWN The cluster is down".into());
+        }
+
+        let owner = match cluster.owner(slot) {
+            Some(owner) => owner,
+            None => return Err("CLUSTERDOWN Hash slot not served".into()),
+        };
+
+        if owner.id != cluster.myself().id {
+            if asking && cluster.is_importing(slot) {
+                return Ok(());
+            }
+            return Err(format!("MOVED {} {}:{}", slot, owner.ip, owner.port).into());
+        }
+
+        if let Some(target) = cluster.migrating_to(slot) {
+            let missing = keys
+                .iter()
+                .filter(|key| match std::str::from_utf8(key) {
+                    Ok(key) => !state.entries.contains_key(key),
+                    Err(_) => true,
+                })
+                .count();
+
+            if missing == keys.len() {
+                return Err(format!("ASK {} {}:{}", slot, target.ip, target.port).into());
+            }
+            if missing > 0 {
+                return Err("TRYAGAIN Multiple keys request during rehashing of slot".into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns up to `count` keys of `slot`, sorted.
+    pub(crate) fn keys_in_slot(&mut self, slot: u16, count: usize) -> Vec<String> {
+        let mut keys: Vec<_> = self
+            .state()
+            .entries
+            .keys()
+            .filter(|key| key_slot(key.as_bytes()) == slot)
+            .cloned()
+            .collect();
+
+        keys.sort();
+        keys.truncate(count);
+        keys
+    }
+
+    /// Returns the heartbeat of this node, to send to the node `id` with
+    /// `Ping`, recording that it was sent.
+    pub(crate) fn cluster_ping(&mut self, id: &str) -> Option<Heartbeat> {
+        let cluster = self.state().cluster.as_mut()?;
+        cluster.ping_sent(id);
+        Some(cluster.heartbeat())
+    }
+
+    /// Returns the heartbeat of this node, to send with `Meet`.
+    pub(crate) fn cluster_heartbeat(&mut self) -> Option<Heartbeat> {
+        Some(self.state().cluster.as_ref()?.heartbeat())
+    }
+
+    /// Returns the address of the cluster bus of the node `id`, if known.
+    pub(crate) fn cluster_bus_addr(&mut self, id: &str) -> Option<(String, u16)> {
+        self.state().cluster.as_ref()?.bus_addr(id)
+    }
+
+    /// Process `msg`, received on the cluster bus from `ip`, and return the
+    /// reply to send, if any.
+    pub(crate) fn cluster_receive(&mut self, msg: BusMessage, ip: &str) -> Option<BusMessage> {
+        self.state().cluster.as_mut()?.receive(msg, ip)
+    }
+
+    /// Check for failing nodes, and return what the cluster bus has to do
+    /// next.
+    pub(crate) fn cluster_cron(&mut self) -> Option<Outbox> {
+        let state = self.state();
+        let node_timeout = Duration::from_millis(state.config.cluster_node_timeout);
+        Some(state.cluster.as_mut()?.cron(node_timeout))
+    }
+
+    /// Returns the cluster node timeout.
+    pub(crate) fn cluster_node_timeout(&mut self) -> Duration {
+        Duration::from_millis(self.state().config.cluster_node_timeout)
+    }
+
+    /// Start rewriting the append-only file in the background. The lock is
+    /// only held while the commands rebuilding the data are generated.
+    pub(crate) fn bgrewriteaof(&mut self) -> Result<(), DbError> {
+        let shared = self.shared;
+        self.state().bgrewriteaof(shared)
+    }
+
+    /// Set the parameter `name` to `value` on startup, before any command is
+    /// executed. Unlike `config_set`, this accepts every parameter.
+    pub(crate) fn config_init(&mut self, name: &str, value: &str) -> Result<(), DbError> {
+        Ok(self.state().config.set(name, value)?)
+    }
+
+    /// Returns the parameters matching the glob-style `pattern`, with their
+    /// values.
+    pub(crate) fn config_get(&mut self, pattern: &[u8]) -> Vec<(&'static str, String)> {
+        let state = self.state();
+        state.config.get(pattern)
+    }
+
+    /// Set the parameter `name` to `value`.
+    pub(crate) fn config_set(&mut self, name: &str, value: &str) -> Result<(), DbError> {
+        if STARTUP_PARAMETERS
+            .iter()
+            .any(|param| name.eq_ignore_ascii_case(param))
+        {
+            return Err(format!(
+                "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                name
+            )
+            .into());
+        }
+
+        let shared = self.shared;
+        let state = self.state();
+        state.config.set(name, value)?;
+
+        // Turning the append-only file on writes it from the current data,
+        // after which write commands are appended to it.
+        if name.eq_ignore_ascii_case("appendonly") {
+            if !state.config.appendonly {
+                state.aof = None;
+            } else if state.aof.is_none() && state.aof_rewrite.is_none() {
+                state.bgrewriteaof(shared)?;
+            }
+        }
+
+        // Logs shrink lazily, on the next message. Turning logging off drops
+        // them at once.
+        if state.config.pubsub_log_size == 0 {
+            state.logs.clear();
+        }
+
+        // The background task checks the save rules, flushes the append-only
+        // file and evicts logs.
+        if name.eq_ignore_ascii_case("save")
+            || name.eq_ignore_ascii_case("appendfsync")
+            || name.eq_ignore_ascii_case("pubsub-log-size")
+        {
+            self.notify_background_task();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for LockedDb<'_> {
+    fn drop(&mut self) {
+        // Release the mutex before notifying the background task. This helps
+        // reduce contention by avoiding the background task waking up only to
+        // be unable to acquire the mutex due to this guard still holding it.
+        drop(self.state.take());
+
+        if self.notify {
+            self.shared.backgroup_task.notify_one();
+        }
+    }
+}
+
+impl Shared {
+    /// See `Db::lock`.
+    async fn lock(self: &Arc<Self>) -> LockedDb<'_> {
+        let gate = self.script_gate.read().await;
+        self.locked(Gate::Read(gate))
+    }
+
+    /// Lock `state` from a blocking thread, such as those of
+    /// `tokio::task::spawn_blocking`. Must not be called from a task.
+    fn blocking_lock(self: &Arc<Self>) -> LockedDb<'_> {
+        let gate = self.script_gate.blocking_read();
+        self.locked(Gate::Read(gate))
+    }
+
+    /// Lock `state`, holding `gate`.
+    fn locked<'a>(self: &'a Arc<Self>, gate: Gate<'a>) -> LockedDb<'a> {
+        LockedDb {
+            state: Some(self.state.lock().unwrap()),
+            _gate: gate,
+            shared: self,
+            notify: false,
+        }
+    }
+
+    /// Purge all expired keys and return the `Instant` at which the **next**
+    /// key will expire. The background task will sleep until this instant.
+    async fn purge_expired_key(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown {
+            // The database is shutting down. All handles to the shared state
+            // have dropped. The background task should exit.
+            return None;
+        }
+
+        // Find all keys scheduled to expire **before** now.
+        let now = Instant::now();
+
+        while let Some(&(when, ref key)) = state.expirations.iter().next() {
+            if when > now {
+                return Some(when);
+            }
+
+            // The key iss expired, remove it
+            let key = key.clone();
+            state.entries.remove(&key);
+            state.reindex(&key);
+            state.touch(&key);
+            state.notify_keyspace_event(KeyspaceEvents::EXPIRED, "expired", &key);
+            state.expirations.remove(&(when, key));
+        }
+        None
+    }
+
+    /// Trim every time series with a retention period. Returns the `Instant`
+    /// at which the next trim is due, or `None` if no series needs trimming.
+    async fn trim_time_series(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown {
+            return None;
+        }
+
+        state
+            .retained_series
+            .retain(|key| match state.entries.get_mut(key) {
+                Some(Entry {
+                    value: Value::TimeSeries(series),
+                    ..
+                }) => {
+                    series.trim();
+                    true
+                }
+                // The series was deleted or overwritten by another value type.
+                _ => false,
+            });
+
+        if state.retained_series.is_empty() {
+            None
+        } else {
+            Some(Instant::now() + RETENTION_INTERVAL)
+        }
+    }
+
+    /// Drop the logs of the channels without subscribers that received no
+    /// message for `pubsub-log-ttl` seconds. Returns the `Instant` at which
+    /// the logs must be checked again, or `None` if logging is disabled.
+    ///
+    /// A subscriber coming back after the log of its channel was dropped
+    /// replays nothing, and the offsets of the channel start over from zero.
+    async fn evict_logs(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown || state.config.pubsub_log_size == 0 {
+            return None;
+        }
+
+        let ttl = state.config.pubsub_log_ttl;
+        if ttl > 0 {
+            let now = Instant::now();
+            state.logs.retain(|channel, log| {
+                state.pub_sub.contains_key(channel)
+                    || now.duration_since(log.last_append()) < Duration::from_secs(ttl)
+            });
+        }
+
+        Some(Instant::now() + LOG_EVICTION_INTERVAL)
+    }
+
+    /// Start a `BGSAVE` if one of the save rules is met. Returns the
+    /// `Instant` at which the rules must be checked again, or `None` if
+    /// saving is disabled.
+    async fn check_save_rules(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown || state.config.save.is_empty() {
+            return None;
+        }
+
+        let changes = state.dirty - state.saved_dirty;
+        let elapsed = state.last_save_attempt.elapsed();
+        let due = state.config.save.iter().any(|&(seconds, min_changes)| {
+            changes >= min_changes && elapsed >= Duration::from_secs(seconds)
+        });
+
+        if due && !state.saving {
+            // Errors are logged by `bgsave`.
+            let _ = state.bgsave(self);
+        }
+
+        Some(Instant::now() + SAVE_CHECK_INTERVAL)
+    }
+
+    /// Flush the append-only file to disk in the background, with the
+    /// `everysec` policy. Returns the `Instant` of the next flush, or `None`
+    /// if the policy is different.
+    async fn fsync_aof(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown || state.config.appendfsync != AppendFsync::EverySec {
+            return None;
+        }
+
+        let file = match state.aof.as_mut()?.take_unsynced() {
+            Ok(file) => file,
+            Err(err) => {
+                error!(cause = %err, "failed to flush the append-only file");
+                None
+            }
+        };
+
+        if let Some(file) = file {
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = file.sync_data() {
+                    error!(cause = %err, "failed to flush the append-only file");
+                }
+            });
+        }
+
+        Some(Instant::now() + AOF_FSYNC_INTERVAL)
+    }
+
+    /// Propagate a `PING` to the replicas if none was for `REPL_PING_PERIOD`.
+    /// Returns the `Instant` of the next ping, or `None` if no replica is
+    /// attached.
+    ///
+    /// The ping is part of the history, like the write commands.
+    async fn ping_replicas(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown || !state.replication.is_active() || !state.replication.has_replicas() {
+            return None;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(state.replication.last_ping) >= REPL_PING_PERIOD {
+            let frame = Ping::new(None).into_frame();
+            state.feed_replicas(&aof::encode(&frame));
+            state.replication.last_ping = now;
+        }
+
+        Some(state.replication.last_ping + REPL_PING_PERIOD)
+    }
+
+    /// Start rewriting the append-only file if it grew by
+    /// `auto-aof-rewrite-percentage` since it was last rewritten. Returns the
+    /// `Instant` at which to check again, or `None` if the file is not open.
+    async fn check_aof_rewrite(self: &Arc<Self>) -> Option<Instant> {
+        let mut db = self.lock().await;
+        let state = db.state();
+
+        if state.shutdown {
+            return None;
+        }
+
+        let percentage = state.config.auto_aof_rewrite_percentage;
+        let min_size = state.config.auto_aof_rewrite_min_size;
+        let due = state.aof.as_ref()?.needs_rewrite(percentage, min_size);
+
+        if due && state.aof_rewrite.is_none() {
+            // Errors are logged by `bgrewriteaof`.
+            let _ = state.bgrewriteaof(self);
+        }
+
+        Some(Instant::now() + AOF_REWRITE_CHECK_INTERVAL)
+    }
+
+    async fn is_shutdown(self: &Arc<Self>) -> bool {
+        self.lock().await.state().shutdown
+    }
+}
+
+impl RunningScript<'_> {
+    /// Returns `true` once the script was killed by `SCRIPT KILL`.
+    pub(crate) fn killed(&self) -> bool {
+        *self.control.status.lock().unwrap() == ScriptStatus::Killed
+    }
+
+    /// Execute `call`, a command called by the script, against `db`. Returns
+    /// `None` without executing it if the script was killed.
+    ///
+    /// `SCRIPT KILL` waits for the command to complete, so that a script is
+    /// either killed before its first write or not at all.
+    pub(crate) fn call<T>(
+        &self,
+        db: &mut LockedDb,
+        call: impl FnOnce(&mut LockedDb) -> T,
+    ) -> Option<T> {
+        let mut status = self.control.status.lock().unwrap();
+        if *status == ScriptStatus::Killed {
+            return None;
+        }
+
+        let dirty = db.state().dirty;
+        let resp = call(db);
+        if db.state().dirty != dirty {
+            *status = ScriptStatus::Wrote;
+        }
+
+        Some(resp)
+    }
+}
+
+impl Drop for RunningScript<'_> {
+    fn drop(&mut self) {
+        *self.shared.running_script.lock().unwrap() = None;
+        self.shared.script_changed.notify_waiters();
+    }
+}
+
+impl WatchedKeys {
+    /// Watch `keys` as well.
+    pub(crate) async fn add(&mut self, keys: Vec<String>) {
+        let mut db = self.db.lock().await;
+        let state = db.state();
+
+        for key in keys {
+            state
+                .watched
+                .entry(key.clone())
+                .or_default()
+                .push(self.dirty.clone());
+            self.keys.push(key);
+        }
+    }
+
+    /// Returns `true` if a key was modified since it was added.
+    ///
+    /// Keys are only modified while holding the lock, so `db` must be the lock
+    /// of the watched `Db`. Checking with the lock held guarantees no key is
+    /// modified between the check and the commands that rely on it. The lock
+    /// also orders the check after the flagging, so a relaxed load is enough.
+    pub(crate) fn is_dirty(&self, db: &LockedDb) -> bool {
+        assert!(
+            Arc::ptr_eq(&self.db.shared, db.shared),
+            "keys checked under the lock of another database"
+        );
+        self.dirty.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WatchedKeys {
+    fn drop(&mut self) {
+        let keys = std::mem::take(&mut self.keys);
+
+        if let Some(mut db) = self.db.try_lock() {
+            db.state().unwatch(&keys, &self.dirty);
+            return;
+        }
+
+        let db = self.db.clone();
+        let dirty = self.dirty.clone();
+        tokio::spawn(async move { db.lock().await.state().unwatch(&keys, &dirty) });
+    }
+}
+
+impl<T: Clone> Subscriber<T> {
+    /// Receive the next message, see `broadcast::Receiver::recv`.
+    ///
+    /// Logged messages requested with `Db::subscribe` are received first.
+    pub(crate) async fn recv(&mut self) -> Result<T, broadcast::error::RecvError> {
+        if self.missed > 0 {
+            let missed = std::mem::take(&mut self.missed);
+            return Err(broadcast::error::RecvError::Lagged(missed));
+        }
+
+        if let Some(msg) = self.replay.pop_front() {
+            return Ok(msg);
+        }
+
+        let msg = self.rx.recv().await;
+
+        // Receiving a message makes room for publishers waiting on us.
+        if let Some(space) = &self.space {
+            space.0.notify_waiters();
+        }
+
+        msg
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        self.db.release(self.kind, &self.name);
+    }
+}
+
+impl Drop for SpaceNotifier {
+    fn drop(&mut self) {
+        // The receiver was dropped first, releasing the messages held for it.
+        self.0.notify_waiters();
+    }
+}
+
+impl Value {
+    /// Name of the value's type, as reported to clients. Module types use the
+    /// names given by Redis Stack.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Bloom(_) => "MBbloom--",
+            Value::Cuckoo(_) => "MBbloomCF",
+            Value::Cms(_) => "CMSk-TYPE",
+            Value::TopK(_) => "TopK-TYPE",
+            Value::TimeSeries(_) => "TSDB-TYPE",
+            Value::Hash(_) => "hash",
+        }
+    }
+
+    /// Convert a value read from a Redis RDB file. Returns `None` for the
+    /// types mini-redis does not support, and for empty hashes, which Redis
+    /// does not store.
+    fn from_rdb(value: rdb::Value) -> Option<Value> {
+        match value {
+            rdb::Value::String(value) => Some(Value::String(value)),
+            rdb::Value::Hash(fields) if !fields.is_empty() => {
+                Some(Value::Hash(fields.into_iter().collect()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Serialize the hash made of `fields`, which expires at the Unix time
+/// `expires_at` in milliseconds, as `DUMP` would.
+///
+/// Used to import the hashes of RDB files that expire with `RESTORE`, as
+/// `HSET` cannot set an expiration.
+pub(crate) fn dump_hash(fields: Vec<(Bytes, Bytes)>, expires_at: u64) -> Bytes {
+    let value = Value::Hash(fields.into_iter().collect());
+    dump::encode(&value, Some(expires_at))
+}
+
+/// Returns the channels of `channels` with at least one subscriber,
+/// optionally only those matching the glob-style `pattern`.
+fn active_channels<T: Clone>(
+    channels: &HashMap<String, Channel<T>>,
+    pattern: Option<&[u8]>,
+) -> Vec<String> {
+    channels
+        .iter()
+        .filter(|(_, tx)| tx.receiver_count() > 0)
+        .filter(|(channel, _)| {
+            pattern.is_none_or(|pattern| glob_match(pattern, channel.as_bytes()))
+        })
+        .map(|(channel, _)| channel.clone())
+        .collect()
+}
+
+/// Returns the number of subscribers of each of `names` in `channels`.
+fn count_subscribers<T: Clone>(
+    channels: &HashMap<String, Channel<T>>,
+    names: &[String],
+) -> Vec<usize> {
+    names
+        .iter()
+        .map(|name| {
+            channels
+                .get(name)
+                .map(|tx| tx.receiver_count())
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+impl State {
+    fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.iter().next().map(|expire| expire.0)
+    }
+
+    /// Stop flagging `dirty` on changes to `keys`.
+    fn unwatch(&mut self, keys: &[String], dirty: &Arc<AtomicBool>) {
+        for key in keys {
+            if let Some(watchers) = self.watched.get_mut(key) {
+                watchers.retain(|other| !Arc::ptr_eq(other, dirty));
+                if watchers.is_empty() {
+                    self.watched.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Remove the channel `name` of the `kind` namespace if no more than
+    /// `held` receivers are left, those of the `Subscriber` being dropped.
+    fn release(&mut self, kind: SubscriptionKind, name: &str, held: usize) {
+        match kind {
+            SubscriptionKind::Channel => {
+                if self
+                    .pub_sub
+                    .get(name)
+                    .is_some_and(|tx| tx.receiver_count() <= held)
+                {
+                    self.pub_sub.remove(name);
+                }
+            }
+            SubscriptionKind::Pattern => {
+                if self
+                    .pattern_sub
+                    .get(name)
+                    .is_some_and(|tx| tx.receiver_count() <= held)
+                {
+                    self.pattern_sub.remove(name);
+                }
+            }
+            SubscriptionKind::ShardChannel => {
+                if self
+                    .shard_sub
+                    .get(name)
+                    .is_some_and(|tx| tx.receiver_count() <= held)
+                {
+                    self.shard_sub.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Send `value` to the subscribers of `channel` and of every pattern
+    /// matching it, logging it if enabled. Returns the number of subscribers.
+    fn publish(&mut self, channel: &str, value: Bytes) -> usize {
+        let log_size = self.config.pubsub_log_size;
+        let offset = (log_size > 0).then(|| {
+            self.logs
+                .entry(channel.to_string())
+                .or_insert_with(Log::new)
+                .append(value.clone(), log_size)
+        });
+
+        let message = Message {
+            offset,
+            payload: value.clone(),
+        };
+
+        let subscribers = self
+            .pub_sub
+            .get(channel)
+            .map(|tx| tx.send(message))
+            // If there is no entry for the channel key, then there are no
+            // subscribers. In this case, return `0`.
+            .unwrap_or(0);
+
+        let pattern_subscribers: usize = self
+            .pattern_sub
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            .map(|(_, tx)| tx.send((channel.to_string(), value.clone())))
+            .sum();
+
+        subscribers + pattern_subscribers
+    }
+
+    /// Send a message to the shard channel. Returns the number of
+    /// subscribers.
+    fn spublish(&mut self, channel: &str, value: Bytes) -> usize {
+        self.shard_sub
+            .get(channel)
+            .map(|tx| tx.send(value))
+            .unwrap_or(0)
+    }
+
+    /// Publish the keyspace event `event` on `key`, if events of `class` are
+    /// enabled by `notify-keyspace-events`.
+    ///
+    /// This is called while the lock is held, so unlike `Db::publish` it
+    /// does not wait for subscribers applying backpressure. These may lag
+    /// behind keyspace channels.
+    fn notify_keyspace_event(&mut self, class: KeyspaceEvents, event: &str, key: &str) {
+        let events = self.config.notify_keyspace_events;
+        if !events.publishes(class) {
+            return;
+        }
+
+        if events.contains(KeyspaceEvents::KEYSPACE) {
+            let channel = format!("__keyspace@0__:{}", key);
+            self.publish(&channel, Bytes::copy_from_slice(event.as_bytes()));
+        }
+
+        if events.contains(KeyspaceEvents::KEYEVENT) {
+            let channel = format!("__keyevent@0__:{}", event);
+            self.publish(&channel, Bytes::copy_from_slice(key.as_bytes()));
+        }
+    }
+
+    /// Returns the `Notify` of a channel or pattern that `channel` is
+    /// published to, if one of their backpressure subscribers is full.
+    fn full_channel(&self, channel: &str) -> Option<&Arc<Notify>> {
+        if let Some(tx) = self.pub_sub.get(channel).filter(|tx| tx.is_full()) {
+            return Some(tx.space());
+        }
+
+        self.pattern_sub
+            .iter()
+            .find(|(pattern, tx)| {
+                tx.is_full() && glob_match(pattern.as_bytes(), channel.as_bytes())
+            })
+            .map(|(_, tx)| tx.space())
+    }
+
+    /// Associate `value` with `key`, replacing any previous value along with
+    /// its expiration.
+    ///
+    /// Returns `true` if the background task must be notified because the new
+    /// expiration is the **next** key to evict.
+    fn insert(&mut self, key: String, value: Value, expire: Option<Duration>) -> bool {
+        let mut notify = false;
+
+        let exipires_at = expire.map(|dur| {
+            let when = Instant::now() + dur;
+
+            // Only notify the worker task if the newly inserted expiration is the
+            // **next** key to evict. In this case, the worker needs to be woken up
+            // to update its state.
+            notify = self
+                .next_expiration()
+                .map(|expiration| expiration > when)
+                .unwrap_or(true);
+
+            // track the expiration
+            self.expirations.insert((when, key.clone()));
+            when
+        });
+
+        // Insert the entry into the `HashMap`.
+        let prev = self.entries.insert(
+            key.clone(),
+            Entry {
+                value,
+                expires_at: exipires_at,
+            },
+        );
+
+        // If there was a value previously associated with the key **and** it
+        // had an expiration time. The associated entry in the `expirations` map
+        // must also be removed. This avoids leaking data.
+        if let Some(prev) = prev {
+            if let Some(when) = prev.expires_at {
+                self.expirations.remove(&(when, key.clone()));
+            }
+        }
+
+        self.reindex(&key);
+        self.touch(&key);
+
+        notify
+    }
+
+    /// Remove `key` along with its expiration. Returns `true` if the key
+    /// existed.
+    fn remove(&mut self, key: &str) -> bool {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+
+        self.reindex(key);
+        self.touch(key);
+        true
+    }
+
+    /// Bring every search index covering `key` up to date with the value now
+    /// stored at the key.
+    ///
+    /// Must be called by every path that replaces or removes a value, or
+    /// modifies a hash.
+    fn reindex(&mut self, key: &str) {
+        let hash = match self.entries.get(key).map(|entry| &entry.value) {
+            Some(Value::Hash(hash)) => Some(&**hash),
+            _ => None,
+        };
+
+        for index in self.indexes.values_mut() {
+            index.update(key, hash);
+        }
+    }
+
+    /// Propagate `buf` to the backlog and the replicas, within the limits
+    /// configured.
+    fn feed_replicas(&mut self, buf: &[u8]) {
+        let backlog_size = self.config.repl_backlog_size;
+        let limit = self.config.replica_output_buffer_limit;
+        self.replication.feed(buf, backlog_size, limit);
+    }
+
+    /// Clone the entries into a `Snapshot`, for it to be encoded and written
+    /// without holding the lock.
+    fn snapshot(&self) -> Snapshot {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at))
+            .collect();
+
+        Snapshot::new(entries)
+    }
+
+    /// Start writing a snapshot in the background.
+    fn bgsave(&mut self, shared: &Arc<Shared>) -> Result<(), DbError> {
+        if self.saving {
+            return Err("ERR Background save already in progress".into());
+        }
+
+        self.saving = true;
+        self.last_save_attempt = Instant::now();
+
+        let snapshot = self.snapshot();
+        let path = self.config.snapshot_path();
+        let dirty = self.dirty;
+        let shared = shared.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let res = snapshot.write(&path);
+
+            let mut db = shared.blocking_lock();
+            let state = db.state();
+            state.saving = false;
+            match res {
+                Ok(()) => {
+                    state.saved(dirty);
+                    debug!(path = %path.display(), "background save done");
+                }
+                Err(err) => error!(cause = %err, "background save failed"),
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Add the keys of `snapshot` that have not expired yet. Returns the
+    /// number of keys added.
+    fn load(&mut self, snapshot: Snapshot) -> usize {
+        let mut count = 0;
+
+        for (key, value, expire) in snapshot.into_entries() {
+            if let Value::TimeSeries(series) = &value {
+                if series.retention() > 0 {
+                    self.retained_series.insert(key.clone());
+                }
+            }
+
+            self.insert(key, value, expire);
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Start rewriting the append-only file in the background.
+    fn bgrewriteaof(&mut self, shared: &Arc<Shared>) -> Result<(), DbError> {
+        if self.aof_rewrite.is_some() {
+            return Err("ERR Background append only file rewriting already in progress".into());
+        }
+
+        let (preamble, commands) = self.rewrite_commands();
+        let path = self.config.aof_path();
+        let tmp = path.with_extension("rewrite");
+        let shared = shared.clone();
+
+        self.aof_rewrite = Some(vec![]);
+
+        tokio::task::spawn_blocking(move || {
+            let res = aof::write_rewrite(&tmp, preamble, commands);
+
+            // The lock is held from the moment the commands executed in the
+            // meantime are taken, until the new file replaces the current
+            // one, so that no command is missed.
+            let mut db = shared.blocking_lock();
+            let state = db.state();
+            let buffered = state.aof_rewrite.take().unwrap_or_default();
+
+            match res.and_then(|()| aof::finish_rewrite(&tmp, &buffered, &path)) {
+                Ok(aof) => {
+                    if state.config.appendonly {
+                        state.aof = Some(aof);
+                    }
+                    debug!(path = %path.display(), "append-only file rewrite done");
+                }
+                Err(err) => {
+                    let _ = std::fs::remove_file(&tmp);
+                    error!(cause = %err, "append-only file rewrite failed");
+                }
+            }
+
+            db.notify_background_task();
+        });
+
+        Ok(())
+    }
+
+    /// Returns the contents of a minimal append-only file rebuilding the
+    /// data: a snapshot preamble, if needed, and commands.
+    ///
+    /// Strings and hashes are rebuilt by commands. Other values, which no
+    /// command can rebuild exactly, go to the preamble. With
+    /// `aof-use-snapshot-preamble`, every value does.
+    fn rewrite_commands(&self) -> (Option<Snapshot>, Vec<Frame>) {
+        let use_preamble = self.config.aof_use_snapshot_preamble;
+        let now = Instant::now();
+        let mut preamble = vec![];
+        let mut commands = vec![];
+
+        for (key, entry) in &self.entries {
+            let expire = match entry.expires_at {
+                Some(when) if when <= now => continue,
+                Some(when) => Some(when - now),
+                None => None,
+            };
+
+            match &entry.value {
+                Value::String(value) if !use_preamble => {
+                    let mut cmd = crate::cmd::Set::new(key, value.clone(), expire);
+                    cmd.make_absolute();
+                    commands.push(cmd.into_frame());
+                }
+                Value::Hash(hash) if !use_preamble && expire.is_none() && !hash.is_empty() => {
+                    let fields = hash
+                        .iter()
+                        .map(|(field, value)| (field.clone(), value.clone()))
+                        .collect();
+                    commands.push(crate::cmd::Hset::new(key, fields).into_frame());
+                }
+                value => preamble.push((key.clone(), value.clone(), entry.expires_at)),
+            }
+        }
+
+        // Indexes are not part of snapshots.
+        for (name, index) in &self.indexes {
+            let cmd = crate::cmd::FtCreate::new(name, index.prefixes().to_vec(), index.schema());
+            commands.push(cmd.into_frame());
+        }
+
+        let preamble = (use_preamble || !preamble.is_empty()).then(|| Snapshot::new(preamble));
+        (preamble, commands)
+    }
+
+    /// Record a successful save of the state as of the change counter
+    /// `dirty`.
+    fn saved(&mut self, dirty: u64) {
+        self.saved_dirty = dirty;
+        self.last_save = unix_time();
+    }
+
+    /// Flag the watchers of `key` after the value at `key` was modified,
+    /// removed or expired.
+    ///
+    /// Like `reindex`, this must be called by every path that modifies a key.
+    fn touch(&mut self, key: &str) {
+        self.dirty += 1;
+
+        // Flagged watchers stay flagged, they no longer need to be listed.
+        if let Some(watchers) = self.watched.remove(key) {
+            for dirty in watchers {
+                dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Add a sample to the time series at `key`, then set the samples
+    /// produced by its compaction rules in their destination series.
+    ///
+    /// Destinations have no rules of their own, `ts_create_rule` makes sure of
+    /// it, so compacted samples produce nothing further.
+    fn ts_add(&mut self, key: String, timestamp: u64, value: f64) -> Result<(), DbError> {
+        let compacted = match self.entries.get_mut(&key).map(|entry| &mut entry.value) {
+            Some(Value::TimeSeries(series)) => series.add(timestamp, value)?,
+            Some(_) => return Err(DbError::WrongType),
+            None => return Err("ERR TSDB: the key does not exist".into()),
+        };
+        self.touch(&key);
+
+        for (dst, timestamp, value) in compacted {
+            if let Some(Value::TimeSeries(series)) =
+                self.entries.get_mut(&dst).map(|entry| &mut entry.value)
+            {
+                series.upsert(timestamp, value);
+                self.touch(&dst);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the time series at `key` is the destination of a
+    /// compaction rule.
+    fn ts_is_destination(&self, key: &str) -> bool {
+        self.entries.values().any(|entry| match &entry.value {
+            Value::TimeSeries(series) => series.rules().iter().any(|rule| rule.dst == key),
+            _ => false,
+        })
+    }
+}
+
+impl From<&str> for DbError {
+    fn from(value: &str) -> Self {
+        DbError::Other(value.to_string())
+    }
+}
+
+impl From<String> for DbError {
+    fn from(value: String) -> Self {
+        DbError::Other(value)
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::WrongType => {
+                "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(f)
+            }
+            DbError::Other(msg) => msg.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+/// Routine executed by the background task.
+///
+/// Wait to be notified. On notification, purge any expired keys from the shared
+/// state handle. If `shutdown` is set, terminate the task.
+async fn purge_expired_tasks(shared: Arc<Shared>) {
+    // If the shutdown flag is set, then the task should exit.
+
+    while !shared.is_shutdown().await {
+        // Purge all keys that are expired. The function returns the instant at
+        // which the **next** key will expire. The worker should wait until the
+        // instant has passed then purge again.
+        let next_expiration = shared.purge_expired_key().await;
+
+        // Time series retention is enforced from the same task. The worker
+        // also wakes up when the next trim is due.
+        let next_trim = shared.trim_time_series().await;
+
+        // And so are the logs of channels left without subscribers.
+        let next_eviction = shared.evict_logs().await;
+
+        // As are the save rules, and the flushes and rewrites of the
+        // append-only file.
+        let next_save = shared.check_save_rules().await;
+        let next_fsync = shared.fsync_aof().await;
+        let next_rewrite = shared.check_aof_rewrite().await;
+
+        // And the pings of the replicas.
+        let next_ping = shared.ping_replicas().await;
+
+        if let Some(when) = next_expiration
+            .into_iter()
+            .chain(next_trim)
+            .chain(next_eviction)
+            .chain(next_save)
+            .chain(next_fsync)
+            .chain(next_rewrite)
+            .chain(next_ping)
+            .min()
+        {
             // Wait until the next key expires **or** until the background task
             // is notified. If the task is notified, then it must reload its
             // state as new keys have been set to expire early. This is done by
@@ -0,0 +1,231 @@
+//! The append-only file, logging write commands as they are executed so that
+//! they can be replayed on startup.
+//!
+//! The file is a sequence of RESP arrays of bulk strings, the frames of the
+//! commands as sent by clients. The commands of a transaction are wrapped in
+//! `MULTI` and `EXEC`. A crash may leave the last command partially written,
+//! or the last transaction incomplete. This tail is cut off when the file is
+//! read.
+//!
+//! `BGREWRITEAOF` replaces the file by a minimal one rebuilding the current
+//! data. Values are written as commands where possible, and to a snapshot
+//! preamble at the start of the file otherwise. The preamble may also hold
+//! every value, with `aof-use-snapshot-preamble`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+
+use bytes::Bytes;
+use tracing::warn;
+
+use super::config::AppendFsync;
+use super::snapshot::{is_snapshot, Snapshot};
+use crate::frame::{self, Frame};
+
+/// The append-only file, opened for appending.
+#[derive(Debug)]
+pub(super) struct Aof {
+    file: File,
+    /// Current size of the file in bytes.
+    size: u64,
+    /// Size of the file when it was opened, after being loaded or rewritten.
+    /// Automatic rewrites are triggered by the growth since then.
+    base_size: u64,
+    /// True if commands were written since the last `fsync`.
+    unsynced: bool,
+}
+
+impl Aof {
+    /// Open the file at `path` for appending, creating it if needed.
+    pub(super) fn open(path: &Path) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Aof {
+            file,
+            size,
+            base_size: size,
+            unsynced: false,
+        })
+    }
+
+    /// Append the encoded commands `buf`. With the `Always` policy, the file
+    /// is flushed to disk before returning.
+    pub(super) fn append(&mut self, buf: &[u8], fsync: AppendFsync) -> io::Result<()> {
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+
+        if fsync == AppendFsync::Always {
+            self.file.sync_data()
+        } else {
+            self.unsynced = true;
+            Ok(())
+        }
+    }
+
+    /// Returns `true` if the file grew by at least `percentage` percent since
+    /// it was opened, and is at least `min_size` bytes.
+    pub(super) fn needs_rewrite(&self, percentage: u64, min_size: u64) -> bool {
+        let growth = self.size.saturating_sub(self.base_size);
+        percentage > 0
+            && self.size >= min_size
+            && growth.saturating_mul(100) >= self.base_size.max(1).saturating_mul(percentage)
+    }
+
+    /// Returns a handle to flush the file with, if commands were written
+    /// since the last flush.
+    ///
+    /// The flush itself may take a while. It is done with the returned
+    /// handle, without holding the lock.
+    pub(super) fn take_unsynced(&mut self) -> io::Result<Option<File>> {
+        if !self.unsynced {
+            return Ok(None);
+        }
+
+        self.unsynced = false;
+        self.file.try_clone().map(Some)
+    }
+}
+
+/// Read the snapshot preamble, if any, and the commands logged at `path`.
+/// There are none if the file does not exist.
+///
+/// A partially written last command, or a last transaction without its
+/// `EXEC`, is removed from the file, so that the commands appended next
+/// follow the last complete one.
+pub(super) fn read(path: &Path) -> Result<(Option<Snapshot>, Vec<Frame>), String> {
+    let data = match std::fs::read(path) {
+        Ok(data) => Bytes::from(data),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((None, vec![])),
+        Err(err) => return Err(format!("cannot read {}: {}", path.display(), err)),
+    };
+
+    let (preamble, offset) = if is_snapshot(&data) {
+        match Snapshot::decode_prefix(&data) {
+            Some((snapshot, len)) => (Some(snapshot), len),
+            None => return Err(format!("{} has an invalid preamble", path.display())),
+        }
+    } else {
+        (None, 0)
+    };
+
+    let mut frames = vec![];
+    let mut buf = Cursor::new(&data[..]);
+    buf.set_position(offset as u64);
+    // The offset and index of the `MULTI` of the transaction being read.
+    let mut multi = None;
+
+    while (buf.position() as usize) < data.len() {
+        let start = buf.position();
+
+        match Frame::check(&mut buf) {
+            Ok(()) => {
+                buf.set_position(start);
+                let frame = Frame::parse(&mut buf)
+                    .map_err(|err| format!("{} is corrupted: {}", path.display(), err))?;
+                match command_name(&frame).as_deref() {
+                    Some("multi") => multi = Some((start, frames.len())),
+                    Some("exec") => multi = None,
+                    _ => {}
+                }
+                frames.push(frame);
+            }
+            Err(frame::Error::InComplete) => {
+                warn!(
+                    path = %path.display(),
+                    offset = start,
+                    "truncating incomplete command at the end of the append-only file"
+                );
+                truncate(path, start)?;
+                break;
+            }
+            Err(err) => return Err(format!("{} is corrupted: {}", path.display(), err)),
+        }
+    }
+
+    if let Some((start, len)) = multi {
+        warn!(
+            path = %path.display(),
+            offset = start,
+            "truncating incomplete transaction at the end of the append-only file"
+        );
+        truncate(path, start)?;
+        frames.truncate(len);
+    }
+
+    Ok((preamble, frames))
+}
+
+/// Cut the file at `path` off at `len` bytes.
+fn truncate(path: &Path, len: u64) -> Result<(), String> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(len))
+        .map_err(|err| format!("cannot truncate {}: {}", path.display(), err))
+}
+
+/// Returns the name of the command `frame`, lowercase.
+fn command_name(frame: &Frame) -> Option<String> {
+    match frame {
+        Frame::Array(frames) => match frames.first()? {
+            Frame::Bulk(name) => Some(String::from_utf8_lossy(name).to_lowercase()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Write a new append-only file to `path`: the snapshot `preamble`, if any,
+/// followed by `commands`.
+///
+/// The file is completed by `finish_rewrite`, once the commands executed in
+/// the meantime are known.
+pub(super) fn write_rewrite(
+    path: &Path,
+    preamble: Option<Snapshot>,
+    commands: Vec<Frame>,
+) -> io::Result<()> {
+    let mut buf = preamble
+        .map(|preamble| preamble.encode())
+        .unwrap_or_default();
+    for frame in &commands {
+        buf.extend_from_slice(&encode(frame));
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&buf)?;
+    file.sync_data()
+}
+
+/// Append the commands executed during the rewrite, `buffered`, to the file
+/// written at `tmp` by `write_rewrite`, then move it to `path`, replacing the
+/// current file at once.
+///
+/// Returns the new file, opened for appending.
+pub(super) fn finish_rewrite(tmp: &Path, buffered: &[u8], path: &Path) -> io::Result<Aof> {
+    let mut aof = Aof::open(tmp)?;
+    aof.append(buffered, AppendFsync::Always)?;
+    aof.base_size = aof.size;
+    std::fs::rename(tmp, path)?;
+    Ok(aof)
+}
+
+/// Encode the command `frame` as logged.
+pub(super) fn encode(frame: &Frame) -> Vec<u8> {
+    let mut buf = vec![];
+    to_bulk(frame).encode(&mut buf);
+    buf
+}
+
+/// Convert the integers in `frame` to bulk strings, which every command
+/// parses. `into_frame` encodes some arguments as integers, which not all
+/// commands accept.
+fn to_bulk(frame: &Frame) -> Frame {
+    match frame {
+        Frame::Int(value) => Frame::Bulk(Bytes::from(value.to_string())),
+        Frame::Array(entries) => Frame::Array(entries.iter().map(to_bulk).collect()),
+        frame => frame.clone(),
+    }
+}
@@ -0,0 +1,277 @@
+use super::hash::{hash64, mix};
+use super::snapshot::{Decoder, Encoder};
+
+/// Error rate used when `BF.ADD` creates a filter implicitly.
+pub(crate) const DEFAULT_ERROR_RATE: f64 = 0.01;
+
+/// Capacity used when `BF.ADD` creates a filter implicitly.
+pub(crate) const DEFAULT_CAPACITY: u64 = 100;
+
+/// Growth factor applied to the capacity of each new sub-filter.
+pub(crate) const DEFAULT_EXPANSION: u64 = 2;
+
+/// Each new sub-filter has its error rate multiplied by this ratio. This keeps
+/// the compound error rate of all sub-filters bounded by the requested rate.
+const TIGHTENING_RATIO: f64 = 0.5;
+
+/// A scalable Bloom filter.
+///
+/// The filter is a stack of plain Bloom filters. Items are only ever added to
+/// the last one. Once it holds `capacity` items, a new, larger filter with a
+/// tighter error rate is pushed. Lookups check every filter.
+///
+/// A non scaling filter refuses new items once its single filter is full.
+#[derive(Debug, Clone)]
+pub(crate) struct ScalingBloom {
+    /// Requested false positive rate for the whole filter.
+    error_rate: f64,
+    /// Growth factor applied to the capacity of each new sub-filter. `None`
+    /// when the filter is non scaling.
+    expansion: Option<u64>,
+    /// Sub-filters, oldest first.
+    filters: Vec<Bloom>,
+}
+
+/// A single, fixed size Bloom filter.
+#[derive(Debug, Clone)]
+struct Bloom {
+    /// Number of items this filter was sized for.
+    capacity: u64,
+    /// Number of items added so far.
+    count: u64,
+    /// Number of hash functions.
+    hashes: u32,
+    /// Number of bits in `bits`.
+    num_bits: u64,
+    bits: Vec<u64>,
+}
+
+impl ScalingBloom {
+    /// Create a filter for `capacity` items at the given false positive rate.
+    ///
+    /// `expansion` is the growth factor of new sub-filters. Passing `None`
+    /// creates a non scaling filter.
+    pub(crate) fn new(error_rate: f64, capacity: u64, expansion: Option<u64>) -> ScalingBloom {
+        ScalingBloom {
+            error_rate,
+            expansion,
+            filters: vec![Bloom::new(error_rate * TIGHTENING_RATIO, capacity)],
+        }
+    }
+
+    /// Add `item` to the filter.
+    ///
+    /// Returns `Ok(true)` if the item was newly added and `Ok(false)` if it may
+    /// already have existed. Returns `Err` when a non scaling filter is full.
+    pub(crate) fn add(&mut self, item: &[u8]) -> Result<bool, &'static str> {
+        if self.contains(item) {
+            return Ok(false);
+        }
+
+        let last = self.filters.last().expect("filter has no sub-filters");
+
+        if last.count >= last.capacity {
+            let expansion = match self.expansion {
+                Some(expansion) => expansion,
+                None => return Err("ERR non scaling filter is full"),
+            };
+
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.filters.len() as i32 + 1);
+            let capacity = last.capacity.saturating_mul(expansion);
+            self.filters.push(Bloom::new(error_rate, capacity));
+        }
+
+        self.filters.last_mut().unwrap().insert(item);
+        Ok(true)
+    }
+
+    /// Returns `true` if `item` may have been added to the filter, `false` if
+    /// it definitely was not.
+    pub(crate) fn contains(&self, item: &[u8]) -> bool {
+        self.filters.iter().any(|filter| filter.contains(item))
+    }
+
+    /// Encode the filter for a snapshot.
+    pub(crate) fn encode(&self, enc: &mut Encoder) {
+        enc.f64(self.error_rate);
+        // The expansion is at least 1, zero stands for a non scaling filter.
+        enc.u64(self.expansion.unwrap_or(0));
+        enc.u64(self.filters.len() as u64);
+
+        for filter in &self.filters {
+            enc.u64(filter.capacity);
+            enc.u64(filter.count);
+            enc.u32(filter.hashes);
+            enc.u64(filter.num_bits);
+            for word in &filter.bits {
+                enc.u64(*word);
+            }
+        }
+    }
+
+    /// Decode a filter encoded by `encode`.
+    pub(crate) fn decode(dec: &mut Decoder) -> Option<ScalingBloom> {
+        let error_rate = dec.f64()?;
+        let expansion = match dec.u64()? {
+            0 => None,
+            expansion => Some(expansion),
+        };
+
+        let mut filters = vec![];
+        for _ in 0..dec.u64()? {
+            let capacity = dec.u64()?;
+            let count = dec.u64()?;
+            let hashes = dec.u32()?;
+            let num_bits = dec.u64()?;
+            if num_bits == 0 {
+                return None;
+            }
+
+            let mut bits = vec![];
+            for _ in 0..num_bits.div_ceil(64) {
+                bits.push(dec.u64()?);
+            }
+
+            filters.push(Bloom {
+                capacity,
+                count,
+                hashes,
+                num_bits,
+                bits,
+            });
+        }
+
+        if filters.is_empty() {
+            return None;
+        }
+
+        Some(ScalingBloom {
+            error_rate,
+            expansion,
+            filters,
+        })
+    }
+}
+
+impl Bloom {
+    fn new(error_rate: f64, capacity: u64) -> Bloom {
+        let capacity = capacity.max(1);
+        let ln2 = std::f64::consts::LN_2;
+
+        // Optimal number of bits and hash functions for the requested capacity
+        // and error rate.
+        let num_bits = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let hashes = (-error_rate.log2()).ceil().max(1.0) as u32;
+
+        Bloom {
+            capacity,
+            count: 0,
+            hashes,
+            num_bits,
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+        }
+    }
+
+    fn insert(&mut self, item: &[u8]) {
+        for bit in self.positions(item) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.count += 1;
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Bit positions for `item`, using double hashing to derive `hashes`
+    /// positions from two base hashes.
+    ///
+    /// Each combined hash is mixed again before being reduced. Small filters
+    /// otherwise see the positions of an item collapse onto each other when
+    /// `h2` shares a factor with `num_bits`.
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = u64> {
+        let h1 = hash64(item, 0);
+        let h2 = mix(h1) | 1;
+        let num_bits = self.num_bits;
+
+        (0..self.hashes as u64).map(move |i| mix(h1.wrapping_add(i.wrapping_mul(h2))) % num_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(i: u64) -> Vec<u8> {
+        format!("item:{}", i).into_bytes()
+    }
+
+    /// Returns the share of the items `from..to`, never added, that `filter`
+    /// reports as present.
+    fn false_positive_rate(filter: &ScalingBloom, from: u64, to: u64) -> f64 {
+        let positives = (from..to).filter(|i| filter.contains(&item(*i))).count();
+        positives as f64 / (to - from) as f64
+    }
+
+    #[test]
+    fn added_items_are_found() {
+        let mut filter = ScalingBloom::new(0.01, 1000, Some(DEFAULT_EXPANSION));
+
+        for i in 0..1000 {
+            filter.add(&item(i)).unwrap();
+        }
+
+        assert!((0..1000).all(|i| filter.contains(&item(i))));
+        assert_eq!(filter.filters.len(), 1);
+    }
+
+    #[test]
+    fn false_positive_rate_stays_below_the_error_rate() {
+        let mut filter = ScalingBloom::new(0.01, 1000, Some(DEFAULT_EXPANSION));
+
+        for i in 0..1000 {
+            filter.add(&item(i)).unwrap();
+        }
+
+        assert!(false_positive_rate(&filter, 1000, 101_000) < 0.01);
+    }
+
+    #[test]
+    fn grows_once_full() {
+        let mut filter = ScalingBloom::new(0.01, 100, Some(DEFAULT_EXPANSION));
+
+        for i in 0..1000 {
+            filter.add(&item(i)).unwrap();
+        }
+
+        // 100, 200, 400, then 800 items.
+        assert_eq!(filter.filters.len(), 4);
+        assert_eq!(filter.filters[3].capacity, 800);
+        assert!((0..1000).all(|i| filter.contains(&item(i))));
+
+        // The tightened error rates of the sub-filters keep the compound
+        // rate within the requested one.
+        assert!(false_positive_rate(&filter, 1000, 101_000) < 0.01);
+    }
+
+    #[test]
+    fn non_scaling_filter_refuses_items_once_full() {
+        let mut filter = ScalingBloom::new(0.01, 10, None);
+
+        for i in 0..10 {
+            filter.add(&item(i)).unwrap();
+        }
+
+        let new = (10..)
+            .map(item)
+            .find(|item| !filter.contains(item))
+            .unwrap();
+        assert_eq!(filter.add(&new), Err("ERR non scaling filter is full"));
+
+        // Items already present are still reported as such.
+        assert_eq!(filter.add(&item(0)), Ok(false));
+        assert_eq!(filter.filters.len(), 1);
+    }
+}
@@ -0,0 +1,650 @@
+//! The state of the cluster, as seen by a node: the nodes it knows, the hash
+//! slots each of them serves, and the slots being moved between them.
+//!
+//! Nodes exchange their view on the cluster bus, see `crate::cluster`. Each
+//! heartbeat carries the slots of its sender, and gossip about the other
+//! nodes it knows, through which nodes met with `CLUSTER MEET` get to know
+//! the whole cluster.
+//!
+//! Claims on the same slot are settled by configuration epoch: a node taking
+//! over a slot with `CLUSTER SETSLOT NODE` bumps its own, and wins. Nodes of
+//! the same configuration epoch bump theirs in turn, until they all differ.
+//!
+//! A node that was not heard from for `cluster-node-timeout` is possibly
+//! failing, `PFAIL`. Nodes report it in their gossip, and it fails, `FAIL`,
+//! once a majority of the nodes serving slots agree. The node finding out
+//! announces it to all the others. It is cleared as soon as the node is
+//! heard from again.
+
+use std::collections::{HashMap, HashSet};
+
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::crc16::SLOTS;
+use super::{new_replid, DbError};
+
+/// State of a `Db` in cluster mode.
+#[derive(Debug)]
+pub(crate) struct Cluster {
+    /// ID of this node, 40 hexadecimal characters.
+    myself: String,
+    /// The largest epoch seen in the cluster.
+    current_epoch: u64,
+    /// The nodes known, this one included, by ID.
+    nodes: HashMap<String, Node>,
+    /// The ID of the node serving each slot, if any.
+    slots: Vec<Option<String>>,
+    /// Slots of this node being moved to another one, with the ID of the
+    /// target.
+    migrating: HashMap<u16, String>,
+    /// Slots being moved to this node, with the ID of the source.
+    importing: HashMap<u16, String>,
+    /// Bus addresses given to `CLUSTER MEET`, not contacted yet.
+    meets: Vec<(String, u16)>,
+    /// Failures found by this node, to announce to the other ones.
+    failures: Vec<String>,
+    /// The task serving the cluster bus.
+    pub(super) task: JoinHandle<()>,
+}
+
+/// A node of the cluster.
+#[derive(Debug)]
+pub(crate) struct Node {
+    pub(crate) id: String,
+    pub(crate) ip: String,
+    /// Port clients connect to.
+    pub(crate) port: u16,
+    /// Port of the cluster bus.
+    pub(crate) bus_port: u16,
+    /// Epoch of the last change to the slots the node serves.
+    pub(crate) config_epoch: u64,
+    /// When the node was last heard from, or added.
+    last_seen: Instant,
+    /// When the ping waiting for a reply was sent, if any.
+    pub(crate) ping_sent: Option<Instant>,
+    /// When the node last replied to a ping.
+    pub(crate) pong_received: Option<Instant>,
+    /// Whether the node was not heard from for the node timeout.
+    pub(crate) pfail: bool,
+    /// Whether a majority of the nodes serving slots agree it fails.
+    pub(crate) fail: bool,
+    /// The nodes reporting it as failing, and when they last did.
+    fail_reports: HashMap<String, Instant>,
+}
+
+/// A message exchanged on the cluster bus.
+#[derive(Debug)]
+pub(crate) enum BusMessage {
+    /// Sent every second to every node, which replies with `Pong`.
+    Ping(Heartbeat),
+    Pong(Heartbeat),
+    /// Sent instead of `Ping` to a node met with `CLUSTER MEET`, which adds
+    /// the sender to the nodes it knows.
+    Meet(Heartbeat),
+    /// Announces that `node` fails.
+    Fail {
+        sender: String,
+        node: String,
+    },
+}
+
+/// The state of its sender carried by a `Ping`, `Pong` or `Meet`.
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    pub(crate) sender: String,
+    pub(crate) port: u16,
+    pub(crate) bus_port: u16,
+    pub(crate) config_epoch: u64,
+    pub(crate) current_epoch: u64,
+    /// The ranges of slots the sender serves.
+    pub(crate) slots: Vec<(u16, u16)>,
+    /// The other nodes the sender knows.
+    pub(crate) gossip: Vec<Gossip>,
+}
+
+/// What the sender of a heartbeat knows about another node.
+#[derive(Debug)]
+pub(crate) struct Gossip {
+    pub(crate) id: String,
+    pub(crate) ip: String,
+    pub(crate) port: u16,
+    pub(crate) bus_port: u16,
+    /// Whether the sender considers the node as possibly failing, or
+    /// failing.
+    pub(crate) failing: bool,
+}
+
+/// Change to a slot made with `CLUSTER SETSLOT`.
+#[derive(Debug)]
+pub(crate) enum SetSlot {
+    /// Move the slot of this node to the node with the given ID.
+    Migrating(String),
+    /// Move the slot of the node with the given ID to this node.
+    Importing(String),
+    /// The slot is served by the node with the given ID, once moved.
+    Node(String),
+    /// Cancel the move of the slot.
+    Stable,
+}
+
+/// What the cluster bus has to do after a periodic check.
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    /// Bus addresses to send `Meet` to.
+    pub(crate) meets: Vec<(String, u16)>,
+    /// `Fail` messages to send to every node.
+    pub(crate) failures: Vec<BusMessage>,
+    /// The IDs of the other nodes, to keep a link to.
+    pub(crate) peers: Vec<String>,
+}
+
+impl Cluster {
+    /// Create the state of a new cluster of a single node, this one,
+    /// serving no slots.
+    pub(super) fn new(ip: String, port: u16, bus_port: u16, task: JoinHandle<()>) -> Cluster {
+        // Node IDs have the format of replication IDs.
+        let myself = new_replid();
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            myself.clone(),
+            Node::new(myself.clone(), ip, port, bus_port),
+        );
+
+        Cluster {
+            myself,
+            current_epoch: 0,
+            nodes,
+            slots: vec![None; SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            meets: vec![],
+            failures: vec![],
+            task,
+        }
+    }
+
+    pub(crate) fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    pub(crate) fn current_epoch(&self) -> u64 {
+        self.current_epoch
+    }
+
+    /// Returns the nodes known, sorted by ID.
+    pub(crate) fn nodes(&self) -> Vec<&Node> {
+        let mut nodes: Vec<_> = self.nodes.values().collect();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        nodes
+    }
+
+    /// Returns the node serving `slot`, if any.
+    pub(crate) fn owner(&self, slot: u16) -> Option<&Node> {
+        let id = self.slots[slot as usize].as_ref()?;
+        self.nodes.get(id)
+    }
+
+    /// Returns the node `slot` is being moved to, if it is.
+    pub(crate) fn migrating_to(&self, slot: u16) -> Option<&Node> {
+        self.nodes.get(self.migrating.get(&slot)?)
+    }
+
+    pub(crate) fn is_importing(&self, slot: u16) -> bool {
+        self.importing.contains_key(&slot)
+    }
+
+    /// Returns the ranges of consecutive slots served by the same node,
+    /// along with the node, in order.
+    pub(crate) fn ranges(&self) -> Vec<(u16, u16, &Node)> {
+        let mut ranges: Vec<(u16, u16, &Node)> = vec![];
+
+        for slot in 0..SLOTS as u16 {
+            let owner = match self.owner(slot) {
+                Some(owner) => owner,
+                None => continue,
+            };
+
+            match ranges.last_mut() {
+                Some((_, end, node)) if *end + 1 == slot && node.id == owner.id => *end = slot,
+                _ => ranges.push((slot, slot, owner)),
+            }
+        }
+
+        ranges
+    }
+
+    /// Returns the ranges of slots served by the node `id`, in order.
+    pub(crate) fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        self.ranges()
+            .into_iter()
+            .filter(|(_, _, node)| node.id == id)
+            .map(|(start, end, _)| (start, end))
+            .collect()
+    }
+
+    /// Returns the slots of this node being moved to another one, with the
+    /// ID of that node, in order.
+    pub(crate) fn migrating(&self) -> Vec<(u16, &str)> {
+        sorted(&self.migrating)
+    }
+
+    /// Returns the slots being moved to this node, with the ID of the node
+    /// they are moved from, in order.
+    pub(crate) fn importing(&self) -> Vec<(u16, &str)> {
+        sorted(&self.importing)
+    }
+
+    /// Returns `true` if every slot is served by a node that does not fail.
+    pub(crate) fn is_ok(&self) -> bool {
+        (0..SLOTS as u16).all(|slot| self.owner(slot).is_some_and(|node| !node.fail))
+    }
+
+    /// Returns the number of nodes serving slots.
+    pub(crate) fn size(&self) -> usize {
+        self.serving().len()
+    }
+
+    fn serving(&self) -> HashSet<String> {
+        self.slots.iter().flatten().cloned().collect()
+    }
+
+    /// Meet the node with the cluster bus at `ip` and `bus_port`.
+    pub(crate) fn meet(&mut self, ip: String, bus_port: u16) {
+        self.meets.push((ip, bus_port));
+    }
+
+    /// Serve `slots` from this node. Fails if one of them is served already.
+    pub(crate) fn add_slots(&mut self, slots: &[u16]) -> Result<(), DbError> {
+        if let Some(slot) = slots
+            .iter()
+            .find(|slot| self.slots[**slot as usize].is_some())
+        {
+            return Err(format!("ERR Slot {} is already busy", slot).into());
+        }
+
+        for slot in slots {
+            self.slots[*slot as usize] = Some(self.myself.clone());
+            self.importing.remove(slot);
+        }
+        Ok(())
+    }
+
+    /// Stop serving `slots`, by any node. Fails if one of them is not
+    /// served.
+    pub(crate) fn del_slots(&mut self, slots: &[u16]) -> Result<(), DbError> {
+        if let Some(slot) = slots
+            .iter()
+            .find(|slot| self.slots[**slot as usize].is_none())
+        {
+            return Err(format!("ERR Slot {} is already unassigned", slot).into());
+        }
+
+        for slot in slots {
+            self.slots[*slot as usize] = None;
+            self.migrating.remove(slot);
+            self.importing.remove(slot);
+        }
+        Ok(())
+    }
+
+    /// Change `slot` as `CLUSTER SETSLOT` does. `has_keys` tells whether
+    /// this node still holds keys of the slot.
+    pub(crate) fn set_slot(
+        &mut self,
+        slot: u16,
+        change: SetSlot,
+        has_keys: bool,
+    ) -> Result<(), DbError> {
+        let owned = self.slots[slot as usize].as_ref() == Some(&self.myself);
+
+        match change {
+            SetSlot::Migrating(id) => {
+                if !owned {
+                    return Err(format!("ERR I'm not the owner of hash slot {}", slot).into());
+                }
+                self.check_node(&id)?;
+                self.migrating.insert(slot, id);
+            }
+            SetSlot::Importing(id) => {
+                if owned {
+                    return Err(format!("ERR I'm already the owner of hash slot {}", slot).into());
+                }
+                self.check_node(&id)?;
+                self.importing.insert(slot, id);
+            }
+            SetSlot::Stable => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            SetSlot::Node(id) => {
+                self.check_node(&id)?;
+
+                if id != self.myself {
+                    if owned && has_keys {
+                        return Err(format!(
+                            "ERR Can't assign hashslot {} to a different node while I still \
+                             hold keys for this hash slot.",
+                            slot
+                        )
+                        .into());
+                    }
+                    self.migrating.remove(&slot);
+                } else if self.importing.remove(&slot).is_some() {
+                    // The other nodes adopt the move as this node's claim on
+                    // the slot is of a later epoch than the former owner's.
+                    self.bump_config_epoch();
+                }
+
+                self.slots[slot as usize] = Some(id);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_node(&self, id: &str) -> Result<(), DbError> {
+        match self.nodes.contains_key(id) {
+            true => Ok(()),
+            false => Err(format!("ERR I don't know about node {}", id).into()),
+        }
+    }
+
+    /// Give this node a new configuration epoch, the largest in the
+    /// cluster.
+    fn bump_config_epoch(&mut self) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        if let Some(myself) = self.nodes.get_mut(&self.myself) {
+            myself.config_epoch = epoch;
+        }
+        info!(epoch, "bumped configuration epoch");
+    }
+
+    /// Returns the address of the cluster bus of the node `id`.
+    pub(super) fn bus_addr(&self, id: &str) -> Option<(String, u16)> {
+        let node = self.nodes.get(id)?;
+        Some((node.ip.clone(), node.bus_port))
+    }
+
+    /// Returns the heartbeat of this node.
+    pub(super) fn heartbeat(&self) -> Heartbeat {
+        let myself = self.myself();
+        let gossip = self
+            .nodes
+            .values()
+            .filter(|node| node.id != self.myself)
+            .map(|node| Gossip {
+                id: node.id.clone(),
+                ip: node.ip.clone(),
+                port: node.port,
+                bus_port: node.bus_port,
+                failing: node.pfail || node.fail,
+            })
+            .collect();
+
+        Heartbeat {
+            sender: self.myself.clone(),
+            port: myself.port,
+            bus_port: myself.bus_port,
+            config_epoch: myself.config_epoch,
+            current_epoch: self.current_epoch,
+            slots: self.slot_ranges(&self.myself),
+            gossip,
+        }
+    }
+
+    /// Record that a ping was sent to the node `id`.
+    pub(super) fn ping_sent(&mut self, id: &str) {
+        if let Some(node) = self.nodes.get_mut(id) {
+            node.ping_sent.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Process `msg`, received from `ip`, and return the reply to send, if
+    /// any.
+    pub(super) fn receive(&mut self, msg: BusMessage, ip: &str) -> Option<BusMessage> {
+        match msg {
+            BusMessage::Ping(hb) => {
+                self.receive_heartbeat(hb, ip, false);
+                Some(BusMessage::Pong(self.heartbeat()))
+            }
+            BusMessage::Meet(hb) => {
+                self.receive_heartbeat(hb, ip, true);
+                Some(BusMessage::Pong(self.heartbeat()))
+            }
+            // Pongs from unknown nodes reply to a meet.
+            BusMessage::Pong(hb) => {
+                let sender = hb.sender.clone();
+                if self.receive_heartbeat(hb, ip, true) {
+                    if let Some(node) = self.nodes.get_mut(&sender) {
+                        node.ping_sent = None;
+                        node.pong_received = Some(Instant::now());
+                    }
+                }
+                None
+            }
+            BusMessage::Fail { sender, node } => {
+                self.receive_fail(&sender, &node);
+                None
+            }
+        }
+    }
+
+    /// Update the sender of `hb`, its slots and what it knows about the
+    /// other nodes. Unknown senders are ignored, unless `add` is set.
+    ///
+    /// Returns `true` if the sender is known.
+    fn receive_heartbeat(&mut self, hb: Heartbeat, ip: &str, add: bool) -> bool {
+        if hb.sender == self.myself {
+            return false;
+        }
+
+        if !self.nodes.contains_key(&hb.sender) {
+            if !add {
+                return false;
+            }
+            info!(id = %hb.sender, %ip, port = hb.port, "met node");
+            let node = Node::new(hb.sender.clone(), ip.to_string(), hb.port, hb.bus_port);
+            self.nodes.insert(hb.sender.clone(), node);
+        }
+
+        if let Some(node) = self.nodes.get_mut(&hb.sender) {
+            node.ip = ip.to_string();
+            node.port = hb.port;
+            node.bus_port = hb.bus_port;
+            node.config_epoch = hb.config_epoch;
+            node.last_seen = Instant::now();
+            if node.fail {
+                node.fail = false;
+                info!(id = %node.id, "node is reachable again");
+            }
+        }
+
+        self.current_epoch = self.current_epoch.max(hb.current_epoch);
+        self.update_slots(&hb.sender, hb.config_epoch, &hb.slots);
+
+        if hb.config_epoch == self.myself().config_epoch && hb.sender < self.myself {
+            self.bump_config_epoch();
+        }
+
+        self.receive_gossip(&hb.sender, hb.gossip);
+        true
+    }
+
+    /// Adopt the claim of the node `sender`, of `config_epoch`, on the
+    /// slots of `ranges`. Slots it served and no longer claims are no
+    /// longer served.
+    fn update_slots(&mut self, sender: &str, config_epoch: u64, ranges: &[(u16, u16)]) {
+        let mut claimed = vec![false; SLOTS];
+        for (start, end) in ranges {
+            for slot in *start..=*end {
+                claimed[slot as usize] = true;
+            }
+        }
+
+        for (slot, claimed) in claimed.into_iter().enumerate() {
+            if !claimed {
+                if self.slots[slot].as_deref() == Some(sender) {
+                    self.slots[slot] = None;
+                }
+                continue;
+            }
+
+            // Slots being imported change hands with `CLUSTER SETSLOT`.
+            if self.importing.contains_key(&(slot as u16)) {
+                continue;
+            }
+
+            let (wins, lost) = match &self.slots[slot] {
+                Some(id) if id == sender => continue,
+                Some(id) => (
+                    self.nodes
+                        .get(id)
+                        .is_none_or(|owner| owner.config_epoch < config_epoch),
+                    *id == self.myself,
+                ),
+                None => (true, false),
+            };
+
+            if wins {
+                if lost {
+                    warn!(slot, node = %sender, "slot taken over by another node");
+                    self.migrating.remove(&(slot as u16));
+                }
+                self.slots[slot] = Some(sender.to_string());
+            }
+        }
+    }
+
+    /// Record the failure reports of `gossip`, sent by the node `sender`,
+    /// and add the nodes it knows that this one does not.
+    fn receive_gossip(&mut self, sender: &str, gossip: Vec<Gossip>) {
+        for entry in gossip {
+            if entry.id == self.myself {
+                continue;
+            }
+
+            match self.nodes.get_mut(&entry.id) {
+                Some(node) => {
+                    if entry.failing {
+                        node.fail_reports.insert(sender.to_string(), Instant::now());
+                    } else {
+                        node.fail_reports.remove(sender);
+                    }
+                }
+                None if !entry.failing => {
+                    info!(id = %entry.id, ip = %entry.ip, port = entry.port, "discovered node");
+                    let node = Node::new(entry.id.clone(), entry.ip, entry.port, entry.bus_port);
+                    self.nodes.insert(entry.id, node);
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn receive_fail(&mut self, sender: &str, id: &str) {
+        if !self.nodes.contains_key(sender) || id == self.myself {
+            return;
+        }
+
+        if let Some(node) = self.nodes.get_mut(id) {
+            if !node.fail {
+                node.fail = true;
+                warn!(%id, reporter = %sender, "node failed");
+            }
+        }
+    }
+
+    /// Flag the nodes not heard from for `node_timeout` as possibly
+    /// failing, and those a majority of the nodes serving slots report as
+    /// failing.
+    ///
+    /// Returns what the cluster bus has to do next.
+    pub(super) fn cron(&mut self, node_timeout: Duration) -> Outbox {
+        let serving = self.serving();
+        let majority = serving.len() / 2 + 1;
+        let own_report = usize::from(serving.contains(&self.myself));
+
+        for node in self.nodes.values_mut() {
+            if node.id == self.myself {
+                continue;
+            }
+
+            node.fail_reports
+                .retain(|_, at| at.elapsed() <= 2 * node_timeout);
+
+            let pfail = node.last_seen.elapsed() > node_timeout;
+            if pfail != node.pfail {
+                node.pfail = pfail;
+                if pfail {
+                    warn!(id = %node.id, "node is possibly failing");
+                }
+            }
+
+            if node.pfail && !node.fail {
+                let reports = own_report
+                    + node
+                        .fail_reports
+                        .keys()
+                        .filter(|id| serving.contains(*id))
+                        .count();
+                if reports >= majority {
+                    node.fail = true;
+                    warn!(id = %node.id, reports, "node failed");
+                    self.failures.push(node.id.clone());
+                }
+            }
+        }
+
+        let failures = self
+            .failures
+            .drain(..)
+            .map(|node| BusMessage::Fail {
+                sender: self.myself.clone(),
+                node,
+            })
+            .collect();
+        let peers = self
+            .nodes
+            .keys()
+            .filter(|id| **id != self.myself)
+            .cloned()
+            .collect();
+
+        Outbox {
+            meets: std::mem::take(&mut self.meets),
+            failures,
+            peers,
+        }
+    }
+}
+
+impl Node {
+    fn new(id: String, ip: String, port: u16, bus_port: u16) -> Node {
+        Node {
+            id,
+            ip,
+            port,
+            bus_port,
+            config_epoch: 0,
+            last_seen: Instant::now(),
+            ping_sent: None,
+            pong_received: None,
+            pfail: false,
+            fail: false,
+            fail_reports: HashMap::new(),
+        }
+    }
+}
+
+/// Returns the slots of `slots` with the ID of the other node, in order.
+fn sorted(slots: &HashMap<u16, String>) -> Vec<(u16, &str)> {
+    let mut slots: Vec<_> = slots
+        .iter()
+        .map(|(slot, id)| (*slot, id.as_str()))
+        .collect();
+    slots.sort();
+    slots
+}
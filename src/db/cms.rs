@@ -0,0 +1,189 @@
+use super::hash::hash64;
+use super::snapshot::{Decoder, Encoder};
+
+/// Largest number of counters of a sketch, 512MB worth. Larger sketches are
+/// refused rather than failing to allocate.
+pub(crate) const MAX_COUNTERS: u64 = 64 << 20;
+
+/// A Count-Min sketch.
+///
+/// The sketch estimates how often each item was counted using a fixed
+/// `depth` x `width` matrix of counters, regardless of how many distinct items
+/// are seen. Each row hashes the item to one counter. The estimate is the
+/// smallest of those counters, so it may over count but never under counts.
+#[derive(Debug, Clone)]
+pub(crate) struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    /// Row major `depth` x `width` counters.
+    counters: Vec<u64>,
+}
+
+impl CountMinSketch {
+    /// Create a sketch with the given dimensions.
+    pub(crate) fn new(width: usize, depth: usize) -> CountMinSketch {
+        let width = width.max(1);
+        let depth = depth.max(1);
+
+        CountMinSketch {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    /// Create a sketch whose estimates exceed the true count by at most
+    /// `error` times the total count, with the given `probability` of the
+    /// bound not holding.
+    pub(crate) fn with_error(error: f64, probability: f64) -> CountMinSketch {
+        let (width, depth) = CountMinSketch::dimensions(error, probability);
+
+        CountMinSketch::new(width as usize, depth as usize)
+    }
+
+    /// Returns the width and depth of the sketch `with_error` creates. They
+    /// saturate at `u64::MAX` for tiny `error` and `probability`.
+    pub(crate) fn dimensions(error: f64, probability: f64) -> (u64, u64) {
+        let width = (std::f64::consts::E / error).ceil() as u64;
+        let depth = (1.0 / probability).ln().ceil() as u64;
+
+        (width, depth)
+    }
+
+    /// Returns `true` if a sketch of `width` x `depth` counters is no larger
+    /// than `MAX_COUNTERS`.
+    pub(crate) fn fits(width: u64, depth: u64) -> bool {
+        width
+            .checked_mul(depth)
+            .is_some_and(|counters| counters <= MAX_COUNTERS)
+    }
+
+    /// Increase the count of `item` by `increment`. Returns the new estimate.
+    pub(crate) fn incr_by(&mut self, item: &[u8], increment: u64) -> u64 {
+        let mut estimate = u64::MAX;
+
+        for row in 0..self.depth {
+            let i = self.index(item, row);
+            self.counters[i] = self.counters[i].saturating_add(increment);
+            estimate = estimate.min(self.counters[i]);
+        }
+
+        estimate
+    }
+
+    /// Returns the estimated count of `item`.
+    pub(crate) fn query(&self, item: &[u8]) -> u64 {
+        (0..self.depth)
+            .map(|row| self.counters[self.index(item, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Replace the counters with the weighted sum of the counters of
+    /// `sources`.
+    ///
+    /// Returns `Err` if any source has different dimensions. The sketch is
+    /// left unchanged in that case.
+    pub(crate) fn merge(&mut self, sources: &[(CountMinSketch, u64)]) -> Result<(), &'static str> {
+        if sources
+            .iter()
+            .any(|(src, _)| src.width != self.width || src.depth != self.depth)
+        {
+            return Err("ERR CMS: width/depth is not equal");
+        }
+
+        let mut counters = vec![0u64; self.counters.len()];
+        for (src, weight) in sources {
+            for (dst, count) in counters.iter_mut().zip(&src.counters) {
+                *dst = dst.saturating_add(count.saturating_mul(*weight));
+            }
+        }
+
+        self.counters = counters;
+        Ok(())
+    }
+
+    /// Encode the sketch for a snapshot.
+    pub(crate) fn encode(&self, enc: &mut Encoder) {
+        enc.u64(self.width as u64);
+        enc.u64(self.depth as u64);
+        for count in &self.counters {
+            enc.u64(*count);
+        }
+    }
+
+    /// Decode a sketch encoded by `encode`.
+    pub(crate) fn decode(dec: &mut Decoder) -> Option<CountMinSketch> {
+        let width = dec.len()?;
+        let depth = dec.len()?;
+        if width == 0 || depth == 0 {
+            return None;
+        }
+
+        let mut counters = vec![];
+        for _ in 0..width.checked_mul(depth)? {
+            counters.push(dec.u64()?);
+        }
+
+        Some(CountMinSketch {
+            width,
+            depth,
+            counters,
+        })
+    }
+
+    fn index(&self, item: &[u8], row: usize) -> usize {
+        row * self.width + (hash64(item, row as u64) % self.width as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_never_under_count() {
+        let mut sketch = CountMinSketch::with_error(0.01, 0.01);
+
+        for i in 0..1000u64 {
+            sketch.incr_by(format!("item:{}", i).as_bytes(), i % 10 + 1);
+        }
+
+        // 5500 counted in total, so estimates are at most 55 over.
+        for i in 0..1000u64 {
+            let count = i % 10 + 1;
+            let estimate = sketch.query(format!("item:{}", i).as_bytes());
+            assert!(estimate >= count);
+            assert!(estimate <= count + 55, "{} estimated {}", i, estimate);
+        }
+    }
+
+    #[test]
+    fn tiny_error_bounds_do_not_fit() {
+        let (width, depth) = CountMinSketch::dimensions(0.001, 0.01);
+        assert_eq!((width, depth), (2719, 5));
+        assert!(CountMinSketch::fits(width, depth));
+
+        let (width, depth) = CountMinSketch::dimensions(1e-300, 1e-300);
+        assert_eq!(width, u64::MAX);
+        assert!(!CountMinSketch::fits(width, depth));
+        assert!(!CountMinSketch::fits(MAX_COUNTERS, 2));
+        assert!(CountMinSketch::fits(MAX_COUNTERS, 1));
+    }
+
+    #[test]
+    fn merge_sums_weighted_counters() {
+        let mut a = CountMinSketch::new(100, 5);
+        let mut b = CountMinSketch::new(100, 5);
+        a.incr_by(b"x", 3);
+        b.incr_by(b"x", 4);
+
+        let mut dst = CountMinSketch::new(100, 5);
+        dst.merge(&[(a, 1), (b, 2)]).unwrap();
+        assert_eq!(dst.query(b"x"), 11);
+
+        let other = CountMinSketch::new(10, 5);
+        assert!(dst.merge(&[(other, 1)]).is_err());
+        assert_eq!(dst.query(b"x"), 11);
+    }
+}
@@ -0,0 +1,392 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use super::glob_match;
+use super::KeyspaceEvents;
+
+/// Server parameters, read and changed at runtime with `CONFIG GET` and
+/// `CONFIG SET`.
+#[derive(Debug)]
+pub(crate) struct Config {
+    /// Number of messages a pub/sub channel buffers for its slowest
+    /// subscriber. Applies to channels created after the change.
+    pub(crate) pubsub_channel_capacity: usize,
+    /// Number of messages logged per channel for subscribers to replay with
+    /// `SUBSCRIBE channel FROM offset`. Zero, the default, disables logging.
+    pub(crate) pubsub_log_size: usize,
+    /// Seconds after its last message that the log of a channel without
+    /// subscribers is dropped. Zero keeps logs until logging is turned off.
+    pub(crate) pubsub_log_ttl: u64,
+    /// Keyspace events published on changes to the keys, none by default.
+    pub(crate) notify_keyspace_events: KeyspaceEvents,
+    /// Milliseconds a script may run before other connections are replied
+    /// `BUSY` and `SCRIPT KILL` becomes useful.
+    pub(crate) busy_reply_threshold: u64,
+    /// Rules triggering a `BGSAVE`: after the given number of seconds, if at
+    /// least the given number of changes were made. None by default, in
+    /// which case the snapshot is not loaded on startup either.
+    pub(crate) save: Vec<(u64, u64)>,
+    /// Directory the snapshot and the other files are written to. Only set
+    /// on startup.
+    pub(crate) dir: String,
+    /// File name of the snapshot, within `dir`.
+    pub(crate) dbfilename: String,
+    /// Whether write commands are logged to the append-only file, which is
+    /// then loaded on startup instead of the snapshot.
+    pub(crate) appendonly: bool,
+    /// When the append-only file is flushed to disk.
+    pub(crate) appendfsync: AppendFsync,
+    /// File name of the append-only file, within `dir`.
+    pub(crate) appendfilename: String,
+    /// Whether rewrites of the append-only file write every value to a
+    /// snapshot preamble, rather than only those no command can rebuild.
+    pub(crate) aof_use_snapshot_preamble: bool,
+    /// Growth of the append-only file since it was last rewritten, as a
+    /// percentage, that triggers a rewrite. Zero disables automatic rewrites.
+    pub(crate) auto_aof_rewrite_percentage: u64,
+    /// Size in bytes below which the append-only file is not rewritten
+    /// automatically.
+    pub(crate) auto_aof_rewrite_min_size: u64,
+    /// Redis RDB file whose keys are imported on startup, after the data
+    /// saved by a previous run is loaded. Empty, the default, for none.
+    pub(crate) rdb_import: String,
+    /// Size in bytes of the replication backlog, from which replicas that
+    /// reconnect continue without a full synchronization.
+    pub(crate) repl_backlog_size: usize,
+    /// Seconds without hearing from the other end after which a replication
+    /// link is dropped, on both the primary and the replica. Primaries ping
+    /// their replicas every 10 seconds, so it must be longer than that.
+    pub(crate) repl_timeout: u64,
+    /// Limits on the bytes queued for a replica that does not keep up.
+    pub(crate) replica_output_buffer_limit: OutputBufferLimit,
+    /// Primary replicated from startup, as `host port`. Empty, the default,
+    /// for none. `REPLICAOF` changes the primary at runtime.
+    pub(crate) replicaof: String,
+    /// Whether the server runs as a node of a cluster.
+    pub(crate) cluster_enabled: bool,
+    /// Milliseconds a node of the cluster may not reply before it is
+    /// considered failing.
+    pub(crate) cluster_node_timeout: u64,
+}
+
+/// Limits on the bytes queued for a connection, past which it is closed. Set
+/// with `client-output-buffer-limit`, for replicas only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OutputBufferLimit {
+    /// Bytes past which the connection is closed right away. Zero for no
+    /// limit.
+    pub(crate) hard: usize,
+    /// Bytes past which the connection is closed if it stays past them for
+    /// `soft_seconds`. Zero for no limit.
+    pub(crate) soft: usize,
+    pub(crate) soft_seconds: u64,
+}
+
+/// Policies for flushing the append-only file to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AppendFsync {
+    /// After every write command, before replying.
+    Always,
+    /// Once per second, from the background task.
+    EverySec,
+    /// Never, leaving it to the operating system.
+    No,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pubsub_channel_capacity: 1024,
+            pubsub_log_size: 0,
+            pubsub_log_ttl: 3600,
+            notify_keyspace_events: KeyspaceEvents::default(),
+            busy_reply_threshold: 5000,
+            save: vec![],
+            dir: ".".to_string(),
+            dbfilename: "dump.snapshot".to_string(),
+            appendonly: false,
+            appendfsync: AppendFsync::EverySec,
+            appendfilename: "appendonly.aof".to_string(),
+            aof_use_snapshot_preamble: false,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            rdb_import: String::new(),
+            repl_backlog_size: 1024 * 1024,
+            repl_timeout: 60,
+            replica_output_buffer_limit: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            replicaof: String::new(),
+            cluster_enabled: false,
+            cluster_node_timeout: 15000,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the path of the snapshot file.
+    pub(crate) fn snapshot_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.dbfilename)
+    }
+
+    /// Returns the path of the append-only file.
+    pub(crate) fn aof_path(&self) -> PathBuf {
+        PathBuf::from(&self.dir).join(&self.appendfilename)
+    }
+
+    /// Returns the address of the primary set with `replicaof`, if any.
+    pub(crate) fn replicaof(&self) -> Option<(String, u16)> {
+        parse_host_port(&self.replicaof)
+    }
+
+    /// Returns the parameters whose name matches the glob-style `pattern`,
+    /// along with their values.
+    pub(crate) fn get(&self, pattern: &[u8]) -> Vec<(&'static str, String)> {
+        let params = [
+            (
+                "pubsub-channel-capacity",
+                self.pubsub_channel_capacity.to_string(),
+            ),
+            ("pubsub-log-size", self.pubsub_log_size.to_string()),
+            ("pubsub-log-ttl", self.pubsub_log_ttl.to_string()),
+            (
+                "notify-keyspace-events",
+                self.notify_keyspace_events.to_string(),
+            ),
+            (
+                "busy-reply-threshold",
+                self.busy_reply_threshold.to_string(),
+            ),
+            (
+                "save",
+                self.save
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            ("dir", self.dir.clone()),
+            ("dbfilename", self.dbfilename.clone()),
+            ("appendonly", yes_no(self.appendonly)),
+            ("appendfsync", self.appendfsync.to_string()),
+            ("appendfilename", self.appendfilename.clone()),
+            (
+                "aof-use-snapshot-preamble",
+                yes_no(self.aof_use_snapshot_preamble),
+            ),
+            (
+                "auto-aof-rewrite-percentage",
+                self.auto_aof_rewrite_percentage.to_string(),
+            ),
+            (
+                "auto-aof-rewrite-min-size",
+                self.auto_aof_rewrite_min_size.to_string(),
+            ),
+            ("rdb-import", self.rdb_import.clone()),
+            ("repl-backlog-size", self.repl_backlog_size.to_string()),
+            ("repl-timeout", self.repl_timeout.to_string()),
+            (
+                "client-output-buffer-limit",
+                self.replica_output_buffer_limit.to_string(),
+            ),
+            ("replicaof", self.replicaof.clone()),
+            ("cluster-enabled", yes_no(self.cluster_enabled)),
+            (
+                "cluster-node-timeout",
+                self.cluster_node_timeout.to_string(),
+            ),
+        ];
+
+        params
+            .into_iter()
+            .filter(|(name, _)| glob_match(pattern, name.as_bytes()))
+            .collect()
+    }
+
+    /// Set the parameter `name` to `value`.
+    pub(crate) fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match &name.to_lowercase()[..] {
+            "pubsub-channel-capacity" => {
+                self.pubsub_channel_capacity = match value.parse() {
+                    Ok(capacity) if capacity > 0 => capacity,
+                    _ => return Err(invalid_argument(name, value)),
+                };
+            }
+            "pubsub-log-size" => {
+                self.pubsub_log_size = value.parse().map_err(|_| invalid_argument(name, value))?;
+            }
+            "pubsub-log-ttl" => {
+                self.pubsub_log_ttl = value.parse().map_err(|_| invalid_argument(name, value))?;
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events =
+                    KeyspaceEvents::parse(value).ok_or_else(|| invalid_argument(name, value))?;
+            }
+            "busy-reply-threshold" => {
+                self.busy_reply_threshold =
+                    value.parse().map_err(|_| invalid_argument(name, value))?;
+            }
+            "save" => {
+                self.save = parse_save_rules(value).ok_or_else(|| invalid_argument(name, value))?;
+            }
+            "dir" => self.dir = value.to_string(),
+            "dbfilename" => {
+                // The snapshot is always written to `dir`.
+                if value.is_empty() || value.contains('/') {
+                    return Err(invalid_argument(name, value));
+                }
+                self.dbfilename = value.to_string();
+            }
+            "appendonly" => {
+                self.appendonly =
+                    parse_yes_no(value).ok_or_else(|| invalid_argument(name, value))?;
+            }
+            "appendfsync" => {
+                self.appendfsync = match &value.to_lowercase()[..] {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => return Err(invalid_argument(name, value)),
+                };
+            }
+            "appendfilename" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err(invalid_argument(name, value));
+                }
+                self.appendfilename = value.to_string();
+            }
+            "aof-use-snapshot-preamble" => {
+                self.aof_use_snapshot_preamble =
+                    parse_yes_no(value).ok_or_else(|| invalid_argument(name, value))?;
+            }
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage =
+                    value.parse().map_err(|_| invalid_argument(name, value))?;
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size =
+                    value.parse().map_err(|_| invalid_argument(name, value))?;
+            }
+            "rdb-import" => self.rdb_import = value.to_string(),
+            "repl-backlog-size" => {
+                self.repl_backlog_size =
+                    value.parse().map_err(|_| invalid_argument(name, value))?;
+            }
+            "repl-timeout" => {
+                self.repl_timeout = match value.parse() {
+                    Ok(timeout) if timeout > 0 => timeout,
+                    _ => return Err(invalid_argument(name, value)),
+                };
+            }
+            "client-output-buffer-limit" => {
+                self.replica_output_buffer_limit = parse_output_buffer_limit(value)
+                    .ok_or_else(|| invalid_argument(name, value))?;
+            }
+            "replicaof" => {
+                if !value.is_empty() && parse_host_port(value).is_none() {
+                    return Err(invalid_argument(name, value));
+                }
+                self.replicaof = value.to_string();
+            }
+            "cluster-enabled" => {
+                self.cluster_enabled =
+                    parse_yes_no(value).ok_or_else(|| invalid_argument(name, value))?;
+            }
+            "cluster-node-timeout" => {
+                self.cluster_node_timeout = match value.parse() {
+                    Ok(timeout) if timeout > 0 => timeout,
+                    _ => return Err(invalid_argument(name, value)),
+                };
+            }
+            _ => {
+                return Err(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse save rules given as pairs of seconds and changes, such as
+/// `"3600 1 300 100"`. An empty string disables saving.
+fn parse_save_rules(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+
+    Some(numbers.chunks(2).map(|rule| (rule[0], rule[1])).collect())
+}
+
+/// Parse the limits of the replica class given as `"replica hard soft
+/// seconds"`, in bytes. `slave` is accepted for `replica`, as in Redis.
+/// Limits cannot be set for other classes.
+fn parse_output_buffer_limit(value: &str) -> Option<OutputBufferLimit> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    match &parts[..] {
+        [class, hard, soft, soft_seconds]
+            if class.eq_ignore_ascii_case("replica") || class.eq_ignore_ascii_case("slave") =>
+        {
+            Some(OutputBufferLimit {
+                hard: hard.parse().ok()?,
+                soft: soft.parse().ok()?,
+                soft_seconds: soft_seconds.parse().ok()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Parse an address given as `"host port"`.
+fn parse_host_port(value: &str) -> Option<(String, u16)> {
+    let mut parts = value.split_whitespace();
+    let host = parts.next()?;
+    let port = parts.next()?.parse().ok()?;
+
+    parts.next().is_none().then(|| (host.to_string(), port))
+}
+
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match &value.to_lowercase()[..] {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn invalid_argument(name: &str, value: &str) -> String {
+    format!("ERR Invalid argument '{}' for CONFIG SET '{}'", value, name)
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppendFsync::Always => "always".fmt(f),
+            AppendFsync::EverySec => "everysec".fmt(f),
+            AppendFsync::No => "no".fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for OutputBufferLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replica {} {} {}",
+            self.hard, self.soft, self.soft_seconds
+        )
+    }
+}
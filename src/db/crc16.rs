@@ -0,0 +1,76 @@
+//! CRC16, used to map keys to the hash slots of the cluster.
+//!
+//! Clients compute the same slots to send commands to the right node, so this
+//! must be the variant Redis uses: CCITT, also known as XMODEM.
+
+/// Number of hash slots the keys are divided into.
+pub(crate) const SLOTS: usize = 16384;
+
+/// Returns the hash slot of `key`.
+///
+/// Only the part between the first `{` and the next `}` is hashed, if it is
+/// not empty, so that related keys may be kept in the same slot.
+pub(crate) fn key_slot(key: &[u8]) -> u16 {
+    let key = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+
+    crc16(key) % SLOTS as u16
+}
+
+/// Returns the CRC16 checksum of `data`.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_is_the_xmodem_variant() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn keys_map_to_the_slots_of_redis() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b""), 0);
+    }
+
+    #[test]
+    fn only_the_hashtag_is_hashed() {
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        // The first `{` and the next `}` delimit the hashtag.
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[test]
+    fn keys_without_a_hashtag_are_hashed_whole() {
+        // An empty hashtag, or a `{` never closed.
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS as u16);
+        assert_eq!(key_slot(b"{bar"), crc16(b"{bar") % SLOTS as u16);
+        assert_eq!(key_slot(b"bar}"), crc16(b"bar}") % SLOTS as u16);
+    }
+}
@@ -0,0 +1,312 @@
+use super::hash::{hash64, mix, next_random};
+use super::snapshot::{Decoder, Encoder};
+
+/// Number of items `CF.ADD` sizes a filter for when creating it implicitly.
+pub(crate) const DEFAULT_CAPACITY: u64 = 1024;
+
+/// Number of fingerprints stored in each bucket.
+const BUCKET_SIZE: usize = 4;
+
+/// Number of evictions attempted before an insert gives up on a sub-filter.
+const MAX_KICKS: u32 = 500;
+
+/// A cuckoo filter.
+///
+/// Unlike a Bloom filter, a cuckoo filter supports deleting items. Each item is
+/// reduced to a 16 bit fingerprint that is stored in one of two candidate
+/// buckets. When both buckets are full, resident fingerprints are kicked to
+/// their alternate bucket to make room.
+///
+/// When a sub-filter is too full for an insert to succeed, a new sub-filter
+/// twice the size is added. Lookups and deletes check every sub-filter.
+#[derive(Debug, Clone)]
+pub(crate) struct Cuckoo {
+    filters: Vec<SubFilter>,
+    /// State of the xorshift generator used to pick eviction victims.
+    rng: u64,
+}
+
+#[derive(Debug, Clone)]
+struct SubFilter {
+    /// Buckets of fingerprints. `0` marks an empty slot. The number of buckets
+    /// is a power of two so the alternate bucket can be derived with a xor.
+    buckets: Vec<[u16; BUCKET_SIZE]>,
+}
+
+impl Cuckoo {
+    /// Create a filter with room for roughly `capacity` items before it needs
+    /// to grow.
+    pub(crate) fn new(capacity: u64) -> Cuckoo {
+        Cuckoo {
+            filters: vec![SubFilter::new(capacity)],
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Add `item` to the filter. The same item may be added more than once, in
+    /// which case it must be deleted as many times.
+    pub(crate) fn add(&mut self, item: &[u8]) {
+        let (fingerprint, index) = fingerprint(item);
+
+        let last = self.filters.last_mut().unwrap();
+        if last.insert(fingerprint, index, &mut self.rng) {
+            return;
+        }
+
+        // The newest sub-filter is full. Grow by adding a larger one. A fresh
+        // filter always has room for a single fingerprint.
+        let capacity = (last.buckets.len() * BUCKET_SIZE * 2) as u64;
+        let mut filter = SubFilter::new(capacity);
+        filter.insert(fingerprint, index, &mut self.rng);
+        self.filters.push(filter);
+    }
+
+    /// Remove one occurrence of `item`. Returns `false` if it was not found.
+    pub(crate) fn delete(&mut self, item: &[u8]) -> bool {
+        let (fingerprint, index) = fingerprint(item);
+
+        // Delete from the newest sub-filter first, which is the most likely
+        // one to hold recently added items.
+        self.filters
+            .iter_mut()
+            .rev()
+            .any(|filter| filter.delete(fingerprint, index))
+    }
+
+    /// Returns `true` if `item` may be in the filter, `false` if it definitely
+    /// is not.
+    pub(crate) fn contains(&self, item: &[u8]) -> bool {
+        let (fingerprint, index) = fingerprint(item);
+
+        self.filters
+            .iter()
+            .any(|filter| filter.contains(fingerprint, index))
+    }
+
+    /// Encode the filter for a snapshot.
+    pub(crate) fn encode(&self, enc: &mut Encoder) {
+        enc.u64(self.rng);
+        enc.u64(self.filters.len() as u64);
+
+        for filter in &self.filters {
+            enc.u64(filter.buckets.len() as u64);
+            for fingerprint in filter.buckets.iter().flatten() {
+                enc.u16(*fingerprint);
+            }
+        }
+    }
+
+    /// Decode a filter encoded by `encode`.
+    pub(crate) fn decode(dec: &mut Decoder) -> Option<Cuckoo> {
+        let rng = dec.u64()?;
+
+        let mut filters = vec![];
+        for _ in 0..dec.u64()? {
+            let len = dec.len()?;
+            if !len.is_power_of_two() {
+                return None;
+            }
+
+            let mut buckets = vec![];
+            for _ in 0..len {
+                let mut bucket = [0; BUCKET_SIZE];
+                for fingerprint in &mut bucket {
+                    *fingerprint = dec.u16()?;
+                }
+                buckets.push(bucket);
+            }
+            filters.push(SubFilter { buckets });
+        }
+
+        // The generator state must not be zero.
+        if filters.is_empty() || rng == 0 {
+            return None;
+        }
+
+        Some(Cuckoo { filters, rng })
+    }
+}
+
+impl SubFilter {
+    fn new(capacity: u64) -> SubFilter {
+        let buckets = (capacity.max(1) as usize).div_ceil(BUCKET_SIZE);
+
+        SubFilter {
+            buckets: vec![[0; BUCKET_SIZE]; buckets.next_power_of_two()],
+        }
+    }
+
+    /// Store `fingerprint` in one of its candidate buckets, relocating other
+    /// fingerprints if needed. Returns `false`, leaving the sub-filter
+    /// unchanged, if no room could be made.
+    fn insert(&mut self, mut fingerprint: u16, index: u64, rng: &mut u64) -> bool {
+        let i1 = self.bucket(index);
+        let i2 = self.alternate(i1, fingerprint);
+
+        if self.put(i1, fingerprint) || self.put(i2, fingerprint) {
+            return true;
+        }
+
+        // Both buckets are full. Evict a random resident and move it to its
+        // alternate bucket, repeating until a slot is found. Every swap is
+        // recorded so it can be undone if the sub-filter turns out to be full.
+        let mut i = if next_random(rng) & 1 == 1 { i1 } else { i2 };
+        let mut path = Vec::with_capacity(MAX_KICKS as usize);
+
+        for _ in 0..MAX_KICKS {
+            let slot = (next_random(rng) % BUCKET_SIZE as u64) as usize;
+            std::mem::swap(&mut fingerprint, &mut self.buckets[i][slot]);
+            path.push((i, slot));
+
+            i = self.alternate(i, fingerprint);
+            if self.put(i, fingerprint) {
+                return true;
+            }
+        }
+
+        for (i, slot) in path.into_iter().rev() {
+            std::mem::swap(&mut fingerprint, &mut self.buckets[i][slot]);
+        }
+
+        false
+    }
+
+    fn delete(&mut self, fingerprint: u16, index: u64) -> bool {
+        let i1 = self.bucket(index);
+        let i2 = self.alternate(i1, fingerprint);
+
+        for i in [i1, i2] {
+            if let Some(slot) = self.buckets[i].iter_mut().find(|f| **f == fingerprint) {
+                *slot = 0;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn contains(&self, fingerprint: u16, index: u64) -> bool {
+        let i1 = self.bucket(index);
+        let i2 = self.alternate(i1, fingerprint);
+
+        self.buckets[i1].contains(&fingerprint) || self.buckets[i2].contains(&fingerprint)
+    }
+
+    /// Store `fingerprint` in the first empty slot of bucket `i`.
+    fn put(&mut self, i: usize, fingerprint: u16) -> bool {
+        match self.buckets[i].iter_mut().find(|f| **f == 0) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bucket(&self, index: u64) -> usize {
+        (index as usize) & (self.buckets.len() - 1)
+    }
+
+    /// The other candidate bucket of `fingerprint` when it is stored in `i`.
+    /// Applying this twice returns `i`.
+    fn alternate(&self, i: usize, fingerprint: u16) -> usize {
+        (i ^ mix(fingerprint as u64) as usize) & (self.buckets.len() - 1)
+    }
+}
+
+/// Returns the non-zero fingerprint of `item` and the hash used to pick its
+/// primary bucket.
+fn fingerprint(item: &[u8]) -> (u16, u64) {
+    let hash = hash64(item, 0);
+    let fingerprint = (hash >> 48) as u16;
+
+    // `0` marks empty slots, so it cannot be used as a fingerprint.
+    (fingerprint.max(1), hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(i: u64) -> Vec<u8> {
+        format!("item:{}", i).into_bytes()
+    }
+
+    #[test]
+    fn deleted_items_are_no_longer_found() {
+        let mut filter = Cuckoo::new(DEFAULT_CAPACITY);
+
+        filter.add(b"a");
+        filter.add(b"a");
+        assert!(filter.contains(b"a"));
+
+        // Added twice, it must be deleted twice.
+        assert!(filter.delete(b"a"));
+        assert!(filter.contains(b"a"));
+        assert!(filter.delete(b"a"));
+        assert!(!filter.contains(b"a"));
+        assert!(!filter.delete(b"a"));
+    }
+
+    #[test]
+    fn false_positive_rate_is_low() {
+        let mut filter = Cuckoo::new(DEFAULT_CAPACITY);
+
+        for i in 0..1000 {
+            filter.add(&item(i));
+        }
+
+        assert!((0..1000).all(|i| filter.contains(&item(i))));
+
+        // Two buckets of four 16 bit fingerprints: about 8 / 65536.
+        let positives = (1000..101_000)
+            .filter(|i| filter.contains(&item(*i)))
+            .count();
+        assert!(positives < 100, "{} false positives", positives);
+    }
+
+    #[test]
+    fn kicks_fill_a_sub_filter_before_it_gives_up() {
+        let mut filter = SubFilter::new(1024);
+        let mut rng = 1;
+
+        let inserted: Vec<_> = (0..)
+            .map(|i| fingerprint(&item(i)))
+            .take_while(|(fingerprint, index)| filter.insert(*fingerprint, *index, &mut rng))
+            .collect();
+
+        // Without moving fingerprints to their alternate bucket, inserts
+        // would fail far below this load.
+        assert!(
+            inserted.len() > 1024 * 9 / 10,
+            "{} inserted",
+            inserted.len()
+        );
+
+        // The failed insert undid its kicks.
+        assert!(inserted
+            .iter()
+            .all(|(fingerprint, index)| filter.contains(*fingerprint, *index)));
+    }
+
+    #[test]
+    fn grows_once_full() {
+        let mut filter = Cuckoo::new(64);
+
+        for i in 0..1000 {
+            filter.add(&item(i));
+        }
+
+        assert!(filter.filters.len() > 1);
+        assert!((0..1000).all(|i| filter.contains(&item(i))));
+
+        for i in 0..1000 {
+            assert!(filter.delete(&item(i)));
+        }
+        assert!(filter.filters.iter().all(|filter| filter
+            .buckets
+            .iter()
+            .flatten()
+            .all(|fingerprint| *fingerprint == 0)));
+    }
+}
@@ -0,0 +1,63 @@
+//! The payload of `DUMP`, recreated by `RESTORE`.
+//!
+//! # Format
+//!
+//! ```text
+//! value          type:u8 value, as in snapshots
+//! expires_at:u64 Unix time in milliseconds, zero for no expiration
+//! version:u8
+//! checksum:u64   `hash64` of everything before it
+//! ```
+//!
+//! The version and checksum come last, as in Redis, so that the payload
+//! starts with the type of the value.
+
+use bytes::Bytes;
+
+use super::hash::hash64;
+use super::snapshot::{decode_value, encode_value, Decoder, Encoder};
+use super::Value;
+
+const VERSION: u8 = 1;
+
+/// Serialize `value`, which expires at the Unix time `expires_at` in
+/// milliseconds, if any.
+pub(super) fn encode(value: &Value, expires_at: Option<u64>) -> Bytes {
+    let mut enc = Encoder::default();
+    encode_value(value, &mut enc);
+    enc.u64(expires_at.unwrap_or(0));
+    enc.u8(VERSION);
+
+    let mut payload = enc.into_bytes();
+    let checksum = hash64(&payload, 0);
+    payload.extend_from_slice(&checksum.to_le_bytes());
+    Bytes::from(payload)
+}
+
+/// Decode a payload serialized by `encode`. Returns `None` if the payload
+/// is corrupted or of another version.
+pub(super) fn decode(payload: &Bytes) -> Option<(Value, Option<u64>)> {
+    let len = payload.len().checked_sub(8)?;
+    let checksum = u64::from_le_bytes(payload[len..].try_into().ok()?);
+    if hash64(&payload[..len], 0) != checksum {
+        return None;
+    }
+
+    let mut dec = Decoder::new(payload.slice(..len));
+    let value = decode_value(&mut dec)?;
+    let expires_at = match dec.u64()? {
+        0 => None,
+        when => Some(when),
+    };
+
+    (dec.u8()? == VERSION && dec.is_empty()).then_some((value, expires_at))
+}
+
+/// Returns `true` if the payloads `a` and `b` hold the same value, whatever
+/// their expirations.
+pub(super) fn same_value(a: &[u8], b: &[u8]) -> bool {
+    // The expiration, version and checksum.
+    const FOOTER: usize = 8 + 1 + 8;
+
+    a.len() >= FOOTER && b.len() >= FOOTER && a[..a.len() - FOOTER] == b[..b.len() - FOOTER]
+}
@@ -0,0 +1,160 @@
+//! Redis-compatible glob-style pattern matching, used by `KEYS`, the `MATCH`
+//! option of the `SCAN` family and pattern subscriptions.
+
+/// Returns `true` if `text` matches `pattern`.
+///
+/// The pattern syntax is:
+///
+/// * `*` matches any sequence of bytes, including an empty one.
+/// * `?` matches a single byte.
+/// * `[abc]` matches one of the listed bytes, `[a-c]` a range of bytes and
+///   `[^abc]` any byte not listed.
+/// * `\` escapes the next byte, matching it literally.
+///
+/// Matching is byte-wise and case sensitive.
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and of the text it was matched against.
+    // On a mismatch, the `*` is extended by one byte and matching resumes.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+
+            if let Some(next) = match_one(pattern, p, text[t]) {
+                p = next;
+                t += 1;
+                continue;
+            }
+        }
+
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Match the single byte `c` against the pattern element starting at `p`.
+/// Returns the position of the next pattern element on a match.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+
+            let mut matched = false;
+
+            // An unterminated class extends to the end of the pattern.
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (lo, hi) = (
+                        pattern[i].min(pattern[i + 2]),
+                        pattern[i].max(pattern[i + 2]),
+                    );
+                    matched |= (lo..=hi).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        b => (b == c).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn literals() {
+        assert!(matches("hello", "hello"));
+        assert!(!matches("hello", "hell"));
+        assert!(!matches("hello", "hello!"));
+        assert!(!matches("hello", "Hello"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn stars() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("h*o", "ho"));
+        assert!(matches("h*o", "hello"));
+        assert!(!matches("h*o", "help"));
+        assert!(matches("*llo", "hello"));
+        assert!(matches("he**", "hello"));
+        // The star must backtrack past the first `b`.
+        assert!(matches("a*b*c", "abxbyc"));
+        assert!(!matches("a*b*c", "abxbyd"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+        // Reversed ranges are accepted, as in Redis.
+        assert!(matches("h[b-a]llo", "hallo"));
+        assert!(matches("[\\]]", "]"));
+        // An unterminated class extends to the end of the pattern.
+        assert!(matches("h[ae", "ha"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("a\\?", "a?"));
+        assert!(!matches("a\\?", "ab"));
+        // A trailing backslash matches itself.
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn bytes() {
+        assert!(glob_match(b"\xff*", b"\xff\x00\x01"));
+        assert!(glob_match(b"?", b"\x80"));
+    }
+}
@@ -0,0 +1,40 @@
+//! Hashing and pseudo-random numbers shared by the probabilistic value types.
+//!
+//! The filters and sketches store bit positions and fingerprints derived from
+//! these hashes, so the algorithm must be stable across processes. This rules
+//! out `std::collections::hash_map::DefaultHasher`, whose algorithm is not
+//! guaranteed to stay the same between Rust releases.
+
+/// Hash `data` to a 64 bit value. Different `seed`s yield independent hashes.
+///
+/// This is FNV-1a followed by the `splitmix64` finalizer, which fixes the poor
+/// avalanche behaviour of plain FNV on short inputs.
+pub(crate) fn hash64(data: &[u8], seed: u64) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = OFFSET ^ mix(seed);
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+
+    mix(hash)
+}
+
+/// The `splitmix64` finalizer.
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Advance a xorshift64 generator and return its next value. `state` must not
+/// be zero.
+pub(crate) fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
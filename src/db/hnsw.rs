@@ -0,0 +1,316 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use super::hash::next_random;
+
+/// A Hierarchical Navigable Small World graph for approximate nearest
+/// neighbour search.
+///
+/// Every vector is a node in a stack of proximity graphs. Each node is placed
+/// in layer `0` and, with exponentially decreasing probability, in the layers
+/// above. Searches start at the sparse top layer and greedily descend,
+/// refining the candidates in each denser layer.
+///
+/// Nodes live in `nodes`, addressed by their slot. Removed nodes leave a
+/// `None` slot that is reused by the next insert.
+#[derive(Debug)]
+pub(crate) struct Hnsw {
+    /// Maximum number of neighbours per node in the layers above `0`. Layer
+    /// `0` allows twice as many.
+    m: usize,
+    /// Size of the candidate list used while inserting.
+    ef_construction: usize,
+    nodes: Vec<Option<Node>>,
+    /// Slot of each key.
+    slots: HashMap<String, usize>,
+    /// Slots of removed nodes, ready for reuse.
+    free: Vec<usize>,
+    /// Slot of the node searches start from. It is a node on the top layer.
+    entry: Option<usize>,
+    /// State of the xorshift generator used to draw node levels.
+    rng: u64,
+}
+
+#[derive(Debug)]
+struct Node {
+    key: String,
+    vector: Vec<f32>,
+    /// Neighbour slots, per layer. The node's level is `neighbours.len() - 1`.
+    neighbours: Vec<Vec<usize>>,
+}
+
+/// A candidate found while searching: its distance to the query and its slot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate(f32, usize);
+
+impl Hnsw {
+    pub(crate) fn new(m: usize, ef_construction: usize) -> Hnsw {
+        Hnsw {
+            m: m.max(2),
+            ef_construction: ef_construction.max(1),
+            nodes: vec![],
+            slots: HashMap::new(),
+            free: vec![],
+            entry: None,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns the vector of `key`.
+    pub(crate) fn vector(&self, key: &str) -> Option<&[f32]> {
+        self.slots.get(key).map(|&slot| &self.node(slot).vector[..])
+    }
+
+    /// Insert the vector of `key`, replacing its previous vector.
+    pub(crate) fn insert(
+        &mut self,
+        key: String,
+        vector: Vec<f32>,
+        distance: impl Fn(&[f32], &[f32]) -> f32,
+    ) {
+        self.remove(&key, &distance);
+
+        let level = self.random_level();
+        let node = Node {
+            key: key.clone(),
+            vector,
+            neighbours: vec![vec![]; level + 1],
+        };
+
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.slots.insert(key, slot);
+
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.entry = Some(slot);
+                return;
+            }
+        };
+
+        let query = self.node(slot).vector.clone();
+        let top = self.level(entry);
+
+        // Greedily descend through the layers above the new node's level.
+        let mut nearest = vec![Candidate(distance(&query, &self.node(entry).vector), entry)];
+        for layer in (level + 1..=top).rev() {
+            nearest = self.search_layer(&query, nearest, 1, layer, &distance);
+        }
+
+        // Connect the node in every layer it belongs to.
+        for layer in (0..=level.min(top)).rev() {
+            nearest = self.search_layer(&query, nearest, self.ef_construction, layer, &distance);
+
+            let neighbours: Vec<usize> = nearest.iter().take(self.m).map(|c| c.1).collect();
+            self.node_mut(slot).neighbours[layer] = neighbours.clone();
+
+            for neighbour in neighbours {
+                self.node_mut(neighbour).neighbours[layer].push(slot);
+                self.prune(neighbour, layer, &distance);
+            }
+        }
+
+        if level > top {
+            self.entry = Some(slot);
+        }
+    }
+
+    /// Remove the vector of `key`.
+    ///
+    /// Pruning makes links one-way, so every node is checked for links to the
+    /// removed node. Nodes that lost a link are reconnected to the removed
+    /// node's own neighbours to limit the loss of connectivity.
+    pub(crate) fn remove(&mut self, key: &str, distance: impl Fn(&[f32], &[f32]) -> f32) {
+        let slot = match self.slots.remove(key) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let removed = self.nodes[slot].take().unwrap();
+        self.free.push(slot);
+
+        let mut repaired = vec![];
+
+        for (other, node) in self.nodes.iter_mut().enumerate() {
+            let node = match node {
+                Some(node) => node,
+                None => continue,
+            };
+
+            for (layer, links) in node.neighbours.iter_mut().enumerate() {
+                if !links.contains(&slot) {
+                    continue;
+                }
+
+                links.retain(|&link| link != slot);
+
+                for &replacement in &removed.neighbours[layer] {
+                    if replacement != other && !links.contains(&replacement) {
+                        links.push(replacement);
+                    }
+                }
+
+                repaired.push((other, layer));
+            }
+        }
+
+        for (other, layer) in repaired {
+            self.prune(other, layer, &distance);
+        }
+
+        // Pick a new entry point if needed, preferring the node on the highest
+        // layer.
+        if self.entry == Some(slot) {
+            self.entry = self
+                .nodes
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, node)| node.as_ref().map(|node| (slot, node.neighbours.len())))
+                .max_by_key(|(_, levels)| *levels)
+                .map(|(slot, _)| slot);
+        }
+    }
+
+    /// Returns up to `k` keys closest to `query`, with their distance, closest
+    /// first. `ef` is the size of the candidate list and trades speed for
+    /// recall.
+    pub(crate) fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        ef: usize,
+        distance: impl Fn(&[f32], &[f32]) -> f32,
+    ) -> Vec<(String, f32)> {
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+
+        let mut nearest = vec![Candidate(distance(query, &self.node(entry).vector), entry)];
+        for layer in (1..=self.level(entry)).rev() {
+            nearest = self.search_layer(query, nearest, 1, layer, &distance);
+        }
+
+        self.search_layer(query, nearest, ef.max(k), 0, &distance)
+            .into_iter()
+            .take(k)
+            .map(|Candidate(dist, slot)| (self.node(slot).key.clone(), dist))
+            .collect()
+    }
+
+    /// Beam search of a single layer, starting from `entries`. Returns up to
+    /// `ef` candidates, closest first.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entries: Vec<Candidate>,
+        ef: usize,
+        layer: usize,
+        distance: &impl Fn(&[f32], &[f32]) -> f32,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().map(|c| c.1).collect();
+        // Min-heap of candidates to expand and max-heap of the best results.
+        let mut candidates: BinaryHeap<std::cmp::Reverse<Candidate>> =
+            entries.iter().map(|c| std::cmp::Reverse(*c)).collect();
+        let mut results: BinaryHeap<Candidate> = entries.into_iter().collect();
+
+        while let Some(std::cmp::Reverse(current)) = candidates.pop() {
+            let furthest = results.peek().map(|c| c.0).unwrap_or(f32::INFINITY);
+            if current.0 > furthest && results.len() >= ef {
+                break;
+            }
+
+            for &neighbour in &self.node(current.1).neighbours[layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+
+                let dist = distance(query, &self.node(neighbour).vector);
+                let furthest = results.peek().map(|c| c.0).unwrap_or(f32::INFINITY);
+
+                if results.len() < ef || dist < furthest {
+                    candidates.push(std::cmp::Reverse(Candidate(dist, neighbour)));
+                    results.push(Candidate(dist, neighbour));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// Keep only the closest neighbours of `slot` in `layer`.
+    fn prune(&mut self, slot: usize, layer: usize, distance: &impl Fn(&[f32], &[f32]) -> f32) {
+        let max = if layer == 0 { self.m * 2 } else { self.m };
+
+        let node = self.node(slot);
+        if node.neighbours[layer].len() <= max {
+            return;
+        }
+
+        let mut neighbours: Vec<Candidate> = node.neighbours[layer]
+            .iter()
+            .filter_map(|&n| {
+                self.nodes[n]
+                    .as_ref()
+                    .map(|other| Candidate(distance(&node.vector, &other.vector), n))
+            })
+            .collect();
+        neighbours.sort();
+        neighbours.truncate(max);
+
+        self.node_mut(slot).neighbours[layer] = neighbours.into_iter().map(|c| c.1).collect();
+    }
+
+    /// Draw the level of a new node. Each level is `1 / m` times less likely
+    /// than the one below.
+    fn random_level(&mut self) -> usize {
+        let uniform = (next_random(&mut self.rng) >> 11) as f64 / (1u64 << 53) as f64;
+        let level = -(1.0 - uniform).ln() / (self.m as f64).ln();
+        (level as usize).min(16)
+    }
+
+    fn level(&self, slot: usize) -> usize {
+        self.node(slot).neighbours.len() - 1
+    }
+
+    fn node(&self, slot: usize) -> &Node {
+        self.nodes[slot].as_ref().expect("dangling HNSW slot")
+    }
+
+    fn node_mut(&mut self, slot: usize) -> &mut Node {
+        self.nodes[slot].as_mut().expect("dangling HNSW slot")
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .partial_cmp(&other.0)
+            .unwrap_or(Ordering::Equal)
+            .then(self.1.cmp(&other.1))
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// Classes of keyspace events to publish, as set by the
+/// `notify-keyspace-events` parameter.
+///
+/// Uses the flags of Redis: `K` and `E` select the `__keyspace@0__` and
+/// `__keyevent@0__` channels, the other flags select the classes of events
+/// sent to them. Nothing is published unless both a channel and a class are
+/// selected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct KeyspaceEvents(u16);
+
+/// The flags, in the order they are displayed.
+const FLAGS: [(char, KeyspaceEvents); 12] = [
+    ('g', KeyspaceEvents::GENERIC),
+    ('$', KeyspaceEvents::STRING),
+    ('l', KeyspaceEvents::LIST),
+    ('s', KeyspaceEvents::SET),
+    ('h', KeyspaceEvents::HASH),
+    ('z', KeyspaceEvents::ZSET),
+    ('x', KeyspaceEvents::EXPIRED),
+    ('e', KeyspaceEvents::EVICTED),
+    ('t', KeyspaceEvents::STREAM),
+    ('d', KeyspaceEvents::MODULE),
+    ('K', KeyspaceEvents::KEYSPACE),
+    ('E', KeyspaceEvents::KEYEVENT),
+];
+
+impl KeyspaceEvents {
+    /// Publish to `__keyspace@0__:<key>`, with the event as the message.
+    pub(crate) const KEYSPACE: KeyspaceEvents = KeyspaceEvents(1 << 0);
+    /// Publish to `__keyevent@0__:<event>`, with the key as the message.
+    pub(crate) const KEYEVENT: KeyspaceEvents = KeyspaceEvents(1 << 1);
+    /// Type independent events, such as `del` and `expire`.
+    pub(crate) const GENERIC: KeyspaceEvents = KeyspaceEvents(1 << 2);
+    /// String events, such as `set`.
+    pub(crate) const STRING: KeyspaceEvents = KeyspaceEvents(1 << 3);
+    /// Hash events, such as `hset` and `hdel`.
+    pub(crate) const HASH: KeyspaceEvents = KeyspaceEvents(1 << 6);
+    /// Keys removed by the background task once their TTL elapsed.
+    pub(crate) const EXPIRED: KeyspaceEvents = KeyspaceEvents(1 << 8);
+
+    // Classes this server publishes no events for. They are accepted so that
+    // configurations written for Redis are valid.
+    const LIST: KeyspaceEvents = KeyspaceEvents(1 << 4);
+    const SET: KeyspaceEvents = KeyspaceEvents(1 << 5);
+    const ZSET: KeyspaceEvents = KeyspaceEvents(1 << 7);
+    const EVICTED: KeyspaceEvents = KeyspaceEvents(1 << 9);
+    const STREAM: KeyspaceEvents = KeyspaceEvents(1 << 10);
+    const MODULE: KeyspaceEvents = KeyspaceEvents(1 << 11);
+
+    /// Every class of events, selected by the `A` flag.
+    const ALL: KeyspaceEvents = KeyspaceEvents(0b1111_1111_1100);
+
+    /// Parse the flags of `notify-keyspace-events`. Returns `None` if a flag
+    /// is unknown.
+    pub(crate) fn parse(flags: &str) -> Option<KeyspaceEvents> {
+        flags.chars().try_fold(KeyspaceEvents(0), |events, flag| {
+            let class = match flag {
+                'A' => KeyspaceEvents::ALL,
+                _ => FLAGS.iter().find(|(f, _)| *f == flag)?.1,
+            };
+            Some(KeyspaceEvents(events.0 | class.0))
+        })
+    }
+
+    /// Returns true if events of `class` are published to at least one
+    /// channel.
+    pub(crate) fn publishes(self, class: KeyspaceEvents) -> bool {
+        self.0 & class.0 != 0
+            && self.0 & (KeyspaceEvents::KEYSPACE.0 | KeyspaceEvents::KEYEVENT.0) != 0
+    }
+
+    /// Returns true if all the flags of `other` are set.
+    pub(crate) fn contains(self, other: KeyspaceEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (flag, class) in FLAGS {
+            if self.contains(class) {
+                write!(fmt, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
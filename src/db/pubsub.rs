@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Instant;
+
+/// A pub/sub channel or pattern.
+///
+/// Subscribers either tolerate losing messages or push back on publishers,
+/// see `LagPolicy`. Each group has its own broadcast channel so that a slow
+/// lossy subscriber never blocks publishers.
+#[derive(Debug)]
+pub(crate) struct Channel<T> {
+    /// Broadcast to subscribers that may lag behind. When the channel's
+    /// capacity fills up, publishing drops their oldest messages.
+    lossy: broadcast::Sender<T>,
+    /// Broadcast to subscribers applying backpressure. Publishers wait for
+    /// these to catch up instead of dropping messages.
+    blocking: broadcast::Sender<T>,
+    /// Number of messages buffered for the slowest subscriber.
+    capacity: usize,
+    /// Notified whenever a backpressure subscriber makes room in `blocking`.
+    space: Arc<Notify>,
+}
+
+/// What to do when a subscriber falls behind publishers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Drop the oldest messages and send the subscriber a `lag` frame
+    /// carrying the number of dropped messages.
+    #[default]
+    Notify,
+    /// Drop the oldest messages and close the subscriber's connection.
+    Disconnect,
+    /// Never drop messages, publishers wait until the subscriber catches up.
+    Backpressure,
+}
+
+impl<T: Clone> Channel<T> {
+    /// Create a channel buffering up to `capacity` messages per subscriber.
+    pub(crate) fn new(capacity: usize) -> Channel<T> {
+        Channel {
+            lossy: broadcast::channel(capacity).0,
+            blocking: broadcast::channel(capacity).0,
+            capacity,
+            space: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Returns a new receiver. With `LagPolicy::Backpressure`, the receiver
+    /// must notify `space` whenever it receives a message.
+    pub(crate) fn subscribe(&self, policy: LagPolicy) -> broadcast::Receiver<T> {
+        match policy {
+            LagPolicy::Backpressure => self.blocking.subscribe(),
+            LagPolicy::Notify | LagPolicy::Disconnect => self.lossy.subscribe(),
+        }
+    }
+
+    /// Returns the `Notify` signalled when a backpressure subscriber makes
+    /// room.
+    pub(crate) fn space(&self) -> &Arc<Notify> {
+        &self.space
+    }
+
+    /// Returns the number of subscribers.
+    pub(crate) fn receiver_count(&self) -> usize {
+        self.lossy.receiver_count() + self.blocking.receiver_count()
+    }
+
+    /// Returns true when a backpressure subscriber has `capacity` messages
+    /// left to receive. Publishing must wait until this returns false.
+    pub(crate) fn is_full(&self) -> bool {
+        self.blocking.receiver_count() > 0 && self.blocking.len() >= self.capacity
+    }
+
+    /// Send `msg` to every subscriber. Returns the number of subscribers.
+    pub(crate) fn send(&self, msg: T) -> usize {
+        // An error indicates there are no receivers, in which case, `0` should
+        // be returned.
+        self.lossy.send(msg.clone()).unwrap_or(0) + self.blocking.send(msg).unwrap_or(0)
+    }
+}
+
+/// A message published to a regular channel.
+#[derive(Debug, Clone)]
+pub(crate) struct Message {
+    /// Position of the message in the channel's `Log`. Only set when
+    /// `pubsub-log-size` is not zero.
+    pub(crate) offset: Option<u64>,
+    pub(crate) payload: Bytes,
+}
+
+/// The last messages published to a channel, kept so that subscribers can
+/// replay the messages they missed.
+///
+/// Offsets start at zero and increase by one with every message.
+#[derive(Debug)]
+pub(crate) struct Log {
+    /// Offset of the next message.
+    next: u64,
+    /// The retained messages, oldest first. The last one has offset
+    /// `next - 1`.
+    entries: VecDeque<Bytes>,
+    /// When the last message was appended.
+    last_append: Instant,
+}
+
+impl Log {
+    pub(crate) fn new() -> Log {
+        Log {
+            next: 0,
+            entries: VecDeque::new(),
+            last_append: Instant::now(),
+        }
+    }
+
+    /// Append `payload`, dropping the oldest messages to keep at most `size`
+    /// of them. Returns the offset of the message.
+    pub(crate) fn append(&mut self, payload: Bytes, size: usize) -> u64 {
+        self.entries.push_back(payload);
+        while self.entries.len() > size {
+            self.entries.pop_front();
+        }
+
+        self.last_append = Instant::now();
+        self.next += 1;
+        self.next - 1
+    }
+
+    /// Returns when the last message was appended.
+    pub(crate) fn last_append(&self) -> Instant {
+        self.last_append
+    }
+
+    /// Returns the retained messages from `offset` onward, along with the
+    /// number of messages from `offset` that are no longer retained.
+    pub(crate) fn since(&self, offset: u64) -> (u64, VecDeque<Message>) {
+        let first = self.next - self.entries.len() as u64;
+        let start = offset.clamp(first, self.next);
+
+        let messages = self
+            .entries
+            .iter()
+            .zip(first..)
+            .skip((start - first) as usize)
+            .map(|(payload, offset)| Message {
+                offset: Some(offset),
+                payload: payload.clone(),
+            })
+            .collect();
+
+        (start.saturating_sub(offset), messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(messages: u64, size: usize) -> Log {
+        let mut log = Log::new();
+        for i in 0..messages {
+            assert_eq!(log.append(Bytes::from(i.to_string()), size), i);
+        }
+        log
+    }
+
+    fn offsets(messages: &VecDeque<Message>) -> Vec<u64> {
+        messages.iter().map(|msg| msg.offset.unwrap()).collect()
+    }
+
+    #[test]
+    fn since_replays_from_the_offset() {
+        let log = log(5, 10);
+
+        let (missed, messages) = log.since(2);
+        assert_eq!(missed, 0);
+        assert_eq!(offsets(&messages), [2, 3, 4]);
+        assert_eq!(messages[0].payload, "2");
+
+        assert_eq!(offsets(&log.since(0).1), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn since_the_next_offset_replays_nothing() {
+        let log = log(5, 10);
+
+        let (missed, messages) = log.since(5);
+        assert_eq!(missed, 0);
+        assert!(messages.is_empty());
+
+        // Offsets not reached yet replay nothing either.
+        let (missed, messages) = log.since(100);
+        assert_eq!(missed, 0);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn since_reports_trimmed_messages() {
+        // Only the last 3 messages, offsets 7 to 9, are retained.
+        let log = log(10, 3);
+
+        let (missed, messages) = log.since(5);
+        assert_eq!(missed, 2);
+        assert_eq!(offsets(&messages), [7, 8, 9]);
+
+        let (missed, messages) = log.since(0);
+        assert_eq!(missed, 7);
+        assert_eq!(offsets(&messages), [7, 8, 9]);
+
+        let (missed, messages) = log.since(8);
+        assert_eq!(missed, 0);
+        assert_eq!(offsets(&messages), [8, 9]);
+    }
+
+    #[test]
+    fn an_empty_log_replays_nothing() {
+        let log = Log::new();
+
+        let (missed, messages) = log.since(0);
+        assert_eq!(missed, 0);
+        assert!(messages.is_empty());
+    }
+}
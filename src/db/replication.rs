@@ -0,0 +1,390 @@
+//! The state of replication: the history of the data set, the backlog of
+//! the commands last propagated, and the feeds of the attached replicas.
+//!
+//! Write commands are propagated encoded as in the append-only file. The
+//! replication offset counts the bytes propagated in the history identified
+//! by the replication ID. Replicas feed the bytes received from their primary
+//! to their own backlog, so that both agree on offsets, and a replica may
+//! serve the history to replicas of its own.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::warn;
+
+use super::config::OutputBufferLimit;
+use super::hash;
+use super::Snapshot;
+
+/// Replication state of a `Db`.
+#[derive(Debug)]
+pub(super) struct Replication {
+    /// ID of the history of the data set, 40 hexadecimal characters. A new
+    /// one is generated when a replica is promoted.
+    pub(super) replid: String,
+    /// ID of the history before the last promotion, along with the offset
+    /// up to which it is shared with the current one. Replicas of the former
+    /// primary may continue from it.
+    replid2: Option<(String, u64)>,
+    /// Number of bytes propagated in the history.
+    pub(super) offset: u64,
+    /// The last bytes propagated. Created once the first replica attaches.
+    backlog: Option<VecDeque<u8>>,
+    /// The replicas attached to this server.
+    replicas: Vec<Replica>,
+    next_replica_id: u64,
+    /// When the replicas were last pinged.
+    pub(super) last_ping: Instant,
+    /// The primary this server replicates, if it is a replica.
+    pub(super) primary: Option<Primary>,
+    /// Port this server listens on, announced to the primary.
+    pub(super) listening_port: u16,
+}
+
+/// A replica attached to this server.
+#[derive(Debug)]
+struct Replica {
+    id: u64,
+    /// Address of the replica: the IP it connects from and the port it
+    /// listens on.
+    ip: String,
+    port: u16,
+    /// Receives the bytes propagated, forwarded to the replica.
+    tx: mpsc::UnboundedSender<Bytes>,
+    /// The bytes sent through `tx` and not taken yet.
+    queue: Arc<Queue>,
+    /// When the queued bytes went past the soft limit, while they are.
+    over_soft_limit: Option<Instant>,
+    /// The offset the replica last acknowledged with `REPLCONF ACK`.
+    ack_offset: u64,
+    /// When the replica last acknowledged its offset, or attached.
+    last_ack: Instant,
+}
+
+/// The bytes propagated to a replica, taken by the connection to the replica
+/// to send them.
+#[derive(Debug)]
+pub(crate) struct ReplicaFeed {
+    rx: mpsc::UnboundedReceiver<Bytes>,
+    queue: Arc<Queue>,
+}
+
+/// The state of the bytes queued for a replica, shared by the `Replica` and
+/// its `ReplicaFeed`.
+#[derive(Debug, Default)]
+struct Queue {
+    /// Number of bytes queued.
+    len: AtomicUsize,
+    /// Set once the replica is detached for queuing too many bytes. The
+    /// bytes still queued are dropped.
+    overflowed: AtomicBool,
+}
+
+/// The state of replication, as reported by `ROLE` and `INFO replication`.
+#[derive(Debug)]
+pub(crate) struct ReplicationInfo {
+    /// The primary of this server, if it is a replica.
+    pub(crate) primary: Option<PrimaryInfo>,
+    /// The replicas attached to this server, in the order they attached.
+    pub(crate) replicas: Vec<ReplicaInfo>,
+    pub(crate) replid: String,
+    pub(crate) replid2: Option<(String, u64)>,
+    pub(crate) offset: u64,
+    /// The offset of the first byte in the backlog and the number of bytes
+    /// it holds, if there is a backlog.
+    pub(crate) backlog: Option<(u64, u64)>,
+    /// The maximum size of the backlog, `repl-backlog-size`.
+    pub(crate) backlog_size: usize,
+}
+
+/// The primary of a replica.
+#[derive(Debug)]
+pub(crate) struct PrimaryInfo {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) link_up: bool,
+    /// Seconds since the last bytes were received from the primary.
+    pub(crate) last_io: Option<u64>,
+}
+
+/// A replica attached to this server.
+#[derive(Debug)]
+pub(crate) struct ReplicaInfo {
+    pub(crate) ip: String,
+    pub(crate) port: u16,
+    pub(crate) ack_offset: u64,
+    /// Seconds since the replica last acknowledged its offset.
+    pub(crate) lag: u64,
+}
+
+/// The primary of a replica, and the task keeping the link to it.
+#[derive(Debug)]
+pub(super) struct Primary {
+    pub(super) host: String,
+    pub(super) port: u16,
+    /// True once synchronized, while the link is up.
+    pub(super) link_up: bool,
+    /// When the last bytes were received from the primary.
+    pub(super) last_io: Option<Instant>,
+    pub(super) task: JoinHandle<()>,
+}
+
+/// How a replica starts synchronizing with `PSYNC`.
+#[derive(Debug)]
+pub(crate) enum SyncStart {
+    /// The replica continues from its offset. The bytes it misses are sent
+    /// first.
+    Continue { replid: String, missed: Bytes },
+    /// The replica loads a snapshot of the data set, taken at `offset`. It
+    /// is encoded once the lock is released.
+    Full {
+        replid: String,
+        offset: u64,
+        snapshot: Snapshot,
+    },
+}
+
+impl Replication {
+    pub(super) fn new() -> Replication {
+        Replication {
+            replid: new_replid(),
+            replid2: None,
+            offset: 0,
+            backlog: None,
+            replicas: vec![],
+            next_replica_id: 0,
+            last_ping: Instant::now(),
+            primary: None,
+            listening_port: 0,
+        }
+    }
+
+    /// Returns `true` if the commands executed must be propagated: this
+    /// server is a primary and keeps a backlog.
+    pub(super) fn is_active(&self) -> bool {
+        self.primary.is_none() && self.backlog.is_some()
+    }
+
+    /// Propagate `buf` to the backlog and the attached replicas. The backlog
+    /// is trimmed to `backlog_size` bytes, and the replicas whose queued
+    /// bytes exceed `limit` are detached.
+    pub(super) fn feed(&mut self, buf: &[u8], backlog_size: usize, limit: OutputBufferLimit) {
+        self.offset += buf.len() as u64;
+
+        if let Some(backlog) = &mut self.backlog {
+            backlog.extend(buf);
+            let excess = backlog.len().saturating_sub(backlog_size);
+            backlog.drain(..excess);
+        }
+
+        if !self.replicas.is_empty() {
+            let buf = Bytes::copy_from_slice(buf);
+            let now = Instant::now();
+
+            self.replicas.retain_mut(|replica| {
+                // Replicas that disconnected dropped their receiver.
+                if replica.tx.send(buf.clone()).is_err() {
+                    return false;
+                }
+
+                let queued = replica.queue.len.fetch_add(buf.len(), Ordering::Relaxed) + buf.len();
+                if !replica.exceeds(queued, limit, now) {
+                    return true;
+                }
+
+                warn!(
+                    ip = %replica.ip,
+                    port = replica.port,
+                    queued,
+                    "replica disconnected for exceeding the output buffer limit"
+                );
+                replica.queue.overflowed.store(true, Ordering::Relaxed);
+                false
+            });
+        }
+    }
+
+    /// Returns `true` if replicas are attached to this server.
+    pub(super) fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    /// Attach the replica at `ip` and `port`, to which the bytes propagated
+    /// from now on are sent through the returned receiver.
+    pub(super) fn attach(&mut self, ip: String, port: u16) -> (u64, ReplicaFeed) {
+        // The backlog starts with the first replica.
+        self.backlog.get_or_insert_with(VecDeque::new);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queue = Arc::new(Queue::default());
+        let id = self.next_replica_id;
+        self.next_replica_id += 1;
+        self.replicas.push(Replica {
+            id,
+            ip,
+            port,
+            tx,
+            queue: queue.clone(),
+            over_soft_limit: None,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+        });
+        (id, ReplicaFeed { rx, queue })
+    }
+
+    pub(super) fn detach(&mut self, id: u64) {
+        self.replicas.retain(|replica| replica.id != id);
+    }
+
+    /// Record that the replica `id` has processed the history up to
+    /// `offset`.
+    pub(super) fn ack(&mut self, id: u64, offset: u64) {
+        if let Some(replica) = self.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    /// Returns the number of replicas that acknowledged `offset`.
+    pub(super) fn acked(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Returns the state of replication, as reported by `ROLE` and `INFO
+    /// replication`.
+    pub(super) fn info(&self, backlog_size: usize) -> ReplicationInfo {
+        ReplicationInfo {
+            primary: self.primary.as_ref().map(|primary| PrimaryInfo {
+                host: primary.host.clone(),
+                port: primary.port,
+                link_up: primary.link_up,
+                last_io: primary.last_io.map(|at| at.elapsed().as_secs()),
+            }),
+            replicas: self
+                .replicas
+                .iter()
+                .map(|replica| ReplicaInfo {
+                    ip: replica.ip.clone(),
+                    port: replica.port,
+                    ack_offset: replica.ack_offset,
+                    lag: replica.last_ack.elapsed().as_secs(),
+                })
+                .collect(),
+            replid: self.replid.clone(),
+            replid2: self.replid2.clone(),
+            offset: self.offset,
+            backlog: self.backlog.as_ref().map(|backlog| {
+                let len = backlog.len() as u64;
+                (self.offset - len + 1, len)
+            }),
+            backlog_size,
+        }
+    }
+
+    /// Returns the bytes propagated since `offset` in the history `replid`,
+    /// or `None` if they are not all in the backlog.
+    pub(super) fn since(&self, replid: &str, offset: u64) -> Option<Bytes> {
+        let known = replid == self.replid
+            || matches!(&self.replid2, Some((id, end)) if id == replid && offset <= *end);
+        if !known || offset > self.offset {
+            return None;
+        }
+
+        let backlog = self.backlog.as_ref()?;
+        let start = self.offset - backlog.len() as u64;
+        if offset < start {
+            return None;
+        }
+
+        let skip = (offset - start) as usize;
+        Some(backlog.iter().skip(skip).copied().collect())
+    }
+
+    /// Adopt the history of the primary, after loading its snapshot taken at
+    /// `offset`. The replicas attached to this server must synchronize again.
+    pub(super) fn reset(&mut self, replid: String, offset: u64) {
+        self.replid = replid;
+        self.replid2 = None;
+        self.offset = offset;
+        self.backlog = Some(VecDeque::new());
+        self.replicas.clear();
+    }
+
+    /// Adopt the ID of the primary, which the replica continues with. The
+    /// primary was promoted if it changed.
+    pub(super) fn continue_as(&mut self, replid: String) {
+        if replid != self.replid {
+            let previous = std::mem::replace(&mut self.replid, replid);
+            self.replid2 = Some((previous, self.offset));
+        }
+    }
+
+    /// Start a new history from the current one, once promoted to primary.
+    /// The replicas of the former primary may continue from it.
+    pub(super) fn promote(&mut self) {
+        let previous = std::mem::replace(&mut self.replid, new_replid());
+        self.replid2 = Some((previous, self.offset));
+        self.backlog.get_or_insert_with(VecDeque::new);
+    }
+
+    /// Detach every replica, which must synchronize again.
+    pub(super) fn detach_all(&mut self) {
+        self.replicas.clear();
+    }
+}
+
+impl Replica {
+    /// Returns `true` if `queued` bytes exceed `limit`: the hard limit, or the
+    /// soft limit for the last `soft_seconds` up to `now`.
+    fn exceeds(&mut self, queued: usize, limit: OutputBufferLimit, now: Instant) -> bool {
+        if limit.hard > 0 && queued > limit.hard {
+            return true;
+        }
+
+        if limit.soft == 0 || queued <= limit.soft {
+            self.over_soft_limit = None;
+            return false;
+        }
+
+        let since = *self.over_soft_limit.get_or_insert(now);
+        now - since >= Duration::from_secs(limit.soft_seconds)
+    }
+}
+
+impl ReplicaFeed {
+    /// Returns the next bytes to send to the replica, or `None` once it was
+    /// detached and must synchronize again.
+    pub(crate) async fn recv(&mut self) -> Option<Bytes> {
+        let buf = self.rx.recv().await?;
+        if self.queue.overflowed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        self.queue.len.fetch_sub(buf.len(), Ordering::Relaxed);
+        Some(buf)
+    }
+}
+
+/// Generate a random replication ID.
+pub(crate) fn new_replid() -> String {
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|now| now.as_nanos() as u64)
+        .unwrap_or_default();
+
+    let mut id = String::with_capacity(40);
+    let mut state = hash::mix(seed ^ std::process::id() as u64) | 1;
+    while id.len() < 40 {
+        id.push_str(&format!("{:016x}", hash::next_random(&mut state)));
+    }
+    id.truncate(40);
+    id
+}
@@ -0,0 +1,255 @@
+//! Cursor-based iteration for the `SCAN` family of commands.
+//!
+//! Items are visited in the order of a stable hash of their name, and the
+//! cursor is the hash to resume from. Adding or removing other items does not
+//! move an item in that order, so every item present for the whole iteration
+//! is returned, at least once. Items added or removed during the iteration
+//! may or may not be returned.
+//!
+//! `ScanMap` keeps its names sorted in that order alongside the map, so that a
+//! page costs `O(count)` rather than a pass over every item.
+
+use super::hash::hash64;
+
+use std::borrow::Borrow;
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::hash::Hash;
+use std::ops::Deref;
+
+/// A `HashMap` that can be iterated a page at a time with `scan`.
+///
+/// It derefs to the underlying map for lookups. Changes must go through the
+/// methods below, which keep the scan order up to date.
+#[derive(Debug, Clone)]
+pub(crate) struct ScanMap<K, V> {
+    map: HashMap<K, V>,
+    /// The names in `map`, by the hash of their name. Names that hash alike
+    /// share a bucket.
+    order: BTreeMap<u64, Vec<K>>,
+}
+
+impl<K, V> ScanMap<K, V>
+where
+    K: Hash + Eq + Clone + AsRef<[u8]>,
+{
+    pub(crate) fn new() -> ScanMap<K, V> {
+        ScanMap {
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Returns a mutable reference to the value of `name`.
+    pub(crate) fn get_mut<Q>(&mut self, name: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.get_mut(name)
+    }
+
+    /// Inserts `value` at `name`, returning the value it replaces.
+    pub(crate) fn insert(&mut self, name: K, value: V) -> Option<V> {
+        match self.map.entry(name) {
+            hash_map::Entry::Occupied(mut entry) => Some(entry.insert(value)),
+            hash_map::Entry::Vacant(entry) => {
+                let bucket = self.order.entry(hash64(entry.key().as_ref(), 0));
+                bucket.or_default().push(entry.key().clone());
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Returns the value of `name`, inserting the one returned by `f` first if
+    /// `name` is missing.
+    pub(crate) fn get_or_insert_with(&mut self, name: K, f: impl FnOnce() -> V) -> &mut V {
+        match self.map.entry(name) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                let bucket = self.order.entry(hash64(entry.key().as_ref(), 0));
+                bucket.or_default().push(entry.key().clone());
+                entry.insert(f())
+            }
+        }
+    }
+
+    /// Removes `name`, returning its value.
+    pub(crate) fn remove<Q>(&mut self, name: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + AsRef<[u8]> + ?Sized,
+    {
+        let value = self.map.remove(name)?;
+
+        let hash = hash64(name.as_ref(), 0);
+        if let Some(bucket) = self.order.get_mut(&hash) {
+            bucket.retain(|other| Borrow::<Q>::borrow(other) != name);
+            if bucket.is_empty() {
+                self.order.remove(&hash);
+            }
+        }
+
+        Some(value)
+    }
+
+    /// Returns the page of items starting at `cursor`, holding about `count`
+    /// items, along with the cursor of the next page. The cursor `0` starts an
+    /// iteration and is returned once it completes.
+    ///
+    /// Items whose names hash alike are always returned in the same page, so a
+    /// page may hold more than `count` items.
+    pub(crate) fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&K, &V)>) {
+        let count = count.max(1);
+        let mut page = Vec::with_capacity(count);
+        let mut buckets = self.order.range(cursor..).peekable();
+
+        while let Some((&hash, names)) = buckets.next() {
+            page.extend(names.iter().filter_map(|name| self.map.get_key_value(name)));
+
+            if page.len() >= count {
+                let cursor = match buckets.peek() {
+                    Some(_) => hash.checked_add(1).unwrap_or(0),
+                    None => 0,
+                };
+                return (cursor, page);
+            }
+        }
+
+        (0, page)
+    }
+}
+
+impl<K, V> Default for ScanMap<K, V>
+where
+    K: Hash + Eq + Clone + AsRef<[u8]>,
+{
+    fn default() -> ScanMap<K, V> {
+        ScanMap::new()
+    }
+}
+
+impl<K, V> Deref for ScanMap<K, V> {
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &HashMap<K, V> {
+        &self.map
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a ScanMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = hash_map::Iter<'a, K, V>;
+
+    fn into_iter(self) -> hash_map::Iter<'a, K, V> {
+        self.map.iter()
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for ScanMap<K, V>
+where
+    K: Hash + Eq + Clone + AsRef<[u8]>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(items: I) -> ScanMap<K, V> {
+        let mut map = ScanMap::new();
+        for (name, value) in items {
+            map.insert(name, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    fn map(len: usize) -> ScanMap<String, usize> {
+        (0..len).map(|i| (format!("key:{}", i), i)).collect()
+    }
+
+    /// Scans `map` to completion, returning the values seen.
+    fn scan_all(map: &ScanMap<String, usize>, count: usize) -> Vec<usize> {
+        let mut seen = vec![];
+        let mut cursor = 0;
+        loop {
+            let (next, page) = map.scan(cursor, count);
+            seen.extend(page.into_iter().map(|(_, value)| *value));
+            if next == 0 {
+                return seen;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn a_full_iteration_returns_every_item_once() {
+        let map = map(1000);
+
+        for count in [1, 10, 999, 1000, 5000] {
+            let mut seen = scan_all(&map, count);
+            seen.sort_unstable();
+            assert_eq!(seen, (0..1000).collect::<Vec<_>>(), "count {}", count);
+        }
+    }
+
+    #[test]
+    fn pages_hold_count_items() {
+        let map = map(100);
+
+        let (cursor, page) = map.scan(0, 10);
+        assert_ne!(cursor, 0);
+        assert_eq!(page.len(), 10);
+
+        let (_, page) = map.scan(cursor, 10);
+        assert_eq!(page.len(), 10);
+    }
+
+    #[test]
+    fn the_last_page_returns_cursor_zero() {
+        let map = map(10);
+        assert_eq!(map.scan(0, 10).0, 0);
+        assert_eq!(map.scan(0, 100).0, 0);
+        assert_eq!(ScanMap::<String, usize>::new().scan(0, 10), (0, vec![]));
+    }
+
+    #[test]
+    fn items_present_throughout_are_returned_despite_changes() {
+        let mut map = map(1000);
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut round = 0;
+
+        loop {
+            let (next, page) = map.scan(cursor, 50);
+            seen.extend(page.into_iter().map(|(_, value)| *value));
+
+            // Keys below 500 stay, the others come and go.
+            round += 1;
+            map.remove(&format!("key:{}", 500 + round * 7));
+            map.insert(format!("key:{}", 2000 + round), 2000 + round);
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        assert!((0..500).all(|i| seen.contains(&i)));
+    }
+
+    #[test]
+    fn removed_names_leave_the_scan_order() {
+        let mut map = map(100);
+        for i in 0..90 {
+            assert_eq!(map.remove(&format!("key:{}", i)), Some(i));
+        }
+        assert_eq!(map.remove("key:0"), None);
+
+        let mut seen = scan_all(&map, 7);
+        seen.sort_unstable();
+        assert_eq!(seen, (90..100).collect::<Vec<_>>());
+        assert_eq!(map.order.values().map(Vec::len).sum::<usize>(), 10);
+    }
+}
@@ -0,0 +1,291 @@
+//! The scripting language of `EVAL`, a subset of Lua.
+//!
+//! Scripts are compiled once, cached by their SHA-1 digest and run while
+//! holding the lock, so that nothing interleaves with the commands they call.
+//!
+//! # Language
+//!
+//! ```text
+//! local a, b = 1, "two"        locals, scoped to their block
+//! a = a + 1                    assignment to locals and table fields
+//! t = {1, 2, name = "x"}       tables, `t[1]`, `t.name`, `#t`
+//! if a > 1 then ... elseif ... else ... end
+//! while cond do ... end        also `repeat ... until cond` and `break`
+//! for i = 1, 10, 2 do ... end  numeric for
+//! for i, v in ipairs(t) do ... end
+//! for k, v in pairs(t) do ... end
+//! return value
+//! ```
+//!
+//! Values are nil, booleans, numbers, strings and tables. Operators are the
+//! Lua ones: `+ - * / % ^`, `..`, `== ~= < <= > >=`, `and or not` and `#`.
+//! Scripts cannot define functions or create global variables.
+//!
+//! The keys and arguments are in the `KEYS` and `ARGV` tables. The
+//! following functions are available:
+//!
+//! ```text
+//! redis.call(cmd, ...)         run a command, raising its error replies
+//! redis.pcall(cmd, ...)        run a command, returning its error replies
+//! redis.error_reply(msg)       returns {err = msg}
+//! redis.status_reply(msg)      returns {ok = msg}
+//! redis.sha1hex(s)
+//! error(msg), tostring(v), tonumber(s), type(v)
+//! table.insert(t, [pos,] v), table.remove(t, [pos]), table.concat(t, [sep])
+//! string.len(s), string.sub(s, i, [j]), string.upper(s), string.lower(s),
+//! string.rep(s, n)
+//! math.floor(x), math.ceil(x), math.abs(x), math.max(x, ...), math.min(x, ...)
+//! ```
+//!
+//! # Conversions
+//!
+//! Command replies are converted to values as Redis does for Lua scripts:
+//! integers to numbers, bulk strings to strings, nulls to `false`, arrays to
+//! tables, status replies to `{ok = status}` and error replies to
+//! `{err = message}`. The value returned by a script is converted back the
+//! other way around, `true` being the integer `1`. Numbers are truncated to
+//! integers.
+
+mod interp;
+mod parser;
+
+use bytes::Bytes;
+
+use crate::Frame;
+
+/// A compiled script, as kept in the script cache.
+#[derive(Debug)]
+pub(crate) struct CachedScript {
+    body: Block,
+}
+
+/// Runs the commands called by a script. Implemented by `EVAL`, which
+/// executes them against the locked `Db`.
+pub(crate) trait Host {
+    /// Execute the command made of `args` and return its reply.
+    fn call(&mut self, args: Vec<Bytes>) -> Frame;
+
+    /// Returns `true` once the script must stop, as requested by
+    /// `SCRIPT KILL`. This is polled while the script runs.
+    fn killed(&self) -> bool;
+}
+
+type Block = Vec<Stat>;
+
+/// A statement, with the line it starts on for error messages.
+#[derive(Debug)]
+struct Stat {
+    line: usize,
+    kind: StatKind,
+}
+
+#[derive(Debug)]
+enum StatKind {
+    /// `local names = values`
+    Local(Vec<String>, Vec<Expr>),
+    /// `targets = values`, the targets being names or indexes.
+    Assign(Vec<Expr>, Vec<Expr>),
+    /// A function call, its value discarded.
+    Call(Expr),
+    Do(Block),
+    While(Expr, Block),
+    Repeat(Block, Expr),
+    /// `if` and `elseif` branches, then the `else` block.
+    If(Vec<(Expr, Block)>, Option<Block>),
+    NumericFor {
+        var: String,
+        start: Expr,
+        end: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    /// `for key, value in pairs(table)` or `ipairs(table)`.
+    GenericFor {
+        key: String,
+        value: Option<String>,
+        ordered: bool,
+        table: Expr,
+        body: Block,
+    },
+    Return(Option<Expr>),
+    Break,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(Bytes),
+    Name(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    /// Table constructor. Fields without a key are positional.
+    Table(Vec<(Option<Expr>, Expr)>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum UnOp {
+    Neg,
+    Not,
+    Len,
+}
+
+impl CachedScript {
+    /// Compile `source`. Returns the error reply on syntax errors.
+    pub(crate) fn compile(source: &[u8]) -> Result<CachedScript, String> {
+        let body = parser::parse(source).map_err(|(line, msg)| {
+            format!(
+                "ERR Error compiling script (new function): user_script:{}: {}",
+                line, msg
+            )
+        })?;
+
+        Ok(CachedScript { body })
+    }
+
+    /// Run the script with `keys` and `args`, calling commands through
+    /// `host`. Returns the reply to the client, which is an error reply if
+    /// the script failed.
+    pub(crate) fn run(&self, keys: Vec<Bytes>, args: Vec<Bytes>, host: &mut dyn Host) -> Frame {
+        interp::run(&self.body, keys, args, host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replies to `redis.call` with its arguments.
+    struct Echo;
+
+    impl Host for Echo {
+        fn call(&mut self, args: Vec<Bytes>) -> Frame {
+            Frame::Array(args.into_iter().map(Frame::Bulk).collect())
+        }
+
+        fn killed(&self) -> bool {
+            false
+        }
+    }
+
+    fn run(source: &str, keys: &[&str], args: &[&str]) -> Frame {
+        let to_bytes = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| Bytes::copy_from_slice(value.as_bytes()))
+                .collect()
+        };
+        CachedScript::compile(source.as_bytes()).unwrap().run(
+            to_bytes(keys),
+            to_bytes(args),
+            &mut Echo,
+        )
+    }
+
+    #[test]
+    fn compile_errors_are_error_replies() {
+        let error = CachedScript::compile(b"return\n1 +").unwrap_err();
+        assert!(
+            error.starts_with("ERR Error compiling script (new function): user_script:2: "),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn return_values_are_converted() {
+        assert!(matches!(run("return 1 + 2", &[], &[]), Frame::Int(3)));
+        assert!(matches!(run("return 3.9", &[], &[]), Frame::Int(3)));
+        assert!(matches!(run("return true", &[], &[]), Frame::Int(1)));
+        assert!(matches!(run("return false", &[], &[]), Frame::Null));
+        assert!(matches!(run("return", &[], &[]), Frame::Null));
+        assert_eq!(run("return KEYS[1] .. ARGV[2]", &["k"], &["a", "b"]), "kb");
+        assert_eq!(run("return redis.status_reply('FINE')", &[], &[]), "FINE");
+        assert!(matches!(
+            run("return redis.error_reply('ERR no')", &[], &[]),
+            Frame::Error(msg) if msg == "ERR no"
+        ));
+
+        // Tables stop at their first nil.
+        let Frame::Array(frames) = run("return {1, 'two', nil, 4}", &[], &[]) else {
+            panic!("expected an array");
+        };
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1], "two");
+    }
+
+    #[test]
+    fn calls_go_through_the_host() {
+        let reply = run(
+            "local reply = redis.call('GET', KEYS[1])
+            return reply[1] .. ' ' .. reply[2] .. ' ' .. #reply",
+            &["key"],
+            &[],
+        );
+        assert_eq!(reply, "GET key 2");
+    }
+
+    #[test]
+    fn globals_cannot_be_created() {
+        assert!(matches!(
+            run("x = 1", &[], &[]),
+            Frame::Error(msg) if msg.contains("Script attempted to create global variable 'x'")
+        ));
+        assert!(matches!(
+            run("return y", &[], &[]),
+            Frame::Error(msg)
+                if msg.contains("Script attempted to access nonexistent global variable 'y'")
+        ));
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "local sum = 0
+            for i = 1, 10 do
+                if i % 2 == 0 then sum = sum + i elseif i == 9 then break end
+            end
+            local n = 0
+            repeat n = n + 1 until n >= 3
+            while true do n = n * 2 if n > 20 then break end end
+            local t = {}
+            for _, v in ipairs({5, 6, 7}) do table.insert(t, v) end
+            return {sum, n, table.concat(t, ',')}";
+        let Frame::Array(frames) = run(source, &[], &[]) else {
+            panic!("expected an array");
+        };
+        assert!(matches!(frames[0], Frame::Int(20)));
+        assert!(matches!(frames[1], Frame::Int(24)));
+        assert_eq!(frames[2], "5,6,7");
+    }
+
+    #[test]
+    fn strings_are_bounded() {
+        assert_eq!(run("return string.rep('ab', 3)", &[], &[]), "ababab");
+        assert!(matches!(
+            run("return string.rep('a', 1e15)", &[], &[]),
+            Frame::Error(msg) if msg.contains("resulting string too large")
+        ));
+    }
+}
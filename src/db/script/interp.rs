@@ -0,0 +1,1113 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use bytes::Bytes;
+
+use super::{BinOp, Block, Expr, Host, Stat, StatKind, UnOp};
+use crate::db::sha1::sha1_hex;
+use crate::Frame;
+
+/// Number of steps between two checks of `Host::killed`.
+const KILL_CHECK_INTERVAL: u64 = 1000;
+
+/// Longest string a script can build, like `proto-max-bulk-len` for the
+/// strings of clients. Longer ones raise an error rather than failing to
+/// allocate.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(Bytes),
+    Table(Rc<RefCell<Table>>),
+    Function(Builtin),
+}
+
+type Table = BTreeMap<Key, Value>;
+
+/// A table key. Numbers used as keys must be integers.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Bool(bool),
+    Int(i64),
+    Str(Bytes),
+}
+
+/// The functions available to scripts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Builtin {
+    RedisCall,
+    RedisPcall,
+    ErrorReply,
+    StatusReply,
+    Sha1Hex,
+    Error,
+    ToString,
+    ToNumber,
+    Type,
+    TableInsert,
+    TableRemove,
+    TableConcat,
+    StringLen,
+    StringSub,
+    StringUpper,
+    StringLower,
+    StringRep,
+    MathFloor,
+    MathCeil,
+    MathAbs,
+    MathMax,
+    MathMin,
+}
+
+/// How a statement completed.
+enum Flow {
+    Next,
+    Break,
+    Return(Value),
+}
+
+/// Why a script stopped before completing.
+enum Error {
+    /// A runtime error, already prefixed with its location.
+    Raise(String),
+    /// An error reply to send as is: raised by `redis.call` or by calling
+    /// `error` with an error table.
+    Reply(String),
+    /// The script was killed by `SCRIPT KILL`.
+    Killed,
+}
+
+struct Interp<'a> {
+    /// Local variables, innermost last. Blocks truncate it when exiting.
+    locals: Vec<(String, Value)>,
+    globals: HashMap<&'static str, Value>,
+    host: &'a mut dyn Host,
+    /// Line of the statement being executed, for error messages.
+    line: usize,
+    steps: u64,
+}
+
+/// Run `body` and return the reply to the client.
+pub(super) fn run(body: &Block, keys: Vec<Bytes>, args: Vec<Bytes>, host: &mut dyn Host) -> Frame {
+    let mut interp = Interp {
+        locals: vec![],
+        globals: globals(keys, args),
+        host,
+        line: 1,
+        steps: 0,
+    };
+
+    match interp.block(body) {
+        Ok(Flow::Return(value)) => to_frame(&value),
+        Ok(_) => Frame::Null,
+        Err(Error::Raise(msg)) => Frame::Error(format!("ERR {}", msg)),
+        Err(Error::Reply(msg)) => Frame::Error(msg),
+        Err(Error::Killed) => {
+            Frame::Error("ERR Script killed by user with SCRIPT KILL...".to_string())
+        }
+    }
+}
+
+fn globals(keys: Vec<Bytes>, args: Vec<Bytes>) -> HashMap<&'static str, Value> {
+    let library = |functions: &[(&str, Builtin)]| {
+        new_table(
+            functions
+                .iter()
+                .map(|(name, builtin)| {
+                    (
+                        Key::Str(Bytes::copy_from_slice(name.as_bytes())),
+                        Value::Function(*builtin),
+                    )
+                })
+                .collect(),
+        )
+    };
+
+    let array = |values: Vec<Bytes>| {
+        new_table(
+            values
+                .into_iter()
+                .zip(1..)
+                .map(|(value, i)| (Key::Int(i), Value::Str(value)))
+                .collect(),
+        )
+    };
+
+    HashMap::from([
+        ("KEYS", array(keys)),
+        ("ARGV", array(args)),
+        (
+            "redis",
+            library(&[
+                ("call", Builtin::RedisCall),
+                ("pcall", Builtin::RedisPcall),
+                ("error_reply", Builtin::ErrorReply),
+                ("status_reply", Builtin::StatusReply),
+                ("sha1hex", Builtin::Sha1Hex),
+            ]),
+        ),
+        (
+            "table",
+            library(&[
+                ("insert", Builtin::TableInsert),
+                ("remove", Builtin::TableRemove),
+                ("concat", Builtin::TableConcat),
+            ]),
+        ),
+        (
+            "string",
+            library(&[
+                ("len", Builtin::StringLen),
+                ("sub", Builtin::StringSub),
+                ("upper", Builtin::StringUpper),
+                ("lower", Builtin::StringLower),
+                ("rep", Builtin::StringRep),
+            ]),
+        ),
+        (
+            "math",
+            library(&[
+                ("floor", Builtin::MathFloor),
+                ("ceil", Builtin::MathCeil),
+                ("abs", Builtin::MathAbs),
+                ("max", Builtin::MathMax),
+                ("min", Builtin::MathMin),
+            ]),
+        ),
+        ("error", Value::Function(Builtin::Error)),
+        ("tostring", Value::Function(Builtin::ToString)),
+        ("tonumber", Value::Function(Builtin::ToNumber)),
+        ("type", Value::Function(Builtin::Type)),
+    ])
+}
+
+fn new_table(table: Table) -> Value {
+    Value::Table(Rc::new(RefCell::new(table)))
+}
+
+/// A table with a single string field, such as `{err = msg}`.
+fn reply_table(field: &str, msg: Bytes) -> Value {
+    new_table(Table::from([(
+        Key::Str(Bytes::copy_from_slice(field.as_bytes())),
+        Value::Str(msg),
+    )]))
+}
+
+/// Convert a command reply to a value.
+fn from_frame(frame: Frame) -> Value {
+    match frame {
+        Frame::Simple(status) => reply_table("ok", Bytes::from(status)),
+        Frame::Error(msg) => reply_table("err", Bytes::from(msg)),
+        Frame::Int(value) => Value::Number(value as f64),
+        Frame::Bulk(value) => Value::Str(value),
+        Frame::Null => Value::Bool(false),
+        Frame::Array(frames) => new_table(
+            frames
+                .into_iter()
+                .zip(1..)
+                .map(|(frame, i)| (Key::Int(i), from_frame(frame)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert the value returned by a script to a reply.
+fn to_frame(value: &Value) -> Frame {
+    match value {
+        Value::Nil | Value::Bool(false) | Value::Function(_) => Frame::Null,
+        Value::Bool(true) => Frame::Int(1),
+        Value::Number(number) => {
+            let number = number.trunc();
+            if number >= 0.0 {
+                Frame::Int(number as u64)
+            } else {
+                // Integer replies are unsigned, negative numbers are sent
+                // as their decimal representation.
+                Frame::Bulk(Bytes::from((number as i64).to_string()))
+            }
+        }
+        Value::Str(value) => Frame::Bulk(value.clone()),
+        Value::Table(table) => {
+            let table = table.borrow();
+
+            let field =
+                |name: &str| match table.get(&Key::Str(Bytes::copy_from_slice(name.as_bytes()))) {
+                    Some(Value::Str(msg)) => Some(String::from_utf8_lossy(msg).into_owned()),
+                    _ => None,
+                };
+            if let Some(msg) = field("err") {
+                return Frame::Error(msg);
+            }
+            if let Some(status) = field("ok") {
+                return Frame::Simple(status);
+            }
+
+            // The array stops at the first nil.
+            let frames = (1..)
+                .map_while(|i| table.get(&Key::Int(i)))
+                .map(to_frame)
+                .collect();
+            Frame::Array(frames)
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::Str(_) => "string",
+        Value::Table(_) => "table",
+        Value::Function(_) => "function",
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    !matches!(value, Value::Nil | Value::Bool(false))
+}
+
+/// Format a number as Lua does, with `%.14g`.
+fn format_number(number: f64) -> String {
+    if number.is_nan() {
+        return "nan".to_string();
+    }
+    if number.is_infinite() {
+        return if number > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if number == number.trunc() && number.abs() < 1e15 {
+        return (number as i64).to_string();
+    }
+
+    let scientific = format!("{:.13e}", number);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    let trim = |digits: &str| {
+        if digits.contains('.') {
+            digits
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_string()
+        } else {
+            digits.to_string()
+        }
+    };
+
+    if !(-4..14).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    } else {
+        trim(&format!("{:.*}", (13 - exponent) as usize, number))
+    }
+}
+
+/// Parse a number as `tonumber` does: decimal or hexadecimal, surrounding
+/// spaces allowed.
+fn parse_number(value: &[u8]) -> Option<f64> {
+    let text = std::str::from_utf8(value).ok()?.trim();
+
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        let number = i64::from_str_radix(hex, 16).ok()? as f64;
+        return Some(if negative { -number } else { number });
+    }
+
+    // Rust also parses `inf` and `nan`, which Lua does not.
+    let valid = text.bytes().any(|c| c.is_ascii_digit())
+        && text
+            .bytes()
+            .all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'e' | b'E' | b'+' | b'-'));
+    if valid {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Numbers and strings that can be converted to numbers.
+fn to_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => Some(*number),
+        Value::Str(value) => parse_number(value),
+        _ => None,
+    }
+}
+
+/// Strings and numbers, which are converted to strings.
+fn to_str(value: &Value) -> Option<Bytes> {
+    match value {
+        Value::Str(value) => Some(value.clone()),
+        Value::Number(number) => Some(Bytes::from(format_number(*number))),
+        _ => None,
+    }
+}
+
+fn to_string(value: &Value) -> Bytes {
+    match value {
+        Value::Nil => Bytes::from_static(b"nil"),
+        Value::Bool(true) => Bytes::from_static(b"true"),
+        Value::Bool(false) => Bytes::from_static(b"false"),
+        Value::Number(number) => Bytes::from(format_number(*number)),
+        Value::Str(value) => value.clone(),
+        Value::Table(table) => Bytes::from(format!("table: {:p}", Rc::as_ptr(table))),
+        Value::Function(builtin) => Bytes::from(format!("function: builtin: {:?}", builtin)),
+    }
+}
+
+/// Length of the array part of `table`: the last index before the first nil.
+fn table_len(table: &Table) -> i64 {
+    (1..)
+        .take_while(|i| table.contains_key(&Key::Int(*i)))
+        .count() as i64
+}
+
+fn values_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::Str(a), Value::Str(b)) => a == b,
+        (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+        (Value::Function(a), Value::Function(b)) => a == b,
+        _ => false,
+    }
+}
+
+impl Builtin {
+    fn name(self) -> &'static str {
+        match self {
+            Builtin::RedisCall => "call",
+            Builtin::RedisPcall => "pcall",
+            Builtin::ErrorReply => "error_reply",
+            Builtin::StatusReply => "status_reply",
+            Builtin::Sha1Hex => "sha1hex",
+            Builtin::Error => "error",
+            Builtin::ToString => "tostring",
+            Builtin::ToNumber => "tonumber",
+            Builtin::Type => "type",
+            Builtin::TableInsert => "insert",
+            Builtin::TableRemove => "remove",
+            Builtin::TableConcat => "concat",
+            Builtin::StringLen => "len",
+            Builtin::StringSub => "sub",
+            Builtin::StringUpper => "upper",
+            Builtin::StringLower => "lower",
+            Builtin::StringRep => "rep",
+            Builtin::MathFloor => "floor",
+            Builtin::MathCeil => "ceil",
+            Builtin::MathAbs => "abs",
+            Builtin::MathMax => "max",
+            Builtin::MathMin => "min",
+        }
+    }
+}
+
+/// The arguments of a builtin call, to report bad arguments consistently.
+struct Args<'a> {
+    builtin: Builtin,
+    values: &'a [Value],
+}
+
+impl Args<'_> {
+    fn get(&self, i: usize) -> &Value {
+        self.values.get(i).unwrap_or(&Value::Nil)
+    }
+
+    fn bad(&self, i: usize, expected: &str) -> String {
+        let got = match self.values.get(i) {
+            Some(value) => type_name(value),
+            None => "no value",
+        };
+        format!(
+            "bad argument #{} to '{}' ({} expected, got {})",
+            i + 1,
+            self.builtin.name(),
+            expected,
+            got
+        )
+    }
+
+    fn str(&self, i: usize) -> Result<Bytes, String> {
+        to_str(self.get(i)).ok_or_else(|| self.bad(i, "string"))
+    }
+
+    fn number(&self, i: usize) -> Result<f64, String> {
+        to_number(self.get(i)).ok_or_else(|| self.bad(i, "number"))
+    }
+
+    fn int(&self, i: usize) -> Result<i64, String> {
+        self.number(i).map(|number| number.floor() as i64)
+    }
+
+    fn table(&self, i: usize) -> Result<Rc<RefCell<Table>>, String> {
+        match self.get(i) {
+            Value::Table(table) => Ok(table.clone()),
+            _ => Err(self.bad(i, "table")),
+        }
+    }
+}
+
+impl Interp<'_> {
+    /// Returns a runtime error located at the current line.
+    fn raise(&self, msg: impl std::fmt::Display) -> Error {
+        Error::Raise(format!("user_script:{}: {}", self.line, msg))
+    }
+
+    /// Count a step, checking from time to time whether the script was
+    /// killed.
+    fn step(&mut self) -> Result<(), Error> {
+        self.steps += 1;
+        if self.steps.is_multiple_of(KILL_CHECK_INTERVAL) && self.host.killed() {
+            return Err(Error::Killed);
+        }
+        Ok(())
+    }
+
+    /// Execute `block` in a new scope.
+    fn block(&mut self, block: &Block) -> Result<Flow, Error> {
+        let mark = self.locals.len();
+        let flow = self.stats(block);
+        self.locals.truncate(mark);
+        flow
+    }
+
+    fn stats(&mut self, block: &Block) -> Result<Flow, Error> {
+        for stat in block {
+            match self.stat(stat)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn stat(&mut self, stat: &Stat) -> Result<Flow, Error> {
+        self.line = stat.line;
+        self.step()?;
+
+        match &stat.kind {
+            StatKind::Local(names, exprs) => {
+                let mut values = self.exprs(exprs)?.into_iter();
+                for name in names {
+                    let value = values.next().unwrap_or(Value::Nil);
+                    self.locals.push((name.clone(), value));
+                }
+            }
+            StatKind::Assign(targets, exprs) => {
+                let mut values = self.exprs(exprs)?.into_iter();
+                for target in targets {
+                    let value = values.next().unwrap_or(Value::Nil);
+                    self.assign(target, value)?;
+                }
+            }
+            StatKind::Call(expr) => {
+                self.eval(expr)?;
+            }
+            StatKind::Do(body) => return self.block(body),
+            StatKind::While(cond, body) => loop {
+                self.step()?;
+                if !truthy(&self.eval(cond)?) {
+                    break;
+                }
+                match self.block(body)? {
+                    Flow::Next => {}
+                    Flow::Break => break,
+                    flow => return Ok(flow),
+                }
+            },
+            StatKind::Repeat(body, cond) => loop {
+                self.step()?;
+
+                // The condition sees the locals of the body.
+                let mark = self.locals.len();
+                let flow = self.stats(body).and_then(|flow| match flow {
+                    Flow::Next => Ok(truthy(&self.eval(cond)?).then_some(Flow::Break)),
+                    Flow::Break => Ok(Some(Flow::Break)),
+                    flow => Ok(Some(flow)),
+                });
+                self.locals.truncate(mark);
+
+                match flow? {
+                    None => {}
+                    Some(Flow::Break) => break,
+                    Some(flow) => return Ok(flow),
+                }
+            },
+            StatKind::If(branches, otherwise) => {
+                for (cond, body) in branches {
+                    if truthy(&self.eval(cond)?) {
+                        return self.block(body);
+                    }
+                }
+                if let Some(body) = otherwise {
+                    return self.block(body);
+                }
+            }
+            StatKind::NumericFor {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let start = to_number(&self.eval(start)?)
+                    .ok_or_else(|| self.raise("'for' initial value must be a number"))?;
+                let end = to_number(&self.eval(end)?)
+                    .ok_or_else(|| self.raise("'for' limit must be a number"))?;
+                let step = match step {
+                    Some(step) => to_number(&self.eval(step)?)
+                        .ok_or_else(|| self.raise("'for' step must be a number"))?,
+                    None => 1.0,
+                };
+                if step == 0.0 {
+                    return Err(self.raise("'for' step is zero"));
+                }
+
+                let mut i = start;
+                while (step > 0.0 && i <= end) || (step < 0.0 && i >= end) {
+                    self.step()?;
+
+                    let mark = self.locals.len();
+                    self.locals.push((var.clone(), Value::Number(i)));
+                    let flow = self.block(body);
+                    self.locals.truncate(mark);
+
+                    match flow? {
+                        Flow::Next => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                    i += step;
+                }
+            }
+            StatKind::GenericFor {
+                key,
+                value,
+                ordered,
+                table,
+                body,
+            } => {
+                let iterator = if *ordered { "ipairs" } else { "pairs" };
+                let table = match self.eval(table)? {
+                    Value::Table(table) => table,
+                    other => {
+                        return Err(self.raise(format!(
+                            "bad argument #1 to '{}' (table expected, got {})",
+                            iterator,
+                            type_name(&other)
+                        )))
+                    }
+                };
+
+                // `pairs` iterates over a snapshot, `ipairs` reads the table
+                // at each step and stops at the first nil.
+                let snapshot: Vec<(Key, Value)> = if *ordered {
+                    vec![]
+                } else {
+                    let table = table.borrow();
+                    table.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+                };
+                let mut entries = snapshot.into_iter();
+
+                for i in 1.. {
+                    self.step()?;
+
+                    let entry = if *ordered {
+                        let entry = table.borrow().get(&Key::Int(i)).cloned();
+                        entry.map(|v| (Key::Int(i), v))
+                    } else {
+                        entries.next()
+                    };
+                    let (k, v) = match entry {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+
+                    let mark = self.locals.len();
+                    self.locals.push((key.clone(), key_value(k)));
+                    if let Some(value) = value {
+                        self.locals.push((value.clone(), v));
+                    }
+                    let flow = self.block(body);
+                    self.locals.truncate(mark);
+
+                    match flow? {
+                        Flow::Next => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            StatKind::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.eval(expr)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+            StatKind::Break => return Ok(Flow::Break),
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn assign(&mut self, target: &Expr, value: Value) -> Result<(), Error> {
+        match target {
+            Expr::Name(name) => {
+                match self
+                    .locals
+                    .iter_mut()
+                    .rev()
+                    .find(|(local, _)| local == name)
+                {
+                    Some((_, local)) => *local = value,
+                    None if self.globals.contains_key(name.as_str()) => {
+                        return Err(self.raise("Attempt to modify a readonly table"))
+                    }
+                    None => {
+                        return Err(self.raise(format!(
+                            "Script attempted to create global variable '{}'",
+                            name
+                        )))
+                    }
+                }
+            }
+            Expr::Index(table, key) => {
+                let table = match self.eval(table)? {
+                    Value::Table(table) => table,
+                    other => {
+                        return Err(
+                            self.raise(format!("attempt to index a {} value", type_name(&other)))
+                        )
+                    }
+                };
+                let key = self.eval(key)?;
+                let key = match self.key(key)? {
+                    Some(key) => key,
+                    None => return Err(self.raise("table index is nil")),
+                };
+
+                let mut table = table.borrow_mut();
+                match value {
+                    Value::Nil => table.remove(&key),
+                    value => table.insert(key, value),
+                };
+            }
+            // Rejected by the parser.
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Convert a value to a table key. Returns `None` for nil.
+    fn key(&self, value: Value) -> Result<Option<Key>, Error> {
+        match value {
+            Value::Nil => Ok(None),
+            Value::Bool(value) => Ok(Some(Key::Bool(value))),
+            Value::Number(number) if number == number.trunc() && number.is_finite() => {
+                Ok(Some(Key::Int(number as i64)))
+            }
+            Value::Str(value) => Ok(Some(Key::Str(value))),
+            other => Err(self.raise(format!("unsupported table key type: {}", type_name(&other)))),
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) -> Result<Vec<Value>, Error> {
+        exprs.iter().map(|expr| self.eval(expr)).collect()
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, Error> {
+        let value = match expr {
+            Expr::Nil => Value::Nil,
+            Expr::Bool(value) => Value::Bool(*value),
+            Expr::Number(number) => Value::Number(*number),
+            Expr::Str(value) => Value::Str(value.clone()),
+            Expr::Name(name) => match self.locals.iter().rev().find(|(local, _)| local == name) {
+                Some((_, value)) => value.clone(),
+                None => match self.globals.get(name.as_str()) {
+                    Some(value) => value.clone(),
+                    None => {
+                        return Err(self.raise(format!(
+                            "Script attempted to access nonexistent global variable '{}'",
+                            name
+                        )))
+                    }
+                },
+            },
+            Expr::Index(table, key) => {
+                let table = match self.eval(table)? {
+                    Value::Table(table) => table,
+                    other => {
+                        return Err(
+                            self.raise(format!("attempt to index a {} value", type_name(&other)))
+                        )
+                    }
+                };
+                let key = self.eval(key)?;
+                match self.key(key)? {
+                    Some(key) => table.borrow().get(&key).cloned().unwrap_or(Value::Nil),
+                    None => Value::Nil,
+                }
+            }
+            Expr::Call(function, args) => {
+                let builtin = match self.eval(function)? {
+                    Value::Function(builtin) => builtin,
+                    other => {
+                        return Err(
+                            self.raise(format!("attempt to call a {} value", type_name(&other)))
+                        )
+                    }
+                };
+                let args = self.exprs(args)?;
+                self.call(builtin, &args)?
+            }
+            Expr::Table(fields) => {
+                let mut table = Table::new();
+                let mut next = 1;
+
+                for (key, value) in fields {
+                    let key = match key {
+                        Some(key) => {
+                            let key = self.eval(key)?;
+                            match self.key(key)? {
+                                Some(key) => key,
+                                None => return Err(self.raise("table index is nil")),
+                            }
+                        }
+                        None => {
+                            next += 1;
+                            Key::Int(next - 1)
+                        }
+                    };
+                    match self.eval(value)? {
+                        Value::Nil => {}
+                        value => {
+                            table.insert(key, value);
+                        }
+                    }
+                }
+
+                new_table(table)
+            }
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                if truthy(&lhs) {
+                    self.eval(rhs)?
+                } else {
+                    lhs
+                }
+            }
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                if truthy(&lhs) {
+                    lhs
+                } else {
+                    self.eval(rhs)?
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                self.binary(*op, lhs, rhs)?
+            }
+            Expr::Unary(op, operand) => {
+                let operand = self.eval(operand)?;
+                match op {
+                    UnOp::Not => Value::Bool(!truthy(&operand)),
+                    UnOp::Neg => match to_number(&operand) {
+                        Some(number) => Value::Number(-number),
+                        None => {
+                            return Err(self.raise(format!(
+                                "attempt to perform arithmetic on a {} value",
+                                type_name(&operand)
+                            )))
+                        }
+                    },
+                    UnOp::Len => match &operand {
+                        Value::Str(value) => Value::Number(value.len() as f64),
+                        Value::Table(table) => Value::Number(table_len(&table.borrow()) as f64),
+                        other => {
+                            return Err(self.raise(format!(
+                                "attempt to get length of a {} value",
+                                type_name(other)
+                            )))
+                        }
+                    },
+                }
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn binary(&self, op: BinOp, lhs: Value, rhs: Value) -> Result<Value, Error> {
+        let arithmetic = |f: fn(f64, f64) -> f64| match (to_number(&lhs), to_number(&rhs)) {
+            (Some(a), Some(b)) => Ok(Value::Number(f(a, b))),
+            (None, _) => Err(self.raise(format!(
+                "attempt to perform arithmetic on a {} value",
+                type_name(&lhs)
+            ))),
+            (_, None) => Err(self.raise(format!(
+                "attempt to perform arithmetic on a {} value",
+                type_name(&rhs)
+            ))),
+        };
+
+        // `a > b` is evaluated as `b < a`.
+        let compare = |a: &Value, b: &Value, or_equal: bool| match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Bool(a < b || (or_equal && a == b))),
+            (Value::Str(a), Value::Str(b)) => Ok(Value::Bool(a < b || (or_equal && a == b))),
+            _ => Err(self.raise(format!(
+                "attempt to compare {} with {}",
+                type_name(a),
+                type_name(b)
+            ))),
+        };
+
+        match op {
+            BinOp::Add => arithmetic(|a, b| a + b),
+            BinOp::Sub => arithmetic(|a, b| a - b),
+            BinOp::Mul => arithmetic(|a, b| a * b),
+            BinOp::Div => arithmetic(|a, b| a / b),
+            BinOp::Mod => arithmetic(|a, b| a - (a / b).floor() * b),
+            BinOp::Pow => arithmetic(f64::powf),
+            BinOp::Concat => match (to_str(&lhs), to_str(&rhs)) {
+                (Some(a), Some(b)) if a.len() + b.len() > MAX_STRING_LEN => {
+                    Err(self.raise("string length overflow"))
+                }
+                (Some(a), Some(b)) => Ok(Value::Str(Bytes::from([a, b].concat()))),
+                (None, _) => Err(self.raise(format!(
+                    "attempt to concatenate a {} value",
+                    type_name(&lhs)
+                ))),
+                (_, None) => Err(self.raise(format!(
+                    "attempt to concatenate a {} value",
+                    type_name(&rhs)
+                ))),
+            },
+            BinOp::Eq => Ok(Value::Bool(values_eq(&lhs, &rhs))),
+            BinOp::Ne => Ok(Value::Bool(!values_eq(&lhs, &rhs))),
+            BinOp::Lt => compare(&lhs, &rhs, false),
+            BinOp::Le => compare(&lhs, &rhs, true),
+            BinOp::Gt => compare(&rhs, &lhs, false),
+            BinOp::Ge => compare(&rhs, &lhs, true),
+            // Short-circuited by `eval`.
+            BinOp::And | BinOp::Or => unreachable!(),
+        }
+    }
+
+    fn call(&mut self, builtin: Builtin, values: &[Value]) -> Result<Value, Error> {
+        let args = Args { builtin, values };
+
+        let value = match builtin {
+            Builtin::RedisCall | Builtin::RedisPcall => {
+                if values.is_empty() {
+                    return Err(
+                        self.raise("Please specify at least one argument for this redis lib call")
+                    );
+                }
+
+                let mut command = Vec::with_capacity(values.len());
+                for value in values {
+                    match value {
+                        Value::Str(_) | Value::Number(_) => command.push(to_str(value).unwrap()),
+                        _ => {
+                            return Err(self.raise(
+                                "Lua redis lib command arguments must be strings or integers",
+                            ))
+                        }
+                    }
+                }
+
+                match self.host.call(command) {
+                    Frame::Error(msg) if builtin == Builtin::RedisCall => {
+                        return Err(Error::Reply(msg))
+                    }
+                    frame => from_frame(frame),
+                }
+            }
+            Builtin::ErrorReply | Builtin::StatusReply => {
+                let msg = args.str(0).map_err(|msg| self.raise(msg))?;
+                let field = if builtin == Builtin::ErrorReply {
+                    "err"
+                } else {
+                    "ok"
+                };
+                reply_table(field, msg)
+            }
+            Builtin::Sha1Hex => {
+                let value = args.str(0).map_err(|msg| self.raise(msg))?;
+                Value::Str(Bytes::from(sha1_hex(&value)))
+            }
+            Builtin::Error => {
+                // An error table is sent to the client as is.
+                if let Value::Table(table) = args.get(0) {
+                    if let Frame::Error(msg) = to_frame(&Value::Table(table.clone())) {
+                        return Err(Error::Reply(msg));
+                    }
+                }
+                let msg = to_string(args.get(0));
+                return Err(self.raise(String::from_utf8_lossy(&msg)));
+            }
+            Builtin::ToString => Value::Str(to_string(args.get(0))),
+            Builtin::ToNumber => match to_number(args.get(0)) {
+                Some(number) => Value::Number(number),
+                None => Value::Nil,
+            },
+            Builtin::Type => Value::Str(Bytes::from_static(type_name(args.get(0)).as_bytes())),
+            _ => self.library(&args).map_err(|msg| self.raise(msg))?,
+        };
+
+        Ok(value)
+    }
+
+    /// Call one of the functions of the `table`, `string` and `math`
+    /// libraries.
+    fn library(&self, args: &Args) -> Result<Value, String> {
+        let value = match args.builtin {
+            Builtin::TableInsert => {
+                let table = args.table(0)?;
+                let mut table = table.borrow_mut();
+                let len = table_len(&table);
+
+                let (pos, value) = match args.values.len() {
+                    2 => (len + 1, args.get(1).clone()),
+                    3 => (args.int(1)?, args.get(2).clone()),
+                    _ => return Err("wrong number of arguments to 'insert'".to_string()),
+                };
+                if pos < 1 || pos > len + 1 {
+                    return Err("bad argument #2 to 'insert' (position out of bounds)".to_string());
+                }
+
+                for i in (pos..=len).rev() {
+                    let moved = table.remove(&Key::Int(i)).unwrap();
+                    table.insert(Key::Int(i + 1), moved);
+                }
+                table.insert(Key::Int(pos), value);
+                Value::Nil
+            }
+            Builtin::TableRemove => {
+                let table = args.table(0)?;
+                let mut table = table.borrow_mut();
+                let len = table_len(&table);
+
+                let pos = match args.values.len() {
+                    1 => len,
+                    _ => args.int(1)?,
+                };
+                if len == 0 && args.values.len() == 1 {
+                    return Ok(Value::Nil);
+                }
+                if pos < 1 || pos > len + 1 {
+                    return Err("bad argument #2 to 'remove' (position out of bounds)".to_string());
+                }
+
+                let removed = table.remove(&Key::Int(pos)).unwrap_or(Value::Nil);
+                for i in pos + 1..=len {
+                    let moved = table.remove(&Key::Int(i)).unwrap();
+                    table.insert(Key::Int(i - 1), moved);
+                }
+                removed
+            }
+            Builtin::TableConcat => {
+                let table = args.table(0)?;
+                let table = table.borrow();
+                let sep = match args.get(1) {
+                    Value::Nil => Bytes::new(),
+                    _ => args.str(1)?,
+                };
+
+                let mut items = vec![];
+                let mut len = 0;
+                for i in 1..=table_len(&table) {
+                    match to_str(&table[&Key::Int(i)]) {
+                        Some(item) => {
+                            len += item.len() + sep.len();
+                            if len > MAX_STRING_LEN {
+                                return Err("resulting string too large".to_string());
+                            }
+                            items.push(item)
+                        }
+                        None => {
+                            return Err(format!(
+                                "invalid value (at index {}) in table for 'concat'",
+                                i
+                            ))
+                        }
+                    }
+                }
+                Value::Str(Bytes::from(items.join(&sep[..])))
+            }
+            Builtin::StringLen => Value::Number(args.str(0)?.len() as f64),
+            Builtin::StringSub => {
+                let value = args.str(0)?;
+                let len = value.len() as i64;
+
+                // Negative positions count from the end.
+                let position = |pos: i64| if pos < 0 { len + pos + 1 } else { pos };
+                let start = position(args.int(1)?).max(1);
+                let end = match args.get(2) {
+                    Value::Nil => len,
+                    _ => position(args.int(2)?).min(len),
+                };
+
+                if start > end {
+                    Value::Str(Bytes::new())
+                } else {
+                    Value::Str(value.slice(start as usize - 1..end as usize))
+                }
+            }
+            Builtin::StringUpper => Value::Str(Bytes::from(args.str(0)?.to_ascii_uppercase())),
+            Builtin::StringLower => Value::Str(Bytes::from(args.str(0)?.to_ascii_lowercase())),
+            Builtin::StringRep => {
+                let value = args.str(0)?;
+                let count = args.int(1)?.max(0) as usize;
+                match value.len().checked_mul(count) {
+                    Some(len) if len <= MAX_STRING_LEN => {
+                        Value::Str(Bytes::from(value.repeat(count)))
+                    }
+                    _ => return Err("resulting string too large".to_string()),
+                }
+            }
+            Builtin::MathFloor => Value::Number(args.number(0)?.floor()),
+            Builtin::MathCeil => Value::Number(args.number(0)?.ceil()),
+            Builtin::MathAbs => Value::Number(args.number(0)?.abs()),
+            Builtin::MathMax | Builtin::MathMin => {
+                let mut result = args.number(0)?;
+                for i in 1..args.values.len() {
+                    let number = args.number(i)?;
+                    if (args.builtin == Builtin::MathMax && number > result)
+                        || (args.builtin == Builtin::MathMin && number < result)
+                    {
+                        result = number;
+                    }
+                }
+                Value::Number(result)
+            }
+            // Handled by `call`.
+            _ => unreachable!(),
+        };
+
+        Ok(value)
+    }
+}
+
+/// Convert a table key back to a value, for `pairs`.
+fn key_value(key: Key) -> Value {
+    match key {
+        Key::Bool(value) => Value::Bool(value),
+        Key::Int(i) => Value::Number(i as f64),
+        Key::Str(value) => Value::Str(value),
+    }
+}
@@ -0,0 +1,1045 @@
+use bytes::Bytes;
+
+use super::{BinOp, Block, Expr, Stat, StatKind, UnOp};
+
+/// A syntax error: the line it was found on and the message.
+type Error = (usize, String);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Number(f64),
+    Str(Bytes),
+    /// A keyword or a symbol.
+    Symbol(&'static str),
+    Eof,
+}
+
+/// The keywords. These cannot be used as names.
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local",
+    "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// The symbols, longest first so that `..` is not read as two `.`.
+const SYMBOLS: &[&str] = &[
+    "...", "..", "==", "~=", "<=", ">=", "+", "-", "*", "/", "%", "^", "#", "<", ">", "=", "(",
+    ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+/// Priority of unary operators, see `Parser::subexpr`.
+const UNARY_PRIORITY: u8 = 8;
+
+/// Deepest nesting of blocks and expressions, as `LUAI_MAXCCALLS` in Lua.
+/// Both the parser and the interpreter recurse once per level, so deeper
+/// scripts would overflow the stack.
+const MAX_DEPTH: usize = 200;
+
+/// Recursive descent parser. The whole source is split into tokens first.
+struct Parser {
+    /// Tokens along with their line.
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// Current nesting level, see `enter`.
+    depth: usize,
+}
+
+/// Parse the body of a script.
+pub(super) fn parse(source: &[u8]) -> Result<Block, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+        depth: 0,
+    };
+
+    let block = parser.block()?;
+    match parser.peek() {
+        Token::Eof => Ok(block),
+        token => Err(parser.error(format!("'<eof>' expected near {}", describe(token)))),
+    }
+}
+
+fn tokenize(source: &[u8]) -> Result<Vec<(Token, usize)>, Error> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut pos = 0;
+
+    while pos < source.len() {
+        let c = source[pos];
+
+        if c == b'\n' {
+            line += 1;
+            pos += 1;
+        } else if c.is_ascii_whitespace() {
+            pos += 1;
+        } else if source[pos..].starts_with(b"--") {
+            pos += 2;
+            match long_bracket(source, pos) {
+                Some(level) => {
+                    let (_, end) = long_string(source, pos, level, &mut line)?;
+                    pos = end;
+                }
+                None => {
+                    while pos < source.len() && source[pos] != b'\n' {
+                        pos += 1;
+                    }
+                }
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = pos;
+            while pos < source.len() && (source[pos].is_ascii_alphanumeric() || source[pos] == b'_')
+            {
+                pos += 1;
+            }
+
+            let word = std::str::from_utf8(&source[start..pos]).unwrap();
+            let token = match KEYWORDS.iter().find(|keyword| **keyword == word) {
+                Some(keyword) => Token::Symbol(keyword),
+                None => Token::Name(word.to_string()),
+            };
+            tokens.push((token, line));
+        } else if c.is_ascii_digit()
+            || (c == b'.' && source.get(pos + 1).is_some_and(u8::is_ascii_digit))
+        {
+            let start = pos;
+            let hex = source[pos..].starts_with(b"0x") || source[pos..].starts_with(b"0X");
+            if hex {
+                pos += 2;
+            }
+            while pos < source.len()
+                && (source[pos].is_ascii_alphanumeric()
+                    || source[pos] == b'.'
+                    || ((source[pos] == b'-' || source[pos] == b'+')
+                        && !hex
+                        && matches!(source[pos - 1], b'e' | b'E')))
+            {
+                pos += 1;
+            }
+
+            let text = std::str::from_utf8(&source[start..pos]).unwrap();
+            let number = if hex {
+                i64::from_str_radix(&text[2..], 16).ok().map(|n| n as f64)
+            } else {
+                text.parse().ok()
+            };
+            match number {
+                Some(number) => tokens.push((Token::Number(number), line)),
+                None => return Err((line, format!("malformed number near '{}'", text))),
+            }
+        } else if c == b'"' || c == b'\'' {
+            let (string, end) = quoted_string(source, pos, &mut line)?;
+            tokens.push((Token::Str(string), line));
+            pos = end;
+        } else if let Some(level) = long_bracket(source, pos) {
+            let start_line = line;
+            let (string, end) = long_string(source, pos, level, &mut line)?;
+            tokens.push((Token::Str(string), start_line));
+            pos = end;
+        } else {
+            match SYMBOLS
+                .iter()
+                .find(|symbol| source[pos..].starts_with(symbol.as_bytes()))
+            {
+                Some(symbol) => {
+                    tokens.push((Token::Symbol(symbol), line));
+                    pos += symbol.len();
+                }
+                None => {
+                    return Err((
+                        line,
+                        format!("unexpected symbol near '{}'", c.escape_ascii()),
+                    ))
+                }
+            }
+        }
+    }
+
+    tokens.push((Token::Eof, line));
+    Ok(tokens)
+}
+
+/// If a long bracket, `[[` or `[==[`, starts at `pos`, returns its level:
+/// the number of `=`.
+fn long_bracket(source: &[u8], pos: usize) -> Option<usize> {
+    if source.get(pos) != Some(&b'[') {
+        return None;
+    }
+
+    let level = source[pos + 1..].iter().take_while(|c| **c == b'=').count();
+    (source.get(pos + 1 + level) == Some(&b'[')).then_some(level)
+}
+
+/// Read the long string starting at `pos`. Returns its content and the
+/// position following it.
+fn long_string(
+    source: &[u8],
+    pos: usize,
+    level: usize,
+    line: &mut usize,
+) -> Result<(Bytes, usize), Error> {
+    let start = pos + level + 2;
+    let close = format!("]{}]", "=".repeat(level));
+
+    let len = source[start..]
+        .windows(close.len())
+        .position(|window| window == close.as_bytes())
+        .ok_or((*line, "unfinished long string".to_string()))?;
+
+    let mut content = &source[start..start + len];
+    *line += content.iter().filter(|c| **c == b'\n').count();
+
+    // A newline right after the opening bracket is skipped.
+    if content.first() == Some(&b'\n') {
+        content = &content[1..];
+    }
+
+    Ok((Bytes::copy_from_slice(content), start + len + close.len()))
+}
+
+/// Read the string quoted with `"` or `'` starting at `pos`, handling
+/// escape sequences. Returns its content and the position following it.
+fn quoted_string(source: &[u8], pos: usize, line: &mut usize) -> Result<(Bytes, usize), Error> {
+    let quote = source[pos];
+    let mut string = vec![];
+    let mut pos = pos + 1;
+
+    loop {
+        let c = match source.get(pos) {
+            Some(b'\n') | None => return Err((*line, "unfinished string".to_string())),
+            Some(c) => *c,
+        };
+        pos += 1;
+
+        if c == quote {
+            return Ok((Bytes::from(string), pos));
+        }
+        if c != b'\\' {
+            string.push(c);
+            continue;
+        }
+
+        let escaped = match source.get(pos) {
+            Some(c) => *c,
+            None => return Err((*line, "unfinished string".to_string())),
+        };
+        pos += 1;
+
+        match escaped {
+            b'n' => string.push(b'\n'),
+            b't' => string.push(b'\t'),
+            b'r' => string.push(b'\r'),
+            b'a' => string.push(0x07),
+            b'b' => string.push(0x08),
+            b'f' => string.push(0x0c),
+            b'v' => string.push(0x0b),
+            b'\\' | b'"' | b'\'' => string.push(escaped),
+            b'\n' => {
+                string.push(b'\n');
+                *line += 1;
+            }
+            b'x' => {
+                let byte = source
+                    .get(pos..pos + 2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or((*line, "hexadecimal digit expected".to_string()))?;
+                string.push(byte);
+                pos += 2;
+            }
+            b'0'..=b'9' => {
+                let digits = source[pos - 1..]
+                    .iter()
+                    .take(3)
+                    .take_while(|c| c.is_ascii_digit())
+                    .count();
+                let value: u32 = std::str::from_utf8(&source[pos - 1..pos - 1 + digits])
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let byte = u8::try_from(value)
+                    .map_err(|_| (*line, "escape sequence too large".to_string()))?;
+                string.push(byte);
+                pos += digits - 1;
+            }
+            _ => return Err((*line, "invalid escape sequence".to_string())),
+        }
+    }
+}
+
+/// Describe `token` in an error message.
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Name(name) => format!("'{}'", name),
+        Token::Number(number) => format!("'{}'", number),
+        Token::Str(string) => format!("'{}'", String::from_utf8_lossy(string)),
+        Token::Symbol(symbol) => format!("'{}'", symbol),
+        Token::Eof => "<eof>".to_string(),
+    }
+}
+
+/// Returns the left and right priorities of the binary operator `symbol`.
+/// Right associative operators have a lower right priority.
+fn binary_op(token: &Token) -> Option<(BinOp, u8, u8)> {
+    let symbol = match token {
+        Token::Symbol(symbol) => *symbol,
+        _ => return None,
+    };
+
+    let op = match symbol {
+        "or" => (BinOp::Or, 1, 1),
+        "and" => (BinOp::And, 2, 2),
+        "<" => (BinOp::Lt, 3, 3),
+        "<=" => (BinOp::Le, 3, 3),
+        ">" => (BinOp::Gt, 3, 3),
+        ">=" => (BinOp::Ge, 3, 3),
+        "==" => (BinOp::Eq, 3, 3),
+        "~=" => (BinOp::Ne, 3, 3),
+        ".." => (BinOp::Concat, 5, 4),
+        "+" => (BinOp::Add, 6, 6),
+        "-" => (BinOp::Sub, 6, 6),
+        "*" => (BinOp::Mul, 7, 7),
+        "/" => (BinOp::Div, 7, 7),
+        "%" => (BinOp::Mod, 7, 7),
+        "^" => (BinOp::Pow, 10, 9),
+        _ => return None,
+    };
+    Some(op)
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error(&self, msg: String) -> Error {
+        (self.line(), msg)
+    }
+
+    /// Count one more level of nesting, failing past `MAX_DEPTH`. Callers
+    /// restore `depth` once done with the level.
+    fn enter(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("chunk has too many syntax levels".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Consume `symbol` if it is next.
+    fn accept(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Token::Symbol(next) if *next == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), Error> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(format!(
+                "'{}' expected near {}",
+                symbol,
+                describe(self.peek())
+            )))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        match self.next() {
+            Token::Name(name) => Ok(name),
+            token => {
+                self.pos -= 1;
+                Err(self.error(format!("<name> expected near {}", describe(&token))))
+            }
+        }
+    }
+
+    /// Returns true if the next token ends a block.
+    fn block_follows(&self) -> bool {
+        matches!(
+            self.peek(),
+            Token::Eof | Token::Symbol("end" | "else" | "elseif" | "until")
+        )
+    }
+
+    fn block(&mut self) -> Result<Block, Error> {
+        let depth = self.depth;
+        self.enter()?;
+        let mut block = vec![];
+
+        while !self.block_follows() {
+            if self.accept(";") {
+                continue;
+            }
+
+            let line = self.line();
+            if self.accept("return") {
+                let value = if self.block_follows() || matches!(self.peek(), Token::Symbol(";")) {
+                    None
+                } else {
+                    // Only the first value is returned to the client.
+                    let values = self.exprs()?;
+                    values.into_iter().next()
+                };
+                self.accept(";");
+
+                block.push(Stat {
+                    line,
+                    kind: StatKind::Return(value),
+                });
+
+                // `return` must be the last statement of its block.
+                if !self.block_follows() {
+                    return Err(
+                        self.error(format!("'end' expected near {}", describe(self.peek())))
+                    );
+                }
+                break;
+            }
+
+            let kind = self.statement()?;
+            block.push(Stat { line, kind });
+        }
+
+        self.depth = depth;
+        Ok(block)
+    }
+
+    fn statement(&mut self) -> Result<StatKind, Error> {
+        if self.accept("local") {
+            if matches!(self.peek(), Token::Symbol("function")) {
+                return Err(self.error("functions are not supported".to_string()));
+            }
+
+            let mut names = vec![self.name()?];
+            while self.accept(",") {
+                names.push(self.name()?);
+            }
+
+            let values = if self.accept("=") {
+                self.exprs()?
+            } else {
+                vec![]
+            };
+            return Ok(StatKind::Local(names, values));
+        }
+
+        if self.accept("if") {
+            let mut branches = vec![];
+            let mut otherwise = None;
+
+            let cond = self.expr()?;
+            self.expect("then")?;
+            branches.push((cond, self.block()?));
+
+            loop {
+                if self.accept("elseif") {
+                    let cond = self.expr()?;
+                    self.expect("then")?;
+                    branches.push((cond, self.block()?));
+                } else if self.accept("else") {
+                    otherwise = Some(self.block()?);
+                    self.expect("end")?;
+                    break;
+                } else {
+                    self.expect("end")?;
+                    break;
+                }
+            }
+
+            return Ok(StatKind::If(branches, otherwise));
+        }
+
+        if self.accept("while") {
+            let cond = self.expr()?;
+            self.expect("do")?;
+            let body = self.block()?;
+            self.expect("end")?;
+            return Ok(StatKind::While(cond, body));
+        }
+
+        if self.accept("repeat") {
+            let body = self.block()?;
+            self.expect("until")?;
+            let cond = self.expr()?;
+            return Ok(StatKind::Repeat(body, cond));
+        }
+
+        if self.accept("do") {
+            let body = self.block()?;
+            self.expect("end")?;
+            return Ok(StatKind::Do(body));
+        }
+
+        if self.accept("for") {
+            return self.for_statement();
+        }
+
+        if self.accept("break") {
+            return Ok(StatKind::Break);
+        }
+
+        if matches!(self.peek(), Token::Symbol("function")) {
+            return Err(self.error("functions are not supported".to_string()));
+        }
+
+        // An assignment or a function call.
+        let target = self.suffixed_expr()?;
+
+        if matches!(self.peek(), Token::Symbol("=" | ",")) {
+            let mut targets = vec![target];
+            while self.accept(",") {
+                targets.push(self.suffixed_expr()?);
+            }
+
+            if let Some(target) = targets
+                .iter()
+                .find(|target| !matches!(target, Expr::Name(_) | Expr::Index(..)))
+            {
+                return Err(self.error(format!("cannot assign to {:?}", target)));
+            }
+
+            self.expect("=")?;
+            let values = self.exprs()?;
+            return Ok(StatKind::Assign(targets, values));
+        }
+
+        match target {
+            Expr::Call(..) => Ok(StatKind::Call(target)),
+            _ => Err(self.error(format!("syntax error near {}", describe(self.peek())))),
+        }
+    }
+
+    /// The `for` keyword has already been consumed.
+    fn for_statement(&mut self) -> Result<StatKind, Error> {
+        let var = self.name()?;
+
+        if self.accept("=") {
+            let start = self.expr()?;
+            self.expect(",")?;
+            let end = self.expr()?;
+            let step = if self.accept(",") {
+                Some(self.expr()?)
+            } else {
+                None
+            };
+
+            self.expect("do")?;
+            let body = self.block()?;
+            self.expect("end")?;
+
+            return Ok(StatKind::NumericFor {
+                var,
+                start,
+                end,
+                step,
+                body,
+            });
+        }
+
+        let value = if self.accept(",") {
+            Some(self.name()?)
+        } else {
+            None
+        };
+        self.expect("in")?;
+
+        // Only the `pairs` and `ipairs` iterators are supported.
+        let ordered = match self.next() {
+            Token::Name(name) if name == "ipairs" => true,
+            Token::Name(name) if name == "pairs" => false,
+            token => {
+                self.pos -= 1;
+                return Err(self.error(format!(
+                    "'pairs' or 'ipairs' expected near {}",
+                    describe(&token)
+                )));
+            }
+        };
+        self.expect("(")?;
+        let table = self.expr()?;
+        self.expect(")")?;
+
+        self.expect("do")?;
+        let body = self.block()?;
+        self.expect("end")?;
+
+        Ok(StatKind::GenericFor {
+            key: var,
+            value,
+            ordered,
+            table,
+            body,
+        })
+    }
+
+    fn exprs(&mut self) -> Result<Vec<Expr>, Error> {
+        let mut exprs = vec![self.expr()?];
+        while self.accept(",") {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, Error> {
+        self.subexpr(0)
+    }
+
+    /// Parse an expression whose binary operators all have a left priority
+    /// greater than `limit`.
+    ///
+    /// Operators applied in a row nest the expression without recursing
+    /// here, but each one still counts as a level: the interpreter recurses
+    /// once per operator.
+    fn subexpr(&mut self, limit: u8) -> Result<Expr, Error> {
+        let depth = self.depth;
+        self.enter()?;
+
+        let unary = match self.peek() {
+            Token::Symbol("not") => Some(UnOp::Not),
+            Token::Symbol("-") => Some(UnOp::Neg),
+            Token::Symbol("#") => Some(UnOp::Len),
+            _ => None,
+        };
+
+        let mut expr = match unary {
+            Some(op) => {
+                self.next();
+                Expr::Unary(op, Box::new(self.subexpr(UNARY_PRIORITY)?))
+            }
+            None => self.simple_expr()?,
+        };
+
+        while let Some((op, left, right)) = binary_op(self.peek()) {
+            if left <= limit {
+                break;
+            }
+            self.next();
+            self.enter()?;
+
+            let rhs = self.subexpr(right)?;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+        }
+
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, Error> {
+        let expr = match self.peek() {
+            Token::Number(number) => Expr::Number(*number),
+            Token::Str(string) => Expr::Str(string.clone()),
+            Token::Symbol("nil") => Expr::Nil,
+            Token::Symbol("true") => Expr::Bool(true),
+            Token::Symbol("false") => Expr::Bool(false),
+            Token::Symbol("{") => return self.table(),
+            Token::Symbol("function") => {
+                return Err(self.error("functions are not supported".to_string()))
+            }
+            _ => return self.suffixed_expr(),
+        };
+
+        self.next();
+        Ok(expr)
+    }
+
+    /// A name or parenthesized expression, followed by any number of
+    /// indexes and calls. Like operators, each of them counts as a level.
+    fn suffixed_expr(&mut self) -> Result<Expr, Error> {
+        let depth = self.depth;
+        let mut expr = match self.next() {
+            Token::Name(name) => Expr::Name(name),
+            Token::Symbol("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                expr
+            }
+            token => {
+                self.pos -= 1;
+                return Err(self.error(format!("unexpected symbol near {}", describe(&token))));
+            }
+        };
+
+        loop {
+            if matches!(
+                self.peek(),
+                Token::Symbol("." | "[" | "(" | "{") | Token::Str(_)
+            ) {
+                self.enter()?;
+            }
+
+            match self.peek() {
+                Token::Symbol(".") => {
+                    self.next();
+                    let name = self.name()?;
+                    expr = Expr::Index(Box::new(expr), Box::new(Expr::Str(Bytes::from(name))));
+                }
+                Token::Symbol("[") => {
+                    self.next();
+                    let key = self.expr()?;
+                    self.expect("]")?;
+                    expr = Expr::Index(Box::new(expr), Box::new(key));
+                }
+                Token::Symbol("(") => {
+                    self.next();
+                    let args = if self.accept(")") {
+                        vec![]
+                    } else {
+                        let args = self.exprs()?;
+                        self.expect(")")?;
+                        args
+                    };
+                    expr = Expr::Call(Box::new(expr), args);
+                }
+                Token::Str(string) => {
+                    let arg = Expr::Str(string.clone());
+                    self.next();
+                    expr = Expr::Call(Box::new(expr), vec![arg]);
+                }
+                Token::Symbol("{") => {
+                    let arg = self.table()?;
+                    expr = Expr::Call(Box::new(expr), vec![arg]);
+                }
+                Token::Symbol(":") => {
+                    return Err(self.error("method calls are not supported".to_string()))
+                }
+                _ => {
+                    self.depth = depth;
+                    return Ok(expr);
+                }
+            }
+        }
+    }
+
+    fn table(&mut self) -> Result<Expr, Error> {
+        let depth = self.depth;
+        self.enter()?;
+        self.expect("{")?;
+        let mut fields = vec![];
+
+        while !self.accept("}") {
+            let field = if self.accept("[") {
+                let key = self.expr()?;
+                self.expect("]")?;
+                self.expect("=")?;
+                (Some(key), self.expr()?)
+            } else if matches!(self.peek(), Token::Name(_))
+                && matches!(self.tokens[self.pos + 1].0, Token::Symbol("="))
+            {
+                let key = Expr::Str(Bytes::from(self.name()?));
+                self.expect("=")?;
+                (Some(key), self.expr()?)
+            } else {
+                (None, self.expr()?)
+            };
+            fields.push(field);
+
+            if !self.accept(",") && !self.accept(";") {
+                self.expect("}")?;
+                break;
+            }
+        }
+
+        self.depth = depth;
+        Ok(Expr::Table(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn name(name: &str) -> Token {
+        Token::Name(name.to_string())
+    }
+
+    fn string(string: &str) -> Token {
+        Token::Str(Bytes::copy_from_slice(string.as_bytes()))
+    }
+
+    #[test]
+    fn keywords_are_symbols() {
+        assert_eq!(
+            tokens("local x = nil"),
+            [
+                Token::Symbol("local"),
+                name("x"),
+                Token::Symbol("="),
+                Token::Symbol("nil"),
+                Token::Eof
+            ]
+        );
+        assert_eq!(
+            tokens("ends _end end_"),
+            [name("ends"), name("_end"), name("end_"), Token::Eof]
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(
+            tokens("3 3.5 .5 1e2 2E-1 0xff 0X10"),
+            [
+                Token::Number(3.0),
+                Token::Number(3.5),
+                Token::Number(0.5),
+                Token::Number(100.0),
+                Token::Number(0.2),
+                Token::Number(255.0),
+                Token::Number(16.0),
+                Token::Eof
+            ]
+        );
+        assert_eq!(
+            tokenize(b"x = 3x"),
+            Err((1, "malformed number near '3x'".to_string()))
+        );
+        assert_eq!(
+            tokenize(b"\n0xg"),
+            Err((2, "malformed number near '0xg'".to_string()))
+        );
+    }
+
+    #[test]
+    fn quoted_strings() {
+        assert_eq!(
+            tokens(
+                r#""a\tb" 'it''s' "\"q\"" "\65\066\x43" 'a\
+b'"#
+            ),
+            [
+                string("a\tb"),
+                string("it"),
+                string("s"),
+                string("\"q\""),
+                string("ABC"),
+                string("a\nb"),
+                Token::Eof
+            ]
+        );
+        assert_eq!(tokenize(b"'abc"), Err((1, "unfinished string".to_string())));
+        assert_eq!(
+            tokenize(b"'a\nb'"),
+            Err((1, "unfinished string".to_string()))
+        );
+        assert_eq!(
+            tokenize(b"'\\256'"),
+            Err((1, "escape sequence too large".to_string()))
+        );
+        assert_eq!(
+            tokenize(b"'\\q'"),
+            Err((1, "invalid escape sequence".to_string()))
+        );
+    }
+
+    #[test]
+    fn long_strings() {
+        assert_eq!(
+            tokens("[[a\\n]] [==[\nb]]c]==]"),
+            [string("a\\n"), string("b]]c"), Token::Eof]
+        );
+        assert_eq!(
+            tokenize(b"[=[abc]]"),
+            Err((1, "unfinished long string".to_string()))
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(
+            tokens("a -- b c\nd --[[ e\nf ]] g --[==[ ]] ]==] h"),
+            [name("a"), name("d"), name("g"), name("h"), Token::Eof]
+        );
+    }
+
+    #[test]
+    fn symbols_take_the_longest_match() {
+        assert_eq!(
+            tokens("a...b..c.d<=e=f~=g"),
+            [
+                name("a"),
+                Token::Symbol("..."),
+                name("b"),
+                Token::Symbol(".."),
+                name("c"),
+                Token::Symbol("."),
+                name("d"),
+                Token::Symbol("<="),
+                name("e"),
+                Token::Symbol("="),
+                name("f"),
+                Token::Symbol("~="),
+                name("g"),
+                Token::Eof
+            ]
+        );
+        assert_eq!(
+            tokenize(b"a = $"),
+            Err((1, "unexpected symbol near '$'".to_string()))
+        );
+    }
+
+    #[test]
+    fn tokens_carry_their_line() {
+        let lines: Vec<usize> = tokenize(b"a\n[[\n\n]] b\n--[[\n]]\nc")
+            .unwrap()
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(lines, [1, 2, 4, 7, 7]);
+    }
+
+    #[test]
+    fn statements() {
+        let block = parse(
+            b"local a, b = 1, 'x'
+            a = a + 1
+            redis.call('SET', KEYS[1], a)
+            if a > 1 then return a elseif a then return b else return nil end",
+        )
+        .unwrap();
+
+        let lines: Vec<usize> = block.iter().map(|stat| stat.line).collect();
+        assert_eq!(lines, [1, 2, 3, 4]);
+        assert!(matches!(&block[0].kind, StatKind::Local(names, values)
+            if names == &["a", "b"] && values.len() == 2));
+        assert!(matches!(&block[1].kind, StatKind::Assign(targets, _) if targets.len() == 1));
+        assert!(matches!(&block[2].kind, StatKind::Call(Expr::Call(_, args)) if args.len() == 3));
+        assert!(matches!(&block[3].kind, StatKind::If(branches, Some(_)) if branches.len() == 2));
+    }
+
+    #[test]
+    fn loops() {
+        let block = parse(
+            b"for i = 1, 10, 2 do end
+            for k, v in pairs(t) do break end
+            for i in ipairs(t) do end
+            while true do end
+            repeat until false",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &block[0].kind,
+            StatKind::NumericFor { step: Some(_), .. }
+        ));
+        assert!(matches!(
+            &block[1].kind,
+            StatKind::GenericFor {
+                value: Some(_),
+                ordered: false,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &block[2].kind,
+            StatKind::GenericFor {
+                value: None,
+                ordered: true,
+                ..
+            }
+        ));
+        assert!(matches!(&block[3].kind, StatKind::While(..)));
+        assert!(matches!(&block[4].kind, StatKind::Repeat(..)));
+    }
+
+    #[test]
+    fn operator_priorities() {
+        let block = parse(b"return 1 + 2 * 3 ^ 2 ^ 3 .. 'a' .. 'b'").unwrap();
+        let expr = match &block[0].kind {
+            StatKind::Return(Some(expr)) => expr,
+            kind => panic!("unexpected statement {:?}", kind),
+        };
+
+        // Concatenation binds loosest here and is right associative.
+        let Expr::Binary(BinOp::Concat, sum, rest) = expr else {
+            panic!("unexpected expression {:?}", expr)
+        };
+        assert!(matches!(**rest, Expr::Binary(BinOp::Concat, ..)));
+
+        let Expr::Binary(BinOp::Add, _, product) = &**sum else {
+            panic!("unexpected expression {:?}", sum)
+        };
+        let Expr::Binary(BinOp::Mul, _, power) = &**product else {
+            panic!("unexpected expression {:?}", product)
+        };
+
+        // So is `^`: 3 ^ (2 ^ 3).
+        let Expr::Binary(BinOp::Pow, base, exponent) = &**power else {
+            panic!("unexpected expression {:?}", power)
+        };
+        assert!(matches!(**base, Expr::Number(n) if n == 3.0));
+        assert!(matches!(**exponent, Expr::Binary(BinOp::Pow, ..)));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |source: &str| parse(source.as_bytes()).unwrap_err();
+
+        assert_eq!(
+            error("x = 1 end"),
+            (1, "'<eof>' expected near 'end'".to_string())
+        );
+        assert_eq!(
+            error("return 1 2"),
+            (1, "'end' expected near '2'".to_string())
+        );
+        assert_eq!(error("if x then\nreturn 1").0, 2);
+        assert!(error("local = 1").1.contains("expected"));
+        assert!(error("x = ").1.contains("near <eof>"));
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        let nested = |depth: usize, open: &str, inner: &str, close: &str| {
+            format!(
+                "return {}{}{}",
+                open.repeat(depth),
+                inner,
+                close.repeat(depth)
+            )
+        };
+        let too_deep = (1, "chunk has too many syntax levels".to_string());
+
+        assert!(parse(nested(100, "(", "1", ")").as_bytes()).is_ok());
+        assert_eq!(
+            parse(nested(100_000, "(", "1", ")").as_bytes()).unwrap_err(),
+            too_deep
+        );
+        assert_eq!(
+            parse(nested(100_000, "{", "1", "}").as_bytes()).unwrap_err(),
+            too_deep
+        );
+        assert_eq!(
+            parse(nested(100_000, "not ", "1", "").as_bytes()).unwrap_err(),
+            too_deep
+        );
+        let chain = format!("return 1{}", " + 1".repeat(100_000));
+        assert_eq!(parse(chain.as_bytes()).unwrap_err(), too_deep);
+        let blocks = format!("{}{}", "do ".repeat(100_000), "end ".repeat(100_000));
+        assert_eq!(parse(blocks.as_bytes()).unwrap_err(), too_deep);
+    }
+}
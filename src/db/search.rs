@@ -0,0 +1,665 @@
+mod query;
+pub(crate) use query::{Filter, Query};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+
+use bytes::Bytes;
+
+use super::hnsw::Hnsw;
+
+/// A secondary index over the hashes whose key starts with one of a set of
+/// prefixes.
+///
+/// The `Db` keeps every index in sync: each write to a matching key
+/// re-indexes the key, and each removal drops it from the index.
+#[derive(Debug)]
+pub(crate) struct Index {
+    /// Key prefixes covered by the index. An empty prefix covers every key.
+    prefixes: Vec<String>,
+    fields: Vec<Field>,
+    /// Keys currently in the index.
+    docs: HashSet<String>,
+}
+
+/// The type of an indexed hash field, as declared by `FT.CREATE`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldType {
+    /// Full text, split into lowercase alphanumeric terms. Terms found in
+    /// fields with a higher `weight` score higher.
+    Text { weight: f64 },
+    /// A number, searched by range.
+    Numeric,
+    /// A list of exact values separated by `separator`, such as categories.
+    /// Unless `case_sensitive`, tags are compared ignoring case.
+    Tag {
+        separator: char,
+        case_sensitive: bool,
+    },
+    /// An embedding, stored in the hash as `dim` little endian `f32`s.
+    Vector {
+        algorithm: VectorAlgorithm,
+        dim: usize,
+        metric: DistanceMetric,
+    },
+}
+
+/// How a vector field is searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorAlgorithm {
+    /// Exact search, comparing the query with every vector.
+    Flat,
+    /// Approximate search over an HNSW graph.
+    Hnsw {
+        /// Maximum number of neighbours per node.
+        m: usize,
+        /// Size of the candidate list while building the graph.
+        ef_construction: usize,
+        /// Size of the candidate list while searching.
+        ef_runtime: usize,
+    },
+}
+
+/// The distance between two vectors. Lower is closer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceMetric {
+    /// Squared euclidean distance.
+    L2,
+    /// `1 - a·b`. Meant for normalized vectors.
+    Ip,
+    /// `1 - cos(a, b)`.
+    Cosine,
+}
+
+/// A document returned by a search: its key, its score if the query computed
+/// one, and its fields.
+pub(crate) type SearchResult = (String, Option<f32>, Vec<(Bytes, Bytes)>);
+
+pub(crate) const DEFAULT_M: usize = 16;
+pub(crate) const DEFAULT_EF_CONSTRUCTION: usize = 200;
+pub(crate) const DEFAULT_EF_RUNTIME: usize = 10;
+pub(crate) const DEFAULT_TAG_SEPARATOR: char = ',';
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    ty: FieldType,
+    store: FieldStore,
+}
+
+/// Indexed values of a field.
+#[derive(Debug)]
+enum FieldStore {
+    Text {
+        /// Documents containing each term, with the number of occurrences.
+        postings: HashMap<String, HashMap<String, u32>>,
+        /// Distinct terms of each document, to clean up `postings`.
+        terms: HashMap<String, Vec<String>>,
+    },
+    Numeric {
+        values: HashMap<String, f64>,
+        /// Documents ordered by value. Values are stored as `sort_key`s.
+        sorted: BTreeSet<(u64, String)>,
+    },
+    Tag {
+        /// Documents holding each tag.
+        docs: HashMap<String, HashSet<String>>,
+        /// Tags of each document, to clean up `docs`.
+        tags: HashMap<String, Vec<String>>,
+    },
+    Flat(HashMap<String, Vec<f32>>),
+    Hnsw(Hnsw),
+}
+
+impl Index {
+    pub(crate) fn new(prefixes: Vec<String>, schema: Vec<(String, FieldType)>) -> Index {
+        let fields = schema
+            .into_iter()
+            .map(|(name, ty)| {
+                let store = match &ty {
+                    FieldType::Text { .. } => FieldStore::Text {
+                        postings: HashMap::new(),
+                        terms: HashMap::new(),
+                    },
+                    FieldType::Numeric => FieldStore::Numeric {
+                        values: HashMap::new(),
+                        sorted: BTreeSet::new(),
+                    },
+                    FieldType::Tag { .. } => FieldStore::Tag {
+                        docs: HashMap::new(),
+                        tags: HashMap::new(),
+                    },
+                    FieldType::Vector {
+                        algorithm: VectorAlgorithm::Flat,
+                        ..
+                    } => FieldStore::Flat(HashMap::new()),
+                    FieldType::Vector {
+                        algorithm:
+                            VectorAlgorithm::Hnsw {
+                                m, ef_construction, ..
+                            },
+                        ..
+                    } => FieldStore::Hnsw(Hnsw::new(*m, *ef_construction)),
+                };
+
+                Field { name, ty, store }
+            })
+            .collect();
+
+        Index {
+            prefixes,
+            fields,
+            docs: HashSet::new(),
+        }
+    }
+
+    /// Returns the key prefixes covered by the index.
+    pub(crate) fn prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    /// Returns the indexed fields and their types, as declared.
+    pub(crate) fn schema(&self) -> Vec<(String, FieldType)> {
+        self.fields
+            .iter()
+            .map(|field| (field.name.clone(), field.ty.clone()))
+            .collect()
+    }
+
+    /// Returns `true` if `key` is covered by the index.
+    pub(crate) fn covers(&self, key: &str) -> bool {
+        self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(&prefix[..]))
+    }
+
+    /// Returns `true` if the schema declares `name`.
+    pub(crate) fn has_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|field| field.name == name)
+    }
+
+    /// Re-index `key`. `hash` is the hash now stored at the key, or `None` if
+    /// the key was removed or no longer holds a hash.
+    ///
+    /// Fields missing from the hash, or holding a value that does not parse
+    /// as the field's type, are left out of the index.
+    pub(crate) fn update(&mut self, key: &str, hash: Option<&HashMap<Bytes, Bytes>>) {
+        let hash = match hash {
+            Some(hash) if self.covers(key) => hash,
+            _ => {
+                if self.docs.remove(key) {
+                    for field in &mut self.fields {
+                        field.remove(key);
+                    }
+                }
+                return;
+            }
+        };
+
+        for field in &mut self.fields {
+            field.remove(key);
+            if let Some(value) = hash.get(field.name.as_bytes()) {
+                field.insert(key, value);
+            }
+        }
+
+        self.docs.insert(key.to_string());
+    }
+
+    /// Run `query`, returning the matching keys with their score.
+    ///
+    /// Vector queries return their nearest neighbours, closest first, scored
+    /// by distance. Other queries return every match, best first, scored by
+    /// the TF-IDF of the terms they look for.
+    pub(crate) fn search(&self, query: &Query) -> Result<Vec<(String, Option<f32>)>, String> {
+        let allowed = match &query.filter {
+            Filter::All => None,
+            filter => Some(self.filter(filter)?),
+        };
+
+        if let Some(knn) = &query.knn {
+            let field = self.field(&knn.field)?;
+
+            return Ok(field
+                .knn(&knn.vector, knn.k, allowed.as_ref())?
+                .into_iter()
+                .map(|(key, distance)| (key, Some(distance)))
+                .collect());
+        }
+
+        let mut terms = vec![];
+        query.filter.positive_terms(&mut terms);
+
+        let mut matches: Vec<(String, f32)> = allowed
+            .unwrap_or_else(|| self.docs.clone())
+            .into_iter()
+            .map(|key| {
+                let score = self.score(&key, &terms);
+                (key, score)
+            })
+            .collect();
+
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Ok(matches
+            .into_iter()
+            .map(|(key, score)| (key, Some(score).filter(|_| !terms.is_empty())))
+            .collect())
+    }
+
+    /// Returns the keys matching `filter`.
+    fn filter(&self, filter: &Filter) -> Result<HashSet<String>, String> {
+        Ok(match filter {
+            Filter::All => self.docs.clone(),
+            Filter::Term {
+                field,
+                term,
+                prefix,
+            } => {
+                let mut keys = HashSet::new();
+                for field in self.text_fields(field.as_deref())? {
+                    field.terms(term, *prefix, |key, _| {
+                        keys.insert(key.to_string());
+                    });
+                }
+                keys
+            }
+            Filter::Range { field, min, max } => self.field(field)?.range(*min, *max)?,
+            Filter::Tag { field, tags } => self.field(field)?.tagged(tags)?,
+            Filter::And(filters) => {
+                let mut keys = self.filter(&filters[0])?;
+                for filter in &filters[1..] {
+                    let other = self.filter(filter)?;
+                    keys.retain(|key| other.contains(key));
+                }
+                keys
+            }
+            Filter::Or(filters) => {
+                let mut keys = HashSet::new();
+                for filter in filters {
+                    keys.extend(self.filter(filter)?);
+                }
+                keys
+            }
+            Filter::Not(filter) => {
+                let excluded = self.filter(filter)?;
+                self.docs
+                    .iter()
+                    .filter(|key| !excluded.contains(*key))
+                    .cloned()
+                    .collect()
+            }
+        })
+    }
+
+    /// TF-IDF score of `key` for `terms`, weighted by field.
+    fn score(&self, key: &str, terms: &[(Option<&str>, &str, bool)]) -> f32 {
+        let docs = self.docs.len().max(1) as f64;
+        let mut score = 0.0;
+
+        for (field, term, prefix) in terms {
+            // Fields were checked while filtering.
+            for field in self.text_fields(*field).unwrap_or_default() {
+                let weight = match field.ty {
+                    FieldType::Text { weight } => weight,
+                    _ => continue,
+                };
+
+                field.terms(term, *prefix, |doc, postings| {
+                    if doc == key {
+                        let idf = (1.0 + docs / postings.len() as f64).ln();
+                        score += weight * postings[doc] as f64 * idf;
+                    }
+                });
+            }
+        }
+
+        score as f32
+    }
+
+    fn field(&self, name: &str) -> Result<&Field, String> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .ok_or_else(|| format!("ERR Unknown field `{}`", name))
+    }
+
+    /// The TEXT field `name`, or every TEXT field if no name is given.
+    fn text_fields(&self, name: Option<&str>) -> Result<Vec<&Field>, String> {
+        match name {
+            Some(name) => {
+                let field = self.field(name)?;
+                match field.ty {
+                    FieldType::Text { .. } => Ok(vec![field]),
+                    _ => Err(format!("ERR Field `{}` is not a TEXT field", name)),
+                }
+            }
+            None => Ok(self
+                .fields
+                .iter()
+                .filter(|field| matches!(field.ty, FieldType::Text { .. }))
+                .collect()),
+        }
+    }
+}
+
+impl Field {
+    /// Index `value` for `key`. Values that do not parse as the field's type
+    /// are ignored.
+    fn insert(&mut self, key: &str, value: &Bytes) {
+        match (&mut self.store, &self.ty) {
+            (FieldStore::Text { postings, terms }, _) => {
+                let mut counts: HashMap<String, u32> = HashMap::new();
+                for term in tokenize(&String::from_utf8_lossy(value)) {
+                    *counts.entry(term).or_default() += 1;
+                }
+
+                let mut doc_terms = Vec::with_capacity(counts.len());
+                for (term, count) in counts {
+                    postings
+                        .entry(term.clone())
+                        .or_default()
+                        .insert(key.to_string(), count);
+                    doc_terms.push(term);
+                }
+                terms.insert(key.to_string(), doc_terms);
+            }
+            (FieldStore::Numeric { values, sorted }, _) => {
+                let value = match std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<f64>().ok())
+                {
+                    Some(value) if !value.is_nan() => value,
+                    _ => return,
+                };
+
+                values.insert(key.to_string(), value);
+                sorted.insert((sort_key(value), key.to_string()));
+            }
+            (
+                FieldStore::Tag { docs, tags },
+                FieldType::Tag {
+                    separator,
+                    case_sensitive,
+                },
+            ) => {
+                let mut doc_tags: Vec<String> = String::from_utf8_lossy(value)
+                    .split(*separator)
+                    .map(|tag| normalize_tag(tag, *case_sensitive))
+                    .filter(|tag| !tag.is_empty())
+                    .collect();
+                doc_tags.sort();
+                doc_tags.dedup();
+
+                for tag in &doc_tags {
+                    docs.entry(tag.clone()).or_default().insert(key.to_string());
+                }
+                tags.insert(key.to_string(), doc_tags);
+            }
+            (FieldStore::Flat(vectors), FieldType::Vector { dim, .. }) => {
+                if let Some(vector) = parse_vector(value, *dim) {
+                    vectors.insert(key.to_string(), vector);
+                }
+            }
+            (FieldStore::Hnsw(graph), FieldType::Vector { dim, metric, .. }) => {
+                if let Some(vector) = parse_vector(value, *dim) {
+                    let metric = *metric;
+                    graph.insert(key.to_string(), vector, |a, b| metric.distance(a, b));
+                }
+            }
+            _ => unreachable!("field store does not match its type"),
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        match &mut self.store {
+            FieldStore::Text { postings, terms } => {
+                for term in terms.remove(key).unwrap_or_default() {
+                    if let Some(docs) = postings.get_mut(&term) {
+                        docs.remove(key);
+                        if docs.is_empty() {
+                            postings.remove(&term);
+                        }
+                    }
+                }
+            }
+            FieldStore::Numeric { values, sorted } => {
+                if let Some(value) = values.remove(key) {
+                    sorted.remove(&(sort_key(value), key.to_string()));
+                }
+            }
+            FieldStore::Tag { docs, tags } => {
+                for tag in tags.remove(key).unwrap_or_default() {
+                    if let Some(keys) = docs.get_mut(&tag) {
+                        keys.remove(key);
+                        if keys.is_empty() {
+                            docs.remove(&tag);
+                        }
+                    }
+                }
+            }
+            FieldStore::Flat(vectors) => {
+                vectors.remove(key);
+            }
+            FieldStore::Hnsw(graph) => {
+                if let FieldType::Vector { metric, .. } = self.ty {
+                    graph.remove(key, |a, b| metric.distance(a, b));
+                }
+            }
+        }
+    }
+
+    /// Call `f` with each document containing `term`, or a term starting with
+    /// `term` if `prefix` is set, along with the term's postings.
+    fn terms(&self, term: &str, prefix: bool, mut f: impl FnMut(&str, &HashMap<String, u32>)) {
+        let postings = match &self.store {
+            FieldStore::Text { postings, .. } => postings,
+            _ => return,
+        };
+
+        let mut visit = |docs: &HashMap<String, u32>| {
+            for key in docs.keys() {
+                f(key, docs);
+            }
+        };
+
+        if prefix {
+            for (_, docs) in postings.iter().filter(|(t, _)| t.starts_with(term)) {
+                visit(docs);
+            }
+        } else if let Some(docs) = postings.get(term) {
+            visit(docs);
+        }
+    }
+
+    /// Documents whose value lies between `min` and `max`.
+    fn range(&self, min: (f64, bool), max: (f64, bool)) -> Result<HashSet<String>, String> {
+        let sorted = match &self.store {
+            FieldStore::Numeric { sorted, .. } => sorted,
+            _ => return Err(format!("ERR Field `{}` is not a NUMERIC field", self.name)),
+        };
+
+        let lower = match min {
+            (value, true) => Included((sort_key(value), String::new())),
+            // No key sorts after `char::MAX`, so this skips every document
+            // holding exactly `value`.
+            (value, false) => Excluded((sort_key(value), char::MAX.to_string())),
+        };
+
+        let in_range = |value: u64| match max {
+            (max, true) => value <= sort_key(max),
+            (max, false) => value < sort_key(max),
+        };
+
+        Ok(sorted
+            .range((lower, Unbounded))
+            .take_while(|(value, _)| in_range(*value))
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+
+    /// Documents holding one of `tags`.
+    fn tagged(&self, tags: &[String]) -> Result<HashSet<String>, String> {
+        let (docs, case_sensitive) = match (&self.store, &self.ty) {
+            (FieldStore::Tag { docs, .. }, FieldType::Tag { case_sensitive, .. }) => {
+                (docs, *case_sensitive)
+            }
+            _ => return Err(format!("ERR Field `{}` is not a TAG field", self.name)),
+        };
+
+        Ok(tags
+            .iter()
+            .filter_map(|tag| docs.get(&normalize_tag(tag, case_sensitive)))
+            .flatten()
+            .cloned()
+            .collect())
+    }
+
+    /// The `k` keys closest to `query`, closest first. When `allowed` is
+    /// given, only those keys are considered, comparing the query with each
+    /// of their vectors.
+    fn knn(
+        &self,
+        query: &[f32],
+        k: usize,
+        allowed: Option<&HashSet<String>>,
+    ) -> Result<Vec<(String, f32)>, String> {
+        let (algorithm, dim, metric) = match &self.ty {
+            FieldType::Vector {
+                algorithm,
+                dim,
+                metric,
+            } => (algorithm, *dim, *metric),
+            _ => return Err(format!("ERR Field `{}` is not a VECTOR field", self.name)),
+        };
+
+        if query.len() != dim {
+            return Err("ERR query vector blob size does not match the field dimension".into());
+        }
+
+        let nearest = |vectors: &mut dyn Iterator<Item = (&String, &[f32])>| {
+            let mut scored: Vec<(String, f32)> = vectors
+                .map(|(key, vector)| (key.clone(), metric.distance(query, vector)))
+                .collect();
+            scored.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+            scored.truncate(k);
+            scored
+        };
+
+        Ok(match (&self.store, allowed) {
+            (FieldStore::Flat(vectors), _) => nearest(
+                &mut vectors
+                    .iter()
+                    .filter(|(key, _)| allowed.is_none_or(|allowed| allowed.contains(*key)))
+                    .map(|(key, vector)| (key, &vector[..])),
+            ),
+            (FieldStore::Hnsw(graph), None) => {
+                let ef = match algorithm {
+                    VectorAlgorithm::Hnsw { ef_runtime, .. } => *ef_runtime,
+                    VectorAlgorithm::Flat => k,
+                };
+                graph.search(query, k.min(graph.len()), ef, |a, b| metric.distance(a, b))
+            }
+            // Filtered queries compare the query with every allowed vector
+            // rather than walking the graph, which could miss matches when
+            // the filter is selective.
+            (FieldStore::Hnsw(graph), Some(allowed)) => nearest(
+                &mut allowed
+                    .iter()
+                    .filter_map(|key| graph.vector(key).map(|vector| (key, vector))),
+            ),
+            _ => unreachable!("field store does not match its type"),
+        })
+    }
+}
+
+impl VectorAlgorithm {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            VectorAlgorithm::Flat => "FLAT",
+            VectorAlgorithm::Hnsw { .. } => "HNSW",
+        }
+    }
+}
+
+impl DistanceMetric {
+    /// Parse a metric name, ignoring case.
+    pub(crate) fn parse(name: &str) -> Option<DistanceMetric> {
+        Some(match &name.to_uppercase()[..] {
+            "L2" => DistanceMetric::L2,
+            "IP" => DistanceMetric::Ip,
+            "COSINE" => DistanceMetric::Cosine,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            DistanceMetric::L2 => "L2",
+            DistanceMetric::Ip => "IP",
+            DistanceMetric::Cosine => "COSINE",
+        }
+    }
+
+    pub(crate) fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        let dot = || a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+
+        match self {
+            DistanceMetric::L2 => a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum(),
+            DistanceMetric::Ip => 1.0 - dot(),
+            DistanceMetric::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norms = norm(a) * norm(b);
+
+                if norms == 0.0 {
+                    1.0
+                } else {
+                    1.0 - dot() / norms
+                }
+            }
+        }
+    }
+}
+
+/// Split text into lowercase terms made of letters, digits and underscores.
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn normalize_tag(tag: &str, case_sensitive: bool) -> String {
+    if case_sensitive {
+        tag.trim().to_string()
+    } else {
+        tag.trim().to_lowercase()
+    }
+}
+
+/// Map `value` to an integer with the same ordering, so numbers can be kept
+/// in a `BTreeSet`.
+fn sort_key(value: f64) -> u64 {
+    let bits = value.to_bits();
+
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | 1 << 63
+    }
+}
+
+/// Decode a blob of `dim` little endian `f32`s.
+fn parse_vector(blob: &[u8], dim: usize) -> Option<Vec<f32>> {
+    if blob.len() != dim * 4 || dim == 0 {
+        return None;
+    }
+
+    Some(
+        blob.chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect(),
+    )
+}
@@ -0,0 +1,456 @@
+use bytes::Bytes;
+
+/// A query run by `FT.SEARCH`: documents matching `filter`, optionally
+/// narrowed down to the nearest neighbours of a vector.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Query {
+    pub(crate) filter: Filter,
+    pub(crate) knn: Option<Knn>,
+}
+
+/// Selects documents by the content of their fields.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Filter {
+    /// Every document in the index.
+    All,
+    /// Documents containing `term` in `field`, or in any TEXT field if no
+    /// field is given. With `prefix`, any term starting with `term` matches.
+    Term {
+        field: Option<String>,
+        term: String,
+        prefix: bool,
+    },
+    /// Documents whose NUMERIC `field` lies between `min` and `max`.
+    Range {
+        field: String,
+        min: Bound,
+        max: Bound,
+    },
+    /// Documents whose TAG `field` holds one of `tags`.
+    Tag { field: String, tags: Vec<String> },
+    /// Documents matching all the filters.
+    And(Vec<Filter>),
+    /// Documents matching any of the filters.
+    Or(Vec<Filter>),
+    /// Documents not matching the filter.
+    Not(Box<Filter>),
+}
+
+/// A bound of a numeric range: the value and whether it is inclusive.
+pub(crate) type Bound = (f64, bool);
+
+/// The `k` documents whose vector `field` is the closest to `vector`. The
+/// distance is returned as the `score` field.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Knn {
+    pub(crate) k: usize,
+    pub(crate) field: String,
+    pub(crate) vector: Vec<f32>,
+    pub(crate) score: String,
+}
+
+/// Recursive descent parser for the filter part of a query.
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    params: &'a [(String, Bytes)],
+}
+
+/// Characters that end a term.
+const SPECIAL: &str = "()|{}[]@:*$\"";
+
+impl Query {
+    /// Parse a query string. Values prefixed with `$` are looked up in
+    /// `params`.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// *                       every document
+    /// hello world             documents containing both terms
+    /// hello | world           documents containing either term
+    /// -hello                  documents not containing the term
+    /// hel*                    documents containing a term starting with `hel`
+    /// @title:hello            the term in the `title` TEXT field
+    /// @title:(hello | world)  the expression in the `title` TEXT field
+    /// @price:[10 (20]         NUMERIC range, `(` excludes the bound, `-inf`
+    ///                         and `+inf` are open ends
+    /// @genre:{rock | pop}     TAG field holding one of the tags
+    /// (...)                   grouping
+    /// filter=>[KNN k @field $param [AS score]]
+    /// ```
+    ///
+    /// Intersection binds tighter than union, so `a b | c` reads as
+    /// `(a b) | c`.
+    pub(crate) fn parse(query: &str, params: &[(String, Bytes)]) -> Result<Query, String> {
+        let (filter, knn) = match query.find("=>") {
+            Some(at) => (&query[..at], Some(parse_knn(&query[at + 2..], params)?)),
+            None => (query, None),
+        };
+
+        let mut parser = Parser {
+            chars: filter.chars().collect(),
+            pos: 0,
+            params,
+        };
+
+        let filter = parser.union(None)?;
+
+        parser.skip_whitespace();
+        if let Some(c) = parser.peek() {
+            return Err(format!(
+                "ERR Syntax error at offset {} near `{}`",
+                parser.pos, c
+            ));
+        }
+
+        Ok(Query { filter, knn })
+    }
+
+    /// Name of the score field reported with each result, if any.
+    pub(crate) fn score_field(&self) -> Option<&str> {
+        self.knn.as_ref().map(|knn| &knn.score[..])
+    }
+}
+
+impl Filter {
+    /// Collect the terms the filter looks for, skipping negated ones. These
+    /// are the terms scoring the results.
+    pub(crate) fn positive_terms<'a>(&'a self, terms: &mut Vec<(Option<&'a str>, &'a str, bool)>) {
+        match self {
+            Filter::Term {
+                field,
+                term,
+                prefix,
+            } => terms.push((field.as_deref(), term, *prefix)),
+            Filter::And(filters) | Filter::Or(filters) => {
+                for filter in filters {
+                    filter.positive_terms(terms);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Parser<'_> {
+    /// `intersection ('|' intersection)*`
+    fn union(&mut self, field: Option<&str>) -> Result<Filter, String> {
+        let mut filters = vec![self.intersection(field)?];
+
+        loop {
+            self.skip_whitespace();
+            if !self.eat('|') {
+                break;
+            }
+            filters.push(self.intersection(field)?);
+        }
+
+        Ok(flatten(filters, Filter::Or))
+    }
+
+    /// `unary+`
+    fn intersection(&mut self, field: Option<&str>) -> Result<Filter, String> {
+        let mut filters = vec![];
+
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(')') | Some('|') => break,
+                _ => filters.push(self.unary(field)?),
+            }
+        }
+
+        if filters.is_empty() {
+            return Err(format!(
+                "ERR Syntax error at offset {}: empty expression",
+                self.pos
+            ));
+        }
+
+        Ok(flatten(filters, Filter::And))
+    }
+
+    /// `'-' unary | atom`
+    fn unary(&mut self, field: Option<&str>) -> Result<Filter, String> {
+        self.skip_whitespace();
+
+        if self.eat('-') {
+            return Ok(Filter::Not(Box::new(self.unary(field)?)));
+        }
+
+        self.atom(field)
+    }
+
+    fn atom(&mut self, field: Option<&str>) -> Result<Filter, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let filter = self.union(field)?;
+                self.expect(')')?;
+                Ok(filter)
+            }
+            Some('@') => {
+                self.pos += 1;
+                let name = self.word();
+                if name.is_empty() {
+                    return Err(format!(
+                        "ERR Syntax error at offset {}: missing field name",
+                        self.pos
+                    ));
+                }
+                self.expect(':')?;
+                self.skip_whitespace();
+
+                match self.peek() {
+                    Some('[') => self.range(name),
+                    Some('{') => self.tags(name),
+                    Some('(') => {
+                        self.pos += 1;
+                        let filter = self.union(Some(&name))?;
+                        self.expect(')')?;
+                        Ok(filter)
+                    }
+                    _ => self.term(Some(&name)),
+                }
+            }
+            Some('*') => {
+                self.pos += 1;
+                Ok(Filter::All)
+            }
+            _ => self.term(field),
+        }
+    }
+
+    /// A word, tokenized like indexed text. A word made of several tokens
+    /// matches documents containing all of them.
+    fn term(&mut self, field: Option<&str>) -> Result<Filter, String> {
+        let word = match self.param()? {
+            Some(value) => value,
+            None => self.word(),
+        };
+        let prefix = self.eat('*');
+
+        let terms: Vec<Filter> = super::tokenize(&word)
+            .into_iter()
+            .map(|term| Filter::Term {
+                field: field.map(str::to_string),
+                term,
+                prefix,
+            })
+            .collect();
+
+        if terms.is_empty() {
+            return Err(format!("ERR Syntax error at offset {}", self.pos));
+        }
+
+        Ok(flatten(terms, Filter::And))
+    }
+
+    /// `'[' bound bound ']'`
+    fn range(&mut self, field: String) -> Result<Filter, String> {
+        self.expect('[')?;
+        let min = self.bound()?;
+        let max = self.bound()?;
+        self.expect(']')?;
+
+        Ok(Filter::Range { field, min, max })
+    }
+
+    fn bound(&mut self) -> Result<Bound, String> {
+        self.skip_whitespace();
+        let inclusive = !self.eat('(');
+
+        let value = match self.param()? {
+            Some(value) => value,
+            None => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| !c.is_whitespace() && c != ']') {
+                    self.pos += 1;
+                }
+                self.chars[start..self.pos].iter().collect()
+            }
+        };
+
+        let value = match &value.to_lowercase()[..] {
+            "-inf" => f64::NEG_INFINITY,
+            "inf" | "+inf" => f64::INFINITY,
+            _ => value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| !value.is_nan())
+                .ok_or_else(|| format!("ERR Expected a number in range, got `{}`", value))?,
+        };
+
+        Ok((value, inclusive))
+    }
+
+    /// `'{' tag ('|' tag)* '}'`. Tags may contain spaces and escaped
+    /// characters.
+    fn tags(&mut self, field: String) -> Result<Filter, String> {
+        self.expect('{')?;
+
+        let mut tags = vec![];
+        let mut tag = String::new();
+
+        loop {
+            match self.next() {
+                Some('\\') => tag.extend(self.next()),
+                Some('|') => tags.push(std::mem::take(&mut tag)),
+                Some('}') => break,
+                Some(c) => tag.push(c),
+                None => return Err("ERR Syntax error: unterminated tag list".into()),
+            }
+        }
+        tags.push(tag);
+
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+
+        if tags.is_empty() {
+            return Err("ERR Syntax error: empty tag list".into());
+        }
+
+        Ok(Filter::Tag { field, tags })
+    }
+
+    /// Read a `$name` parameter, if one starts here.
+    fn param(&mut self) -> Result<Option<String>, String> {
+        if !self.eat('$') {
+            return Ok(None);
+        }
+
+        let name = self.word();
+        lookup(self.params, &name).map(|value| Some(String::from_utf8_lossy(value).into_owned()))
+    }
+
+    /// Read characters up to whitespace or a special character. A backslash
+    /// escapes the next character.
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                self.pos += 1;
+                word.extend(self.next());
+            } else if c.is_whitespace() || c == '-' && word.is_empty() || SPECIAL.contains(c) {
+                break;
+            } else {
+                word.push(c);
+                self.pos += 1;
+            }
+        }
+
+        word
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += c.is_some() as usize;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!(
+                "ERR Syntax error at offset {}: expected `{}`",
+                self.pos, c
+            ))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+}
+
+/// Parse the `[KNN k @field $param [AS score]]` clause of a query.
+fn parse_knn(clause: &str, params: &[(String, Bytes)]) -> Result<Knn, String> {
+    let clause = clause
+        .trim()
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .ok_or("ERR Syntax error: vector query must be enclosed in `[...]`")?;
+
+    let tokens: Vec<&str> = clause.split_whitespace().collect();
+
+    let (k, field, param, score) = match tokens[..] {
+        [knn, k, field, param] if knn.eq_ignore_ascii_case("KNN") => (k, field, param, None),
+        [knn, k, field, param, alias, score]
+            if knn.eq_ignore_ascii_case("KNN") && alias.eq_ignore_ascii_case("AS") =>
+        {
+            (k, field, param, Some(score))
+        }
+        _ => return Err("ERR Syntax error in KNN clause".into()),
+    };
+
+    let k = match k.strip_prefix('$') {
+        Some(name) => String::from_utf8_lossy(lookup(params, name)?).into_owned(),
+        None => k.to_string(),
+    };
+    let k = k
+        .parse::<usize>()
+        .map_err(|_| "ERR KNN k must be a non-negative integer".to_string())?;
+
+    let field = field
+        .strip_prefix('@')
+        .ok_or("ERR KNN field must start with `@`")?
+        .to_string();
+
+    let param = param
+        .strip_prefix('$')
+        .ok_or("ERR KNN vector must be a `$` parameter")?;
+
+    let blob = lookup(params, param)?;
+    let vector = super::parse_vector(blob, blob.len() / 4)
+        .ok_or("ERR query vector blob size must be a multiple of 4")?;
+
+    let score = score
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("__{}_score", field));
+
+    Ok(Knn {
+        k,
+        field,
+        vector,
+        score,
+    })
+}
+
+fn lookup<'a>(params: &'a [(String, Bytes)], name: &str) -> Result<&'a Bytes, String> {
+    params
+        .iter()
+        .find(|(param, _)| param == name)
+        .map(|(_, value)| value)
+        .ok_or_else(|| format!("ERR No such parameter `{}`", name))
+}
+
+/// Combine `filters` with `combine`, unless there is a single filter.
+fn flatten(mut filters: Vec<Filter>, combine: fn(Vec<Filter>) -> Filter) -> Filter {
+    if filters.len() == 1 {
+        filters.pop().unwrap()
+    } else {
+        combine(filters)
+    }
+}
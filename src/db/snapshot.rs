@@ -0,0 +1,336 @@
+//! The snapshot file written by `SAVE` and `BGSAVE`, and loaded on startup.
+//!
+//! # Format
+//!
+//! ```text
+//! "MRSNAP" version:u8 count:u64
+//! entry*         key:bytes expires_at:u64 type:u8 value
+//! checksum:u64   `hash64` of everything before it
+//! ```
+//!
+//! Integers and floats are little endian, `bytes` are a `u64` length followed
+//! by the data. `expires_at` is an absolute Unix time in milliseconds, so that
+//! keys expire at the same time whenever the snapshot is loaded. Zero stands
+//! for no expiration. Each value type encodes its own `value`.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, Bytes};
+use tokio::time::{Duration, Instant};
+
+use super::hash::hash64;
+use super::{CountMinSketch, Cuckoo, ScalingBloom, ScanMap, TimeSeries, TopK, Value};
+
+const MAGIC: &[u8] = b"MRSNAP";
+
+const VERSION: u8 = 1;
+
+/// Type tags of the values.
+const STRING: u8 = 0;
+const BLOOM: u8 = 1;
+const CUCKOO: u8 = 2;
+const CMS: u8 = 3;
+const TOPK: u8 = 4;
+const TIME_SERIES: u8 = 5;
+const HASH: u8 = 6;
+
+/// The entries of the `Db` at some point in time.
+///
+/// `BGSAVE` and full synchronizations of replicas take a snapshot while
+/// holding the lock, which only clones the values, then encode it once the
+/// lock is released.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    /// Keys, values and expiration Unix times in milliseconds.
+    entries: Vec<(String, Value, Option<u64>)>,
+}
+
+/// Serializes values into a buffer.
+#[derive(Debug, Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+/// Deserializes values from a buffer. Every method returns `None` once the
+/// buffer is exhausted.
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    buf: Bytes,
+}
+
+impl Snapshot {
+    pub(super) fn new(entries: Vec<(String, Value, Option<Instant>)>) -> Snapshot {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value, expires_at)| (key, value, expires_at.map(unix_time_ms)))
+            .collect();
+
+        Snapshot { entries }
+    }
+
+    /// Returns the entries that have not expired yet, with the time left
+    /// before they do.
+    pub(super) fn into_entries(self) -> impl Iterator<Item = (String, Value, Option<Duration>)> {
+        let now = unix_time_ms(Instant::now());
+
+        self.entries
+            .into_iter()
+            .filter_map(move |(key, value, expires_at)| match expires_at {
+                Some(when) if when <= now => None,
+                Some(when) => Some((key, value, Some(Duration::from_millis(when - now)))),
+                None => Some((key, value, None)),
+            })
+    }
+
+    /// Encode the snapshot and write it to `path`.
+    ///
+    /// The snapshot is written to a temporary file first, then renamed, so
+    /// that `path` always holds a complete snapshot.
+    pub(super) fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&self.encode())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Encode the snapshot, checksum included.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        enc.buf.extend_from_slice(MAGIC);
+        enc.u8(VERSION);
+        enc.u64(self.entries.len() as u64);
+
+        for (key, value, expires_at) in &self.entries {
+            enc.str(key);
+            enc.u64(expires_at.unwrap_or(0));
+            encode_value(value, &mut enc);
+        }
+
+        let checksum = hash64(&enc.buf, 0);
+        enc.u64(checksum);
+        enc.buf
+    }
+
+    /// Read the snapshot at `path`. Returns `Ok(None)` if there is no file.
+    pub(super) fn read(path: &Path) -> Result<Option<Snapshot>, String> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("cannot read {}: {}", path.display(), err)),
+        };
+
+        Snapshot::decode(Bytes::from(data))
+            .map(Some)
+            .ok_or_else(|| format!("{} is not a valid snapshot", path.display()))
+    }
+
+    pub(super) fn decode(data: Bytes) -> Option<Snapshot> {
+        let (snapshot, len) = Snapshot::decode_prefix(&data)?;
+        (len == data.len()).then_some(snapshot)
+    }
+
+    /// Decode the snapshot at the start of `data`, which may be followed by
+    /// other data. Returns the snapshot and its length in bytes.
+    pub(super) fn decode_prefix(data: &Bytes) -> Option<(Snapshot, usize)> {
+        if !is_snapshot(data) {
+            return None;
+        }
+
+        let mut dec = Decoder::new(data.slice(MAGIC.len()..));
+        if dec.u8()? != VERSION {
+            return None;
+        }
+
+        let count = dec.u64()?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let key = dec.string()?;
+            let expires_at = match dec.u64()? {
+                0 => None,
+                when => Some(when),
+            };
+            let value = decode_value(&mut dec)?;
+            entries.push((key, value, expires_at));
+        }
+
+        let len = data.len() - dec.buf.remaining();
+        if hash64(&data[..len], 0) != dec.u64()? {
+            return None;
+        }
+
+        Some((Snapshot { entries }, len + 8))
+    }
+}
+
+/// Returns `true` if `data` starts like a snapshot.
+pub(super) fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encode `value` along with its type.
+pub(super) fn encode_value(value: &Value, enc: &mut Encoder) {
+    match value {
+        Value::String(value) => {
+            enc.u8(STRING);
+            enc.bytes(value);
+        }
+        Value::Bloom(bloom) => {
+            enc.u8(BLOOM);
+            bloom.encode(enc);
+        }
+        Value::Cuckoo(cuckoo) => {
+            enc.u8(CUCKOO);
+            cuckoo.encode(enc);
+        }
+        Value::Cms(cms) => {
+            enc.u8(CMS);
+            cms.encode(enc);
+        }
+        Value::TopK(topk) => {
+            enc.u8(TOPK);
+            topk.encode(enc);
+        }
+        Value::TimeSeries(series) => {
+            enc.u8(TIME_SERIES);
+            series.encode(enc);
+        }
+        Value::Hash(hash) => {
+            enc.u8(HASH);
+            enc.u64(hash.len() as u64);
+            for (field, value) in hash {
+                enc.bytes(field);
+                enc.bytes(value);
+            }
+        }
+    }
+}
+
+/// Decode a value encoded by `encode_value`.
+pub(super) fn decode_value(dec: &mut Decoder) -> Option<Value> {
+    let value = match dec.u8()? {
+        STRING => Value::String(dec.bytes()?),
+        BLOOM => Value::Bloom(ScalingBloom::decode(dec)?),
+        CUCKOO => Value::Cuckoo(Cuckoo::decode(dec)?),
+        CMS => Value::Cms(CountMinSketch::decode(dec)?),
+        TOPK => Value::TopK(TopK::decode(dec)?),
+        TIME_SERIES => Value::TimeSeries(TimeSeries::decode(dec)?),
+        HASH => {
+            let len = dec.u64()?;
+            let mut hash = ScanMap::new();
+            for _ in 0..len {
+                hash.insert(dec.bytes()?, dec.bytes()?);
+            }
+            Value::Hash(hash)
+        }
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// Returns the current Unix time in seconds.
+pub(super) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Convert `when` to a Unix time in milliseconds.
+pub(crate) fn unix_time_ms(when: Instant) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let now_instant = Instant::now();
+    let when = if when >= now_instant {
+        now + (when - now_instant)
+    } else {
+        now.saturating_sub(now_instant - when)
+    };
+
+    when.as_millis() as u64
+}
+
+impl Encoder {
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn f64(&mut self, value: f64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) {
+        self.u64(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub(crate) fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Decoder {
+    pub(crate) fn new(buf: Bytes) -> Decoder {
+        Decoder { buf }
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        (self.buf.remaining() >= 1).then(|| self.buf.get_u8())
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        (self.buf.remaining() >= 2).then(|| self.buf.get_u16_le())
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        (self.buf.remaining() >= 4).then(|| self.buf.get_u32_le())
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        (self.buf.remaining() >= 8).then(|| self.buf.get_u64_le())
+    }
+
+    pub(crate) fn f64(&mut self) -> Option<f64> {
+        (self.buf.remaining() >= 8).then(|| self.buf.get_f64_le())
+    }
+
+    /// Decode a `u64` length, checking it fits `usize`.
+    pub(crate) fn len(&mut self) -> Option<usize> {
+        self.u64()?.try_into().ok()
+    }
+
+    pub(crate) fn bytes(&mut self) -> Option<Bytes> {
+        let len = self.len()?;
+        (self.buf.remaining() >= len).then(|| self.buf.split_to(len))
+    }
+
+    pub(crate) fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    /// Returns `true` if the whole buffer was decoded.
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.buf.has_remaining()
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use super::snapshot::{Decoder, Encoder};
+
+/// How samples falling in the same time bucket are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+    First,
+    Last,
+    Range,
+}
+
+/// A time series: samples ordered by their millisecond timestamp.
+///
+/// Samples older than `retention` milliseconds, measured from the newest
+/// sample, are trimmed by the background task.
+#[derive(Debug, Clone)]
+pub(crate) struct TimeSeries {
+    /// Maximum age of samples in milliseconds. `0` keeps samples forever.
+    retention: u64,
+    /// Labels used by `TS.MRANGE` to select series.
+    labels: Vec<(String, String)>,
+    samples: BTreeMap<u64, f64>,
+    /// Compaction rules downsampling this series into other keys.
+    rules: Vec<CompactionRule>,
+}
+
+/// Downsamples every completed bucket of the source series into `dst`.
+#[derive(Debug, Clone)]
+pub(crate) struct CompactionRule {
+    pub(crate) dst: String,
+    pub(crate) aggregation: Aggregation,
+    /// Bucket duration in milliseconds.
+    pub(crate) bucket: u64,
+}
+
+/// Matches series by one of their labels. A label that is not set matches as
+/// the empty string, so `label=` selects series without the label and
+/// `label!=` selects series with it.
+#[derive(Debug, Clone)]
+pub enum LabelFilter {
+    /// The label has one of the values.
+    Eq(String, Vec<String>),
+    /// The label has none of the values.
+    NotEq(String, Vec<String>),
+}
+
+impl Aggregation {
+    /// Parse an aggregation name, ignoring case.
+    pub(crate) fn parse(name: &str) -> Option<Aggregation> {
+        use Aggregation::*;
+
+        Some(match &name.to_lowercase()[..] {
+            "avg" => Avg,
+            "sum" => Sum,
+            "min" => Min,
+            "max" => Max,
+            "count" => Count,
+            "first" => First,
+            "last" => Last,
+            "range" => Range,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        use Aggregation::*;
+
+        match self {
+            Avg => "avg",
+            Sum => "sum",
+            Min => "min",
+            Max => "max",
+            Count => "count",
+            First => "first",
+            Last => "last",
+            Range => "range",
+        }
+    }
+
+    /// Combine `values`, given in timestamp order. `values` must not be empty.
+    fn apply(&self, values: &[f64]) -> f64 {
+        use Aggregation::*;
+
+        let min = || values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = || values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+        match self {
+            Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Sum => values.iter().sum(),
+            Min => min(),
+            Max => max(),
+            Count => values.len() as f64,
+            First => values[0],
+            Last => values[values.len() - 1],
+            Range => max() - min(),
+        }
+    }
+}
+
+impl TimeSeries {
+    pub(crate) fn new(retention: u64, labels: Vec<(String, String)>) -> TimeSeries {
+        TimeSeries {
+            retention,
+            labels,
+            samples: BTreeMap::new(),
+            rules: vec![],
+        }
+    }
+
+    pub(crate) fn retention(&self) -> u64 {
+        self.retention
+    }
+
+    pub(crate) fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+
+    pub(crate) fn rules(&self) -> &[CompactionRule] {
+        &self.rules
+    }
+
+    pub(crate) fn rules_mut(&mut self) -> &mut Vec<CompactionRule> {
+        &mut self.rules
+    }
+
+    /// Add a sample.
+    ///
+    /// Returns the samples produced by compaction rules as `(dst, timestamp,
+    /// value)`. A rule emits a sample when `timestamp` starts a new bucket,
+    /// completing the bucket of the previous newest sample. A late sample
+    /// landing in a bucket completed already has the rule emit that bucket
+    /// again, for the destination to replace its sample with `upsert`.
+    pub(crate) fn add(
+        &mut self,
+        timestamp: u64,
+        value: f64,
+    ) -> Result<Vec<(String, u64, f64)>, &'static str> {
+        let newest = self.samples.keys().next_back().copied();
+
+        if let Some(newest) = newest {
+            if self.retention > 0 && timestamp < newest.saturating_sub(self.retention) {
+                return Err("ERR TSDB: Timestamp is older than retention");
+            }
+        }
+
+        if self.samples.contains_key(&timestamp) {
+            return Err("ERR TSDB: duplicate sample");
+        }
+
+        self.samples.insert(timestamp, value);
+
+        let newest = match newest {
+            Some(newest) => newest,
+            None => return Ok(vec![]),
+        };
+
+        let mut compacted = vec![];
+
+        for rule in &self.rules {
+            // The bucket of the previous newest sample is still open, the
+            // ones before it are complete.
+            let open = newest - newest % rule.bucket;
+            let bucket = match timestamp - timestamp % rule.bucket {
+                start if start > open => open,
+                start if start < open => start,
+                _ => continue,
+            };
+
+            let values: Vec<f64> = self
+                .samples
+                .range(bucket..bucket + rule.bucket)
+                .map(|(_, value)| *value)
+                .collect();
+
+            compacted.push((rule.dst.clone(), bucket, rule.aggregation.apply(&values)));
+        }
+
+        Ok(compacted)
+    }
+
+    /// Set the sample at `timestamp`, replacing the one there if any. This
+    /// is how the samples of compaction rules are added, as they are emitted
+    /// again when a late sample changes their bucket.
+    pub(crate) fn upsert(&mut self, timestamp: u64, value: f64) {
+        self.samples.insert(timestamp, value);
+    }
+
+    /// Returns the samples between `from` and `to`, inclusive.
+    ///
+    /// With an aggregation, samples are grouped in buckets of `bucket`
+    /// milliseconds and each bucket is reported once, at its start time.
+    pub(crate) fn range(
+        &self,
+        from: u64,
+        to: u64,
+        aggregation: Option<(Aggregation, u64)>,
+    ) -> Vec<(u64, f64)> {
+        if from > to {
+            return vec![];
+        }
+
+        let samples = self
+            .samples
+            .range((Bound::Included(from), Bound::Included(to)))
+            .map(|(timestamp, value)| (*timestamp, *value));
+
+        let (aggregation, bucket) = match aggregation {
+            Some(aggregation) => aggregation,
+            None => return samples.collect(),
+        };
+
+        let mut out = vec![];
+        let mut current: Option<(u64, Vec<f64>)> = None;
+
+        for (timestamp, value) in samples {
+            let start = timestamp - timestamp % bucket;
+
+            match &mut current {
+                Some((current_start, values)) if *current_start == start => values.push(value),
+                _ => {
+                    if let Some((start, values)) = current.take() {
+                        out.push((start, aggregation.apply(&values)));
+                    }
+                    current = Some((start, vec![value]));
+                }
+            }
+        }
+
+        if let Some((start, values)) = current {
+            out.push((start, aggregation.apply(&values)));
+        }
+
+        out
+    }
+
+    /// Returns `true` if the series matches all `filters`.
+    pub(crate) fn matches(&self, filters: &[LabelFilter]) -> bool {
+        let label = |name: &str| {
+            self.labels
+                .iter()
+                .find(|(label, _)| label == name)
+                .map(|(_, value)| &value[..])
+                .unwrap_or("")
+        };
+
+        filters.iter().all(|filter| match filter {
+            LabelFilter::Eq(name, values) => values.iter().any(|value| value == label(name)),
+            LabelFilter::NotEq(name, values) => values.iter().all(|value| value != label(name)),
+        })
+    }
+
+    /// Remove samples that fell out of the retention window.
+    pub(crate) fn trim(&mut self) {
+        if self.retention == 0 {
+            return;
+        }
+
+        if let Some(&newest) = self.samples.keys().next_back() {
+            let oldest = newest.saturating_sub(self.retention);
+            self.samples = self.samples.split_off(&oldest);
+        }
+    }
+
+    /// Encode the series for a snapshot.
+    pub(crate) fn encode(&self, enc: &mut Encoder) {
+        enc.u64(self.retention);
+
+        enc.u64(self.labels.len() as u64);
+        for (name, value) in &self.labels {
+            enc.str(name);
+            enc.str(value);
+        }
+
+        enc.u64(self.samples.len() as u64);
+        for (timestamp, value) in &self.samples {
+            enc.u64(*timestamp);
+            enc.f64(*value);
+        }
+
+        enc.u64(self.rules.len() as u64);
+        for rule in &self.rules {
+            enc.str(&rule.dst);
+            enc.str(rule.aggregation.name());
+            enc.u64(rule.bucket);
+        }
+    }
+
+    /// Decode a series encoded by `encode`.
+    pub(crate) fn decode(dec: &mut Decoder) -> Option<TimeSeries> {
+        let retention = dec.u64()?;
+
+        let mut labels = vec![];
+        for _ in 0..dec.u64()? {
+            labels.push((dec.string()?, dec.string()?));
+        }
+
+        let mut samples = BTreeMap::new();
+        for _ in 0..dec.u64()? {
+            samples.insert(dec.u64()?, dec.f64()?);
+        }
+
+        let mut rules = vec![];
+        for _ in 0..dec.u64()? {
+            rules.push(CompactionRule {
+                dst: dec.string()?,
+                aggregation: Aggregation::parse(&dec.string()?)?,
+                bucket: dec.u64()?,
+            });
+        }
+
+        Some(TimeSeries {
+            retention,
+            labels,
+            samples,
+            rules,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series_with_rule() -> TimeSeries {
+        let mut series = TimeSeries::new(0, vec![]);
+        series.rules_mut().push(CompactionRule {
+            dst: "dst".to_string(),
+            aggregation: Aggregation::Sum,
+            bucket: 10,
+        });
+        series
+    }
+
+    #[test]
+    fn a_new_bucket_completes_the_previous_one() {
+        let mut series = series_with_rule();
+
+        assert_eq!(series.add(1, 1.0).unwrap(), []);
+        assert_eq!(series.add(5, 2.0).unwrap(), []);
+        assert_eq!(series.add(23, 4.0).unwrap(), [("dst".to_string(), 0, 3.0)]);
+        assert_eq!(series.add(25, 8.0).unwrap(), []);
+    }
+
+    #[test]
+    fn late_samples_emit_their_completed_bucket_again() {
+        let mut series = series_with_rule();
+        series.add(1, 1.0).unwrap();
+        series.add(23, 4.0).unwrap();
+
+        // In a completed bucket.
+        assert_eq!(series.add(2, 2.0).unwrap(), [("dst".to_string(), 0, 3.0)]);
+        assert_eq!(series.add(12, 5.0).unwrap(), [("dst".to_string(), 10, 5.0)]);
+        // In the open bucket.
+        assert_eq!(series.add(21, 8.0).unwrap(), []);
+
+        assert_eq!(series.add(2, 1.0), Err("ERR TSDB: duplicate sample"));
+    }
+
+    #[test]
+    fn upsert_replaces_samples() {
+        let mut series = TimeSeries::new(0, vec![]);
+        series.upsert(0, 3.0);
+        series.upsert(0, 5.0);
+        assert_eq!(series.range(0, 10, None), [(0, 5.0)]);
+    }
+}
@@ -0,0 +1,244 @@
+use bytes::Bytes;
+
+use super::hash::{hash64, next_random};
+use super::snapshot::{Decoder, Encoder};
+
+/// Number of counters per row used when `TOPK.RESERVE` omits the dimensions.
+pub(crate) const DEFAULT_WIDTH: usize = 8;
+
+/// Number of rows used when `TOPK.RESERVE` omits the dimensions.
+pub(crate) const DEFAULT_DEPTH: usize = 7;
+
+/// Decay used when `TOPK.RESERVE` omits the dimensions.
+pub(crate) const DEFAULT_DECAY: f64 = 0.9;
+
+/// Largest `k`, and largest number of buckets, of a structure. Larger ones are
+/// refused rather than failing to allocate.
+pub(crate) const MAX_SIZE: u64 = 32 << 20;
+
+/// Tracks the `k` most frequent items of a stream using the HeavyKeeper
+/// algorithm.
+///
+/// Counts are kept in a `depth` x `width` matrix of buckets. Each bucket holds
+/// the fingerprint of the item currently owning it and a count. An item
+/// colliding with another owner decays that owner's count with a probability
+/// that shrinks exponentially as the count grows, so heavy hitters keep their
+/// buckets while the long tail fights over the rest.
+///
+/// The current top `k` items and their estimated counts are kept on the side.
+#[derive(Debug, Clone)]
+pub(crate) struct TopK {
+    k: usize,
+    width: usize,
+    depth: usize,
+    decay: f64,
+    /// Row major `depth` x `width` buckets.
+    buckets: Vec<Bucket>,
+    /// The tracked items with their estimated counts. `k` is small, so a `Vec`
+    /// scanned linearly is simpler and about as fast as a heap.
+    heap: Vec<(Bytes, u64)>,
+    /// State of the xorshift generator used for decay decisions.
+    rng: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    fingerprint: u32,
+    count: u64,
+}
+
+impl TopK {
+    pub(crate) fn new(k: usize, width: usize, depth: usize, decay: f64) -> TopK {
+        let width = width.max(1);
+        let depth = depth.max(1);
+
+        TopK {
+            k: k.max(1),
+            width,
+            depth,
+            decay,
+            buckets: vec![Bucket::default(); width * depth],
+            heap: Vec::new(),
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Returns `true` if a structure tracking `k` items in `width` x `depth`
+    /// buckets is within `MAX_SIZE`.
+    pub(crate) fn fits(k: u64, width: u64, depth: u64) -> bool {
+        k <= MAX_SIZE
+            && width
+                .checked_mul(depth)
+                .is_some_and(|buckets| buckets <= MAX_SIZE)
+    }
+
+    /// Count one occurrence of `item`.
+    ///
+    /// Returns the item that was expelled from the top `k` list to make room
+    /// for `item`, if any.
+    pub(crate) fn add(&mut self, item: &Bytes) -> Option<Bytes> {
+        let fingerprint = hash64(item, u64::MAX) as u32;
+        let mut estimate = 0;
+
+        for row in 0..self.depth {
+            let i = row * self.width + (hash64(item, row as u64) % self.width as u64) as usize;
+            let bucket = &mut self.buckets[i];
+
+            if bucket.count == 0 {
+                bucket.fingerprint = fingerprint;
+                bucket.count = 1;
+            } else if bucket.fingerprint == fingerprint {
+                bucket.count += 1;
+            } else {
+                let chance = self.decay.powf(bucket.count as f64);
+                let roll = next_random(&mut self.rng) as f64 / u64::MAX as f64;
+
+                if roll < chance {
+                    bucket.count -= 1;
+                    if bucket.count == 0 {
+                        bucket.fingerprint = fingerprint;
+                        bucket.count = 1;
+                    }
+                }
+            }
+
+            if bucket.fingerprint == fingerprint {
+                estimate = estimate.max(bucket.count);
+            }
+        }
+
+        if let Some(entry) = self.heap.iter_mut().find(|(tracked, _)| tracked == item) {
+            entry.1 = estimate;
+            return None;
+        }
+
+        if self.heap.len() < self.k {
+            self.heap.push((item.clone(), estimate));
+            return None;
+        }
+
+        // The list is full. `item` replaces the least frequent tracked item
+        // if it is now estimated to be more frequent.
+        let (min, count) = self
+            .heap
+            .iter()
+            .map(|(_, count)| *count)
+            .enumerate()
+            .min_by_key(|(_, count)| *count)?;
+
+        if count >= estimate {
+            return None;
+        }
+
+        Some(std::mem::replace(&mut self.heap[min], (item.clone(), estimate)).0)
+    }
+
+    /// Returns `true` if `item` is currently in the top `k` list.
+    pub(crate) fn contains(&self, item: &[u8]) -> bool {
+        self.heap.iter().any(|(tracked, _)| tracked == item)
+    }
+
+    /// Returns the top `k` list, most frequent item first.
+    pub(crate) fn list(&self) -> Vec<(Bytes, u64)> {
+        let mut list = self.heap.clone();
+        list.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        list
+    }
+
+    /// Encode the structure for a snapshot.
+    pub(crate) fn encode(&self, enc: &mut Encoder) {
+        enc.u64(self.k as u64);
+        enc.u64(self.width as u64);
+        enc.u64(self.depth as u64);
+        enc.f64(self.decay);
+        enc.u64(self.rng);
+
+        for bucket in &self.buckets {
+            enc.u32(bucket.fingerprint);
+            enc.u64(bucket.count);
+        }
+
+        enc.u64(self.heap.len() as u64);
+        for (item, count) in &self.heap {
+            enc.bytes(item);
+            enc.u64(*count);
+        }
+    }
+
+    /// Decode a structure encoded by `encode`.
+    pub(crate) fn decode(dec: &mut Decoder) -> Option<TopK> {
+        let k = dec.len()?;
+        let width = dec.len()?;
+        let depth = dec.len()?;
+        let decay = dec.f64()?;
+        let rng = dec.u64()?;
+        if k == 0 || width == 0 || depth == 0 || rng == 0 {
+            return None;
+        }
+
+        let mut buckets = vec![];
+        for _ in 0..width.checked_mul(depth)? {
+            let fingerprint = dec.u32()?;
+            let count = dec.u64()?;
+            buckets.push(Bucket { fingerprint, count });
+        }
+
+        let mut heap = vec![];
+        for _ in 0..dec.u64()? {
+            heap.push((dec.bytes()?, dec.u64()?));
+        }
+        if heap.len() > k {
+            return None;
+        }
+
+        Some(TopK {
+            k,
+            width,
+            depth,
+            decay,
+            buckets,
+            heap,
+            rng,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_heavy_hitters() {
+        let mut topk = TopK::new(3, DEFAULT_WIDTH * 10, DEFAULT_DEPTH, DEFAULT_DECAY);
+
+        // Three heavy items, interleaved with a long tail of items seen once.
+        for i in 0..3000 {
+            topk.add(&Bytes::from(format!("heavy:{}", i % 3)));
+            topk.add(&Bytes::from(format!("tail:{}", i)));
+        }
+
+        let mut list: Vec<_> = topk.list().into_iter().map(|(item, _)| item).collect();
+        list.sort();
+        assert_eq!(list, ["heavy:0", "heavy:1", "heavy:2"]);
+        assert!(!topk.contains(b"tail:0"));
+    }
+
+    #[test]
+    fn oversized_structures_do_not_fit() {
+        assert!(TopK::fits(10, DEFAULT_WIDTH as u64, DEFAULT_DEPTH as u64));
+        assert!(TopK::fits(MAX_SIZE, MAX_SIZE, 1));
+        assert!(!TopK::fits(3_000_000_000_000, 8, 7));
+        assert!(!TopK::fits(10, MAX_SIZE, 2));
+        assert!(!TopK::fits(10, u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn reports_the_expelled_item() {
+        let mut topk = TopK::new(1, DEFAULT_WIDTH, DEFAULT_DEPTH, DEFAULT_DECAY);
+
+        assert_eq!(topk.add(&Bytes::from("a")), None);
+        assert_eq!(topk.add(&Bytes::from("b")), None);
+        assert_eq!(topk.add(&Bytes::from("b")), Some(Bytes::from("a")));
+        assert_eq!(topk.list(), [(Bytes::from("b"), 2)]);
+    }
+}
@@ -159,7 +159,39 @@ impl Frame {
         }
     }
 
-    pub(crate) fn to_error(&self) -> crate::Error {
+    /// Append the RESP encoding of the frame to `dst`, as
+    /// `Connection::write_frame` sends it.
+    pub(crate) fn encode(&self, dst: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(val) => {
+                dst.push(b'+');
+                dst.extend_from_slice(val.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Error(val) => {
+                dst.push(b'-');
+                dst.extend_from_slice(val.as_bytes());
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Int(val) => {
+                dst.extend_from_slice(format!(":{}\r\n", val).as_bytes());
+            }
+            Frame::Null => dst.extend_from_slice(b"$-1\r\n"),
+            Frame::Bulk(val) => {
+                dst.extend_from_slice(format!("${}\r\n", val.len()).as_bytes());
+                dst.extend_from_slice(val);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Frame::Array(val) => {
+                dst.extend_from_slice(format!("*{}\r\n", val.len()).as_bytes());
+                for entry in val {
+                    entry.encode(dst);
+                }
+            }
+        }
+    }
+
+    pub fn to_error(&self) -> crate::Error {
         format!("unexpected frame: {}", self).into()
     }
 }
@@ -239,11 +271,11 @@ fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     // underlying value length
     let end = src.get_ref().len() - 1;
 
-    for i in start..=end {
+    for i in start..end {
         if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
             src.set_position((i + 2) as u64);
+            return Ok(&src.get_ref()[start..i]);
         }
-        return Ok(&src.get_ref()[start..i]);
     }
     Err(Error::InComplete)
 }
@@ -6,20 +6,30 @@ pub use cmd::Command;
 pub mod frame;
 pub use frame::Frame;
 
+pub mod rdb;
+
+mod cluster;
+
 mod connnection;
 pub use connnection::Connection;
 
 mod db;
 use db::Db;
 use db::DbDropGuard;
+use db::LockedDb;
+use db::WatchedKeys;
 
 mod parse;
 use parse::{Parse, ParseError};
 
+mod replica;
+
 mod shutdown;
 use shutdown::Shutdown;
 
-mod server;
+pub mod sentinel;
+
+pub mod server;
 /// Default port that a redis server listens on.
 ///
 /// Used if no port is specified.
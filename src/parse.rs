@@ -113,6 +113,28 @@ impl Parse {
         }
     }
 
+    /// Return the next entry as a floating point number.
+    ///
+    /// `Int` frames are converted. `Simple` and `Bulk` frames are parsed.
+    ///
+    /// If the next entry cannot be represented as a float, then an error is
+    /// returned.
+    pub(crate) fn next_float(&mut self) -> Result<f64, ParseError> {
+        const MSG: &str = "protocol error; invalid float";
+
+        match self.next()? {
+            Frame::Int(i) => Ok(i as f64),
+            Frame::Simple(s) => s.parse().map_err(|_| MSG.into()),
+            Frame::Bulk(data) => str::from_utf8(&data)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| MSG.into()),
+            frame => {
+                Err(format!("protocol error; expected float frame but got {:?}", frame).into())
+            }
+        }
+    }
+
     pub(crate) fn finish(&mut self) -> Result<(), ParseError> {
         if self.parts.next().is_none() {
             return Ok(());
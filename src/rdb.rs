@@ -0,0 +1,90 @@
+//! Redis RDB files, to migrate data from and to stock Redis servers.
+//!
+//! `read` decodes the files written by Redis 5 to 7.2, RDB versions 9 to 11,
+//! including the older encodings they may still contain. `write` encodes
+//! entries with the plain encodings every Redis since 5 loads, as version 9.
+//!
+//! mini-redis only holds strings and hashes among the Redis types. The other
+//! types are decoded, for completeness, but `Entry::into_command` cannot turn
+//! them into commands.
+
+mod crc64;
+mod lzf;
+mod read;
+mod write;
+
+pub use read::read;
+pub use write::write;
+
+use bytes::Bytes;
+
+use crate::cmd::{Hset, Restore, Set};
+use crate::db::dump_hash;
+use crate::Frame;
+
+/// A key and its value, as stored in an RDB file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The logical database the key belongs to, selected with `SELECT`.
+    pub db: u64,
+    pub key: Bytes,
+    pub value: Value,
+    /// Unix time in milliseconds at which the key expires, if any.
+    pub expires_at: Option<u64>,
+}
+
+/// The value of a key in an RDB file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(Vec<Bytes>),
+    Set(Vec<Bytes>),
+    Hash(Vec<(Bytes, Bytes)>),
+    /// Members and their scores.
+    ZSet(Vec<(Bytes, f64)>),
+}
+
+impl Entry {
+    /// Returns the command recreating the entry in mini-redis, with its
+    /// expiration.
+    ///
+    /// Hashes are recreated with `HSET`, or with `RESTORE` when they expire,
+    /// which `HSET` cannot set. Returns `None` for the types mini-redis does
+    /// not support and for keys that are not valid UTF-8.
+    pub fn into_command(self) -> Option<Frame> {
+        let key = String::from_utf8(self.key.to_vec()).ok()?;
+
+        match self.value {
+            Value::String(value) => {
+                let mut cmd = Set::new(key, value, None);
+                if let Some(when) = self.expires_at {
+                    cmd.set_expire_at(when);
+                }
+                Some(cmd.into_frame())
+            }
+            Value::Hash(fields) if fields.is_empty() => None,
+            Value::Hash(fields) => match self.expires_at {
+                // A zero time to live keeps the expiration of the payload.
+                Some(when) => {
+                    let payload = dump_hash(fields, when);
+                    Some(Restore::new(key, 0, payload, true).into_frame())
+                }
+                None => Some(Hset::new(key, fields).into_frame()),
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Value {
+    /// Returns the name of the type, as reported by `TYPE` in Redis.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::Hash(_) => "hash",
+            Value::ZSet(_) => "zset",
+        }
+    }
+}
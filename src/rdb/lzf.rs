@@ -0,0 +1,74 @@
+//! Decompression of the LZF compressed strings of RDB files.
+
+/// Decompress `src` into a buffer of `len` bytes. Returns `None` if `src` is
+/// not valid LZF data of that length.
+pub(super) fn decompress(src: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < src.len() {
+        let ctrl = src[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            // A run of `ctrl + 1` literal bytes.
+            let run = src.get(i..i + ctrl + 1)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // A back reference: `len + 2` bytes copied from `offset + 1`
+            // bytes back. The copy may overlap the bytes it produces.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *src.get(i)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *src.get(i)? as usize + 1;
+            i += 1;
+
+            let start = out.len().checked_sub(offset)?;
+            for j in 0..run + 2 {
+                out.push(out[start + j]);
+            }
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(decompress(b"\x02abc", 3).unwrap(), b"abc");
+        assert_eq!(decompress(b"\x01ab\x00c", 3).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn back_references() {
+        // "abcd", then 3 bytes from 4 bytes back.
+        assert_eq!(decompress(b"\x03abcd\x20\x03", 7).unwrap(), b"abcdabc");
+
+        // 9 bytes from 3 bytes back, the length being continued in the
+        // following byte. The copy overlaps the bytes it produces.
+        assert_eq!(
+            decompress(b"\x02abc\xe0\x00\x02", 12).unwrap(),
+            b"abcabcabcabc"
+        );
+        assert_eq!(decompress(b"\x00a\xe0\x05\x00", 15).unwrap(), [b'a'; 15]);
+    }
+
+    #[test]
+    fn invalid_data() {
+        // A literal run past the end.
+        assert_eq!(decompress(b"\x05abc", 6), None);
+        // A reference before the start.
+        assert_eq!(decompress(b"\x00a\x20\x01", 4), None);
+        // A reference missing its offset.
+        assert_eq!(decompress(b"\x00a\x20", 4), None);
+        // Data of another length.
+        assert_eq!(decompress(b"\x02abc", 4), None);
+    }
+}
@@ -0,0 +1,517 @@
+//! Decoding of RDB files.
+
+use bytes::Bytes;
+
+use super::crc64::crc64;
+use super::lzf;
+use super::{Entry, Value};
+
+/// Newest RDB version `read` decodes, written by Redis 7.2.
+const MAX_VERSION: u32 = 11;
+
+// Opcodes, found where a value type is expected.
+const OPCODE_FUNCTION2: u8 = 245;
+const OPCODE_FUNCTION_PRE_GA: u8 = 246;
+const OPCODE_MODULE_AUX: u8 = 247;
+const OPCODE_IDLE: u8 = 248;
+const OPCODE_FREQ: u8 = 249;
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+// Value types.
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Special string encodings, flagged by a length starting with `11`.
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+// Opcodes of the self-describing module values.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+// Quicklist node containers.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// Decode the RDB file `data` into its entries.
+///
+/// Returns `Err` for files that are not RDB files, are corrupted, or hold
+/// streams or module values, which cannot be decoded.
+pub fn read(data: &[u8]) -> crate::Result<Vec<Entry>> {
+    let mut reader = Reader { data, pos: 0 };
+
+    if reader.take(5)? != b"REDIS" {
+        return Err("not an RDB file".into());
+    }
+    let version = std::str::from_utf8(reader.take(4)?)
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or("invalid RDB version")?;
+    if version > MAX_VERSION {
+        return Err(format!("unsupported RDB version {}", version).into());
+    }
+
+    let mut entries = vec![];
+    let mut db = 0;
+    let mut expires_at = None;
+
+    loop {
+        match reader.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => db = reader.len()?,
+            OPCODE_RESIZEDB => {
+                reader.len()?;
+                reader.len()?;
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.array()?);
+                expires_at = Some(secs as u64 * 1000);
+            }
+            OPCODE_EXPIRETIME_MS => expires_at = Some(u64::from_le_bytes(reader.array()?)),
+            OPCODE_FREQ => {
+                reader.u8()?;
+            }
+            OPCODE_IDLE => {
+                reader.len()?;
+            }
+            OPCODE_MODULE_AUX => {
+                // The module id, then when the data was saved.
+                reader.len()?;
+                reader.len()?;
+                reader.len()?;
+                reader.skip_module_value()?;
+            }
+            OPCODE_FUNCTION2 => {
+                reader.string()?;
+            }
+            OPCODE_FUNCTION_PRE_GA => {
+                return Err(
+                    "functions saved by Redis 7.0 release candidates are not supported".into(),
+                )
+            }
+            ty => {
+                let key = reader.string()?;
+                let value = reader.value(ty)?;
+                entries.push(Entry {
+                    db,
+                    key,
+                    value,
+                    expires_at: expires_at.take(),
+                });
+            }
+        }
+    }
+
+    // Version 5 and later end with a checksum. Zero means it was disabled.
+    if version >= 5 {
+        let end = reader.pos;
+        let checksum = u64::from_le_bytes(reader.array()?);
+        if checksum != 0 && checksum != crc64(&data[..end]) {
+            return Err("RDB checksum mismatch".into());
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Reads the RDB encodings from a buffer.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> crate::Result<&'a [u8]> {
+        match self.data.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err("unexpected end of RDB data".into()),
+        }
+    }
+
+    fn array<const N: usize>(&mut self) -> crate::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> crate::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Decode a length, or the special encoding of a string, flagged by
+    /// `true`.
+    fn len_or_encoding(&mut self) -> crate::Result<(u64, bool)> {
+        let first = self.u8()?;
+
+        let len = match first >> 6 {
+            0 => (first & 0x3f) as u64,
+            1 => ((first & 0x3f) as u64) << 8 | self.u8()? as u64,
+            2 if first == 0x80 => u32::from_be_bytes(self.array()?) as u64,
+            2 if first == 0x81 => u64::from_be_bytes(self.array()?),
+            2 => return Err("invalid RDB length".into()),
+            _ => return Ok(((first & 0x3f) as u64, true)),
+        };
+
+        Ok((len, false))
+    }
+
+    fn len(&mut self) -> crate::Result<u64> {
+        match self.len_or_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err("invalid RDB length".into()),
+        }
+    }
+
+    /// Decode a length used to size a buffer.
+    fn usize(&mut self) -> crate::Result<usize> {
+        Ok(self.len()?.try_into()?)
+    }
+
+    fn string(&mut self) -> crate::Result<Bytes> {
+        let (len, encoded) = self.len_or_encoding()?;
+        if !encoded {
+            let len = len.try_into()?;
+            return Ok(Bytes::copy_from_slice(self.take(len)?));
+        }
+
+        let int = match len as u8 {
+            ENC_INT8 => self.u8()? as i8 as i64,
+            ENC_INT16 => i16::from_le_bytes(self.array()?) as i64,
+            ENC_INT32 => i32::from_le_bytes(self.array()?) as i64,
+            ENC_LZF => {
+                let compressed = self.usize()?;
+                let len = self.usize()?;
+                let data = self.take(compressed)?;
+                return lzf::decompress(data, len)
+                    .map(Bytes::from)
+                    .ok_or_else(|| "invalid LZF compressed string".into());
+            }
+            _ => return Err("invalid RDB string encoding".into()),
+        };
+
+        Ok(Bytes::from(int.to_string()))
+    }
+
+    /// Decode a score of the original sorted set encoding, stored as text.
+    fn text_double(&mut self) -> crate::Result<f64> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_double(self.take(len as usize)?),
+        }
+    }
+
+    /// Decode a list of `len` strings, read first.
+    fn strings(&mut self) -> crate::Result<Vec<Bytes>> {
+        let len = self.len()?;
+        (0..len).map(|_| self.string()).collect()
+    }
+
+    fn value(&mut self, ty: u8) -> crate::Result<Value> {
+        let value = match ty {
+            TYPE_STRING => Value::String(self.string()?),
+            TYPE_LIST => Value::List(self.strings()?),
+            TYPE_SET => Value::Set(self.strings()?),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.len()?;
+                let mut members = vec![];
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = if ty == TYPE_ZSET {
+                        self.text_double()?
+                    } else {
+                        f64::from_le_bytes(self.array()?)
+                    };
+                    members.push((member, score));
+                }
+                Value::ZSet(members)
+            }
+            TYPE_HASH => {
+                let len = self.len()?;
+                let mut fields = vec![];
+                for _ in 0..len {
+                    fields.push((self.string()?, self.string()?));
+                }
+                Value::Hash(fields)
+            }
+            TYPE_HASH_ZIPMAP => Value::Hash(zipmap(&self.string()?)?),
+            TYPE_LIST_ZIPLIST => Value::List(ziplist(&self.string()?)?),
+            TYPE_SET_INTSET => Value::Set(intset(&self.string()?)?),
+            TYPE_SET_LISTPACK => Value::Set(listpack(&self.string()?)?),
+            TYPE_ZSET_ZIPLIST => Value::ZSet(scores(ziplist(&self.string()?)?)?),
+            TYPE_ZSET_LISTPACK => Value::ZSet(scores(listpack(&self.string()?)?)?),
+            TYPE_HASH_ZIPLIST => Value::Hash(pairs(ziplist(&self.string()?)?)?),
+            TYPE_HASH_LISTPACK => Value::Hash(pairs(listpack(&self.string()?)?)?),
+            TYPE_LIST_QUICKLIST => {
+                let mut items = vec![];
+                for _ in 0..self.len()? {
+                    items.extend(ziplist(&self.string()?)?);
+                }
+                Value::List(items)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut items = vec![];
+                for _ in 0..self.len()? {
+                    let container = self.len()?;
+                    let node = self.string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => items.push(node),
+                        QUICKLIST_NODE_PACKED => items.extend(listpack(&node)?),
+                        _ => return Err("invalid quicklist node".into()),
+                    }
+                }
+                Value::List(items)
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                return Err("streams are not supported".into())
+            }
+            TYPE_MODULE_2 => return Err("module values are not supported".into()),
+            ty => return Err(format!("unknown RDB value type {}", ty).into()),
+        };
+
+        Ok(value)
+    }
+
+    /// Skip module data, which describes itself with opcodes.
+    fn skip_module_value(&mut self) -> crate::Result<()> {
+        loop {
+            match self.len()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.len()?;
+                }
+                MODULE_OPCODE_FLOAT => {
+                    self.take(4)?;
+                }
+                MODULE_OPCODE_DOUBLE => {
+                    self.take(8)?;
+                }
+                MODULE_OPCODE_STRING => {
+                    self.string()?;
+                }
+                _ => return Err("invalid module data".into()),
+            }
+        }
+    }
+}
+
+/// Decode the entries of a ziplist, the compact encoding of small lists,
+/// hashes and sorted sets up to Redis 6.
+fn ziplist(data: &[u8]) -> crate::Result<Vec<Bytes>> {
+    let mut reader = Reader { data, pos: 10 };
+    let mut entries = vec![];
+
+    loop {
+        // The length of the previous entry, used to iterate backwards.
+        match reader.u8()? {
+            0xff => return Ok(entries),
+            0xfe => {
+                reader.take(4)?;
+            }
+            _ => {}
+        }
+
+        let encoding = reader.u8()?;
+        let entry = match encoding >> 6 {
+            0 => string_entry(&mut reader, (encoding & 0x3f) as usize)?,
+            1 => {
+                let len = ((encoding & 0x3f) as usize) << 8 | reader.u8()? as usize;
+                string_entry(&mut reader, len)?
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.array()?) as usize;
+                string_entry(&mut reader, len)?
+            }
+            _ => {
+                let int = match encoding {
+                    0xc0 => i16::from_le_bytes(reader.array()?) as i64,
+                    0xd0 => i32::from_le_bytes(reader.array()?) as i64,
+                    0xe0 => i64::from_le_bytes(reader.array()?),
+                    0xf0 => {
+                        let [a, b, c] = reader.array()?;
+                        i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                    }
+                    0xfe => reader.u8()? as i8 as i64,
+                    0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                    _ => return Err("invalid ziplist entry".into()),
+                };
+                Bytes::from(int.to_string())
+            }
+        };
+
+        entries.push(entry);
+    }
+}
+
+/// Decode the entries of a listpack, which replaced ziplists in Redis 7.
+fn listpack(data: &[u8]) -> crate::Result<Vec<Bytes>> {
+    let mut reader = Reader { data, pos: 6 };
+    let mut entries = vec![];
+
+    loop {
+        let start = reader.pos;
+        let encoding = reader.u8()?;
+
+        let entry = if encoding == 0xff {
+            return Ok(entries);
+        } else if encoding & 0x80 == 0 {
+            Bytes::from((encoding & 0x7f).to_string())
+        } else if encoding & 0xc0 == 0x80 {
+            string_entry(&mut reader, (encoding & 0x3f) as usize)?
+        } else if encoding & 0xe0 == 0xc0 {
+            // A 13 bit signed integer.
+            let int = ((encoding & 0x1f) as i64) << 8 | reader.u8()? as i64;
+            Bytes::from((int << 51 >> 51).to_string())
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = ((encoding & 0x0f) as usize) << 8 | reader.u8()? as usize;
+            string_entry(&mut reader, len)?
+        } else {
+            match encoding {
+                0xf0 => {
+                    let len = u32::from_le_bytes(reader.array()?) as usize;
+                    string_entry(&mut reader, len)?
+                }
+                0xf1 => Bytes::from(i16::from_le_bytes(reader.array()?).to_string()),
+                0xf2 => {
+                    let [a, b, c] = reader.array()?;
+                    Bytes::from((i32::from_le_bytes([0, a, b, c]) >> 8).to_string())
+                }
+                0xf3 => Bytes::from(i32::from_le_bytes(reader.array()?).to_string()),
+                0xf4 => Bytes::from(i64::from_le_bytes(reader.array()?).to_string()),
+                _ => return Err("invalid listpack entry".into()),
+            }
+        };
+
+        // Skip the length of the entry, used to iterate backwards.
+        let len = reader.pos - start;
+        reader.take(match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        })?;
+
+        entries.push(entry);
+    }
+}
+
+fn string_entry(reader: &mut Reader, len: usize) -> crate::Result<Bytes> {
+    Ok(Bytes::copy_from_slice(reader.take(len)?))
+}
+
+/// Decode an intset, the encoding of small sets of integers.
+fn intset(data: &[u8]) -> crate::Result<Vec<Bytes>> {
+    let mut reader = Reader { data, pos: 0 };
+    let width = u32::from_le_bytes(reader.array()?);
+    let len = u32::from_le_bytes(reader.array()?);
+
+    (0..len)
+        .map(|_| {
+            let int = match width {
+                2 => i16::from_le_bytes(reader.array()?) as i64,
+                4 => i32::from_le_bytes(reader.array()?) as i64,
+                8 => i64::from_le_bytes(reader.array()?),
+                _ => return Err("invalid intset encoding".into()),
+            };
+            Ok(Bytes::from(int.to_string()))
+        })
+        .collect()
+}
+
+/// Decode a zipmap, the encoding of small hashes up to Redis 2.6.
+fn zipmap(data: &[u8]) -> crate::Result<Vec<(Bytes, Bytes)>> {
+    let mut reader = Reader { data, pos: 1 };
+    let mut fields = vec![];
+
+    loop {
+        let field = match zipmap_len(&mut reader)? {
+            Some(len) => string_entry(&mut reader, len)?,
+            None => return Ok(fields),
+        };
+        let value_len = zipmap_len(&mut reader)?.ok_or("invalid zipmap")?;
+        let free = reader.u8()? as usize;
+        let value = string_entry(&mut reader, value_len)?;
+        reader.take(free)?;
+
+        fields.push((field, value));
+    }
+}
+
+/// Decode the length of a zipmap string, or `None` at the end of the zipmap.
+fn zipmap_len(reader: &mut Reader) -> crate::Result<Option<usize>> {
+    match reader.u8()? {
+        0xff => Ok(None),
+        0xfe => Ok(Some(u32::from_le_bytes(reader.array()?) as usize)),
+        len => Ok(Some(len as usize)),
+    }
+}
+
+/// Group the entries of a hash encoded as a ziplist or listpack into pairs.
+fn pairs(entries: Vec<Bytes>) -> crate::Result<Vec<(Bytes, Bytes)>> {
+    if !entries.len().is_multiple_of(2) {
+        return Err("odd number of hash entries".into());
+    }
+
+    let mut entries = entries.into_iter();
+    let mut pairs = vec![];
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        pairs.push((field, value));
+    }
+    Ok(pairs)
+}
+
+/// Group the entries of a sorted set encoded as a ziplist or listpack into
+/// members and scores.
+fn scores(entries: Vec<Bytes>) -> crate::Result<Vec<(Bytes, f64)>> {
+    pairs(entries)?
+        .into_iter()
+        .map(|(member, score)| Ok((member, parse_double(&score)?)))
+        .collect()
+}
+
+fn parse_double(text: &[u8]) -> crate::Result<f64> {
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|text| match text {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            text => text.parse().ok(),
+        })
+        .ok_or_else(|| "invalid score".into())
+}
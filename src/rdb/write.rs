@@ -0,0 +1,128 @@
+//! Encoding of RDB files.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::crc64::crc64;
+use super::{Entry, Value};
+
+/// Version of the files written. Redis loads files of its own version and
+/// older ones, so this is loaded by Redis 5 and later.
+const VERSION: &[u8] = b"0009";
+
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+
+/// Encode `entries` as an RDB file.
+pub fn write(entries: &[Entry]) -> Vec<u8> {
+    let mut buf = b"REDIS".to_vec();
+    buf.extend_from_slice(VERSION);
+
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    aux(&mut buf, "redis-bits", "64");
+    aux(&mut buf, "ctime", &ctime.to_string());
+
+    // Keys are grouped by database.
+    let mut dbs: BTreeMap<u64, Vec<&Entry>> = BTreeMap::new();
+    for entry in entries {
+        dbs.entry(entry.db).or_default().push(entry);
+    }
+
+    for (db, entries) in dbs {
+        buf.push(OPCODE_SELECTDB);
+        len(&mut buf, db);
+
+        let expires = entries.iter().filter(|entry| entry.expires_at.is_some());
+        buf.push(OPCODE_RESIZEDB);
+        len(&mut buf, entries.len() as u64);
+        len(&mut buf, expires.count() as u64);
+
+        for entry in entries {
+            if let Some(when) = entry.expires_at {
+                buf.push(OPCODE_EXPIRETIME_MS);
+                buf.extend_from_slice(&when.to_le_bytes());
+            }
+
+            value(&mut buf, &entry.key, &entry.value);
+        }
+    }
+
+    buf.push(OPCODE_EOF);
+    let checksum = crc64(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn aux(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.push(OPCODE_AUX);
+    string(buf, key.as_bytes());
+    string(buf, value.as_bytes());
+}
+
+/// Encode the type of `value`, `key`, then `value`.
+fn value(buf: &mut Vec<u8>, key: &[u8], value: &Value) {
+    let ty = match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::Hash(_) => TYPE_HASH,
+        Value::ZSet(_) => TYPE_ZSET_2,
+    };
+    buf.push(ty);
+    string(buf, key);
+
+    match value {
+        Value::String(value) => string(buf, value),
+        Value::List(items) | Value::Set(items) => {
+            len(buf, items.len() as u64);
+            for item in items {
+                string(buf, item);
+            }
+        }
+        Value::Hash(fields) => {
+            len(buf, fields.len() as u64);
+            for (field, value) in fields {
+                string(buf, field);
+                string(buf, value);
+            }
+        }
+        Value::ZSet(members) => {
+            len(buf, members.len() as u64);
+            for (member, score) in members {
+                string(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn len(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&(len as u16 | 0x4000).to_be_bytes());
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn string(buf: &mut Vec<u8>, value: &[u8]) {
+    len(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
@@ -0,0 +1,197 @@
+//! The replica side of replication: the link to the primary.
+//!
+//! The replica announces its port, then asks to continue from its own
+//! replication ID and offset with `PSYNC`. It either continues, or loads the
+//! snapshot the primary sends, then applies the commands the primary
+//! propagates. It acknowledges its offset every second, and whenever the
+//! primary asks. The link is established again whenever it breaks, or when
+//! the primary sends nothing, not even its periodic pings, for
+//! `repl-timeout`.
+
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::cmd::{Psync, Replconf};
+use crate::{Command, Connection, Db, Frame};
+
+/// How long the replica waits before connecting to its primary again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the replica acknowledges its offset.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Replicate the primary at `host` and `port`, until the task is aborted.
+pub(crate) async fn run(db: Db, host: String, port: u16) {
+    loop {
+        if let Err(err) = sync(&db, &host, port).await {
+            warn!(cause = %err, %host, port, "replication link down");
+        }
+
+        db.lock().await.set_link_up(false);
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Synchronize with the primary, then apply the commands it propagates until
+/// the connection breaks.
+async fn sync(db: &Db, host: &str, port: u16) -> crate::Result<()> {
+    let timeout = db.lock().await.repl_timeout();
+
+    let socket = time::timeout(timeout, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| "timeout connecting to the primary")??;
+    let mut connection = Connection::new(socket);
+
+    let (replid, offset, listening_port) = db.lock().await.replication_position();
+
+    let replconf = Replconf::new(vec![(
+        "listening-port".to_string(),
+        listening_port.to_string(),
+    )]);
+    request(&mut connection, replconf.into_frame(), timeout).await?;
+
+    let psync = Psync::new(replid, offset).into_frame();
+    let resp = request(&mut connection, psync, timeout).await?;
+    let resp = match resp {
+        Frame::Simple(resp) => resp,
+        frame => return Err(format!("unexpected reply to PSYNC: {:?}", frame).into()),
+    };
+
+    let mut words = resp.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset.parse()?;
+            let snapshot = match read(&mut connection, timeout).await? {
+                Frame::Bulk(snapshot) => snapshot,
+                _ => return Err("expected the snapshot of the primary".into()),
+            };
+
+            let count = db
+                .lock()
+                .await
+                .full_sync(snapshot, replid.to_string(), offset)?;
+            info!(%host, port, count, "synchronized with the primary");
+        }
+        (Some("CONTINUE"), Some(replid), None) => {
+            db.lock().await.continue_sync(replid.to_string());
+            info!(%host, port, "continued replication");
+        }
+        _ => return Err(format!("unexpected reply to PSYNC: {}", resp).into()),
+    }
+
+    db.lock().await.set_link_up(true);
+
+    let mut acks = time::interval(ACK_PERIOD);
+    acks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut deadline = Instant::now() + timeout;
+    // The commands of the transaction being received, and their bytes.
+    let mut transaction: Option<(Vec<Command>, Vec<u8>)> = None;
+
+    loop {
+        let frame = select! {
+            frame = connection.read_frame() => match frame? {
+                Some(frame) => frame,
+                None => return Err("connection closed by the primary".into()),
+            },
+            _ = acks.tick() => {
+                ack(db, &mut connection).await?;
+                continue;
+            }
+            _ = time::sleep_until(deadline) => {
+                return Err("timeout reading from the primary".into());
+            }
+        };
+        deadline = Instant::now() + timeout;
+
+        // The offset counts the bytes of the commands as encoded by the
+        // primary, which sends arrays of bulk strings.
+        let mut buf = vec![];
+        frame.encode(&mut buf);
+
+        // The commands of a transaction are applied once its `EXEC` is
+        // received, all at once. Those of a transaction the link breaks in
+        // the middle of are dropped, and received again after reconnecting
+        // as they are not counted in the offset.
+        let command = match (Command::from_frame(frame)?, &mut transaction) {
+            (Command::Multi(_), _) => {
+                transaction = Some((vec![], buf));
+                continue;
+            }
+            (Command::Exec(_), Some((commands, bytes))) => {
+                bytes.extend_from_slice(&buf);
+
+                let mut db = db.lock().await;
+                db.begin_batch();
+                let res = std::mem::take(commands)
+                    .into_iter()
+                    .try_for_each(|command| command.replicate(&mut db).map(drop));
+                db.end_batch();
+                res?;
+                db.replication_feed(bytes);
+
+                transaction = None;
+                continue;
+            }
+            (command, Some((commands, bytes))) => {
+                commands.push(command);
+                bytes.extend_from_slice(&buf);
+                continue;
+            }
+            (command, None) => command,
+        };
+
+        let getack = {
+            let mut db = db.lock().await;
+            match command {
+                // The request is part of the history, and acknowledged once
+                // counted in the offset.
+                Command::Replconf(cmd) if cmd.is_getack() => {
+                    db.replication_feed(&buf);
+                    true
+                }
+                command => {
+                    command.replicate(&mut db)?;
+                    db.replication_feed(&buf);
+                    false
+                }
+            }
+        };
+
+        if getack {
+            ack(db, &mut connection).await?;
+        }
+    }
+}
+
+/// Acknowledge the offset processed to the primary.
+async fn ack(db: &Db, connection: &mut Connection) -> crate::Result<()> {
+    let (_, offset, _) = db.lock().await.replication_position();
+    connection
+        .write_frame(&Replconf::ack(offset).into_frame())
+        .await?;
+    Ok(())
+}
+
+/// Send `frame` and return the reply, or `Err` if it is an error.
+async fn request(
+    connection: &mut Connection,
+    frame: Frame,
+    timeout: Duration,
+) -> crate::Result<Frame> {
+    connection.write_frame(&frame).await?;
+
+    match read(connection, timeout).await? {
+        Frame::Error(err) => Err(err.into()),
+        frame => Ok(frame),
+    }
+}
+
+/// Read a frame from the primary, waiting at most `timeout`.
+async fn read(connection: &mut Connection, timeout: Duration) -> crate::Result<Frame> {
+    match time::timeout(timeout, connection.read_frame()).await {
+        Ok(frame) => frame?.ok_or_else(|| "connection closed by the primary".into()),
+        Err(_) => Err("timeout reading from the primary".into()),
+    }
+}
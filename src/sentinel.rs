@@ -0,0 +1,363 @@
+//! Sentinel mode: monitor primaries and their replicas, and fail over to a
+//! replica when a primary is down.
+//!
+//! A sentinel PINGs every instance of the primaries it monitors, and learns
+//! about the replicas from `INFO replication`. The sentinels monitoring the
+//! same primary discover each other through hello messages, published on the
+//! `__sentinel__:hello` channel of every instance.
+//!
+//! A primary that does not reply for `down_after` is subjectively down for a
+//! sentinel. It is objectively down once `quorum` sentinels agree, which the
+//! sentinel asks with `SENTINEL is-master-down-by-addr`. The sentinels then
+//! elect the leader of the failover, by a majority of votes in a new epoch.
+//! The leader promotes the replica with the largest replication offset and
+//! points the other replicas to it. The other sentinels adopt the new
+//! configuration from its hello messages, as the one of the largest epoch.
+//!
+//! Clients ask the address of the current primary with `SENTINEL
+//! get-master-addr-by-name`.
+
+mod cmd;
+
+mod failover;
+
+mod instance;
+
+mod master;
+use master::{Addr, Master};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::{Connection, Frame};
+
+/// Default port that a sentinel listens on.
+pub const DEFAULT_PORT: u16 = 26379;
+
+/// How often the state of the primaries is checked.
+const CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// How often the other sentinels are asked whether a primary they agree is
+/// down still is.
+const ASK_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long a sentinel waits at most, once a primary is objectively down,
+/// before starting the failover. The actual delay is random, so that the
+/// sentinels do not all start at once and split the votes.
+const MAX_FAILOVER_DELAY: Duration = Duration::from_secs(1);
+
+/// A primary monitored by a sentinel.
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    /// The name clients know the primary by.
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    /// Number of sentinels that must agree the primary is down to fail over.
+    pub quorum: usize,
+    /// How long the primary may not reply before it is considered down.
+    pub down_after: Duration,
+    /// How long a failover may take. The sentinel tries again after twice
+    /// this time.
+    pub failover_timeout: Duration,
+}
+
+impl Monitor {
+    /// Create a new `Monitor` of the primary `name` at `host` and `port`,
+    /// with the default timeouts of Redis.
+    pub fn new(name: impl ToString, host: impl ToString, port: u16, quorum: usize) -> Monitor {
+        Monitor {
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+            quorum,
+            down_after: Duration::from_secs(30),
+            failover_timeout: Duration::from_secs(180),
+        }
+    }
+}
+
+/// Handle to the state of the sentinel, shared by its tasks. Cloning it is
+/// shallow.
+#[derive(Debug, Clone)]
+struct Sentinel {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Identifies the sentinel to the other ones, 40 hexadecimal
+    /// characters.
+    runid: String,
+    /// Port the sentinel listens on, announced in hello messages.
+    port: u16,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The largest epoch seen. Each failover starts a new one.
+    current_epoch: u64,
+    masters: HashMap<String, Master>,
+    /// The task monitoring each instance, by primary and address.
+    tasks: HashMap<(String, Addr), JoinHandle<()>>,
+}
+
+/// Run a sentinel monitoring `monitors`, serving clients on `listener`
+/// until `shutdown` completes.
+pub async fn run(listener: TcpListener, monitors: Vec<Monitor>, shutdown: impl Future) {
+    let port = match listener.local_addr() {
+        Ok(addr) => addr.port(),
+        Err(err) => {
+            error!(cause = %err, "failed to get the local address");
+            return;
+        }
+    };
+
+    let sentinel = Sentinel::new(port, monitors);
+    info!(runid = %sentinel.shared.runid, port, "sentinel started");
+
+    let checks = tokio::spawn(sentinel.clone().check_periodically());
+
+    tokio::select! {
+        res = sentinel.serve(listener) => {
+            if let Err(err) = res {
+                error!(cause = %err, "failed to accept");
+            }
+        }
+        _ = shutdown => {
+            info!("shutting down");
+        }
+    }
+
+    checks.abort();
+    for (_, task) in sentinel.lock().tasks.drain() {
+        task.abort();
+    }
+}
+
+impl Sentinel {
+    fn new(port: u16, monitors: Vec<Monitor>) -> Sentinel {
+        let masters = monitors
+            .into_iter()
+            .map(|monitor| (monitor.name.clone(), Master::new(monitor)))
+            .collect();
+
+        Sentinel {
+            shared: Arc::new(Shared {
+                // Run IDs have the format of replication IDs.
+                runid: crate::db::new_replid(),
+                port,
+                state: Mutex::new(State {
+                    current_epoch: 0,
+                    masters,
+                    tasks: HashMap::new(),
+                }),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    fn runid(&self) -> &str {
+        &self.shared.runid
+    }
+
+    /// Accept clients, each served by its own task.
+    async fn serve(&self, listener: TcpListener) -> crate::Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let sentinel = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = sentinel.handle(Connection::new(socket)).await {
+                    error!(cause = ?err, "connection error");
+                }
+            });
+        }
+    }
+
+    /// Reply to the commands of a client until it disconnects.
+    async fn handle(&self, mut connection: Connection) -> crate::Result<()> {
+        while let Some(frame) = connection.read_frame().await? {
+            let resp = match cmd::Command::from_frame(frame) {
+                Ok(cmd) => {
+                    debug!(?cmd);
+                    cmd.execute(self)
+                }
+                Err(err) => Frame::Error(format!("ERR {}", err)),
+            };
+
+            connection.write_frame(&resp).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_periodically(self) {
+        let mut ticks = time::interval(CHECK_PERIOD);
+        loop {
+            ticks.tick().await;
+            self.check();
+        }
+    }
+
+    /// Monitor the instances discovered since the last check, then find out
+    /// whether each primary is down, and start a failover if it is.
+    fn check(&self) {
+        let mut state = self.lock();
+        let State {
+            current_epoch,
+            masters,
+            tasks,
+        } = &mut *state;
+
+        for (name, master) in masters.iter_mut() {
+            for addr in master.instances.keys() {
+                tasks
+                    .entry((name.clone(), addr.clone()))
+                    .or_insert_with(|| {
+                        tokio::spawn(instance::monitor(self.clone(), name.clone(), addr.clone()))
+                    });
+            }
+
+            let sdown = master.is_down(&master.addr);
+            if sdown != master.sdown {
+                master.sdown = sdown;
+                if sdown {
+                    warn!(%name, addr = ?master.addr, "primary is subjectively down");
+                } else {
+                    info!(%name, addr = ?master.addr, "primary is reachable again");
+                }
+            }
+
+            if !sdown || master.failover.is_some() {
+                master.odown = false;
+                continue;
+            }
+
+            if master.last_ask.is_none_or(|at| at.elapsed() >= ASK_PERIOD) {
+                master.last_ask = Some(Instant::now());
+                tokio::spawn(failover::ask(self.clone(), name.clone(), None));
+            }
+
+            let odown = master.is_odown();
+            if odown != master.odown {
+                master.odown = odown;
+                if odown {
+                    warn!(%name, addr = ?master.addr, "primary is objectively down");
+                    master.failover_not_before =
+                        Some(Instant::now() + random_delay(MAX_FAILOVER_DELAY));
+                }
+            }
+
+            if odown && master.may_fail_over() {
+                *current_epoch += 1;
+                let epoch = *current_epoch;
+                master.vote(self.runid(), epoch);
+                master.start_failover(epoch);
+
+                info!(%name, epoch, "starting failover");
+                tokio::spawn(failover::run(self.clone(), name.clone(), epoch));
+            }
+        }
+    }
+
+    /// Returns the hello message advertising this sentinel and its
+    /// configuration of the primary `name`, announcing `ip`.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// ip,port,runid,current_epoch,name,master_ip,master_port,config_epoch
+    /// ```
+    fn hello(&self, name: &str, ip: &str) -> Option<String> {
+        let state = self.lock();
+        let master = state.masters.get(name)?;
+
+        Some(format!(
+            "{},{},{},{},{},{},{},{}",
+            ip,
+            self.shared.port,
+            self.runid(),
+            state.current_epoch,
+            name,
+            master.addr.0,
+            master.addr.1,
+            master.config_epoch
+        ))
+    }
+
+    /// Record the sentinel advertised by a hello message, and adopt its
+    /// configuration if it is of a later epoch.
+    fn receive_hello(&self, msg: &str) {
+        let fields: Vec<_> = msg.split(',').collect();
+        if fields.len() != 8 {
+            debug!(msg, "invalid hello message");
+            return;
+        }
+
+        let parsed = (
+            fields[1].parse::<u16>(),
+            fields[3].parse::<u64>(),
+            fields[6].parse::<u16>(),
+            fields[7].parse::<u64>(),
+        );
+        let (port, epoch, master_port, config_epoch) = match parsed {
+            (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) => {
+                (port, epoch, master_port, config_epoch)
+            }
+            _ => {
+                debug!(msg, "invalid hello message");
+                return;
+            }
+        };
+
+        let runid = fields[2];
+        if runid == self.runid() {
+            return;
+        }
+
+        let mut state = self.lock();
+        if epoch > state.current_epoch {
+            state.current_epoch = epoch;
+        }
+
+        let name = fields[4];
+        let master = match state.masters.get_mut(name) {
+            Some(master) => master,
+            None => return,
+        };
+
+        let addr = (fields[0].to_string(), port);
+        if master.add_sentinel(runid, addr.clone()) {
+            info!(%name, %runid, ?addr, "discovered sentinel");
+        }
+
+        if config_epoch > master.config_epoch {
+            let primary = (fields[5].to_string(), master_port);
+            if primary != master.addr {
+                info!(%name, from = ?master.addr, to = ?primary, config_epoch, "switching primary");
+            }
+            master.switch(primary, config_epoch);
+        }
+    }
+}
+
+/// Returns a random delay of at most `max`.
+fn random_delay(max: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    max.mul_f64(f64::from(nanos % 1000) / 1000.0)
+}
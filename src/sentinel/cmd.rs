@@ -0,0 +1,322 @@
+//! The commands a sentinel serves, to clients and to the other sentinels.
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use tracing::{debug, info, instrument};
+
+use super::master::{Addr, Master};
+use super::Sentinel;
+use crate::cmd::{Ping, Unknown};
+use crate::{Frame, Parse};
+
+/// A command sent to a sentinel.
+#[derive(Debug)]
+pub(super) enum Command {
+    Ping(Ping),
+    /// `SENTINEL get-master-addr-by-name name`: the address of the primary.
+    GetMasterAddrByName(String),
+    /// `SENTINEL masters`: the state of every primary.
+    Masters,
+    /// `SENTINEL master name`: the state of a primary.
+    Master(String),
+    /// `SENTINEL replicas name`: the state of the replicas of a primary.
+    Replicas(String),
+    /// `SENTINEL sentinels name`: the other sentinels monitoring a primary.
+    Sentinels(String),
+    /// `SENTINEL is-master-down-by-addr ip port epoch runid`, sent by the
+    /// other sentinels: whether the primary at `addr` is down. Unless
+    /// `runid` is `*`, also a request to vote for `runid` as the leader of
+    /// the failover in `epoch`.
+    IsMasterDownByAddr {
+        addr: Addr,
+        epoch: u64,
+        runid: String,
+    },
+    /// `SENTINEL myid`: the run ID of the sentinel.
+    MyId,
+    Unknown(Unknown),
+}
+
+impl Command {
+    /// Create a new `SENTINEL is-master-down-by-addr` command.
+    pub(super) fn is_master_down_by_addr(addr: Addr, epoch: u64, runid: String) -> Command {
+        Command::IsMasterDownByAddr { addr, epoch, runid }
+    }
+
+    /// Parse a command from a received frame.
+    ///
+    /// # Format
+    ///
+    /// ```text
+    /// PING [message]
+    /// SENTINEL GET-MASTER-ADDR-BY-NAME name
+    /// SENTINEL MASTERS
+    /// SENTINEL MASTER name
+    /// SENTINEL REPLICAS|SLAVES name
+    /// SENTINEL SENTINELS name
+    /// SENTINEL IS-MASTER-DOWN-BY-ADDR ip port current-epoch runid|*
+    /// SENTINEL MYID
+    /// ```
+    pub(super) fn from_frame(frame: Frame) -> crate::Result<Command> {
+        let mut parse = Parse::new(frame)?;
+
+        let command_name = parse.next_string()?.to_lowercase();
+        let command = match &command_name[..] {
+            "ping" => Command::Ping(Ping::parse_frames(&mut parse)?),
+            "sentinel" => {
+                let subcommand = parse.next_string()?.to_lowercase();
+                match &subcommand[..] {
+                    "get-master-addr-by-name" => Command::GetMasterAddrByName(parse.next_string()?),
+                    "masters" => Command::Masters,
+                    "master" => Command::Master(parse.next_string()?),
+                    "replicas" | "slaves" => Command::Replicas(parse.next_string()?),
+                    "sentinels" => Command::Sentinels(parse.next_string()?),
+                    "is-master-down-by-addr" => {
+                        let ip = parse.next_string()?;
+                        let port = parse.next_int()?.try_into()?;
+                        let epoch = parse.next_int()?;
+                        let runid = parse.next_string()?;
+                        Command::IsMasterDownByAddr {
+                            addr: (ip, port),
+                            epoch,
+                            runid,
+                        }
+                    }
+                    "myid" => Command::MyId,
+                    _ => return Err(format!("Unknown sentinel subcommand '{}'", subcommand).into()),
+                }
+            }
+            _ => return Ok(Command::Unknown(Unknown::new(command_name))),
+        };
+
+        parse.finish()?;
+        Ok(command)
+    }
+
+    /// Apply the command to the state of `sentinel`, and return the reply.
+    #[instrument(skip(self, sentinel))]
+    pub(super) fn execute(self, sentinel: &Sentinel) -> Frame {
+        let mut state = sentinel.lock();
+
+        let resp = match self {
+            Command::Ping(cmd) => cmd.execute(),
+            Command::GetMasterAddrByName(name) => match state.masters.get(&name) {
+                Some(master) => {
+                    Frame::Array(vec![bulk(&master.addr.0), bulk(&master.addr.1.to_string())])
+                }
+                None => Frame::Null,
+            },
+            Command::Masters => {
+                let mut names: Vec<_> = state.masters.keys().collect();
+                names.sort();
+                Frame::Array(
+                    names
+                        .into_iter()
+                        .map(|name| master_fields(&state.masters[name]))
+                        .collect(),
+                )
+            }
+            Command::Master(name) => match state.masters.get(&name) {
+                Some(master) => master_fields(master),
+                None => no_such_master(),
+            },
+            Command::Replicas(name) => match state.masters.get(&name) {
+                Some(master) => Frame::Array(
+                    master
+                        .replicas()
+                        .iter()
+                        .map(|addr| replica_fields(master, addr))
+                        .collect(),
+                ),
+                None => no_such_master(),
+            },
+            Command::Sentinels(name) => match state.masters.get(&name) {
+                Some(master) => {
+                    let mut peers: Vec<_> = master.sentinels.iter().collect();
+                    peers.sort_by(|a, b| a.1.addr.cmp(&b.1.addr));
+                    Frame::Array(
+                        peers
+                            .into_iter()
+                            .map(|(runid, peer)| {
+                                fields(vec![
+                                    ("name", runid.clone()),
+                                    ("ip", peer.addr.0.clone()),
+                                    ("port", peer.addr.1.to_string()),
+                                    ("runid", runid.clone()),
+                                    ("last-hello-message", millis_since(peer.last_hello)),
+                                ])
+                            })
+                            .collect(),
+                    )
+                }
+                None => no_such_master(),
+            },
+            Command::IsMasterDownByAddr { addr, epoch, runid } => {
+                if epoch > state.current_epoch {
+                    state.current_epoch = epoch;
+                }
+
+                let runid_is_own = runid == sentinel.runid();
+                let master = state
+                    .masters
+                    .values_mut()
+                    .find(|master| master.addr == addr);
+                let (down, leader) = match master {
+                    Some(master) => {
+                        let down = master.is_down(&addr);
+                        let leader = if runid == "*" {
+                            ("*".to_string(), 0)
+                        } else {
+                            let before = master.leader.clone();
+                            let leader = master.vote(&runid, epoch);
+                            if master.leader != before {
+                                info!(%runid, epoch, "voted for the leader of the failover");
+                                // Give the leader the time to fail over
+                                // before trying it.
+                                if !runid_is_own {
+                                    master.failover_start = Some(Instant::now());
+                                }
+                            }
+                            leader
+                        };
+                        (down, leader)
+                    }
+                    None => (false, ("*".to_string(), 0)),
+                };
+
+                Frame::Array(vec![
+                    Frame::Int(u64::from(down)),
+                    bulk(&leader.0),
+                    Frame::Int(leader.1),
+                ])
+            }
+            Command::MyId => bulk(sentinel.runid()),
+            Command::Unknown(cmd) => cmd.execute(),
+        };
+
+        debug!(?resp);
+        resp
+    }
+
+    /// Converts the command into an equivalent `Frame`.
+    pub(super) fn into_frame(self) -> Frame {
+        let mut frame = Frame::array();
+        let (subcommand, args) = match self {
+            Command::Ping(cmd) => return cmd.into_frame(),
+            Command::GetMasterAddrByName(name) => ("get-master-addr-by-name", vec![name]),
+            Command::Masters => ("masters", vec![]),
+            Command::Master(name) => ("master", vec![name]),
+            Command::Replicas(name) => ("replicas", vec![name]),
+            Command::Sentinels(name) => ("sentinels", vec![name]),
+            Command::IsMasterDownByAddr { addr, epoch, runid } => (
+                "is-master-down-by-addr",
+                vec![addr.0, addr.1.to_string(), epoch.to_string(), runid],
+            ),
+            Command::MyId => ("myid", vec![]),
+            Command::Unknown(cmd) => {
+                frame.push_bulk(Bytes::from(cmd.get_name().to_string()));
+                return frame;
+            }
+        };
+
+        frame.push_bulk(Bytes::from("sentinel".as_bytes()));
+        frame.push_bulk(Bytes::from(subcommand.as_bytes()));
+        for arg in args {
+            frame.push_bulk(Bytes::from(arg.into_bytes()));
+        }
+        frame
+    }
+}
+
+/// Returns the state of `master`, as `SENTINEL master` replies it.
+fn master_fields(master: &Master) -> Frame {
+    let mut flags = vec!["master"];
+    if master.is_down(&master.addr) {
+        flags.push("s_down");
+    }
+    if master.is_odown() {
+        flags.push("o_down");
+    }
+    if master.failover.is_some() {
+        flags.push("failover_in_progress");
+    }
+
+    let last_reply = master.instances[&master.addr].last_reply;
+    fields(vec![
+        ("name", master.config.name.clone()),
+        ("ip", master.addr.0.clone()),
+        ("port", master.addr.1.to_string()),
+        ("flags", flags.join(",")),
+        (
+            "last-ok-ping-reply",
+            last_reply.map_or("-1".to_string(), millis_since),
+        ),
+        ("num-slaves", master.replicas().len().to_string()),
+        ("num-other-sentinels", master.sentinels.len().to_string()),
+        ("quorum", master.config.quorum.to_string()),
+        ("config-epoch", master.config_epoch.to_string()),
+        (
+            "down-after-milliseconds",
+            master.config.down_after.as_millis().to_string(),
+        ),
+        (
+            "failover-timeout",
+            master.config.failover_timeout.as_millis().to_string(),
+        ),
+    ])
+}
+
+/// Returns the state of the replica at `addr`, as `SENTINEL replicas`
+/// replies it.
+fn replica_fields(master: &Master, addr: &Addr) -> Frame {
+    let mut flags = vec!["slave"];
+    if master.is_down(addr) {
+        flags.push("s_down");
+    }
+
+    let instance = &master.instances[addr];
+    let mut pairs = vec![
+        ("name", format!("{}:{}", addr.0, addr.1)),
+        ("ip", addr.0.clone()),
+        ("port", addr.1.to_string()),
+        ("flags", flags.join(",")),
+        (
+            "last-ok-ping-reply",
+            instance.last_reply.map_or("-1".to_string(), millis_since),
+        ),
+    ];
+
+    if let Some((info, _)) = &instance.info {
+        if let Some(((host, port), link_up)) = &info.primary {
+            let status = if *link_up { "ok" } else { "err" };
+            pairs.push(("master-host", host.clone()));
+            pairs.push(("master-port", port.to_string()));
+            pairs.push(("master-link-status", status.to_string()));
+        }
+        pairs.push(("slave-repl-offset", info.offset.to_string()));
+    }
+
+    fields(pairs)
+}
+
+/// Returns `pairs` as a flat array of field names and values.
+fn fields(pairs: Vec<(&str, String)>) -> Frame {
+    let mut frame = Frame::array();
+    for (field, value) in pairs {
+        frame.push_bulk(Bytes::from(field.to_string()));
+        frame.push_bulk(Bytes::from(value));
+    }
+    frame
+}
+
+fn bulk(value: &str) -> Frame {
+    Frame::Bulk(Bytes::from(value.to_string()))
+}
+
+fn millis_since(at: Instant) -> String {
+    at.elapsed().as_millis().to_string()
+}
+
+fn no_such_master() -> Frame {
+    Frame::Error("ERR No such master with that name".to_string())
+}
@@ -0,0 +1,189 @@
+//! Agreeing with the other sentinels that a primary is down, electing the
+//! leader of the failover, and failing over to a replica.
+
+use tokio::time::{self, Duration, Instant};
+use tracing::{info, warn};
+
+use super::cmd::Command;
+use super::instance::{connect, request};
+use super::master::Addr;
+use super::{random_delay, Sentinel};
+use crate::cmd::ReplicaOf;
+use crate::Frame;
+
+/// How long the sentinel waits between two rounds of votes, at most. The
+/// actual delay is random, so that candidates of the same epoch do not keep
+/// splitting the votes.
+const MAX_VOTE_DELAY: Duration = Duration::from_secs(1);
+
+/// Ask the other sentinels whether the primary `name` is down. With `epoch`,
+/// also ask them to vote for this sentinel as the leader of its failover in
+/// this epoch.
+///
+/// The replies are recorded with the sentinels that sent them.
+pub(super) async fn ask(sentinel: Sentinel, name: String, epoch: Option<u64>) {
+    let (frame, peers) = {
+        let state = sentinel.lock();
+        let master = match state.masters.get(&name) {
+            Some(master) => master,
+            None => return,
+        };
+
+        let cmd = match epoch {
+            Some(epoch) => Command::is_master_down_by_addr(
+                master.addr.clone(),
+                epoch,
+                sentinel.runid().to_string(),
+            ),
+            None => Command::is_master_down_by_addr(
+                master.addr.clone(),
+                state.current_epoch,
+                "*".to_string(),
+            ),
+        };
+
+        let peers: Vec<_> = master
+            .sentinels
+            .iter()
+            .map(|(runid, peer)| (runid.clone(), peer.addr.clone()))
+            .collect();
+        (cmd.into_frame(), peers)
+    };
+
+    // The sentinels are asked concurrently.
+    let requests: Vec<_> = peers
+        .into_iter()
+        .map(|(runid, addr)| {
+            let frame = frame.clone();
+            let reply = tokio::spawn(async move {
+                let mut connection = connect(&addr).await?;
+                request(&mut connection, frame).await
+            });
+            (runid, reply)
+        })
+        .collect();
+
+    for (runid, reply) in requests {
+        let (down, leader) = match reply.await {
+            Ok(Ok(Frame::Array(frames))) => match &frames[..] {
+                [Frame::Int(down), Frame::Bulk(leader), Frame::Int(leader_epoch)] => {
+                    let leader = String::from_utf8_lossy(leader).into_owned();
+                    (
+                        *down == 1,
+                        (leader != "*").then_some((leader, *leader_epoch)),
+                    )
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        let mut state = sentinel.lock();
+        let peer = match state
+            .masters
+            .get_mut(&name)
+            .and_then(|master| master.sentinels.get_mut(&runid))
+        {
+            Some(peer) => peer,
+            None => continue,
+        };
+
+        peer.down = down.then(Instant::now);
+        if leader.is_some() {
+            peer.leader = leader;
+        }
+    }
+}
+
+/// Fail over the primary `name` in `epoch`, once elected leader by the
+/// other sentinels.
+pub(super) async fn run(sentinel: Sentinel, name: String, epoch: u64) {
+    if let Err(err) = fail_over(&sentinel, &name, epoch).await {
+        warn!(cause = %err, %name, epoch, "failover aborted");
+
+        if let Some(master) = sentinel.lock().masters.get_mut(&name) {
+            if master.failover == Some(epoch) {
+                master.failover = None;
+            }
+        }
+    }
+}
+
+async fn fail_over(sentinel: &Sentinel, name: &str, epoch: u64) -> crate::Result<()> {
+    let timeout = match sentinel.lock().masters.get(name) {
+        Some(master) => master.config.failover_timeout,
+        None => return Err("the primary is no longer monitored".into()),
+    };
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        ask(sentinel.clone(), name.to_string(), Some(epoch)).await;
+
+        let (votes, needed) = match sentinel.lock().masters.get(name) {
+            Some(master) => (master.votes(sentinel.runid(), epoch), master.votes_needed()),
+            None => return Err("the primary is no longer monitored".into()),
+        };
+
+        if votes >= needed {
+            info!(%name, epoch, votes, "elected leader of the failover");
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!("not elected, {} votes of {} needed", votes, needed).into());
+        }
+        time::sleep(random_delay(MAX_VOTE_DELAY)).await;
+    }
+
+    let (promoted, others) = {
+        let state = sentinel.lock();
+        let master = match state.masters.get(name) {
+            Some(master) => master,
+            None => return Err("the primary is no longer monitored".into()),
+        };
+
+        let promoted = master.best_replica().ok_or("no replica to promote")?;
+        let others: Vec<_> = master
+            .replicas()
+            .into_iter()
+            .filter(|addr| *addr != promoted)
+            .collect();
+        (promoted, others)
+    };
+
+    let mut connection = connect(&promoted).await?;
+    if let Frame::Error(err) = request(&mut connection, ReplicaOf::new(None).into_frame()).await? {
+        return Err(format!("failed to promote {:?}: {}", promoted, err).into());
+    }
+    info!(%name, ?promoted, epoch, "promoted replica");
+
+    // The other sentinels adopt the new configuration from the hello
+    // messages, as it is of a later epoch.
+    if let Some(master) = sentinel.lock().masters.get_mut(name) {
+        master.switch(promoted.clone(), epoch);
+    }
+
+    // The former primary is not among them. It is pointed to the new one
+    // once it is back, by the task monitoring it.
+    for addr in others {
+        if let Err(err) = replicate(&addr, &promoted).await {
+            warn!(cause = %err, ?addr, "failed to reconfigure replica");
+        }
+    }
+
+    Ok(())
+}
+
+/// Point the replica at `addr` to the primary at `primary`.
+async fn replicate(addr: &Addr, primary: &Addr) -> crate::Result<()> {
+    let mut connection = connect(addr).await?;
+    let frame = ReplicaOf::new(Some(primary.clone())).into_frame();
+
+    match request(&mut connection, frame).await? {
+        Frame::Error(err) => Err(err.into()),
+        _ => {
+            info!(?addr, ?primary, "reconfigured replica");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! The links of a sentinel to the instances it monitors.
+//!
+//! Each instance is PINGed every second, and asked for `INFO replication`
+//! every other second. The sentinel publishes its hello message on the
+//! instance, and subscribes to the ones of the other sentinels, on a second
+//! connection.
+
+use bytes::Bytes;
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+use super::master::{Addr, Info};
+use super::Sentinel;
+use crate::cmd::{Info as InfoCmd, Ping, Publish, ReplicaOf, Subscribe};
+use crate::{Connection, Frame};
+
+/// The channel hello messages are published on.
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+/// How often instances are PINGed.
+const PING_PERIOD: Duration = Duration::from_secs(1);
+
+/// Instances are asked for `INFO replication` every this many PINGs.
+const INFO_EVERY: u64 = 2;
+
+/// The hello message is published every this many PINGs.
+const HELLO_EVERY: u64 = 2;
+
+/// How long the sentinel waits for an instance or another sentinel to
+/// accept a connection, or to reply.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the sentinel waits before connecting to an instance again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Monitor the instance at `addr` of the primary `name`, until the task is
+/// aborted.
+pub(super) async fn monitor(sentinel: Sentinel, name: String, addr: Addr) {
+    let commands = async {
+        loop {
+            if let Err(err) = send_commands(&sentinel, &name, &addr).await {
+                debug!(cause = %err, ?addr, "link to the instance down");
+            }
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    };
+
+    let hellos = async {
+        loop {
+            if let Err(err) = receive_hellos(&sentinel, &addr).await {
+                debug!(cause = %err, ?addr, "hello subscription down");
+            }
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    };
+
+    tokio::join!(commands, hellos);
+}
+
+/// PING the instance, ask it for `INFO replication` and publish the hello
+/// message periodically, until the connection breaks.
+async fn send_commands(sentinel: &Sentinel, name: &str, addr: &Addr) -> crate::Result<()> {
+    let mut connection = connect(addr).await?;
+    let ip = connection.local_addr()?.ip().to_string();
+
+    let mut ticks = time::interval(PING_PERIOD);
+    let mut n: u64 = 0;
+    loop {
+        ticks.tick().await;
+        n += 1;
+
+        if let Frame::Simple(_) = request(&mut connection, Ping::new(None).into_frame()).await? {
+            if let Some(master) = sentinel.lock().masters.get_mut(name) {
+                master.replied(addr);
+            }
+        }
+
+        if n % INFO_EVERY == 1 {
+            let frame = InfoCmd::new(vec!["replication".to_string()]).into_frame();
+            if let Frame::Bulk(text) = request(&mut connection, frame).await? {
+                let info = parse_info(&String::from_utf8_lossy(&text));
+
+                let primary = match sentinel.lock().masters.get_mut(name) {
+                    Some(master) => master.update_info(addr, info).then(|| master.addr.clone()),
+                    None => None,
+                };
+
+                if let Some((host, port)) = primary {
+                    info!(?addr, %host, port, "pointing the instance to the primary");
+                    let frame = ReplicaOf::new(Some((host, port))).into_frame();
+                    if let Frame::Error(err) = request(&mut connection, frame).await? {
+                        warn!(cause = %err, ?addr, "failed to point the instance to the primary");
+                    }
+                }
+            }
+        }
+
+        if n % HELLO_EVERY == 1 {
+            if let Some(hello) = sentinel.hello(name, &ip) {
+                let frame = Publish::new(HELLO_CHANNEL, Bytes::from(hello)).into_frame();
+                request(&mut connection, frame).await?;
+            }
+        }
+    }
+}
+
+/// Receive the hello messages of the other sentinels published on the
+/// instance, until the connection breaks.
+async fn receive_hellos(sentinel: &Sentinel, addr: &Addr) -> crate::Result<()> {
+    let mut connection = connect(addr).await?;
+    let frame = Subscribe::new(vec![HELLO_CHANNEL.to_string()]).into_frame();
+    connection.write_frame(&frame).await?;
+
+    loop {
+        match connection.read_frame().await? {
+            Some(Frame::Array(frames)) => match &frames[..] {
+                [Frame::Bulk(kind), _, Frame::Bulk(msg), ..] if &kind[..] == b"message" => {
+                    sentinel.receive_hello(&String::from_utf8_lossy(msg));
+                }
+                _ => {}
+            },
+            Some(_) => {}
+            None => return Err("connection closed by the instance".into()),
+        }
+    }
+}
+
+/// Connect to the instance or sentinel at `addr`.
+pub(super) async fn connect(addr: &Addr) -> crate::Result<Connection> {
+    let socket = time::timeout(TIMEOUT, TcpStream::connect((&addr.0[..], addr.1))).await??;
+    Ok(Connection::new(socket))
+}
+
+/// Send `frame` and return the reply.
+pub(super) async fn request(connection: &mut Connection, frame: Frame) -> crate::Result<Frame> {
+    let reply = time::timeout(TIMEOUT, async {
+        connection.write_frame(&frame).await?;
+        connection.read_frame().await
+    })
+    .await??;
+
+    reply.ok_or_else(|| "connection closed by the peer".into())
+}
+
+/// Parse the reply to `INFO replication`.
+fn parse_info(text: &str) -> Info {
+    let mut info = Info {
+        primary: None,
+        offset: 0,
+        replicas: vec![],
+    };
+
+    let mut is_replica = false;
+    let (mut host, mut port, mut link_up) = (String::new(), 0, false);
+
+    for line in text.lines() {
+        let (field, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        match field {
+            "role" => is_replica = value == "slave",
+            "master_host" => host = value.to_string(),
+            "master_port" => port = value.parse().unwrap_or(0),
+            "master_link_status" => link_up = value == "up",
+            "master_repl_offset" => info.offset = value.parse().unwrap_or(0),
+            // slave0:ip=127.0.0.1,port=6380,state=online,offset=42,lag=0
+            _ if field.starts_with("slave") && field[5..].parse::<u64>().is_ok() => {
+                let mut ip = None;
+                let mut port = None;
+                for pair in value.split(',') {
+                    match pair.split_once('=') {
+                        Some(("ip", value)) => ip = Some(value.to_string()),
+                        Some(("port", value)) => port = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+                if let (Some(ip), Some(port)) = (ip, port) {
+                    info.replicas.push((ip, port));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if is_replica {
+        info.primary = Some(((host, port), link_up));
+    }
+    info
+}
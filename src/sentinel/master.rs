@@ -0,0 +1,298 @@
+//! What a sentinel knows about a primary: its instances, the other
+//! sentinels monitoring it, and the state of its failover.
+
+use std::collections::HashMap;
+
+use tokio::time::{Duration, Instant};
+
+use super::Monitor;
+
+/// Host and port of an instance or a sentinel.
+pub(super) type Addr = (String, u16);
+
+/// Replies to `SENTINEL is-master-down-by-addr` older than this are not
+/// counted to agree that a primary is down.
+const DOWN_REPLY_VALIDITY: Duration = Duration::from_secs(5);
+
+/// Replicas whose `INFO` is older than this are not promoted.
+const INFO_VALIDITY: Duration = Duration::from_secs(5);
+
+/// A primary monitored by the sentinel.
+#[derive(Debug)]
+pub(super) struct Master {
+    pub(super) config: Monitor,
+    /// Address of the primary. Changes with failovers.
+    pub(super) addr: Addr,
+    /// Epoch of the failover that made `addr` the primary, zero if none did.
+    pub(super) config_epoch: u64,
+    /// The primary and its replicas, by address. The former primary remains
+    /// an instance, as a replica.
+    pub(super) instances: HashMap<Addr, Instance>,
+    /// The other sentinels monitoring the primary, by run ID.
+    pub(super) sentinels: HashMap<String, Peer>,
+    /// The sentinel voted for as the leader of the failover, and the epoch
+    /// of the vote.
+    pub(super) leader: Option<(String, u64)>,
+    /// The epoch of the failover in progress, if any.
+    pub(super) failover: Option<u64>,
+    /// When the last failover started, or when this sentinel voted for the
+    /// failover of another one.
+    pub(super) failover_start: Option<Instant>,
+    /// No failover is started before this time, set once the primary is
+    /// objectively down.
+    pub(super) failover_not_before: Option<Instant>,
+    /// When the other sentinels were last asked whether the primary is down.
+    pub(super) last_ask: Option<Instant>,
+    /// Whether the primary was subjectively, then objectively, down at the
+    /// last check.
+    pub(super) sdown: bool,
+    pub(super) odown: bool,
+}
+
+/// A primary or replica.
+#[derive(Debug)]
+pub(super) struct Instance {
+    /// When the instance was added, counted as its last reply until it
+    /// replies.
+    added: Instant,
+    /// When the instance last replied to `PING`.
+    pub(super) last_reply: Option<Instant>,
+    /// The last reply to `INFO replication`, and when it was received.
+    pub(super) info: Option<(Info, Instant)>,
+}
+
+/// The replication state of an instance, reported by `INFO replication`.
+#[derive(Debug, Clone)]
+pub(super) struct Info {
+    /// The primary of the instance, and whether the link to it is up, if it
+    /// is a replica.
+    pub(super) primary: Option<(Addr, bool)>,
+    pub(super) offset: u64,
+    /// The replicas attached to the instance.
+    pub(super) replicas: Vec<Addr>,
+}
+
+/// Another sentinel monitoring the primary.
+#[derive(Debug)]
+pub(super) struct Peer {
+    pub(super) addr: Addr,
+    pub(super) last_hello: Instant,
+    /// When the sentinel last replied that the primary is down.
+    pub(super) down: Option<Instant>,
+    /// The sentinel it voted for as the leader of the failover, and the
+    /// epoch of the vote.
+    pub(super) leader: Option<(String, u64)>,
+}
+
+impl Master {
+    pub(super) fn new(config: Monitor) -> Master {
+        let addr = (config.host.clone(), config.port);
+        let mut master = Master {
+            config,
+            addr: addr.clone(),
+            config_epoch: 0,
+            instances: HashMap::new(),
+            sentinels: HashMap::new(),
+            leader: None,
+            failover: None,
+            failover_start: None,
+            failover_not_before: None,
+            last_ask: None,
+            sdown: false,
+            odown: false,
+        };
+        master.add_instance(addr);
+        master
+    }
+
+    fn add_instance(&mut self, addr: Addr) {
+        self.instances.entry(addr).or_insert_with(|| Instance {
+            added: Instant::now(),
+            last_reply: None,
+            info: None,
+        });
+    }
+
+    /// Returns the addresses of the replicas, sorted.
+    pub(super) fn replicas(&self) -> Vec<Addr> {
+        let mut replicas: Vec<_> = self
+            .instances
+            .keys()
+            .filter(|addr| **addr != self.addr)
+            .cloned()
+            .collect();
+        replicas.sort();
+        replicas
+    }
+
+    /// Returns `true` if the instance at `addr` did not reply for
+    /// `down_after`.
+    pub(super) fn is_down(&self, addr: &Addr) -> bool {
+        match self.instances.get(addr) {
+            Some(instance) => {
+                instance.last_reply.unwrap_or(instance.added).elapsed() > self.config.down_after
+            }
+            None => true,
+        }
+    }
+
+    /// Returns `true` if the primary is down for this sentinel and enough of
+    /// the other ones to reach the quorum.
+    pub(super) fn is_odown(&self) -> bool {
+        let agreeing = self
+            .sentinels
+            .values()
+            .filter(|peer| {
+                peer.down
+                    .is_some_and(|at| at.elapsed() <= DOWN_REPLY_VALIDITY)
+            })
+            .count();
+
+        self.is_down(&self.addr) && 1 + agreeing >= self.config.quorum
+    }
+
+    /// Record the reply of the instance at `addr` to `PING`.
+    pub(super) fn replied(&mut self, addr: &Addr) {
+        if let Some(instance) = self.instances.get_mut(addr) {
+            instance.last_reply = Some(Instant::now());
+        }
+    }
+
+    /// Record the reply of the instance at `addr` to `INFO replication`. The
+    /// replicas of the primary are monitored from now on.
+    ///
+    /// Returns `true` if the instance must be pointed to the primary: it is
+    /// a replica that replicates another instance, or a former primary that
+    /// came back. This is only done while the primary is up, and no failover
+    /// is in progress, not to undo one.
+    pub(super) fn update_info(&mut self, addr: &Addr, info: Info) -> bool {
+        if *addr == self.addr && info.primary.is_none() {
+            for replica in &info.replicas {
+                self.add_instance(replica.clone());
+            }
+        }
+
+        let misconfigured = *addr != self.addr
+            && match &info.primary {
+                Some((primary, _)) => *primary != self.addr,
+                None => true,
+            };
+
+        if let Some(instance) = self.instances.get_mut(addr) {
+            instance.info = Some((info, Instant::now()));
+        }
+
+        misconfigured && !self.is_down(&self.addr) && self.failover.is_none()
+    }
+
+    /// Record the sentinel `runid` at `addr`. A sentinel that restarted with
+    /// a new run ID replaces the former one.
+    ///
+    /// Returns `true` if the sentinel was not known.
+    pub(super) fn add_sentinel(&mut self, runid: &str, addr: Addr) -> bool {
+        self.sentinels
+            .retain(|id, peer| id == runid || peer.addr != addr);
+
+        match self.sentinels.get_mut(runid) {
+            Some(peer) => {
+                peer.addr = addr;
+                peer.last_hello = Instant::now();
+                false
+            }
+            None => {
+                let peer = Peer {
+                    addr,
+                    last_hello: Instant::now(),
+                    down: None,
+                    leader: None,
+                };
+                self.sentinels.insert(runid.to_string(), peer);
+                true
+            }
+        }
+    }
+
+    /// Vote for `runid` as the leader of the failover in `epoch`, unless
+    /// this sentinel already voted in this epoch or a later one.
+    ///
+    /// Returns the sentinel voted for, and the epoch of the vote.
+    pub(super) fn vote(&mut self, runid: &str, epoch: u64) -> (String, u64) {
+        if self.leader.as_ref().is_none_or(|(_, voted)| *voted < epoch) {
+            self.leader = Some((runid.to_string(), epoch));
+        }
+
+        self.leader.clone().unwrap()
+    }
+
+    /// Returns the number of votes `runid` got as the leader of the failover
+    /// in `epoch`, including the one of this sentinel.
+    pub(super) fn votes(&self, runid: &str, epoch: u64) -> usize {
+        let is_vote = |leader: &Option<(String, u64)>| matches!(leader, Some((id, voted)) if id == runid && *voted == epoch);
+
+        let own = usize::from(is_vote(&self.leader));
+        own + self
+            .sentinels
+            .values()
+            .filter(|peer| is_vote(&peer.leader))
+            .count()
+    }
+
+    /// Returns the number of votes needed to lead a failover: a majority of
+    /// the sentinels, and at least the quorum.
+    pub(super) fn votes_needed(&self) -> usize {
+        let voters = self.sentinels.len() + 1;
+        let majority = voters / 2 + 1;
+        majority.max(self.config.quorum)
+    }
+
+    /// Returns `true` if no failover was attempted recently, this sentinel
+    /// leading it or voting for another one, and the delay before starting
+    /// one elapsed.
+    pub(super) fn may_fail_over(&self) -> bool {
+        self.failover.is_none()
+            && self
+                .failover_start
+                .is_none_or(|at| at.elapsed() > 2 * self.config.failover_timeout)
+            && self
+                .failover_not_before
+                .is_none_or(|at| Instant::now() >= at)
+    }
+
+    /// Start the failover of `epoch`.
+    pub(super) fn start_failover(&mut self, epoch: u64) {
+        self.failover = Some(epoch);
+        self.failover_start = Some(Instant::now());
+    }
+
+    /// Returns the replica to promote: one that replies, with the largest
+    /// replication offset, then the lowest address.
+    pub(super) fn best_replica(&self) -> Option<Addr> {
+        self.replicas()
+            .into_iter()
+            .filter(|addr| !self.is_down(addr))
+            .filter_map(|addr| match &self.instances[&addr].info {
+                Some((info, at)) if info.primary.is_some() && at.elapsed() <= INFO_VALIDITY => {
+                    Some((info.offset, addr))
+                }
+                _ => None,
+            })
+            // The lowest address wins ties, as the last maximum is returned.
+            .max_by(|(a, a_addr), (b, b_addr)| a.cmp(b).then(b_addr.cmp(a_addr)))
+            .map(|(_, addr)| addr)
+    }
+
+    /// Make `addr` the primary, as decided by the failover of
+    /// `config_epoch`. The former primary becomes a replica.
+    pub(super) fn switch(&mut self, addr: Addr, config_epoch: u64) {
+        self.add_instance(addr.clone());
+        self.addr = addr;
+        self.config_epoch = config_epoch;
+        self.failover = None;
+        self.sdown = false;
+        self.odown = false;
+
+        for peer in self.sentinels.values_mut() {
+            peer.down = None;
+        }
+    }
+}
@@ -1,11 +1,12 @@
-use crate::{Command, Connection, Db, DbDropGuard, Shutdown};
+use crate::cluster::{command_keys, BUS_PORT_OFFSET};
+use crate::cmd::{check_slot, error_reply, Transaction};
+use crate::{Command, Connection, Db, DbDropGuard, Shutdown, WatchedKeys};
 
 use std::future::Future;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, mpsc, Semaphore};
 use tokio::time::{self, Duration};
-use tracing::field::debug;
 use tracing::{debug, error, info, instrument};
 
 /// Server listener state. Created in the `run` call. It includes a `run` method
@@ -57,6 +58,10 @@ struct Listener {
     /// `shutdown_complete_rx.recv()` completing with `None`. At this point, it
     /// is safe to exit the server process.
     shutdown_complete_tx: mpsc::Sender<()>,
+
+    /// Whether the server runs as a node of a cluster, passed to the
+    /// handlers.
+    cluster: bool,
 }
 
 /// Per-connection handler. Reads requests from `connection` and applies the
@@ -87,6 +92,14 @@ struct Handler {
     /// processed for the peer is continued until it reaches a safe state, at
     /// which point the connection is terminated.
     shutdown: Shutdown,
+    /// The transaction started by `MULTI`, if any. While it is set, commands
+    /// are queued instead of being applied.
+    transaction: Option<Transaction>,
+    /// The keys watched by `WATCH`, if any.
+    watched: Option<WatchedKeys>,
+    /// Whether the server runs as a node of a cluster, in which case commands
+    /// on keys of slots served by other nodes are redirected to them.
+    cluster: bool,
     /// Not used directly. Instead, when `Handler` is dropped...?
     _shutdown_complete: mpsc::Sender<()>,
 }
@@ -94,6 +107,17 @@ struct Handler {
 const MAX_CONNECTIONS: usize = 250;
 
 pub async fn run(listener: TcpListener, shutdown: impl Future) {
+    run_with_config(listener, shutdown, vec![]).await
+}
+
+/// Run the server like `run`, with the parameters `config` set beforehand as
+/// `CONFIG SET` would. Parameters that `CONFIG SET` rejects once the server
+/// runs, such as `appendonly`, are accepted.
+pub async fn run_with_config(
+    listener: TcpListener,
+    shutdown: impl Future,
+    config: Vec<(String, String)>,
+) {
     // When the provided `shutdown` future completes, we must send a shutdown
     // message to all active connections. We use a broadcast channel for this
     // purpose. The call below ignores the receiver of the broadcast pair, and when
@@ -103,13 +127,49 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
 
     let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
+    let db_holder = DbDropGuard::new();
+    for (name, value) in &config {
+        if let Err(err) = db_holder.db().lock().await.config_init(name, value) {
+            error!(cause = %err, "invalid configuration");
+            return;
+        }
+    }
+
+    // Load the keys saved by a previous run. Files that cannot be read would
+    // be overwritten, so the server does not start.
+    if let Err(err) = load(&db_holder.db()).await {
+        error!(cause = %err, "failed to load the data");
+        return;
+    }
+
+    // Replicas announce the port to their primary.
+    if let Ok(addr) = listener.local_addr() {
+        db_holder.db().lock().await.set_listening_port(addr.port());
+    }
+
+    let primary = db_holder.db().lock().await.configured_primary();
+    if let Some((host, port)) = primary {
+        db_holder.db().replicaof(host, port).await;
+    }
+
+    // The other nodes of the cluster connect to the cluster bus, on the port
+    // of the clients plus `BUS_PORT_OFFSET`.
+    let cluster = db_holder.db().lock().await.cluster_enabled();
+    if cluster {
+        if let Err(err) = enable_cluster(&db_holder.db(), &listener).await {
+            error!(cause = %err, "failed to listen on the cluster bus");
+            return;
+        }
+    }
+
     // Initialize the listener state
     let mut server = Listener {
         listener,
-        db_holder: DbDropGuard::new(),
+        db_holder,
         limit_connection: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
         notify_shutdown,
         shutdown_complete_tx,
+        cluster,
     };
 
     tokio::select! {
@@ -136,6 +196,65 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
     let _ = shutdown_complete_rx.recv().await;
 }
 
+/// Run `db` in cluster mode, as the node listening on `listener`. Nodes
+/// listening on every interface announce the loopback one.
+async fn enable_cluster(db: &Db, listener: &TcpListener) -> crate::Result<()> {
+    let addr = listener.local_addr()?;
+    let bus_port = addr
+        .port()
+        .checked_add(BUS_PORT_OFFSET)
+        .ok_or("no cluster bus port above the port")?;
+    let bus = TcpListener::bind((addr.ip(), bus_port)).await?;
+
+    let ip = if addr.ip().is_unspecified() {
+        "127.0.0.1".to_string()
+    } else {
+        addr.ip().to_string()
+    };
+    db.enable_cluster(ip, addr.port(), bus).await;
+    Ok(())
+}
+
+/// Load the keys saved by a previous run into `db`: by replaying the
+/// append-only file if `appendonly` is enabled, from the snapshot in `dir`
+/// otherwise, if there is one.
+/// The keys of the RDB file set with `rdb-import`, if any, are added next.
+async fn load(db: &Db) -> crate::Result<()> {
+    let mut db = db.lock().await;
+
+    match db.read_aof()? {
+        Some(frames) => {
+            let count = frames.len();
+            for frame in frames {
+                match Command::from_frame(frame)? {
+                    // The transactions of the file are complete, see
+                    // `aof::read`, and replayed all at once anyway.
+                    Command::Multi(_) | Command::Exec(_) => {}
+                    command => {
+                        command.execute(&mut db)?;
+                    }
+                }
+            }
+
+            // Commands executed from now on are logged.
+            db.open_aof()?;
+            info!(count, "replayed append-only file");
+        }
+        None => {
+            let count = db.load_snapshot()?;
+            info!(count, "loaded snapshot");
+        }
+    }
+
+    let entries = db.read_rdb_import()?;
+    if !entries.is_empty() {
+        let (count, skipped) = db.import_rdb(entries);
+        info!(count, skipped, "imported RDB file");
+    }
+
+    Ok(())
+}
+
 impl Listener {
     async fn run(&mut self) -> crate::Result<()> {
         info!("accepting inbound connections");
@@ -161,6 +280,9 @@ impl Listener {
                 connection: Connection::new(socket),
 
                 shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
+                transaction: None,
+                watched: None,
+                cluster: self.cluster,
                 _shutdown_complete: self.shutdown_complete_tx.clone(),
             };
 
@@ -217,12 +339,57 @@ impl Handler {
                 None => return Ok(()),
             };
 
-            let cmd = Command::from_frame(frame)?;
+            let keys = if self.cluster {
+                command_keys(&frame)
+            } else {
+                vec![]
+            };
+
+            let cmd = match Command::from_frame(frame) {
+                Ok(cmd) => cmd,
+                // A malformed command is replied with an error rather than
+                // closing the connection, and aborts the transaction being
+                // queued, if any.
+                Err(err) => {
+                    // `ASKING` applied to this command.
+                    self.connection.take_asking();
+                    let resp = match &mut self.transaction {
+                        Some(transaction) => transaction.reject(err),
+                        None => error_reply(err),
+                    };
+                    debug!(?resp);
+                    self.connection.write_frame(&resp).await?;
+                    continue;
+                }
+            };
 
             debug!(?cmd);
 
-            cmd.apply(&self.db, &mut self.connection, &mut self.shutdown)
-                .await?;
+            // Commands on keys this node does not serve are redirected, see
+            // `LockedDb::check_slot`. Commands are checked again when
+            // executed, along with the queued ones by `EXEC`.
+            match &mut self.transaction {
+                Some(transaction) if Transaction::queues(&cmd) => {
+                    let asking = self.connection.take_asking();
+                    let resp = match check_slot(&self.db, &keys, asking).await {
+                        Ok(()) => transaction.queue(cmd, keys, asking),
+                        Err(resp) => transaction.refuse(resp),
+                    };
+                    debug!(?resp);
+                    self.connection.write_frame(&resp).await?;
+                }
+                _ => {
+                    cmd.apply(
+                        &self.db,
+                        &mut self.connection,
+                        &mut self.shutdown,
+                        &mut self.transaction,
+                        &mut self.watched,
+                        keys,
+                    )
+                    .await?
+                }
+            }
         }
 
         Ok(())
@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use my_mini_redis::rdb::{self, Entry, Value};
+use my_mini_redis::Frame;
+
+/// An RDB file of version 11, as written by Redis 7.2, holding a key of each
+/// encoding `rdb::read` decodes: plain, integer and LZF compressed strings,
+/// listpack, ziplist and plain hashes, intset and listpack sets, a quicklist
+/// and sorted sets. Keys expire in 2100, except `expired`.
+const FIXTURE: &[u8] = include_bytes!("fixtures/encodings.rdb");
+
+fn bytes(value: &str) -> Bytes {
+    Bytes::copy_from_slice(value.as_bytes())
+}
+
+fn entry(key: &str, value: Value, expires_at: Option<u64>) -> Entry {
+    Entry {
+        db: 0,
+        key: bytes(key),
+        value,
+        expires_at,
+    }
+}
+
+fn pairs(fields: &[(&str, &str)]) -> Vec<(Bytes, Bytes)> {
+    fields
+        .iter()
+        .map(|(field, value)| (bytes(field), bytes(value)))
+        .collect()
+}
+
+#[test]
+fn written_entries_read_back() {
+    let long = "x".repeat(20000);
+    let entries = vec![
+        entry("string", Value::String(bytes("value")), None),
+        entry("empty", Value::String(Bytes::new()), None),
+        entry("long", Value::String(bytes(&long)), Some(4102444800000)),
+        entry(
+            "hash",
+            Value::Hash(pairs(&[("a", "1"), ("b", &long[..100])])),
+            Some(4102444800000),
+        ),
+        entry("list", Value::List(vec![bytes("a"), bytes("b")]), None),
+        entry("set", Value::Set(vec![bytes("x")]), None),
+        entry(
+            "zset",
+            Value::ZSet(vec![(bytes("m"), -1.5), (bytes("n"), f64::INFINITY)]),
+            None,
+        ),
+        Entry {
+            db: 3,
+            ..entry("other db", Value::String(bytes("3")), None)
+        },
+    ];
+
+    assert_eq!(rdb::read(&rdb::write(&entries)).unwrap(), entries);
+}
+
+#[test]
+fn corrupted_files_are_rejected() {
+    let mut data = rdb::write(&[entry("key", Value::String(bytes("value")), None)]);
+
+    let len = data.len();
+    assert!(rdb::read(&data[..len - 4]).is_err());
+
+    // The last byte before the checksum, in the value.
+    data[len - 10] ^= 1;
+    assert!(rdb::read(&data).is_err());
+    assert!(rdb::read(b"REDIS0012").is_err());
+    assert!(rdb::read(b"NOT AN RDB FILE").is_err());
+}
+
+#[test]
+fn fixture_encodings_are_decoded() {
+    let entries = rdb::read(FIXTURE).unwrap();
+    let keys: Vec<_> = entries.iter().map(|entry| entry.key.clone()).collect();
+    assert_eq!(keys.len(), 16, "{:?}", keys);
+
+    let get = |key: &str| {
+        entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .unwrap_or_else(|| panic!("missing key {}", key))
+    };
+
+    assert_eq!(get("string").value, Value::String(bytes("hello")));
+    assert_eq!(get("int8").value, Value::String(bytes("-5")));
+    assert_eq!(get("int16").value, Value::String(bytes("1234")));
+    assert_eq!(get("int32").value, Value::String(bytes("-123456")));
+    assert_eq!(get("lzf").value, Value::String(bytes("abcabcabcabc")));
+    assert_eq!(get("string").expires_at, None);
+    assert_eq!(get("expiring").expires_at, Some(4102444800000));
+    assert_eq!(get("expired").expires_at, Some(946684800000));
+
+    assert_eq!(
+        get("hash:listpack").value,
+        Value::Hash(pairs(&[("name", "ada"), ("year", "1815")]))
+    );
+    assert_eq!(get("hash:expiring").expires_at, Some(4102444800000));
+    assert_eq!(
+        get("hash:ziplist").value,
+        Value::Hash(pairs(&[("a", "1"), ("b", "-100"), ("c", "1000")]))
+    );
+    assert_eq!(get("hash:plain").value, Value::Hash(pairs(&[("f", "v")])));
+
+    assert_eq!(
+        get("set:intset").value,
+        Value::Set(vec![bytes("-1"), bytes("2"), bytes("300")])
+    );
+    assert_eq!(
+        get("set:listpack").value,
+        Value::Set(vec![bytes("x"), bytes("y"), bytes("5000")])
+    );
+    assert_eq!(
+        get("list").value,
+        Value::List(vec![bytes("a"), bytes("b"), bytes("plain node")])
+    );
+    assert_eq!(
+        get("zset:listpack").value,
+        Value::ZSet(vec![(bytes("m1"), 1.0), (bytes("m2"), 2.5)])
+    );
+    assert_eq!(get("zset").value, Value::ZSet(vec![(bytes("m"), -0.5)]));
+}
+
+#[test]
+fn expiring_hashes_are_restored() {
+    let command = |entry: Entry| match entry.into_command() {
+        Some(Frame::Array(frames)) => frames,
+        frame => panic!("unexpected command {:?}", frame),
+    };
+
+    let hash = Value::Hash(pairs(&[("f", "v")]));
+    assert_eq!(command(entry("hash", hash.clone(), None))[0], "hset");
+
+    let frames = command(entry("hash", hash, Some(4102444800000)));
+    assert_eq!(frames[0], "restore");
+    assert_eq!(frames[1], "hash");
+    assert!(matches!(frames[2], Frame::Int(0)));
+    assert_eq!(frames[4], "replace");
+
+    let list = Value::List(vec![bytes("a")]);
+    assert!(entry("list", list, None).into_command().is_none());
+}
@@ -0,0 +1,1153 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use my_mini_redis::server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+/// A command rejected while parsing is replied with an error, and the
+/// connection stays usable.
+#[tokio::test]
+async fn invalid_arguments_keep_the_connection_open() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // BF.RESERVE key 1.5 100
+    stream
+        .write_all(b"*4\r\n$10\r\nBF.RESERVE\r\n$3\r\nkey\r\n$3\r\n1.5\r\n$3\r\n100\r\n")
+        .await
+        .unwrap();
+
+    let expected = b"-ERR (0 < error rate range < 1)\r\n";
+    let mut response = [0; 33];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // BF.RESERVE key 0.01 0
+    stream
+        .write_all(b"*4\r\n$10\r\nBF.RESERVE\r\n$3\r\nkey\r\n$4\r\n0.01\r\n$1\r\n0\r\n")
+        .await
+        .unwrap();
+
+    let expected = b"-ERR (capacity should be larger than 0)\r\n";
+    let mut response = [0; 41];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // GET with a missing argument
+    stream.write_all(b"*1\r\n$3\r\nGET\r\n").await.unwrap();
+
+    let mut response = [0; 1];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"-", &response);
+    read_line(&mut stream).await;
+
+    // PING
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+
+    let mut response = [0; 7];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+PONG\r\n", &response);
+}
+
+/// Items a full non scaling filter rejects get an error in the reply, the
+/// items before them stay added.
+#[tokio::test]
+async fn bf_madd_reports_a_full_filter_per_item() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // BF.RESERVE filter 0.01 2 NONSCALING
+    stream
+        .write_all(
+            b"*5\r\n$10\r\nBF.RESERVE\r\n$6\r\nfilter\r\n$4\r\n0.01\r\n$1\r\n2\r\n\
+              $10\r\nNONSCALING\r\n",
+        )
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // BF.MADD filter a b c
+    stream
+        .write_all(b"*5\r\n$7\r\nBF.MADD\r\n$6\r\nfilter\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n")
+        .await
+        .unwrap();
+
+    let expected = b"*3\r\n:1\r\n:1\r\n-ERR non scaling filter is full\r\n";
+    let mut response = [0; 45];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // BF.MEXISTS filter a b c
+    stream
+        .write_all(b"*5\r\n$10\r\nBF.MEXISTS\r\n$6\r\nfilter\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n")
+        .await
+        .unwrap();
+
+    let expected = b"*3\r\n:1\r\n:1\r\n:0\r\n";
+    let mut response = [0; 16];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+}
+
+/// Sketches too large to allocate are refused, and the server keeps serving.
+#[tokio::test]
+async fn oversized_sketches_are_refused() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    send(&mut stream, &["CMS.INITBYPROB", "cms", "1e-300", "1e-300"]).await;
+    assert_eq!(read_line(&mut stream).await, "-ERR CMS: sketch too large");
+    send(
+        &mut stream,
+        &["CMS.INITBYDIM", "cms", "4294967296", "4294967296"],
+    )
+    .await;
+    assert_eq!(read_line(&mut stream).await, "-ERR CMS: sketch too large");
+    send(&mut stream, &["TOPK.RESERVE", "topk", "3000000000000"]).await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-ERR TopK: structure too large"
+    );
+    send(
+        &mut stream,
+        &[
+            "TOPK.RESERVE",
+            "topk",
+            "10",
+            "100000000",
+            "100000000",
+            "0.9",
+        ],
+    )
+    .await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-ERR TopK: structure too large"
+    );
+
+    send(&mut stream, &["CMS.INITBYPROB", "cms", "0.001", "0.01"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    send(&mut stream, &["TOPK.RESERVE", "topk", "10"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+}
+
+/// Compaction rules do not chain, which rules out cycles, and NaN is not a
+/// sample value.
+#[tokio::test]
+async fn ts_create_rule_refuses_chains() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    for key in ["a", "b", "c"] {
+        send(&mut stream, &["TS.CREATE", key]).await;
+        assert_eq!(read_line(&mut stream).await, "+OK");
+    }
+
+    send(
+        &mut stream,
+        &["TS.CREATERULE", "a", "b", "AGGREGATION", "sum", "10"],
+    )
+    .await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+
+    send(
+        &mut stream,
+        &["TS.CREATERULE", "b", "a", "AGGREGATION", "sum", "10"],
+    )
+    .await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-ERR TSDB: the destination key already has a dst rule"
+    );
+    send(
+        &mut stream,
+        &["TS.CREATERULE", "c", "a", "AGGREGATION", "sum", "10"],
+    )
+    .await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-ERR TSDB: the destination key already has a dst rule"
+    );
+    send(
+        &mut stream,
+        &["TS.CREATERULE", "c", "b", "AGGREGATION", "sum", "10"],
+    )
+    .await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-ERR TSDB: the destination key already has a src rule"
+    );
+    send(
+        &mut stream,
+        &["TS.CREATERULE", "b", "c", "AGGREGATION", "sum", "10"],
+    )
+    .await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-ERR TSDB: the source key already has a source rule"
+    );
+
+    send(&mut stream, &["TS.ADD", "a", "1", "nan"]).await;
+    assert_eq!(read_line(&mut stream).await, "-ERR TSDB: invalid value");
+    send(&mut stream, &["TS.MADD", "a", "1", "1", "a", "2", "NaN"]).await;
+    assert_eq!(read_line(&mut stream).await, "-ERR TSDB: invalid value");
+}
+
+/// Commands queued by MULTI run at once on EXEC, which replies with the
+/// array of their replies.
+#[tokio::test]
+async fn multi_exec_runs_queued_commands() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut other = TcpStream::connect(addr).await.unwrap();
+
+    // MULTI
+    stream.write_all(b"*1\r\n$5\r\nMULTI\r\n").await.unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // SET hello world
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 9];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+QUEUED\r\n", &response);
+
+    // GET hello
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 9];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+QUEUED\r\n", &response);
+
+    // Nothing ran yet, GET hello from another connection
+    other
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    other.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$-1\r\n", &response);
+
+    // EXEC
+    stream.write_all(b"*1\r\n$4\r\nEXEC\r\n").await.unwrap();
+
+    let expected = b"*2\r\n+OK\r\n$5\r\nworld\r\n";
+    let mut response = [0; 20];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // GET hello from the other connection
+    other
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 11];
+    other.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$5\r\nworld\r\n", &response);
+}
+
+/// A command rejected while queueing aborts the transaction: EXEC runs
+/// none of the queued commands.
+#[tokio::test]
+async fn exec_aborts_after_a_rejected_command() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // MULTI
+    stream.write_all(b"*1\r\n$5\r\nMULTI\r\n").await.unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // SET hello world
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 9];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+QUEUED\r\n", &response);
+
+    // GET with a missing argument
+    stream.write_all(b"*1\r\n$3\r\nGET\r\n").await.unwrap();
+
+    let mut response = [0; 1];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"-", &response);
+    read_line(&mut stream).await;
+
+    // EXEC
+    stream.write_all(b"*1\r\n$4\r\nEXEC\r\n").await.unwrap();
+
+    let expected = b"-EXECABORT Transaction discarded because of previous errors.\r\n";
+    let mut response = [0; 62];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // GET hello
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$-1\r\n", &response);
+}
+
+/// DISCARD drops the queued commands and leaves the transaction.
+#[tokio::test]
+async fn discard_drops_queued_commands() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // MULTI
+    stream.write_all(b"*1\r\n$5\r\nMULTI\r\n").await.unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // SET hello world
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 9];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+QUEUED\r\n", &response);
+
+    // DISCARD
+    stream.write_all(b"*1\r\n$7\r\nDISCARD\r\n").await.unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // GET hello, run right away
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$-1\r\n", &response);
+
+    // EXEC without MULTI
+    stream.write_all(b"*1\r\n$4\r\nEXEC\r\n").await.unwrap();
+
+    let expected = b"-ERR EXEC without MULTI\r\n";
+    let mut response = [0; 25];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+}
+
+/// EXEC replies with a null and runs nothing when a watched key was
+/// modified by another connection.
+#[tokio::test]
+async fn exec_fails_when_a_watched_key_changed() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut other = TcpStream::connect(addr).await.unwrap();
+
+    // WATCH hello
+    stream
+        .write_all(b"*2\r\n$5\r\nWATCH\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // SET hello other from the other connection
+    other
+        .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nother\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    other.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // MULTI, SET hello world, EXEC
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .await
+        .unwrap();
+
+    let expected = b"+OK\r\n+QUEUED\r\n$-1\r\n";
+    let mut response = [0; 19];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // GET hello
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 11];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$5\r\nother\r\n", &response);
+}
+
+/// EXEC runs the transaction when the watched keys are unchanged, and the
+/// keys are no longer watched afterwards.
+#[tokio::test]
+async fn exec_runs_when_watched_keys_are_unchanged() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut other = TcpStream::connect(addr).await.unwrap();
+
+    // WATCH hello, then SET other value from the other connection
+    stream
+        .write_all(b"*2\r\n$5\r\nWATCH\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    other
+        .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nother\r\n$5\r\nvalue\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    other.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // MULTI, SET hello world, EXEC
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .await
+        .unwrap();
+
+    let expected = b"+OK\r\n+QUEUED\r\n*1\r\n+OK\r\n";
+    let mut response = [0; 23];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // SET hello again from the other connection, then run a second
+    // transaction: EXEC dropped the watched keys.
+    other
+        .write_all(b"*3\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nagain\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    other.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *2\r\n$3\r\nGET\r\n$5\r\nhello\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .await
+        .unwrap();
+
+    let expected = b"+OK\r\n+QUEUED\r\n*1\r\n$5\r\nagain\r\n";
+    let mut response = [0; 29];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+}
+
+/// Expiring a watched key counts as a modification.
+#[tokio::test]
+async fn exec_fails_when_a_watched_key_expired() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // SET hello world PX 50
+    stream
+        .write_all(b"*5\r\n$3\r\nSET\r\n$5\r\nhello\r\n$5\r\nworld\r\n$2\r\nPX\r\n$2\r\n50\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    // WATCH hello
+    stream
+        .write_all(b"*2\r\n$5\r\nWATCH\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    time::sleep(Duration::from_millis(200)).await;
+
+    // MULTI, GET hello, EXEC
+    stream
+        .write_all(
+            b"*1\r\n$5\r\nMULTI\r\n\
+              *2\r\n$3\r\nGET\r\n$5\r\nhello\r\n\
+              *1\r\n$4\r\nEXEC\r\n",
+        )
+        .await
+        .unwrap();
+
+    let expected = b"+OK\r\n+QUEUED\r\n$-1\r\n";
+    let mut response = [0; 19];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+}
+
+/// Connections waiting for a running script do not block the server, so
+/// that SCRIPT KILL gets through and the waiting commands complete once the
+/// script is killed.
+#[tokio::test]
+async fn script_kill_reaches_a_busy_server() {
+    let addr = start_server_with_config(vec![("busy-reply-threshold", "100")]).await;
+
+    let mut script = TcpStream::connect(addr).await.unwrap();
+    let mut publisher = TcpStream::connect(addr).await.unwrap();
+    let mut watcher = TcpStream::connect(addr).await.unwrap();
+    let mut subscriber = TcpStream::connect(addr).await.unwrap();
+    let mut killer = TcpStream::connect(addr).await.unwrap();
+
+    // EVAL "while true do end" 0
+    script
+        .write_all(b"*3\r\n$4\r\nEVAL\r\n$17\r\nwhile true do end\r\n$1\r\n0\r\n")
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(50)).await;
+
+    // PUBLISH hello world, WATCH hello and SUBSCRIBE hello wait for the
+    // script.
+    publisher
+        .write_all(b"*3\r\n$7\r\nPUBLISH\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(10)).await;
+
+    watcher
+        .write_all(b"*2\r\n$5\r\nWATCH\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(10)).await;
+
+    subscriber
+        .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    // Past busy-reply-threshold, GET hello is refused.
+    time::sleep(Duration::from_millis(100)).await;
+    killer
+        .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    killer.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"-BUSY", &response);
+    read_line(&mut killer).await;
+
+    // SCRIPT KILL
+    killer
+        .write_all(b"*2\r\n$6\r\nSCRIPT\r\n$4\r\nKILL\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    killer.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    let expected = b"-ERR Script killed by user with SCRIPT KILL...\r\n";
+    let mut response = [0; 48];
+    script.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // The waiting commands complete, in order.
+    let mut response = [0; 4];
+    publisher.read_exact(&mut response).await.unwrap();
+    assert_eq!(b":0\r\n", &response);
+
+    let mut response = [0; 5];
+    watcher.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+OK\r\n", &response);
+
+    let expected = b"*3\r\n$9\r\nsubscribe\r\n$5\r\nhello\r\n:1\r\n";
+    let mut response = [0; 34];
+    subscriber.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+
+    // PING
+    killer.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+
+    let mut response = [0; 7];
+    killer.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"+PONG\r\n", &response);
+}
+
+/// The keys of the file set with `rdb-import` are loaded on startup, with
+/// their expirations, hashes that expire included.
+#[tokio::test]
+async fn rdb_import_loads_a_file_on_startup() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/encodings.rdb");
+    let addr = start_server_with_config(vec![("rdb-import", path)]).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // GET lzf
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nlzf\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 19];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$12\r\nabcabcabcabc\r\n", &response);
+
+    // HGET hash:expiring field
+    stream
+        .write_all(b"*3\r\n$4\r\nHGET\r\n$13\r\nhash:expiring\r\n$5\r\nfield\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 11];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$5\r\nvalue\r\n", &response);
+
+    // GET expired
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$7\r\nexpired\r\n")
+        .await
+        .unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b"$-1\r\n", &response);
+
+    // DBSIZE: the sets, list and sorted sets are skipped.
+    stream.write_all(b"*1\r\n$6\r\nDBSIZE\r\n").await.unwrap();
+
+    let mut response = [0; 5];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(b":10\r\n", &response);
+}
+
+/// A snapshot written by `SAVE` is loaded on startup, without save rules.
+#[tokio::test]
+async fn saved_snapshots_are_loaded_on_startup() {
+    let dir = test_dir();
+    let addr = start_server_with_config(vec![("dir", &dir)]).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    send(&mut stream, &["SET", "foo", "bar"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    send(&mut stream, &["SAVE"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+
+    let addr = start_server_with_config(vec![("dir", &dir)]).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    send(&mut stream, &["GET", "foo"]).await;
+    assert_eq!(read_line(&mut stream).await, "$3");
+    assert_eq!(read_line(&mut stream).await, "bar");
+}
+
+/// The commands of a transaction are logged and propagated between `MULTI`
+/// and `EXEC`. A last transaction missing its `EXEC` is not replayed.
+#[tokio::test]
+async fn transactions_are_logged_whole() {
+    let dir = test_dir();
+    let config = vec![("dir", &dir[..]), ("appendonly", "yes")];
+    let addr = start_server_with_config(config.clone()).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+    let resp = psync(&mut replica, "?", "-1").await;
+    assert!(resp.starts_with("+FULLRESYNC "), "{}", resp);
+    read_snapshot(&mut replica).await;
+
+    send(&mut stream, &["MULTI"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    for key in ["a", "b"] {
+        send(&mut stream, &["SET", key, "1"]).await;
+        assert_eq!(read_line(&mut stream).await, "+QUEUED");
+    }
+    send(&mut stream, &["EXEC"]).await;
+    assert_eq!(read_line(&mut stream).await, "*2");
+    read_line(&mut stream).await;
+    read_line(&mut stream).await;
+
+    let transaction = read_until(&mut replica, b"exec\r\n").await;
+    assert!(transaction.starts_with(b"*1\r\n$5\r\nmulti\r\n"));
+
+    let path = std::path::Path::new(&dir).join("appendonly.aof");
+    let logged = std::fs::read(&path).unwrap();
+    assert!(logged.ends_with(&transaction));
+
+    // A transaction cut off before its `EXEC`.
+    let mut cut = logged.clone();
+    cut.extend_from_slice(b"*1\r\n$5\r\nmulti\r\n*3\r\n$3\r\nset\r\n$1\r\nc\r\n$1\r\n1\r\n");
+    std::fs::write(&path, cut).unwrap();
+
+    let addr = start_server_with_config(config).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    send(&mut stream, &["DBSIZE"]).await;
+    assert_eq!(read_line(&mut stream).await, ":2");
+    assert_eq!(std::fs::read(&path).unwrap(), logged);
+}
+
+/// MIGRATE replies with the error of removing the keys once moved, such as
+/// `READONLY` on a replica.
+#[tokio::test]
+async fn migrate_reports_keys_it_cannot_remove() {
+    let target = start_server().await;
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/encodings.rdb");
+    // A replica of a primary that is never reached, keeping the keys of the
+    // imported file.
+    let replica =
+        start_server_with_config(vec![("rdb-import", path), ("replicaof", "127.0.0.1 1")]).await;
+
+    let mut stream = TcpStream::connect(replica).await.unwrap();
+    let port = target.port().to_string();
+    send(
+        &mut stream,
+        &["MIGRATE", "127.0.0.1", &port, "lzf", "0", "1000"],
+    )
+    .await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-READONLY You can't write against a read only replica."
+    );
+
+    // The key is on both servers.
+    for addr in [replica, target] {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        send(&mut stream, &["GET", "lzf"]).await;
+        assert_ne!(read_line(&mut stream).await, "$-1");
+    }
+}
+
+/// The directory files are written to cannot be changed once the server
+/// runs.
+#[tokio::test]
+async fn config_set_rejects_dir() {
+    let addr = start_server().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    // CONFIG SET dir /tmp
+    stream
+        .write_all(b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$3\r\ndir\r\n$4\r\n/tmp\r\n")
+        .await
+        .unwrap();
+
+    let expected =
+        b"-ERR CONFIG SET failed (possibly related to argument 'dir') - can't set immutable config\r\n";
+    let mut response = [0; 90];
+    stream.read_exact(&mut response).await.unwrap();
+    assert_eq!(expected, &response);
+}
+
+/// A replica reconnecting with the offset it reached continues from the
+/// backlog. Unknown histories and offsets get a full resynchronization.
+#[tokio::test]
+async fn psync_continues_from_the_backlog() {
+    let addr = start_server().await;
+
+    let mut writer = TcpStream::connect(addr).await.unwrap();
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+
+    let resp = psync(&mut replica, "?", "-1").await;
+    let words: Vec<&str> = resp.split(' ').collect();
+    assert_eq!(words[0], "+FULLRESYNC", "{}", resp);
+    let replid = words[1].to_string();
+    let offset: usize = words[2].parse().unwrap();
+    read_snapshot(&mut replica).await;
+
+    // SET key value1, propagated to the replica.
+    writer
+        .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$6\r\nvalue1\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_line(&mut writer).await, "+OK");
+
+    let propagated = read_until(&mut replica, b"value1\r\n").await;
+    let offset = offset + propagated.len();
+    drop(replica);
+
+    // SET key value2, missed by the replica.
+    writer
+        .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$6\r\nvalue2\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_line(&mut writer).await, "+OK");
+
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+    let resp = psync(&mut replica, &replid, &offset.to_string()).await;
+    assert_eq!(resp, format!("+CONTINUE {}", replid));
+
+    let missed = read_until(&mut replica, b"value2\r\n").await;
+    assert!(missed.starts_with(b"*3\r\n"));
+    assert_eq!(missed.len(), propagated.len());
+
+    // An offset ahead of the primary.
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+    let resp = psync(&mut replica, &replid, &(offset * 10).to_string()).await;
+    assert!(resp.starts_with("+FULLRESYNC "), "{}", resp);
+
+    // Another history.
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+    let other = "0".repeat(40);
+    let resp = psync(&mut replica, &other, &offset.to_string()).await;
+    assert!(
+        resp.starts_with(&format!("+FULLRESYNC {} ", replid)),
+        "{}",
+        resp
+    );
+}
+
+/// A replica whose queued bytes exceed the hard output buffer limit is
+/// disconnected.
+#[tokio::test]
+async fn replicas_exceeding_the_output_buffer_limit_are_disconnected() {
+    let addr =
+        start_server_with_config(vec![("client-output-buffer-limit", "replica 1000 0 0")]).await;
+
+    let mut writer = TcpStream::connect(addr).await.unwrap();
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+
+    let resp = psync(&mut replica, "?", "-1").await;
+    assert!(resp.starts_with("+FULLRESYNC "), "{}", resp);
+    read_snapshot(&mut replica).await;
+
+    // SET key <2000 bytes>
+    let value = "x".repeat(2000);
+    let cmd = format!(
+        "*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n${}\r\n{}\r\n",
+        value.len(),
+        value
+    );
+    writer.write_all(cmd.as_bytes()).await.unwrap();
+    assert_eq!(read_line(&mut writer).await, "+OK");
+
+    let mut buf = vec![];
+    let read = time::timeout(Duration::from_secs(5), replica.read_to_end(&mut buf));
+    read.await.unwrap().unwrap();
+    assert!(buf.is_empty());
+}
+
+/// A replica that does not acknowledge its offset within `repl-timeout` is
+/// disconnected.
+#[tokio::test]
+async fn silent_replicas_time_out() {
+    let addr = start_server_with_config(vec![("repl-timeout", "1")]).await;
+
+    let mut replica = TcpStream::connect(addr).await.unwrap();
+    let resp = psync(&mut replica, "?", "-1").await;
+    assert!(resp.starts_with("+FULLRESYNC "), "{}", resp);
+    read_snapshot(&mut replica).await;
+
+    let mut buf = vec![];
+    let read = time::timeout(Duration::from_secs(5), replica.read_to_end(&mut buf));
+    read.await.unwrap().unwrap();
+    assert!(buf.is_empty());
+}
+
+/// In cluster mode, commands on keys of slots served by another node are
+/// redirected to it with MOVED, and those of a slot being migrated with ASK
+/// once the keys are no longer here.
+#[tokio::test]
+async fn cluster_nodes_redirect_to_the_node_serving_the_slot() {
+    let a = start_cluster_node().await;
+    let b = start_cluster_node().await;
+
+    let mut a_stream = TcpStream::connect(a).await.unwrap();
+    let mut b_stream = TcpStream::connect(b).await.unwrap();
+
+    let a_id = myid(&mut a_stream).await;
+    let b_id = myid(&mut b_stream).await;
+
+    send(&mut a_stream, &["CLUSTER", "ADDSLOTSRANGE", "0", "8191"]).await;
+    assert_eq!(read_line(&mut a_stream).await, "+OK");
+    send(
+        &mut b_stream,
+        &["CLUSTER", "ADDSLOTSRANGE", "8192", "16383"],
+    )
+    .await;
+    assert_eq!(read_line(&mut b_stream).await, "+OK");
+    let b_port = b.port().to_string();
+    send(&mut a_stream, &["CLUSTER", "MEET", "127.0.0.1", &b_port]).await;
+    assert_eq!(read_line(&mut a_stream).await, "+OK");
+
+    // The slot of `foo`, 12182, is served by `b` once the nodes met.
+    let resp = time::timeout(Duration::from_secs(10), async {
+        loop {
+            send(&mut a_stream, &["GET", "foo"]).await;
+            let resp = read_line(&mut a_stream).await;
+            if !resp.starts_with("-CLUSTERDOWN") {
+                return resp;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+    assert_eq!(resp.await.unwrap(), format!("-MOVED 12182 {}", b));
+
+    // The slot of `bar`, 5061, moves from `a` to `b`.
+    send(
+        &mut a_stream,
+        &["CLUSTER", "SETSLOT", "5061", "MIGRATING", &b_id],
+    )
+    .await;
+    assert_eq!(read_line(&mut a_stream).await, "+OK");
+    send(
+        &mut b_stream,
+        &["CLUSTER", "SETSLOT", "5061", "IMPORTING", &a_id],
+    )
+    .await;
+    assert_eq!(read_line(&mut b_stream).await, "+OK");
+
+    send(&mut a_stream, &["GET", "bar"]).await;
+    assert_eq!(read_line(&mut a_stream).await, format!("-ASK 5061 {}", b));
+
+    // `b` serves the slot only to the command following ASKING.
+    send(&mut b_stream, &["GET", "bar"]).await;
+    assert_eq!(read_line(&mut b_stream).await, format!("-MOVED 5061 {}", a));
+    send(&mut b_stream, &["ASKING"]).await;
+    assert_eq!(read_line(&mut b_stream).await, "+OK");
+    send(&mut b_stream, &["GET", "bar"]).await;
+    assert_eq!(read_line(&mut b_stream).await, "$-1");
+    send(&mut b_stream, &["GET", "bar"]).await;
+    assert_eq!(read_line(&mut b_stream).await, format!("-MOVED 5061 {}", a));
+}
+
+/// A transaction whose queued commands act on keys of different slots is
+/// refused by EXEC, as no node serves all of them.
+#[tokio::test]
+async fn exec_rejects_keys_spanning_slots() {
+    let addr = start_cluster_node().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    send(&mut stream, &["CLUSTER", "ADDSLOTSRANGE", "0", "16383"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+
+    // Keys sharing a hashtag are in the same slot.
+    send(&mut stream, &["MULTI"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    send(&mut stream, &["SET", "{user}a", "1"]).await;
+    assert_eq!(read_line(&mut stream).await, "+QUEUED");
+    send(&mut stream, &["SET", "{user}b", "2"]).await;
+    assert_eq!(read_line(&mut stream).await, "+QUEUED");
+    send(&mut stream, &["EXEC"]).await;
+    assert_eq!(read_line(&mut stream).await, "*2");
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    assert_eq!(read_line(&mut stream).await, "+OK");
+
+    send(&mut stream, &["MULTI"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    send(&mut stream, &["SET", "foo", "1"]).await;
+    assert_eq!(read_line(&mut stream).await, "+QUEUED");
+    send(&mut stream, &["SET", "bar", "2"]).await;
+    assert_eq!(read_line(&mut stream).await, "+QUEUED");
+    send(&mut stream, &["EXEC"]).await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-CROSSSLOT Keys in request don't hash to the same slot"
+    );
+
+    // Nothing was executed.
+    send(&mut stream, &["GET", "foo"]).await;
+    assert_eq!(read_line(&mut stream).await, "$-1");
+}
+
+/// EXEC checks the slots of the queued keys again, as they may have moved
+/// since the commands were queued.
+#[tokio::test]
+async fn exec_checks_the_slots_again() {
+    let addr = start_cluster_node().await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut admin = TcpStream::connect(addr).await.unwrap();
+    send(&mut admin, &["CLUSTER", "ADDSLOTSRANGE", "0", "16383"]).await;
+    assert_eq!(read_line(&mut admin).await, "+OK");
+
+    send(&mut stream, &["MULTI"]).await;
+    assert_eq!(read_line(&mut stream).await, "+OK");
+    send(&mut stream, &["SET", "foo", "1"]).await;
+    assert_eq!(read_line(&mut stream).await, "+QUEUED");
+
+    // The slot of `foo`.
+    send(&mut admin, &["CLUSTER", "DELSLOTS", "12182"]).await;
+    assert_eq!(read_line(&mut admin).await, "+OK");
+
+    send(&mut stream, &["EXEC"]).await;
+    assert_eq!(
+        read_line(&mut stream).await,
+        "-CLUSTERDOWN The cluster is down"
+    );
+}
+
+async fn start_server() -> SocketAddr {
+    start_server_with_config(vec![]).await
+}
+
+/// Start a server with the parameters of `config` set. Unless `config` sets
+/// `dir`, the server gets an empty directory of its own, so that it does not
+/// load the files of another one.
+async fn start_server_with_config(config: Vec<(&str, &str)>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut config: Vec<_> = config
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    if !config.iter().any(|(name, _)| name == "dir") {
+        config.push(("dir".to_string(), test_dir()));
+    }
+    tokio::spawn(async move {
+        server::run_with_config(listener, tokio::signal::ctrl_c(), config).await
+    });
+
+    addr
+}
+
+/// Start a server in cluster mode, on a port leaving room for its cluster
+/// bus, `BUS_PORT_OFFSET` above it.
+async fn start_cluster_node() -> SocketAddr {
+    let listener = loop {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bus_free = port
+            .checked_add(10000)
+            .is_some_and(|bus_port| std::net::TcpListener::bind(("127.0.0.1", bus_port)).is_ok());
+        if bus_free {
+            break listener;
+        }
+    };
+    let addr = listener.local_addr().unwrap();
+
+    let config = vec![
+        ("cluster-enabled".to_string(), "yes".to_string()),
+        ("dir".to_string(), test_dir()),
+    ];
+    tokio::spawn(async move {
+        server::run_with_config(listener, tokio::signal::ctrl_c(), config).await
+    });
+
+    addr
+}
+
+/// Create an empty directory for the files of a server.
+fn test_dir() -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "mini-redis-test-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_str().unwrap().to_string()
+}
+
+/// Read the rest of a line, CRLF included, and return it without the CRLF.
+async fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = vec![];
+    let mut byte = [0; 1];
+    while byte != *b"\n" {
+        stream.read_exact(&mut byte).await.unwrap();
+        line.push(byte[0]);
+    }
+
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).unwrap()
+}
+
+/// Read from `stream` until the bytes read end with `end`, and return them.
+async fn read_until(stream: &mut TcpStream, end: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    let mut byte = [0; 1];
+    while !buf.ends_with(end) {
+        stream.read_exact(&mut byte).await.unwrap();
+        buf.push(byte[0]);
+    }
+    buf
+}
+
+/// Send `PSYNC replid offset` on `stream`, and return the reply line.
+async fn psync(stream: &mut TcpStream, replid: &str, offset: &str) -> String {
+    let cmd = format!(
+        "*3\r\n$5\r\nPSYNC\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+        replid.len(),
+        replid,
+        offset.len(),
+        offset
+    );
+    stream.write_all(cmd.as_bytes()).await.unwrap();
+    read_line(stream).await
+}
+
+/// Read the snapshot following `+FULLRESYNC`.
+async fn read_snapshot(stream: &mut TcpStream) {
+    let len: usize = read_line(stream).await[1..].parse().unwrap();
+    let mut snapshot = vec![0; len + 2];
+    stream.read_exact(&mut snapshot).await.unwrap();
+}
+
+/// Send the command made of `args` on `stream`.
+async fn send(stream: &mut TcpStream, args: &[&str]) {
+    let mut cmd = format!("*{}\r\n", args.len());
+    for arg in args {
+        cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.write_all(cmd.as_bytes()).await.unwrap();
+}
+
+/// Return the ID of the node `stream` is connected to.
+async fn myid(stream: &mut TcpStream) -> String {
+    send(stream, &["CLUSTER", "MYID"]).await;
+    read_line(stream).await;
+    read_line(stream).await
+}